@@ -2,10 +2,18 @@ use aes::{
     cipher::{generic_array, BlockDecrypt, BlockEncrypt, KeyInit},
     Aes256,
 };
-use anyhow::Result;
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, Result};
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use hkdf::Hkdf;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use serde_json::{ser::Formatter, Map, Value};
+use sha2::Sha256;
+use std::io;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::error::AppError;
@@ -18,86 +26,322 @@ pub struct ProxyData {
     pub response_headers: Option<serde_json::Value>,
     pub exp: Option<u64>,
     pub ip: Option<String>,
+    /// Alternative destinations serving the same content, tried in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<String>,
 }
 
+impl ProxyData {
+    /// A string query parameter carried in the proxy data.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query_params
+            .as_ref()
+            .and_then(|params| params.get(name))
+            .and_then(Value::as_str)
+    }
+
+    /// Proxy data from the flat parameters of a Python MediaFlow Proxy token:
+    /// the destination in `d`, request and response headers prefixed with `h_`
+    /// and `r_`, and `exp`/`ip` next to the other query parameters.
+    pub fn from_python_params(mut params: Map<String, Value>) -> Result<Self, AppError> {
+        let destination = match params.get("d") {
+            Some(Value::String(destination)) => destination.clone(),
+            _ => return Err(AppError::Auth("Token has no destination".to_string())),
+        };
+        let exp = match params.remove("exp") {
+            // Python may have written the timestamp as a float
+            Some(exp) => Some(
+                exp.as_f64()
+                    .ok_or_else(|| AppError::Auth("Invalid token expiration".to_string()))?
+                    as u64,
+            ),
+            None => None,
+        };
+        let ip = params
+            .remove("ip")
+            .and_then(|ip| ip.as_str().map(str::to_string));
+        let prefixed = |prefix: &str| -> Map<String, Value> {
+            params
+                .iter()
+                .filter_map(|(k, v)| {
+                    k.strip_prefix(prefix)
+                        .map(|name| (name.to_string(), v.clone()))
+                })
+                .collect()
+        };
+
+        Ok(Self {
+            destination,
+            request_headers: Some(Value::Object(prefixed("h_"))),
+            response_headers: Some(Value::Object(prefixed("r_"))),
+            query_params: Some(Value::Object(params)),
+            exp,
+            ip,
+            mirrors: Vec::new(),
+        })
+    }
+}
+
+/// Version byte prefixed to tokens in the current format.
+const TOKEN_VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+const KEY_SALT: &[u8] = b"mediaflow-proxy-light";
+const KEY_INFO: &[u8] = b"token-v1 aes-256-gcm";
+
+/// The ciphers derived from one API password.
 #[derive(Clone)]
-pub struct EncryptionHandler {
-    cipher: Aes256,
+struct TokenKey {
+    aead: Aes256Gcm,
+    legacy: Aes256,
+    python: PythonTokenCodec,
 }
 
-impl EncryptionHandler {
-    pub fn new(api_password: &[u8]) -> Result<Self> {
-        let key = if api_password.len() < 32 {
-            let mut padded = [0u8; 32];
-            padded[..api_password.len()].copy_from_slice(api_password);
-            padded
-        } else {
-            let mut truncated = [0u8; 32];
-            truncated.copy_from_slice(&api_password[..32]);
-            truncated
-        };
+impl TokenKey {
+    fn new(api_password: &[u8]) -> Result<Self> {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(KEY_SALT), api_password)
+            .expand(KEY_INFO, &mut key)
+            .map_err(|e| anyhow!("Failed to derive token key: {}", e))?;
 
         Ok(Self {
-            cipher: Aes256::new(&key.into()),
+            aead: Aes256Gcm::new(&key.into()),
+            legacy: Aes256::new(&legacy_key(api_password).into()),
+            python: PythonTokenCodec::new(api_password),
         })
     }
+}
 
-    pub fn encrypt(&self, data: &ProxyData) -> Result<String, AppError> {
-        // Serialize the data to JSON
-        let json_data = serde_json::to_vec(data)
-            .map_err(|e| AppError::Internal(format!("Failed to serialize proxy data: {}", e)))?;
+/// The key of legacy tokens: the password zero-padded or truncated to 32 bytes.
+fn legacy_key(api_password: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    let len = api_password.len().min(32);
+    key[..len].copy_from_slice(&api_password[..len]);
+    key
+}
 
-        // Generate random IV
+/// The token codec of the Python MediaFlow Proxy's `EncryptionHandler`.
+///
+/// Tokens are `base64url(IV || AES-256-CBC(PKCS#7(json.dumps(params))))`, keyed with
+/// the UTF-8 password padded with spaces or truncated to 32 bytes. Encoding writes the
+/// JSON the way Python's `json.dumps` does, with the keys in sorted order, so with the
+/// same IV both implementations produce identical tokens for dicts whose keys are sorted.
+#[derive(Clone)]
+pub struct PythonTokenCodec {
+    cipher: Aes256,
+}
+
+impl PythonTokenCodec {
+    pub fn new(api_password: &[u8]) -> Self {
+        let mut key = [b' '; 32];
+        let len = api_password.len().min(32);
+        key[..len].copy_from_slice(&api_password[..len]);
+
+        Self {
+            cipher: Aes256::new(&key.into()),
+        }
+    }
+
+    pub fn encode(&self, params: &Map<String, Value>) -> Result<String, AppError> {
         let mut iv = [0u8; 16];
         rand::rng().fill_bytes(&mut iv);
+        self.encode_with_iv(params, iv)
+    }
 
-        // Pad the data
-        let padded_data = self.pad_data(&json_data);
+    /// Encode `params`, keys in sorted order, with a caller-chosen IV.
+    pub fn encode_with_iv(
+        &self,
+        params: &Map<String, Value>,
+        iv: [u8; 16],
+    ) -> Result<String, AppError> {
+        let mut json_data = Vec::new();
+        params
+            .serialize(&mut serde_json::Serializer::with_formatter(
+                &mut json_data,
+                PythonJsonFormatter,
+            ))
+            .map_err(AppError::SerdeJsonError)?;
 
-        // Encrypt the data
-        let mut encrypted_data = padded_data.clone();
+        let mut encrypted_data = pad_data(&json_data);
+        let mut previous = iv;
         for chunk in encrypted_data.chunks_mut(16) {
-            let block = generic_array::GenericArray::from_mut_slice(chunk);
-            self.cipher.encrypt_block(block);
+            chunk.iter_mut().zip(previous).for_each(|(b, p)| *b ^= p);
+            self.cipher
+                .encrypt_block(generic_array::GenericArray::from_mut_slice(chunk));
+            previous.copy_from_slice(chunk);
         }
 
-        // Combine IV and encrypted data
-        let mut final_data = Vec::with_capacity(iv.len() + encrypted_data.len());
-        final_data.extend_from_slice(&iv);
+        let mut final_data = iv.to_vec();
         final_data.extend_from_slice(&encrypted_data);
-
-        // Encode to base64
         Ok(URL_SAFE.encode(final_data))
     }
 
-    pub fn decrypt(&self, token: &str, client_ip: Option<&str>) -> Result<ProxyData, AppError> {
-        // Decode base64
+    pub fn decode(&self, token: &str) -> Result<Map<String, Value>, AppError> {
         let encrypted_data = URL_SAFE
             .decode(token)
             .map_err(|e| AppError::Auth(format!("Invalid token format: {}", e)))?;
+        self.open(&encrypted_data)
+    }
 
-        if encrypted_data.len() < 16 {
-            return Err(AppError::Auth("Token too short".to_string()));
+    fn open(&self, data: &[u8]) -> Result<Map<String, Value>, AppError> {
+        if data.len() < 32 || !data.len().is_multiple_of(16) {
+            return Err(AppError::Auth("Invalid token length".to_string()));
         }
 
-        // Split IV and data
-        let (_iv, encrypted) = encrypted_data.split_at(16);
+        let (iv, encrypted) = data.split_at(16);
         let mut decrypted = encrypted.to_vec();
+        let mut previous: &[u8] = iv;
+        for (chunk, ciphertext) in decrypted.chunks_mut(16).zip(encrypted.chunks(16)) {
+            self.cipher
+                .decrypt_block(generic_array::GenericArray::from_mut_slice(chunk));
+            chunk.iter_mut().zip(previous).for_each(|(b, p)| *b ^= p);
+            previous = ciphertext;
+        }
+
+        let unpadded_data =
+            unpad_data(&decrypted).map_err(|_| AppError::Auth("Invalid padding".to_string()))?;
+        serde_json::from_slice(&unpadded_data)
+            .map_err(|e| AppError::Auth(format!("Invalid token data: {}", e)))
+    }
+}
+
+/// Writes JSON like Python's `json.dumps` defaults: `", "` and `": "` separators
+/// and everything outside printable ASCII escaped as `\uXXXX`.
+struct PythonJsonFormatter;
+
+impl Formatter for PythonJsonFormatter {
+    fn begin_array_value<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
+        }
+    }
 
-        // Decrypt the data
-        for chunk in decrypted.chunks_mut(16) {
-            let block = generic_array::GenericArray::from_mut_slice(chunk);
-            self.cipher.decrypt_block(block);
+    fn begin_object_key<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
         }
+    }
 
-        // Unpad the data
-        let unpadded_data = self
-            .unpad_data(&decrypted)
-            .map_err(|_| AppError::Auth("Invalid padding".to_string()))?;
+    fn begin_object_value<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(b": ")
+    }
 
-        // Deserialize the JSON data
-        let proxy_data: ProxyData = serde_json::from_slice(&unpadded_data)
-            .map_err(|e| AppError::Auth(format!("Invalid token data: {}", e)))?;
+    fn write_string_fragment<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        fragment: &str,
+    ) -> io::Result<()> {
+        for c in fragment.chars() {
+            if (' '..='~').contains(&c) {
+                writer.write_all(&[c as u8])?;
+            } else {
+                let mut units = [0u16; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    write!(writer, "\\u{:04x}", unit)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Encrypts proxy data into URL tokens and back.
+///
+/// Tokens are `version || nonce || AES-256-GCM ciphertext`, base64url encoded, with
+/// the key derived from the API password through HKDF-SHA256. The first key encrypts;
+/// any key decrypts, which allows rotating the password. Tokens from before the
+/// versioned format (AES-256 blocks encrypted independently, without integrity
+/// check) are still accepted unless disabled with [`with_legacy_tokens`](Self::with_legacy_tokens),
+/// and tokens of the Python MediaFlow Proxy once enabled with
+/// [`with_python_tokens`](Self::with_python_tokens).
+#[derive(Clone)]
+pub struct EncryptionHandler {
+    keys: Vec<TokenKey>,
+    legacy_tokens: bool,
+    python_tokens: bool,
+}
+
+impl EncryptionHandler {
+    pub fn new(api_password: &[u8]) -> Result<Self> {
+        Ok(Self {
+            keys: vec![TokenKey::new(api_password)?],
+            legacy_tokens: true,
+            python_tokens: false,
+        })
+    }
+
+    /// Also decrypt tokens issued with any of `api_passwords`.
+    pub fn with_previous_keys<P: AsRef<[u8]>>(mut self, api_passwords: &[P]) -> Result<Self> {
+        for api_password in api_passwords {
+            self.keys.push(TokenKey::new(api_password.as_ref())?);
+        }
+        Ok(self)
+    }
+
+    pub fn with_legacy_tokens(mut self, enabled: bool) -> Self {
+        self.legacy_tokens = enabled;
+        self
+    }
+
+    pub fn with_python_tokens(mut self, enabled: bool) -> Self {
+        self.python_tokens = enabled;
+        self
+    }
+
+    pub fn encrypt(&self, data: &ProxyData) -> Result<String, AppError> {
+        // Serialize the data to JSON
+        let json_data = serde_json::to_vec(data)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize proxy data: {}", e)))?;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::rng().fill_bytes(&mut nonce);
+
+        // The version byte is authenticated along with the data
+        let encrypted_data = self.keys[0]
+            .aead
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &json_data,
+                    aad: &[TOKEN_VERSION],
+                },
+            )
+            .map_err(|_| AppError::Internal("Failed to encrypt proxy data".to_string()))?;
+
+        let mut final_data = Vec::with_capacity(1 + NONCE_LEN + encrypted_data.len());
+        final_data.push(TOKEN_VERSION);
+        final_data.extend_from_slice(&nonce);
+        final_data.extend_from_slice(&encrypted_data);
+
+        // Encode to base64
+        Ok(URL_SAFE.encode(final_data))
+    }
+
+    pub fn decrypt(&self, token: &str, client_ip: Option<&str>) -> Result<ProxyData, AppError> {
+        // Decode base64
+        let encrypted_data = URL_SAFE
+            .decode(token)
+            .map_err(|e| AppError::Auth(format!("Invalid token format: {}", e)))?;
+
+        let proxy_data = match self.open(&encrypted_data) {
+            Some(json_data) => serde_json::from_slice(&json_data)
+                .map_err(|e| AppError::Auth(format!("Invalid token data: {}", e)))?,
+            None => self
+                .open_legacy(&encrypted_data)
+                .or_else(|| self.open_python(&encrypted_data))
+                .ok_or_else(|| AppError::Auth("Invalid token".to_string()))?,
+        };
 
         // Validate expiration if set
         if let Some(exp) = proxy_data.exp {
@@ -120,30 +364,84 @@ impl EncryptionHandler {
         Ok(proxy_data)
     }
 
-    fn pad_data(&self, data: &[u8]) -> Vec<u8> {
-        let block_size = 16;
-        let padding_len = block_size - (data.len() % block_size);
-        let mut padded = Vec::with_capacity(data.len() + padding_len);
-        padded.extend_from_slice(data);
-        padded.extend(std::iter::repeat(padding_len as u8).take(padding_len));
-        padded
+    /// Decrypt a versioned token with the first key that authenticates it.
+    fn open(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let (&version, data) = data.split_first()?;
+        if version != TOKEN_VERSION || data.len() < NONCE_LEN {
+            return None;
+        }
+
+        let (nonce, encrypted) = data.split_at(NONCE_LEN);
+        self.keys.iter().find_map(|key| {
+            key.aead
+                .decrypt(
+                    Nonce::from_slice(nonce),
+                    Payload {
+                        msg: encrypted,
+                        aad: &[TOKEN_VERSION],
+                    },
+                )
+                .ok()
+        })
     }
 
-    fn unpad_data(&self, data: &[u8]) -> Result<Vec<u8>, ()> {
-        if data.is_empty() {
-            return Err(());
+    /// Decrypt a legacy token, `IV || AES-256 blocks`, whose IV was never used.
+    ///
+    /// Without authentication the right key can only be told by the result
+    /// parsing as proxy data.
+    fn open_legacy(&self, data: &[u8]) -> Option<ProxyData> {
+        if !self.legacy_tokens || data.len() < 32 || !data.len().is_multiple_of(16) {
+            return None;
         }
 
-        let padding_len = *data.last().ok_or(())? as usize;
-        if padding_len == 0 || padding_len > 16 || padding_len > data.len() {
-            return Err(());
-        }
+        let (_iv, encrypted) = data.split_at(16);
+        self.keys.iter().find_map(|key| {
+            let mut decrypted = encrypted.to_vec();
+            for chunk in decrypted.chunks_mut(16) {
+                let block = generic_array::GenericArray::from_mut_slice(chunk);
+                key.legacy.decrypt_block(block);
+            }
+            let unpadded_data = unpad_data(&decrypted).ok()?;
+            serde_json::from_slice(&unpadded_data).ok()
+        })
+    }
 
-        let unpadded_len = data.len() - padding_len;
-        if !data[unpadded_len..].iter().all(|&x| x == padding_len as u8) {
-            return Err(());
+    /// Decrypt a token issued by the Python MediaFlow Proxy.
+    fn open_python(&self, data: &[u8]) -> Option<ProxyData> {
+        if !self.python_tokens {
+            return None;
         }
 
-        Ok(data[..unpadded_len].to_vec())
+        self.keys.iter().find_map(|key| {
+            let params = key.python.open(data).ok()?;
+            ProxyData::from_python_params(params).ok()
+        })
+    }
+}
+
+fn pad_data(data: &[u8]) -> Vec<u8> {
+    let block_size = 16;
+    let padding_len = block_size - (data.len() % block_size);
+    let mut padded = Vec::with_capacity(data.len() + padding_len);
+    padded.extend_from_slice(data);
+    padded.extend(std::iter::repeat_n(padding_len as u8, padding_len));
+    padded
+}
+
+fn unpad_data(data: &[u8]) -> Result<Vec<u8>, ()> {
+    if data.is_empty() {
+        return Err(());
+    }
+
+    let padding_len = *data.last().ok_or(())? as usize;
+    if padding_len == 0 || padding_len > 16 || padding_len > data.len() {
+        return Err(());
     }
+
+    let unpadded_len = data.len() - padding_len;
+    if !data[unpadded_len..].iter().all(|&x| x == padding_len as u8) {
+        return Err(());
+    }
+
+    Ok(data[..unpadded_len].to_vec())
 }
@@ -0,0 +1,261 @@
+use futures::StreamExt;
+use regex::Regex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+use crate::{
+    config::{ApiKeyConfig, AuthConfig, Endpoint},
+    error::{AppError, AppResult},
+    proxy::{stream::ByteStream, throttle::Throttle},
+};
+
+impl Endpoint {
+    /// The endpoint group a request path belongs to, if it is scoped at all.
+    pub fn from_path(path: &str) -> Option<Self> {
+        if path.starts_with("/admin/") {
+            return Some(Self::Admin);
+        }
+        let path = path.strip_prefix("/proxy")?;
+        if path == "/stream" {
+            Some(Self::Stream)
+        } else if path.starts_with("/hls/") {
+            Some(Self::Hls)
+        } else if path.starts_with("/mpd/") {
+            Some(Self::Mpd)
+        } else if path == "/generate_url" {
+            Some(Self::GenerateUrl)
+        } else if path == "/ip" {
+            Some(Self::Ip)
+        } else {
+            None
+        }
+    }
+}
+
+/// An API key with its scopes and quotas.
+///
+/// The key that authenticated a request is stored in the request extensions
+/// as an `Arc<ApiKey>`, next to the `ProxyData`.
+pub struct ApiKey {
+    label: String,
+    secret: String,
+    endpoints: Vec<Endpoint>,
+    hosts: Vec<Regex>,
+    expires_at: Option<u64>,
+    max_streams: Option<usize>,
+    bandwidth: Option<Arc<Throttle>>,
+    /// Shared with the key it replaces on reload, as the streams stay open
+    active_streams: Arc<AtomicUsize>,
+    unrestricted: bool,
+}
+
+impl ApiKey {
+    fn from_config(config: &ApiKeyConfig) -> Self {
+        let hosts = config
+            .hosts
+            .iter()
+            .filter_map(|pattern| {
+                let regex = format!("(?i)^{}$", regex::escape(pattern).replace("\\*", ".*"));
+                Regex::new(&regex)
+                    .inspect_err(|e| {
+                        tracing::error!(
+                            "Invalid host pattern '{}' for key '{}': {}",
+                            pattern,
+                            config.label,
+                            e
+                        )
+                    })
+                    .ok()
+            })
+            .collect();
+
+        Self {
+            label: config.label.clone(),
+            secret: config.key.clone(),
+            endpoints: config.endpoints.clone(),
+            hosts,
+            expires_at: config.expires_at,
+            max_streams: config.max_streams,
+            bandwidth: config
+                .max_bandwidth
+                .map(|rate| Arc::new(Throttle::new(rate))),
+            active_streams: Arc::default(),
+            unrestricted: false,
+        }
+    }
+
+    /// A key with access to everything, for `api_password` and the passwords being rotated out.
+    fn unrestricted(label: &str, secret: &str) -> Self {
+        let key = Self::from_config(&ApiKeyConfig {
+            label: label.to_string(),
+            key: secret.to_string(),
+            endpoints: Vec::new(),
+            hosts: Vec::new(),
+            expires_at: None,
+            max_streams: None,
+            max_bandwidth: None,
+        });
+        Self {
+            unrestricted: true,
+            ..key
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn active_streams(&self) -> usize {
+        self.active_streams.load(Ordering::Relaxed)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Check that the key may use `endpoint` to reach `destination`.
+    pub fn authorize(
+        &self,
+        endpoint: Option<Endpoint>,
+        destination: Option<&str>,
+    ) -> AppResult<()> {
+        if self.is_expired() {
+            return Err(AppError::Auth(format!(
+                "API key '{}' has expired",
+                self.label
+            )));
+        }
+        if let Some(endpoint) = endpoint {
+            let allowed = if self.endpoints.is_empty() {
+                endpoint != Endpoint::Admin || self.unrestricted
+            } else {
+                self.endpoints.contains(&endpoint)
+            };
+            if !allowed {
+                return Err(AppError::Forbidden(format!(
+                    "API key '{}' may not use this endpoint",
+                    self.label
+                )));
+            }
+        }
+        if let Some(destination) = destination.filter(|_| !self.hosts.is_empty()) {
+            let host = Url::parse(destination)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+                .unwrap_or_default();
+            if !self.hosts.iter().any(|pattern| pattern.is_match(&host)) {
+                return Err(AppError::Forbidden(format!(
+                    "API key '{}' may not access {}",
+                    self.label, host
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reserve one of the key's concurrent streams for as long as the returned quota lives.
+    pub fn open_stream(self: &Arc<Self>) -> AppResult<StreamQuota> {
+        let active = self.active_streams.fetch_add(1, Ordering::SeqCst);
+        let quota = StreamQuota { key: self.clone() };
+        if self
+            .max_streams
+            .is_some_and(|max_streams| active >= max_streams)
+        {
+            return Err(AppError::QuotaExceeded(format!(
+                "API key '{}' already has {} open streams",
+                self.label, active
+            )));
+        }
+        Ok(quota)
+    }
+}
+
+/// A stream counted against the quotas of an API key.
+pub struct StreamQuota {
+    key: Arc<ApiKey>,
+}
+
+impl StreamQuota {
+    /// The bandwidth shared by all streams of the key, if limited.
+    pub fn bandwidth(&self) -> Option<Arc<Throttle>> {
+        self.key.bandwidth.clone()
+    }
+
+    /// Hold the stream slot until `stream` ends.
+    pub fn apply(self, mut stream: ByteStream) -> ByteStream {
+        Box::pin(async_stream::stream! {
+            let _quota = self;
+            while let Some(chunk) = stream.next().await {
+                yield chunk;
+            }
+        })
+    }
+}
+
+impl Drop for StreamQuota {
+    fn drop(&mut self) {
+        self.key.active_streams.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// All API keys accepted by the proxy.
+#[derive(Default)]
+pub struct Keyring {
+    keys: Vec<Arc<ApiKey>>,
+}
+
+impl Keyring {
+    /// The configured keys, preceded by `api_password` and the previous passwords
+    /// as unrestricted keys.
+    pub fn from_config(auth: &AuthConfig) -> Self {
+        Self::reloaded(auth, &Self::default())
+    }
+
+    /// Like [`from_config`](Self::from_config), with the keys that `previous`
+    /// had under the same secret still counting the streams they have open.
+    pub fn reloaded(auth: &AuthConfig, previous: &Keyring) -> Self {
+        let mut keys = Vec::new();
+        if !auth.api_password.is_empty() {
+            keys.push(ApiKey::unrestricted("default", &auth.api_password));
+        }
+        for password in &auth.previous_passwords {
+            keys.push(ApiKey::unrestricted("previous", password));
+        }
+        keys.extend(
+            auth.keys
+                .iter()
+                .filter(|config| !config.key.is_empty())
+                .map(ApiKey::from_config),
+        );
+
+        for key in &mut keys {
+            if let Some(previous) = previous.find(&key.secret) {
+                key.active_streams = previous.active_streams.clone();
+            }
+        }
+
+        Self {
+            keys: keys.into_iter().map(Arc::new).collect(),
+        }
+    }
+
+    /// Without keys, authentication is disabled.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn find(&self, secret: &str) -> Option<Arc<ApiKey>> {
+        self.keys.iter().find(|key| key.secret == secret).cloned()
+    }
+
+    /// The secrets that tokens may be encrypted with, `api_password` first.
+    pub fn secrets(&self) -> impl Iterator<Item = &str> {
+        self.keys.iter().map(|key| key.secret.as_str())
+    }
+}
@@ -7,36 +7,127 @@ use futures::future::LocalBoxFuture;
 use serde_json::Value;
 use std::future::{ready, Ready};
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 use crate::auth::encryption::{EncryptionHandler, ProxyData};
+use crate::auth::keyring::Keyring;
+use crate::config::{AuthConfig, Endpoint};
 use crate::error::AppError;
+use crate::models::request::FETCH_URL_PARAMS;
+use crate::proxy::mpd::{self, TEMPLATE_PARAM_PREFIX};
 
 const OPEN_ENDPOINTS: &[&str] = &["/proxy/generate_url", "/health"];
 
+/// The keys accepted by the middleware and the handler decrypting tokens with them.
+struct AuthState {
+    encryption_handler: Option<Arc<EncryptionHandler>>,
+    keyring: Arc<Keyring>,
+}
+
+impl AuthState {
+    fn from_config(auth: &AuthConfig, previous: &Keyring) -> Result<Self, AppError> {
+        let keyring = Keyring::reloaded(auth, previous);
+        // Tokens may have been encrypted with any of the keys
+        let secrets: Vec<&str> = keyring.secrets().collect();
+        let encryption_handler = secrets
+            .split_first()
+            .map(|(primary, others)| {
+                EncryptionHandler::new(primary.as_bytes())
+                    .and_then(|handler| handler.with_previous_keys(others))
+                    .map(|handler| {
+                        Arc::new(
+                            handler
+                                .with_legacy_tokens(auth.legacy_tokens)
+                                .with_python_tokens(auth.python_tokens),
+                        )
+                    })
+                    .map_err(|e| {
+                        AppError::Internal(format!("Failed to create encryption handler: {}", e))
+                    })
+            })
+            .transpose()?;
+
+        Ok(Self {
+            encryption_handler,
+            keyring: Arc::new(keyring),
+        })
+    }
+}
+
+/// Authenticates requests with `api_password`, the API keys or tokens.
+///
+/// Clones share their keys, which [`reload`](Self::reload) swaps for all of them.
 #[derive(Clone)]
 pub struct AuthMiddleware {
-    encryption_handler: Option<Arc<EncryptionHandler>>,
-    api_password: String,
+    state: Arc<RwLock<Arc<AuthState>>>,
 }
 
 impl AuthMiddleware {
-    pub fn new(api_password: String) -> Self {
-        let encryption_handler = if !api_password.is_empty() {
-            Some(Arc::new(
-                EncryptionHandler::new(api_password.as_bytes())
-                    .expect("Failed to create encryption handler"),
-            ))
-        } else {
-            None
-        };
-
+    pub fn new(auth: &AuthConfig) -> Self {
+        let state = AuthState::from_config(auth, &Keyring::default())
+            .expect("Failed to create auth middleware");
         Self {
-            encryption_handler,
-            api_password,
+            state: Arc::new(RwLock::new(Arc::new(state))),
         }
     }
 
+    /// Accept the keys of an updated configuration from now on.
+    ///
+    /// Requests already authenticated keep the key they were admitted with,
+    /// and their streams count against the quota of the key's replacement.
+    pub fn reload(&self, auth: &AuthConfig) -> Result<(), AppError> {
+        let state = AuthState::from_config(auth, &self.keyring())?;
+        *self.state.write().unwrap() = Arc::new(state);
+        Ok(())
+    }
+
+    /// The keys accepted by the middleware, shared with handlers that check keys themselves.
+    pub fn keyring(&self) -> Arc<Keyring> {
+        self.state.read().unwrap().keyring.clone()
+    }
+
+    /// The mirrors of the destination, one per `mirror` parameter.
+    fn extract_mirrors(query_string: &str) -> Vec<String> {
+        query_string
+            .split('&')
+            .filter_map(|pair| pair.strip_prefix("mirror="))
+            .filter(|value| !value.is_empty())
+            .map(|value| {
+                urlencoding::decode(value)
+                    .unwrap_or_else(|_| value.into())
+                    .into_owned()
+            })
+            .collect()
+    }
+
+    /// The upstream URLs fetched next to the destination, such as segment keys.
+    fn fetch_urls(
+        query_params: Option<&serde_json::Map<String, Value>>,
+    ) -> impl Iterator<Item = &str> {
+        FETCH_URL_PARAMS
+            .iter()
+            .filter_map(move |name| query_params?.get(*name)?.as_str())
+    }
+
+    /// Fill a segment template destination with the `tpl0`, `tpl1`… values
+    /// the player substituted, before it is authorized.
+    fn expand_destination(
+        destination: &str,
+        query_params: &serde_json::Map<String, Value>,
+    ) -> Result<String, AppError> {
+        let values: Vec<&str> = (0..)
+            .map_while(|i| {
+                query_params
+                    .get(&format!("{}{}", TEMPLATE_PARAM_PREFIX, i))
+                    .and_then(|v| v.as_str())
+            })
+            .collect();
+        if values.is_empty() {
+            return Ok(destination.to_string());
+        }
+        mpd::expand_template(destination, &values)
+    }
+
     fn extract_query_params(query_string: &str) -> serde_json::Map<String, Value> {
         let mut params = serde_json::Map::new();
         for pair in query_string.split('&') {
@@ -72,16 +163,14 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ready(Ok(AuthMiddlewareService {
             service: Rc::new(service),
-            encryption_handler: self.encryption_handler.clone(),
-            api_password: self.api_password.clone(),
+            state: self.state.clone(),
         }))
     }
 }
 
 pub struct AuthMiddlewareService<S> {
     service: Rc<S>,
-    encryption_handler: Option<Arc<EncryptionHandler>>,
-    api_password: String,
+    state: Arc<RwLock<Arc<AuthState>>>,
 }
 
 impl<S, B> Service<ServiceRequest> for AuthMiddlewareService<S>
@@ -98,8 +187,9 @@ where
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
         let service = self.service.clone();
-        let encryption_handler = self.encryption_handler.clone();
-        let api_password = self.api_password.clone();
+        let state = self.state.read().unwrap().clone();
+        let encryption_handler = state.encryption_handler.clone();
+        let keyring = state.keyring.clone();
 
         Box::pin(async move {
             // Check if path is in open endpoints
@@ -107,11 +197,12 @@ where
                 return service.call(req).await;
             }
 
-            // If API password is not set, allow all requests
-            if api_password.is_empty() {
+            // If no API key is set, allow all requests
+            if keyring.is_empty() {
                 return service.call(req).await;
             }
 
+            let endpoint = Endpoint::from_path(req.path());
             let query_string = req.query_string().to_owned();
             let query_params = AuthMiddleware::extract_query_params(&query_string);
 
@@ -125,66 +216,95 @@ where
                         .map(|s| s.to_string());
 
                     // Decrypt and validate token
-                    let proxy_data = handler
+                    let mut proxy_data = handler
                         .decrypt(token, client_ip.as_deref())
                         .map_err(Error::from)?;
+                    proxy_data.destination =
+                        AuthMiddleware::expand_destination(&proxy_data.destination, &query_params)?;
 
                     // validate api password
-                    if proxy_data
+                    let api_key = proxy_data
                         .query_params
                         .as_ref()
                         .and_then(|v| v.get("api_password"))
                         .and_then(|v| v.as_str())
-                        != Some(&api_password)
+                        .and_then(|password| keyring.find(password))
+                        .ok_or_else(|| {
+                            AppError::Auth("Invalid or missing authentication".to_string())
+                        })?;
+                    for destination in
+                        std::iter::once(&proxy_data.destination).chain(&proxy_data.mirrors)
                     {
-                        return Err(AppError::Auth(
-                            "Invalid or missing authentication".to_string(),
-                        )
-                        .into());
+                        api_key.authorize(endpoint, Some(destination))?;
+                    }
+                    for url in AuthMiddleware::fetch_urls(
+                        proxy_data.query_params.as_ref().and_then(Value::as_object),
+                    ) {
+                        api_key.authorize(endpoint, Some(url))?;
                     }
 
-                    // Store proxy data in request extensions
+                    // Store proxy data, the key and the handler that issued it in request extensions
                     req.extensions_mut().insert(proxy_data);
+                    req.extensions_mut().insert(api_key);
+                    req.extensions_mut().insert(handler);
                     return service.call(req).await;
                 }
             }
 
             // Check for direct API password
-            if let Some(password) = query_params.get("api_password").and_then(|v| v.as_str()) {
-                if password == api_password {
-                    if let Some(destination) = query_params.get("d").and_then(|v| v.as_str()) {
-                        // Create proxy data from query parameters
-                        let proxy_data = ProxyData {
-                            destination: destination.to_string(),
-                            query_params: Some(Value::Object(query_params.clone())),
-                            request_headers: Some(Value::Object(
-                                query_params
-                                    .iter()
-                                    .filter_map(|(k, v)| {
-                                        k.strip_prefix("h_")
-                                            .map(|stripped| (stripped.to_string(), v.clone()))
-                                    })
-                                    .collect(),
-                            )),
-                            response_headers: Some(Value::Object(
-                                query_params
-                                    .iter()
-                                    .filter_map(|(k, v)| {
-                                        k.strip_prefix("r_")
-                                            .map(|stripped| (stripped.to_string(), v.clone()))
-                                    })
-                                    .collect(),
-                            )),
-                            exp: None,
-                            ip: None,
-                        };
-
-                        // Store proxy data in request extensions
-                        req.extensions_mut().insert(proxy_data);
-                        return service.call(req).await;
-                    }
-                    return service.call(req).await;
+            if let Some(api_key) = query_params
+                .get("api_password")
+                .and_then(|v| v.as_str())
+                .and_then(|password| keyring.find(password))
+            {
+                let destination = query_params
+                    .get("d")
+                    .and_then(|v| v.as_str())
+                    .map(|d| AuthMiddleware::expand_destination(d, &query_params))
+                    .transpose()?;
+                let destination = destination.as_deref();
+                api_key.authorize(endpoint, destination)?;
+                let mirrors = AuthMiddleware::extract_mirrors(&query_string);
+                for mirror in &mirrors {
+                    api_key.authorize(endpoint, Some(mirror))?;
+                }
+                for url in AuthMiddleware::fetch_urls(Some(&query_params)) {
+                    api_key.authorize(endpoint, Some(url))?;
                 }
+
+                if let Some(destination) = destination {
+                    // Create proxy data from query parameters
+                    let proxy_data = ProxyData {
+                        destination: destination.to_string(),
+                        query_params: Some(Value::Object(query_params.clone())),
+                        request_headers: Some(Value::Object(
+                            query_params
+                                .iter()
+                                .filter_map(|(k, v)| {
+                                    k.strip_prefix("h_")
+                                        .map(|stripped| (stripped.to_string(), v.clone()))
+                                })
+                                .collect(),
+                        )),
+                        response_headers: Some(Value::Object(
+                            query_params
+                                .iter()
+                                .filter_map(|(k, v)| {
+                                    k.strip_prefix("r_")
+                                        .map(|stripped| (stripped.to_string(), v.clone()))
+                                })
+                                .collect(),
+                        )),
+                        exp: None,
+                        ip: None,
+                        mirrors,
+                    };
+
+                    // Store proxy data in request extensions
+                    req.extensions_mut().insert(proxy_data);
+                }
+                req.extensions_mut().insert(api_key);
+                return service.call(req).await;
             }
 
             Err(AppError::Auth("Invalid or missing authentication".to_string()).into())
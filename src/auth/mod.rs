@@ -1,4 +1,5 @@
 pub mod encryption;
+pub mod keyring;
 pub mod middleware;
 
 pub use encryption::EncryptionHandler;
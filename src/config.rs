@@ -1,11 +1,19 @@
 use config::{Map, Value};
 use regex::Regex;
+use reqwest::{Client, Proxy};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use tracing::warn;
 use url::Url;
 
+use crate::{
+    error::{AppError, AppResult},
+    proxy::pool::{CountConnectionsLayer, PoolStats},
+};
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ServerConfig {
     pub host: String,
@@ -13,8 +21,6 @@ pub struct ServerConfig {
     pub workers: usize,
 }
 
-
-
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
 pub struct ProxyRouteConfig {
     #[serde(default)]
@@ -23,6 +29,9 @@ pub struct ProxyRouteConfig {
     pub proxy_url: Option<String>,
     #[serde(default = "default_verify_ssl")]
     pub verify_ssl: bool,
+    /// HLS segments to fetch ahead of the player, through the segment cache
+    #[serde(default)]
+    pub prefetch: usize,
 }
 
 fn default_verify_ssl() -> bool {
@@ -40,11 +49,255 @@ pub struct ProxyConfig {
     pub all_proxy: bool,
     #[serde(default)]
     pub transport_routes: HashMap<String, ProxyRouteConfig>,
+    /// Share fetched HLS playlists, MPDs, segment keys and init segments
+    /// between clients until they go stale
+    #[serde(default = "default_manifest_cache")]
+    pub manifest_cache: bool,
+    /// Idle keep-alive connections kept per upstream host, for each proxy route
+    #[serde(default = "default_pool_max_idle_per_host")]
+    pub pool_max_idle_per_host: usize,
+    /// Seconds an idle upstream connection is kept open
+    #[serde(default = "default_pool_idle_timeout")]
+    pub pool_idle_timeout: u64,
+    /// Retry HEAD requests the origin rejects with a 405 or 501 as a GET for `bytes=0-0`
+    #[serde(default = "default_head_fallback")]
+    pub head_fallback: bool,
+    /// Times a broken upstream body is resumed with a range request before giving up
+    #[serde(default = "default_resume_retries")]
+    pub resume_retries: u32,
+    /// Milliseconds before the first resume attempt, doubled for each further one
+    #[serde(default = "default_resume_backoff_ms")]
+    pub resume_backoff_ms: u64,
+}
+
+fn default_manifest_cache() -> bool {
+    true
+}
+
+fn default_head_fallback() -> bool {
+    true
+}
+
+fn default_resume_retries() -> u32 {
+    3
+}
+
+fn default_resume_backoff_ms() -> u64 {
+    500
+}
+
+fn default_pool_max_idle_per_host() -> usize {
+    32
+}
+
+fn default_pool_idle_timeout() -> u64 {
+    90
+}
+
+/// Shared in-memory cache for media segments.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CacheConfig {
+    #[serde(default = "default_cache_enabled")]
+    pub enabled: bool,
+    /// Total size of cached segment bodies, in bytes
+    #[serde(default = "default_cache_max_size")]
+    pub max_size: usize,
+    /// Larger responses are streamed through without being shared or cached
+    #[serde(default = "default_cache_max_entry_size")]
+    pub max_entry_size: usize,
+    /// Seconds a segment stays cached, unless upstream `Cache-Control` says less
+    #[serde(default = "default_cache_ttl")]
+    pub ttl: u64,
+    /// Optional on-disk tier, enabled when the `[cache.disk]` section is present
+    #[serde(default)]
+    pub disk: Option<DiskCacheConfig>,
+}
+
+/// On-disk cache for upstream bodies, including partially fetched byte ranges.
+#[derive(Debug, Deserialize, Clone)]
+pub struct DiskCacheConfig {
+    pub path: PathBuf,
+    /// Total size of the stored byte ranges, in bytes
+    #[serde(default = "default_disk_cache_max_size")]
+    pub max_size: u64,
+    #[serde(default)]
+    pub eviction: EvictionPolicy,
+    /// Seconds an entry stays fresh when upstream sends neither `max-age` nor `Expires`
+    #[serde(default = "default_disk_cache_ttl")]
+    pub ttl: u64,
+}
+
+/// Which entries the disk cache removes first when it is full.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EvictionPolicy {
+    /// Least recently served
+    #[default]
+    Lru,
+    /// Oldest stored
+    Fifo,
+}
+
+fn default_disk_cache_max_size() -> u64 {
+    10 * 1024 * 1024 * 1024
+}
+
+fn default_disk_cache_ttl() -> u64 {
+    24 * 60 * 60
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_cache_enabled(),
+            max_size: default_cache_max_size(),
+            max_entry_size: default_cache_max_entry_size(),
+            ttl: default_cache_ttl(),
+            disk: None,
+        }
+    }
+}
+
+fn default_cache_enabled() -> bool {
+    true
+}
+
+fn default_cache_max_size() -> usize {
+    256 * 1024 * 1024
+}
+
+fn default_cache_max_entry_size() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_cache_ttl() -> u64 {
+    60
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct AuthConfig {
     pub api_password: String,
+    /// Former passwords still accepted, so `api_password` can be rotated without
+    /// invalidating URLs handed out before
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub previous_passwords: Vec<String>,
+    /// Accept tokens in the original format, which has no integrity protection
+    #[serde(default = "default_legacy_tokens")]
+    pub legacy_tokens: bool,
+    /// Also accept tokens generated by the Python MediaFlow Proxy
+    #[serde(default)]
+    pub python_tokens: bool,
+    /// Additional API keys, each with its own scopes and quotas
+    #[serde(default)]
+    pub keys: Vec<ApiKeyConfig>,
+}
+
+/// Endpoint groups an API key can be allowed to use.
+///
+/// Keys without endpoints may use all of them but `admin`, which has to be
+/// granted explicitly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endpoint {
+    Stream,
+    Hls,
+    Mpd,
+    GenerateUrl,
+    Ip,
+    Admin,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ApiKeyConfig {
+    /// Name of the key in logs
+    pub label: String,
+    /// The secret, used like `api_password`
+    pub key: String,
+    /// Allowed endpoints; all when empty
+    #[serde(default)]
+    pub endpoints: Vec<Endpoint>,
+    /// Allowed destination hosts, with `*` wildcards; any host when empty
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Unix timestamp after which the key is rejected
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// Streams the key may have open at the same time
+    #[serde(default)]
+    pub max_streams: Option<usize>,
+    /// Bytes per second shared by all streams of the key
+    #[serde(default)]
+    pub max_bandwidth: Option<u64>,
+}
+
+fn default_legacy_tokens() -> bool {
+    true
+}
+
+/// A list given either as a sequence or, from the environment, as a comma-separated string.
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+
+    let values = match StringOrList::deserialize(deserializer)? {
+        StringOrList::String(value) => value.split(',').map(|v| v.trim().to_string()).collect(),
+        StringOrList::List(values) => values,
+    };
+    Ok(values.into_iter().filter(|v| !v.is_empty()).collect())
+}
+
+/// Bandwidth limits for the responses of the whole process; a limit left out is not enforced.
+#[derive(Debug, Default, Deserialize, Clone)]
+pub struct BandwidthConfig {
+    /// Bytes per second of a single response
+    #[serde(default)]
+    pub max_stream_bandwidth: Option<u64>,
+    /// Bytes per second over all responses, shared fairly between those sending
+    #[serde(default)]
+    pub max_total_bandwidth: Option<u64>,
+    /// Bytes sent at once above these rates; defaults to one second worth
+    #[serde(default)]
+    pub burst: Option<u64>,
+}
+
+/// What clients are told apart by for rate limiting.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    /// The client IP, taken from the forwarding headers when present
+    #[default]
+    Ip,
+    /// The API key that authenticated the request, falling back to the client IP
+    ApiKey,
+    /// The subject of the encrypted token: the client IP it is bound to, or
+    /// else the API key that signed it, falling back to the client IP
+    Token,
+}
+
+/// Limits applied to each client; a limit left out is not enforced. The
+/// request rate also applies to each IP before authentication.
+#[derive(Debug, Default, Deserialize, Clone)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub key_by: RateLimitKey,
+    #[serde(default)]
+    pub requests_per_second: Option<u32>,
+    /// Requests allowed at once above the steady rate; defaults to one second worth
+    #[serde(default)]
+    pub burst: Option<u32>,
+    /// Responses a client may be receiving at the same time
+    #[serde(default)]
+    pub max_streams: Option<usize>,
+    /// Bytes per second over all responses to a client
+    #[serde(default)]
+    pub max_bandwidth: Option<u64>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -52,19 +305,37 @@ pub struct Config {
     pub server: ServerConfig,
     pub proxy: ProxyConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
 }
 
 #[derive(Debug, Clone)]
 pub struct ProxyRoute {
+    /// The pattern as configured
+    pub name: String,
     pub pattern: Regex,
     pub config: ProxyRouteConfig,
 }
 
+/// Name of the route of URLs matching no transport route.
+const DEFAULT_ROUTE: &str = "default";
+
+/// Distinct upstream client settings: the proxy to use, if any, and whether to verify TLS.
+type ClientKey = (Option<String>, bool);
+
+/// Matches upstream URLs against the transport routes and owns one pooled
+/// HTTP client per distinct proxy and TLS verification combination.
 #[derive(Debug, Clone)]
 pub struct ProxyRouter {
     default_proxy: Option<String>,
     all_proxy: bool,
     routes: Vec<ProxyRoute>,
+    clients: Arc<HashMap<ClientKey, Client>>,
+    pool_stats: Arc<PoolStats>,
 }
 
 impl ProxyRouter {
@@ -73,10 +344,11 @@ impl ProxyRouter {
         all_proxy: bool,
         routes_config: HashMap<String, ProxyRouteConfig>,
     ) -> Self {
+        let default_proxy = default_proxy.filter(|proxy| !proxy.is_empty());
         let mut routes = Vec::new();
 
-        for (pattern, config) in routes_config {
-            let pattern = pattern
+        for (name, config) in routes_config {
+            let pattern = name
                 .replace(".", "\\.")
                 .replace("*", "[^/]*")
                 .replace("all://", "(http|https)://");
@@ -84,6 +356,7 @@ impl ProxyRouter {
             match Regex::new(&format!("^{}", pattern)) {
                 Ok(regex) => {
                     routes.push(ProxyRoute {
+                        name,
                         pattern: regex,
                         config,
                     });
@@ -105,18 +378,154 @@ impl ProxyRouter {
             default_proxy,
             all_proxy,
             routes,
+            clients: Arc::default(),
+            pool_stats: Arc::default(),
         }
     }
 
     pub fn from_config(config: &ProxyConfig) -> Self {
-        Self::new(
+        let mut router = Self::new(
+            config.proxy_url.clone(),
+            config.all_proxy,
+            config.transport_routes.clone(),
+        );
+        router.build_clients(config);
+        router
+    }
+
+    /// A router for an updated configuration, counting into the same pool statistics.
+    pub fn reload(&self, config: &ProxyConfig) -> Self {
+        let mut router = Self::new(
             config.proxy_url.clone(),
             config.all_proxy,
             config.transport_routes.clone(),
-        )
+        );
+        router.pool_stats = self.pool_stats.clone();
+        router.build_clients(config);
+        router
+    }
+
+    /// Build a pooled client for every distinct route setting, plus the default one.
+    fn build_clients(&mut self, config: &ProxyConfig) {
+        let mut keys = vec![self.default_client_key()];
+        keys.extend(
+            self.routes
+                .iter()
+                .map(|route| Self::client_key(&route.config)),
+        );
+        if self.all_proxy {
+            keys.push((self.default_proxy.clone(), true));
+        }
+
+        let mut clients = HashMap::new();
+        for key in keys {
+            if clients.contains_key(&key) {
+                continue;
+            }
+            match Self::build_client(config, &key, &self.pool_stats) {
+                Ok(client) => {
+                    clients.insert(key, client);
+                }
+                Err(e) => tracing::error!("Failed to create client for proxy {:?}: {}", key.0, e),
+            }
+        }
+
+        tracing::info!("Created {} pooled upstream clients", clients.len());
+        self.clients = Arc::new(clients);
+    }
+
+    fn build_client(
+        config: &ProxyConfig,
+        (proxy_url, verify_ssl): &ClientKey,
+        pool_stats: &Arc<PoolStats>,
+    ) -> reqwest::Result<Client> {
+        let follow_redirects = config.follow_redirects;
+        let mut builder = Client::builder()
+            .connect_timeout(Duration::from_secs(config.connect_timeout))
+            // No overall timeout, it would interrupt long streams
+            .pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout))
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .tcp_keepalive(Duration::from_secs(60))
+            .connector_layer(CountConnectionsLayer::new(pool_stats.clone()))
+            .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                if follow_redirects {
+                    attempt.follow()
+                } else {
+                    attempt.stop()
+                }
+            }));
+
+        if let Some(proxy_url) = proxy_url {
+            builder = builder.proxy(Proxy::all(proxy_url)?);
+        }
+        if !verify_ssl {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+
+        builder.build()
+    }
+
+    fn client_key(route: &ProxyRouteConfig) -> ClientKey {
+        let proxy_url = route
+            .proxy_url
+            .clone()
+            .filter(|proxy| route.proxy && !proxy.is_empty());
+        (proxy_url, route.verify_ssl)
+    }
+
+    fn default_client_key(&self) -> ClientKey {
+        (self.default_proxy.clone(), true)
+    }
+
+    /// The pooled client to reach `url` through.
+    pub fn get_client(&self, url: &str) -> AppResult<Client> {
+        let (name, route) = self.match_route(url).unzip();
+        self.pool_stats.record_route(name.unwrap_or(DEFAULT_ROUTE));
+
+        let key = match route {
+            Some(route) => {
+                if let Some(proxy_url) = route.proxy_url.as_ref().filter(|_| route.proxy) {
+                    tracing::debug!("Using proxy {} for {}", proxy_url, url);
+                }
+                if !route.verify_ssl {
+                    tracing::debug!("SSL verification disabled for {}", url);
+                }
+                Self::client_key(&route)
+            }
+            None => self.default_client_key(),
+        };
+
+        self.clients.get(&key).cloned().ok_or_else(|| {
+            AppError::Internal(format!(
+                "Failed to create proxy: {}",
+                key.0.unwrap_or_default()
+            ))
+        })
+    }
+
+    pub fn pool_stats(&self) -> &Arc<PoolStats> {
+        &self.pool_stats
+    }
+
+    /// The name of the transport route `url` goes through.
+    pub fn route_name(&self, url: &str) -> &str {
+        self.match_route(url)
+            .map_or(DEFAULT_ROUTE, |(name, _)| name)
+    }
+
+    /// How many HLS segments to prefetch for `url`, from its transport route.
+    pub fn prefetch_depth(&self, url: &str) -> usize {
+        self.match_route(url)
+            .map_or(0, |(_, config)| config.prefetch)
     }
 
     pub fn get_proxy_config(&self, url: &str) -> Option<ProxyRouteConfig> {
+        self.match_route(url).map(|(_, config)| config)
+    }
+
+    /// The transport route for `url`, named by its pattern, or `all_proxy`
+    /// for the default proxy.
+    fn match_route(&self, url: &str) -> Option<(&str, ProxyRouteConfig)> {
         match Url::parse(url) {
             Ok(parsed_url) => {
                 let url_str = parsed_url.as_str();
@@ -125,17 +534,21 @@ impl ProxyRouter {
                 for route in &self.routes {
                     if route.pattern.is_match(url_str) {
                         tracing::debug!("Matched route pattern: {}", route.pattern.as_str());
-                        return Some(route.config.clone());
+                        return Some((&route.name, route.config.clone()));
                     }
                 }
 
                 // If no specific route found and all_proxy is true, use default proxy
                 if self.all_proxy {
-                    return Some(ProxyRouteConfig {
-                        proxy: true,
-                        proxy_url: self.default_proxy.clone(),
-                        verify_ssl: true,
-                    });
+                    return Some((
+                        "all_proxy",
+                        ProxyRouteConfig {
+                            proxy: true,
+                            proxy_url: self.default_proxy.clone(),
+                            verify_ssl: true,
+                            prefetch: 0,
+                        },
+                    ));
                 }
             }
             Err(e) => {
@@ -195,6 +608,7 @@ impl Config {
                                 inner_map.insert("proxy_url".into(), Value::from(url));
                             }
                             inner_map.insert("verify_ssl".into(), Value::from(v.verify_ssl));
+                            inner_map.insert("prefetch".into(), Value::from(v.prefetch as u64));
                             (k, Value::from(inner_map))
                         })
                         .collect::<Map<String, Value>>();
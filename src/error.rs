@@ -1,4 +1,7 @@
-use actix_web::{HttpResponse, ResponseError};
+use actix_web::{
+    http::{header::RETRY_AFTER, StatusCode},
+    HttpResponse, ResponseError,
+};
 use serde_json::json;
 use thiserror::Error;
 
@@ -7,6 +10,18 @@ pub enum AppError {
     #[error("Authentication error: {0}")]
     Auth(String),
 
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("Rate limited: {message}")]
+    RateLimited { message: String, retry_after: u64 },
+
     #[error("Proxy error: {0}")]
     Proxy(String),
 
@@ -16,19 +31,55 @@ pub enum AppError {
     #[error("Upstream service error: {0}")]
     Upstream(String),
 
+    #[error("Upstream returned status {status}: {message}")]
+    UpstreamStatus { status: u16, message: String },
+
     #[error("Serde JSON error: {0}")]
     SerdeJsonError(serde_json::Error),
 }
 
+impl AppError {
+    /// The variant, as a metrics label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppError::Auth(_) => "auth",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::QuotaExceeded(_) => "quota_exceeded",
+            AppError::RateLimited { .. } => "rate_limited",
+            AppError::Proxy(_) => "proxy",
+            AppError::Internal(_) => "internal",
+            AppError::Upstream(_) => "upstream",
+            AppError::UpstreamStatus { .. } => "upstream_status",
+            AppError::SerdeJsonError(_) => "serde_json",
+        }
+    }
+}
+
 impl ResponseError for AppError {
     fn error_response(&self) -> HttpResponse {
         match self {
             AppError::Auth(msg) => HttpResponse::Unauthorized().json(json!({ "error": msg })),
+            AppError::Forbidden(msg) => HttpResponse::Forbidden().json(json!({ "error": msg })),
+            AppError::NotFound(msg) => HttpResponse::NotFound().json(json!({ "error": msg })),
+            AppError::QuotaExceeded(msg) => {
+                HttpResponse::TooManyRequests().json(json!({ "error": msg }))
+            }
+            AppError::RateLimited {
+                message,
+                retry_after,
+            } => HttpResponse::TooManyRequests()
+                .insert_header((RETRY_AFTER, retry_after.to_string()))
+                .json(json!({ "error": message })),
             AppError::Proxy(msg) => HttpResponse::BadGateway().json(json!({ "error": msg })),
             AppError::Internal(msg) => {
                 HttpResponse::InternalServerError().json(json!({ "error": msg }))
             }
             AppError::Upstream(msg) => HttpResponse::BadGateway().json(json!({ "error": msg })),
+            AppError::UpstreamStatus { status, message } => {
+                HttpResponse::build(client_status(*status))
+                    .json(json!({ "error": message, "upstream_status": status }))
+            }
             AppError::SerdeJsonError(err) => {
                 HttpResponse::InternalServerError().json(json!({ "error": err.to_string() }))
             }
@@ -36,4 +87,19 @@ impl ResponseError for AppError {
     }
 }
 
+/// The status to answer with when upstream failed with `upstream_status`.
+///
+/// Client errors about the resource itself are passed on, while failures the
+/// client cannot act on, including upstream authentication, become gateway errors.
+fn client_status(upstream_status: u16) -> StatusCode {
+    match StatusCode::from_u16(upstream_status) {
+        Ok(StatusCode::UNAUTHORIZED | StatusCode::PROXY_AUTHENTICATION_REQUIRED) => {
+            StatusCode::BAD_GATEWAY
+        }
+        Ok(status @ (StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT)) => status,
+        Ok(status) if status.is_client_error() => status,
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
 pub type AppResult<T> = Result<T, AppError>;
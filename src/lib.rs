@@ -1,5 +1,7 @@
 pub mod auth;
 pub mod config;
 pub mod error;
+pub mod metrics;
 pub mod models;
 pub mod proxy;
+pub mod reload;
@@ -4,15 +4,13 @@ use actix_web::{middleware, web, App, HttpServer};
 use std::sync::Arc;
 use tracing_subscriber::{fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt};
 
-mod auth;
-mod config;
-mod error;
-mod models;
-mod proxy;
-
-use auth::middleware::AuthMiddleware;
-use config::Config;
-use proxy::{handler, stream::StreamManager};
+use mediaflow_proxy_light::{
+    auth::middleware::AuthMiddleware,
+    config::Config,
+    metrics::{self, RequestMetrics},
+    proxy::{admin, handler, rate_limit::RateLimiter, stream::StreamManager},
+    reload::ConfigReloader,
+};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -38,10 +36,22 @@ async fn main() -> std::io::Result<()> {
     let config = Config::from_env().expect("Failed to load configuration");
 
     // Initialize auth middleware
-    let auth_middleware = AuthMiddleware::new(config.auth.api_password.clone());
+    let auth_middleware = AuthMiddleware::new(&config.auth);
+
+    // Initialize per-client rate limits
+    let rate_limiter = RateLimiter::new(config.rate_limit.clone());
 
     // Initialize stream manager
-    let stream_manager = StreamManager::new(config.proxy.clone());
+    let stream_manager = StreamManager::new(config.proxy.clone(), config.cache.clone())
+        .with_bandwidth(&config.bandwidth);
+
+    // Apply configuration changes without a restart
+    ConfigReloader::new(
+        stream_manager.clone(),
+        auth_middleware.clone(),
+        rate_limiter.clone(),
+    )
+    .spawn(std::env::var_os("CONFIG_PATH").map(Into::into));
 
     // Start HTTP server
     let server_config = Arc::new(config.clone());
@@ -64,19 +74,58 @@ async fn main() -> std::io::Result<()> {
             // FastAPI-style access logs: IP:PORT - "METHOD PATH HTTP/VERSION" STATUS_CODE
             .wrap(Logger::new("%a - \"%r\" %s"))
             .wrap(middleware::Compress::default())
+            // Registered before, so run after, the auth middleware to see the API key
+            .wrap(rate_limiter.clone())
             .wrap(auth_middleware.clone())
+            // Requests failing authentication count against the request rate of their IP
+            .wrap(rate_limiter.before_auth())
+            // Outermost, to count the requests rejected by the other middlewares
+            .wrap(RequestMetrics)
             // Register shared data
             .app_data(web::Data::new(stream_manager.clone()))
             .app_data(web::Data::new(config))
+            .app_data(web::Data::new(auth_middleware.clone()))
             // Configure routes
             .service(
                 web::scope("/proxy")
                     .route("/stream", web::get().to(handler::proxy_stream_get))
                     .route("/stream", web::head().to(handler::proxy_stream_head))
+                    .route(
+                        "/hls/manifest.m3u8",
+                        web::get().to(handler::proxy_hls_manifest),
+                    )
+                    .route("/hls/segment", web::get().to(handler::proxy_hls_segment))
+                    .route(
+                        "/hls/fmp4/init.mp4",
+                        web::get().to(handler::proxy_hls_fmp4_init),
+                    )
+                    .route(
+                        "/hls/fmp4/segment.m4s",
+                        web::get().to(handler::proxy_hls_fmp4_segment),
+                    )
+                    .route(
+                        "/mpd/manifest.m3u8",
+                        web::get().to(handler::proxy_mpd_manifest),
+                    )
+                    .route(
+                        "/mpd/manifest.mpd",
+                        web::get().to(handler::proxy_mpd_passthrough),
+                    )
+                    .route(
+                        "/mpd/playlist.m3u8",
+                        web::get().to(handler::proxy_mpd_playlist),
+                    )
+                    .route("/mpd/segment", web::get().to(handler::proxy_mpd_segment))
                     .route("/generate_url", web::post().to(handler::generate_url))
                     .route("/ip", web::get().to(handler::get_public_ip)),
             )
+            .service(
+                web::scope("/admin")
+                    .route("/streams", web::get().to(admin::list_streams))
+                    .route("/streams/{id}", web::delete().to(admin::kill_stream)),
+            )
             .service(web::scope("/health").route("", web::get().to(|| async { "OK" })))
+            .route("/metrics", web::get().to(metrics::serve))
             // Configure default error handlers
             .default_service(web::route().to(|| async {
                 actix_web::HttpResponse::NotFound().json(serde_json::json!({
@@ -0,0 +1,379 @@
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    web::{self, Bytes},
+    Error, HttpResponse,
+};
+use futures::future::LocalBoxFuture;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::future::{ready, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::{error::AppError, proxy::pool::PoolStats, proxy::stream::StreamManager};
+
+/// Upper bounds, in seconds, of the upstream latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// The metrics of the process, exposed in the Prometheus text format on `/metrics`.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// A histogram with fixed buckets.
+struct Histogram {
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// Counters and histograms describing the traffic of the proxy.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<HashMap<(String, u16), u64>>,
+    errors: Mutex<HashMap<&'static str, u64>>,
+    upstream_latency: Histogram,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    active_streams: AtomicI64,
+}
+
+impl Metrics {
+    /// A request answered with `status`, by the route pattern it matched.
+    pub fn record_request(&self, route: &str, status: u16) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((route.to_string(), status))
+            .or_default() += 1;
+    }
+
+    pub fn record_error(&self, error: &AppError) {
+        *self.errors.lock().unwrap().entry(error.kind()).or_default() += 1;
+    }
+
+    /// Time until upstream sent the response headers.
+    pub fn record_upstream_latency(&self, latency: Duration) {
+        self.upstream_latency.observe(latency);
+    }
+
+    /// Body bytes received from upstream.
+    pub fn record_bytes_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Body bytes sent to clients.
+    pub fn record_bytes_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count a relayed stream as active until the returned guard is dropped.
+    pub fn open_stream(&'static self) -> ActiveStream {
+        self.active_streams.fetch_add(1, Ordering::Relaxed);
+        ActiveStream { metrics: self }
+    }
+
+    pub fn active_streams(&self) -> i64 {
+        self.active_streams.load(Ordering::Relaxed)
+    }
+
+    pub fn requests(&self, route: &str, status: u16) -> u64 {
+        let requests = self.requests.lock().unwrap();
+        requests
+            .get(&(route.to_string(), status))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn errors(&self, kind: &str) -> u64 {
+        self.errors
+            .lock()
+            .unwrap()
+            .get(kind)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The metrics in the Prometheus text exposition format, along with the
+    /// upstream connection pool statistics.
+    pub fn render(&self, pool_stats: &PoolStats) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "mediaflow_requests_total",
+            "counter",
+            "Requests by route and status",
+        );
+        let requests: BTreeMap<_, _> = self.requests.lock().unwrap().clone().into_iter().collect();
+        for ((route, status), count) in requests {
+            let _ = writeln!(
+                out,
+                "mediaflow_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                escape(&route),
+                status,
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "mediaflow_errors_total",
+            "counter",
+            "Errors by kind, for rejected requests and failed streams",
+        );
+        let errors: BTreeMap<_, _> = self.errors.lock().unwrap().clone().into_iter().collect();
+        for (kind, count) in errors {
+            let _ = writeln!(out, "mediaflow_errors_total{{kind=\"{}\"}} {}", kind, count);
+        }
+
+        header(
+            &mut out,
+            "mediaflow_upstream_latency_seconds",
+            "histogram",
+            "Time until upstream sent the response headers",
+        );
+        self.upstream_latency
+            .render(&mut out, "mediaflow_upstream_latency_seconds");
+
+        let counters = [
+            (
+                "mediaflow_upstream_bytes_total",
+                "Body bytes received from upstream",
+                self.bytes_in.load(Ordering::Relaxed),
+            ),
+            (
+                "mediaflow_client_bytes_total",
+                "Body bytes sent to clients",
+                self.bytes_out.load(Ordering::Relaxed),
+            ),
+            (
+                "mediaflow_upstream_requests_total",
+                "Requests sent upstream",
+                pool_stats.requests(),
+            ),
+            (
+                "mediaflow_upstream_connections_total",
+                "Connections opened to upstream",
+                pool_stats.connections(),
+            ),
+        ];
+        for (name, help, value) in counters {
+            header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        header(
+            &mut out,
+            "mediaflow_upstream_connection_reuse_ratio",
+            "gauge",
+            "Share of upstream requests served over an already open connection",
+        );
+        let _ = writeln!(
+            out,
+            "mediaflow_upstream_connection_reuse_ratio {}",
+            pool_stats.reuse_ratio()
+        );
+
+        header(
+            &mut out,
+            "mediaflow_active_streams",
+            "gauge",
+            "Upstream responses being relayed",
+        );
+        let _ = writeln!(out, "mediaflow_active_streams {}", self.active_streams());
+
+        header(
+            &mut out,
+            "mediaflow_transport_route_requests_total",
+            "counter",
+            "Upstream requests by transport route",
+        );
+        for (route, count) in pool_stats.route_usage() {
+            let _ = writeln!(
+                out,
+                "mediaflow_transport_route_requests_total{{route=\"{}\"}} {}",
+                escape(&route),
+                count
+            );
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// A stream counted among the active ones until dropped.
+pub struct ActiveStream {
+    metrics: &'static Metrics,
+}
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.metrics.active_streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Serve the metrics.
+pub async fn serve(stream_manager: web::Data<StreamManager>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics().render(stream_manager.proxy_router().pool_stats()))
+}
+
+/// Middleware counting requests by route and status, errors, and the bytes sent to clients.
+///
+/// It should be the outermost middleware, so that requests rejected by the
+/// others are counted and the bytes are counted as sent.
+#[derive(Clone, Default)]
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<CountedBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestMetricsService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsService {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestMetricsService<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<CountedBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        // Unmatched paths are not labelled by path, to keep the label set bounded
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+
+        Box::pin(async move {
+            match service.call(req).await {
+                Ok(res) => {
+                    metrics().record_request(&route, res.status().as_u16());
+                    if let Some(error) = res
+                        .response()
+                        .error()
+                        .and_then(|e| e.as_error::<AppError>())
+                    {
+                        metrics().record_error(error);
+                    }
+                    Ok(res.map_body(|_, body| CountedBody {
+                        inner: Box::pin(body),
+                    }))
+                }
+                Err(e) => {
+                    if let Some(error) = e.as_error::<AppError>() {
+                        metrics().record_error(error);
+                    }
+                    metrics().record_request(&route, e.error_response().status().as_u16());
+                    Err(e)
+                }
+            }
+        })
+    }
+}
+
+/// A response body counting the bytes sent.
+pub struct CountedBody<B> {
+    inner: Pin<Box<B>>,
+}
+
+impl<B: MessageBody> MessageBody for CountedBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let poll = self.inner.as_mut().poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            metrics().record_bytes_out(chunk.len());
+        }
+        poll
+    }
+}
@@ -26,8 +26,72 @@ pub struct GenerateUrlRequest {
     pub expiration: Option<u64>,
     pub ip: Option<String>,
     pub api_password: Option<String>,
+    /// Alternative destinations serving the same content, tried in order
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MpdPlaylistParams {
+    pub profile_id: String,
+}
+
+/// The init segment of protected representations, needed for ClearKey
+/// decryption, is named by [`INIT_URL_PARAM`] in the proxy data.
+#[derive(Debug, Deserialize)]
+pub struct MpdSegmentParams {
+    pub range: Option<String>,
+    pub init_range: Option<String>,
+}
+
+/// Segments of AES-128 encrypted HLS streams, decrypted by the proxy with the
+/// key named by [`KEY_URL_PARAM`] in the proxy data.
+#[derive(Debug, Deserialize)]
+pub struct HlsSegmentParams {
+    /// Hex IV of the segment, explicit or derived from its media sequence number
+    pub iv: String,
+    /// Byte range of the segment within the resource
+    pub range: Option<String>,
+}
+
+/// MPEG-TS segments remuxed into fMP4 by the proxy, and their init segment.
+#[derive(Debug, Deserialize)]
+pub struct RemuxSegmentParams {
+    /// Hex IV of AES-128 encrypted segments, decrypted before remuxing with the
+    /// key named by [`KEY_URL_PARAM`] in the proxy data
+    pub iv: Option<String>,
+    /// Byte range of the segment within the resource
+    pub range: Option<String>,
+    /// Media sequence number of the segment, numbering its fragment
+    pub seq: Option<u64>,
 }
 
+/// Query parameter asking for AES-128 encrypted HLS segments to be decrypted by the proxy.
+pub const DECRYPT_PARAM: &str = "decrypt";
+
+/// Query parameter asking for MPEG-TS segments to be remuxed, with `fmp4` as its value.
+pub const REMUX_PARAM: &str = "remux";
+
+/// Query parameter naming the key of an AES-128 encrypted HLS segment.
+pub const KEY_URL_PARAM: &str = "key_url";
+
+/// Query parameter naming the init segment a ClearKey protected segment is decrypted with.
+pub const INIT_URL_PARAM: &str = "init_url";
+
+/// Parameters naming upstream URLs fetched next to the destination. Tokens
+/// carry them inside, and they are authorized like the destination.
+pub const FETCH_URL_PARAMS: &[&str] = &[KEY_URL_PARAM, INIT_URL_PARAM];
+
+/// Playback options a client may add next to a token, carried inside the
+/// tokens of the URLs rewritten from it.
+pub const CARRIED_PARAMS: &[&str] = &["key", "key_id", DECRYPT_PARAM, REMUX_PARAM];
+
+/// Query parameter carrying a mirror of the destination, repeated for each one.
+pub const MIRROR_PARAM: &str = "mirror";
+
+/// Response header naming the host that served a stream with mirrors.
+pub const MIRROR_HEADER: &str = "x-mediaflow-mirror";
+
 pub const SUPPORTED_RESPONSE_HEADERS: &[&str] = &[
     "accept-ranges",
     "content-type",
@@ -41,4 +105,10 @@ pub const SUPPORTED_RESPONSE_HEADERS: &[&str] = &[
     "expires",
 ];
 
-pub const SUPPORTED_REQUEST_HEADERS: &[&str] = &["range", "if-range"];
+pub const SUPPORTED_REQUEST_HEADERS: &[&str] = &[
+    "range",
+    "if-range",
+    "if-none-match",
+    "if-modified-since",
+    "if-match",
+];
@@ -0,0 +1,42 @@
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use std::sync::Arc;
+
+use crate::{
+    auth::keyring::ApiKey,
+    error::{AppError, AppResult},
+    proxy::stream::StreamManager,
+};
+
+/// The admin API is only served to requests authenticated with a key allowed
+/// to use it, so never while authentication is disabled.
+fn require_admin(req: &HttpRequest) -> AppResult<()> {
+    if req.extensions().get::<Arc<ApiKey>>().is_none() {
+        return Err(AppError::Forbidden(
+            "The admin API requires authentication to be configured".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// List the streams being relayed.
+pub async fn list_streams(
+    req: HttpRequest,
+    stream_manager: web::Data<StreamManager>,
+) -> AppResult<HttpResponse> {
+    require_admin(&req)?;
+    Ok(HttpResponse::Ok().json(stream_manager.registry().list()))
+}
+
+/// End a stream, cutting its response short.
+pub async fn kill_stream(
+    req: HttpRequest,
+    stream_manager: web::Data<StreamManager>,
+    id: web::Path<u64>,
+) -> AppResult<HttpResponse> {
+    require_admin(&req)?;
+    let id = id.into_inner();
+    if !stream_manager.registry().kill(id) {
+        return Err(AppError::NotFound(format!("No active stream {}", id)));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,96 @@
+use actix_web::web::Bytes;
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, KeyInit},
+    Aes128,
+};
+
+use crate::{
+    error::{AppError, AppResult},
+    proxy::{
+        clearkey::{parse_hex_16, to_hex},
+        stream::SegmentProcessor,
+    },
+};
+
+/// Decrypts HLS segments encrypted with `METHOD=AES-128`: AES-128-CBC over
+/// the whole segment with PKCS#7 padding.
+pub struct Aes128Decryptor {
+    cipher: Aes128,
+    iv: [u8; 16],
+}
+
+impl Aes128Decryptor {
+    pub fn new(key: &[u8], iv: [u8; 16]) -> AppResult<Self> {
+        let cipher = Aes128::new_from_slice(key).map_err(|_| {
+            AppError::Upstream(format!(
+                "AES-128 key must be 16 bytes, got {} bytes",
+                key.len()
+            ))
+        })?;
+        Ok(Self { cipher, iv })
+    }
+}
+
+impl SegmentProcessor for Aes128Decryptor {
+    fn process(&self, segment: Bytes) -> AppResult<Bytes> {
+        if segment.is_empty() || !segment.len().is_multiple_of(16) {
+            return Err(AppError::Upstream(format!(
+                "Encrypted segment length {} is not a multiple of the AES block size",
+                segment.len()
+            )));
+        }
+
+        let mut data = segment.to_vec();
+        let mut chain = self.iv;
+        for block in data.chunks_exact_mut(16) {
+            let mut saved = [0u8; 16];
+            saved.copy_from_slice(block);
+            self.cipher
+                .decrypt_block(GenericArray::from_mut_slice(block));
+            for (b, c) in block.iter_mut().zip(chain.iter()) {
+                *b ^= c;
+            }
+            chain = saved;
+        }
+
+        let padding = data[data.len() - 1] as usize;
+        if padding == 0
+            || padding > 16
+            || !data[data.len() - padding..]
+                .iter()
+                .all(|&b| b as usize == padding)
+        {
+            return Err(AppError::Upstream(
+                "Invalid padding in decrypted segment, the key or IV is wrong".to_string(),
+            ));
+        }
+        data.truncate(data.len() - padding);
+
+        Ok(Bytes::from(data))
+    }
+}
+
+/// The IV of a segment whose key has no `IV` attribute: its media sequence
+/// number as a big-endian 128-bit integer.
+pub fn sequence_iv(media_sequence: u64) -> [u8; 16] {
+    let mut iv = [0u8; 16];
+    iv[8..].copy_from_slice(&media_sequence.to_be_bytes());
+    iv
+}
+
+/// Parse an `IV` attribute (`0x` followed by 32 hex digits) or a plain hex IV.
+pub fn parse_iv(value: &str) -> AppResult<[u8; 16]> {
+    let value = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    // Shorter values are zero-padded on the left, as for an integer
+    if value.len() < 32 {
+        return parse_hex_16(&format!("{:0>32}", value));
+    }
+    parse_hex_16(value)
+}
+
+pub fn format_iv(iv: &[u8; 16]) -> String {
+    to_hex(iv)
+}
@@ -0,0 +1,703 @@
+use actix_web::web::Bytes;
+use futures::StreamExt;
+use reqwest::header::{HeaderMap, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::{Response, StatusCode};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+use url::Url;
+
+use crate::{
+    config::CacheConfig,
+    error::{AppError, AppResult},
+    metrics::metrics,
+    proxy::{disk_cache::DiskCache, hls::Playlist, mpd, stream::ByteStream},
+};
+
+/// TTL for manifests that do not change: HLS master and VOD playlists, static MPDs.
+const STATIC_MANIFEST_TTL: Duration = Duration::from_secs(60);
+/// Refresh interval for live MPDs without `minimumUpdatePeriod`.
+const LIVE_MPD_TTL: Duration = Duration::from_secs(2);
+/// TTL for segment keys and init segments, which every segment of a stream needs.
+const RESOURCE_TTL: Duration = Duration::from_secs(300);
+/// Above this many keys, expired entries are swept on insert.
+const SWEEP_THRESHOLD: usize = 1024;
+/// Segment content types besides `video/*` and `audio/*`.
+const MEDIA_CONTENT_TYPES: &[&str] = &[
+    "application/mp4",
+    "application/octet-stream",
+    "binary/octet-stream",
+];
+
+/// A manifest body together with the final URL it was served from, after redirects.
+#[derive(Debug, Clone)]
+pub struct CachedManifest {
+    pub url: Url,
+    pub body: Arc<str>,
+}
+
+struct Slot<T> {
+    entry: Option<(T, Instant)>,
+    /// The fetch in progress, which publishes its value once it succeeds.
+    inflight: Option<watch::Receiver<Option<T>>>,
+}
+
+impl<T> Default for Slot<T> {
+    fn default() -> Self {
+        Self {
+            entry: None,
+            inflight: None,
+        }
+    }
+}
+
+impl<T: Clone> Slot<T> {
+    fn fresh(&self, now: Instant) -> Option<&T> {
+        self.entry
+            .as_ref()
+            .filter(|(_, expires_at)| now < *expires_at)
+            .map(|(value, _)| value)
+    }
+
+    /// The fetch in progress, unless it was abandoned or failed.
+    fn inflight(&self) -> Option<watch::Receiver<Option<T>>> {
+        self.inflight
+            .as_ref()
+            .filter(|receiver| receiver.has_changed().is_ok())
+            .cloned()
+    }
+}
+
+/// Shared cache of upstream resources keyed by URL and request headers.
+///
+/// Concurrent requests for the same key wait for a single upstream fetch, so
+/// many viewers of one live channel refresh the playlist once per target
+/// duration. They share it even when the resource may not be cached.
+pub struct FetchCache<T> {
+    slots: Mutex<HashMap<String, Slot<T>>>,
+}
+
+/// HLS playlists and MPDs.
+pub type ManifestCache = FetchCache<CachedManifest>;
+
+impl<T> Default for FetchCache<T> {
+    fn default() -> Self {
+        Self {
+            slots: Mutex::default(),
+        }
+    }
+}
+
+impl<T: Clone> FetchCache<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn key(url: &str, headers: &HeaderMap) -> String {
+        request_key(url, headers)
+    }
+
+    /// Return the cached value for `key`, join the fetch of it in progress,
+    /// or run `fetch` and cache its result for the returned TTL.
+    ///
+    /// Requests that joined a fetch which failed make their own.
+    pub async fn get_or_fetch<F, Fut>(&self, key: String, fetch: F) -> AppResult<T>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = AppResult<(T, Duration)>>,
+    {
+        let url = key.lines().next().unwrap_or_default().to_string();
+        let joined = {
+            let mut slots = self.slots.lock().unwrap();
+            if slots.len() > SWEEP_THRESHOLD {
+                Self::sweep(&mut slots);
+            }
+            let slot = slots.entry(key.clone()).or_default();
+            if let Some(value) = slot.fresh(Instant::now()) {
+                tracing::debug!("Cache hit for {}", url);
+                return Ok(value.clone());
+            }
+            match slot.inflight() {
+                Some(receiver) => Err(receiver),
+                None => {
+                    let (sender, receiver) = watch::channel(None);
+                    slot.inflight = Some(receiver);
+                    Ok(sender)
+                }
+            }
+        };
+
+        let sender = match joined {
+            Ok(sender) => sender,
+            Err(mut receiver) => {
+                if let Ok(value) = receiver.wait_for(Option::is_some).await {
+                    if let Some(value) = value.clone() {
+                        tracing::debug!("Joined in-flight fetch for {}", url);
+                        return Ok(value);
+                    }
+                }
+                return fetch().await.map(|(value, _)| value);
+            }
+        };
+
+        // Dropping the sender on failure sends the waiting requests to fetch themselves
+        let (value, ttl) = fetch().await?;
+        {
+            let mut slots = self.slots.lock().unwrap();
+            let slot = slots.entry(key).or_default();
+            if !ttl.is_zero() {
+                slot.entry = Some((value.clone(), Instant::now() + ttl));
+            }
+            slot.inflight = None;
+        }
+        sender.send_replace(Some(value.clone()));
+
+        Ok(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn sweep(slots: &mut HashMap<String, Slot<T>>) {
+        let now = Instant::now();
+        slots.retain(|_, slot| slot.fresh(now).is_some() || slot.inflight().is_some());
+    }
+}
+
+/// Cache key for an upstream request: the URL plus all request headers, in a stable order.
+pub fn request_key(url: &str, headers: &HeaderMap) -> String {
+    let mut pairs: Vec<String> = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}", name, String::from_utf8_lossy(value.as_bytes())))
+        .collect();
+    pairs.sort();
+    format!("{}\n{}", url, pairs.join("\n"))
+}
+
+/// Parsed `Cache-Control` response directives relevant to a shared cache.
+pub(crate) struct CacheControl {
+    pub no_store: bool,
+    pub max_age: Option<Duration>,
+}
+
+impl CacheControl {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let value = headers
+            .get(CACHE_CONTROL)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let directives: Vec<&str> = value.split(',').map(str::trim).collect();
+
+        Self {
+            no_store: directives
+                .iter()
+                .any(|d| *d == "no-store" || *d == "no-cache" || *d == "private"),
+            max_age: directives
+                .iter()
+                .find_map(|d| d.strip_prefix("max-age="))
+                .and_then(|v| v.parse::<u64>().ok())
+                .map(Duration::from_secs),
+        }
+    }
+}
+
+/// How long a manifest may be served from cache.
+///
+/// Live HLS media playlists live for one `#EXT-X-TARGETDURATION`, live MPDs for
+/// their `minimumUpdatePeriod`; upstream `Cache-Control` can shorten that or
+/// disable caching altogether.
+pub fn manifest_ttl(body: &str, headers: &HeaderMap) -> Duration {
+    let CacheControl { no_store, max_age } = CacheControl::from_headers(headers);
+    if no_store {
+        return Duration::ZERO;
+    }
+
+    let live_ttl = if body.trim_start().starts_with("#EXTM3U") {
+        Playlist::parse(body).ok().and_then(|playlist| {
+            let is_live = !playlist.is_master && !playlist.has_tag("#EXT-X-ENDLIST");
+            is_live.then(|| {
+                playlist
+                    .tag_value("#EXT-X-TARGETDURATION")
+                    .and_then(|v| v.trim().parse::<f64>().ok())
+                    .map(Duration::from_secs_f64)
+                    .unwrap_or(Duration::ZERO)
+            })
+        })
+    } else if body.contains("type=\"dynamic\"") {
+        Some(
+            live_mpd_update_period(body)
+                .map(Duration::from_secs_f64)
+                .unwrap_or(LIVE_MPD_TTL),
+        )
+    } else {
+        None
+    };
+
+    match (live_ttl, max_age) {
+        (Some(ttl), Some(max_age)) => ttl.min(max_age),
+        (Some(ttl), None) => ttl,
+        (None, Some(max_age)) => max_age,
+        (None, None) => STATIC_MANIFEST_TTL,
+    }
+}
+
+/// How long a segment key or init segment may be cached, from its response headers.
+pub fn resource_ttl(headers: &HeaderMap) -> Duration {
+    match CacheControl::from_headers(headers) {
+        CacheControl { no_store: true, .. } => Duration::ZERO,
+        CacheControl { max_age, .. } => max_age.unwrap_or(RESOURCE_TTL),
+    }
+}
+
+fn live_mpd_update_period(body: &str) -> Option<f64> {
+    let start = body.find("minimumUpdatePeriod=\"")? + "minimumUpdatePeriod=\"".len();
+    let end = body[start..].find('"')?;
+    mpd::parse_duration(&body[start..start + end])
+}
+
+/// Upstream response headers and body of a complete segment.
+#[derive(Debug, Clone)]
+pub struct CachedSegment {
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+struct LruEntry {
+    segment: CachedSegment,
+    expires_at: Instant,
+    tick: u64,
+}
+
+/// Segments ordered by last use, bounded by total body size.
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, LruEntry>,
+    order: BTreeMap<u64, String>,
+    size: usize,
+    tick: u64,
+}
+
+impl Lru {
+    fn get(&mut self, key: &str) -> Option<CachedSegment> {
+        let entry = self.entries.get_mut(key)?;
+        if entry.expires_at <= Instant::now() {
+            self.remove(key);
+            return None;
+        }
+
+        self.tick += 1;
+        self.order.remove(&entry.tick);
+        entry.tick = self.tick;
+        self.order.insert(self.tick, key.to_string());
+        Some(entry.segment.clone())
+    }
+
+    fn insert(&mut self, key: String, segment: CachedSegment, ttl: Duration, max_size: usize) {
+        let len = segment.body.len();
+        if len > max_size {
+            return;
+        }
+
+        self.remove(&key);
+        while self.size + len > max_size {
+            match self.order.pop_first() {
+                Some((_, oldest)) => {
+                    if let Some(entry) = self.entries.remove(&oldest) {
+                        self.size -= entry.segment.body.len();
+                    }
+                }
+                None => break,
+            }
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.size += len;
+        self.entries.insert(
+            key,
+            LruEntry {
+                segment,
+                expires_at: Instant::now() + ttl,
+                tick: self.tick,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+            self.size -= entry.segment.body.len();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+enum FetchState {
+    #[default]
+    Pending,
+    Complete,
+    Failed(String),
+    /// The response is not shared; waiting clients must fetch it themselves.
+    Unshared,
+}
+
+/// What [`SegmentCache::fetch_shared`] got for a request.
+pub enum SharedFetch {
+    /// A complete `200` response from the cache, or one being shared with other clients.
+    Shared(HeaderMap, ByteStream),
+    /// A response too large, of the wrong type or status to share.
+    Unshared(Response),
+}
+
+/// Progress of an upstream fetch that other clients can follow.
+#[derive(Default)]
+struct Progress {
+    headers: Option<HeaderMap>,
+    chunks: Vec<Bytes>,
+    state: FetchState,
+}
+
+type InflightMap = HashMap<String, Arc<watch::Sender<Progress>>>;
+
+/// Removes an in-flight fetch from the map when it finishes or is abandoned,
+/// waking any clients still waiting on it.
+struct InflightGuard {
+    cache: Arc<SegmentCache>,
+    key: String,
+    sender: Arc<watch::Sender<Progress>>,
+}
+
+impl InflightGuard {
+    fn finish(self, state: FetchState) {
+        self.sender.send_modify(|progress| progress.state = state);
+    }
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.sender.send_if_modified(|progress| {
+            if progress.state == FetchState::Pending {
+                progress.state = FetchState::Failed("Upstream fetch was aborted".to_string());
+                true
+            } else {
+                false
+            }
+        });
+
+        let mut inflight = self.cache.inflight.lock().unwrap();
+        if inflight
+            .get(&self.key)
+            .is_some_and(|sender| Arc::ptr_eq(sender, &self.sender))
+        {
+            inflight.remove(&self.key);
+        }
+    }
+}
+
+/// Shared in-memory cache for media segments.
+///
+/// Concurrent requests for the same segment are coalesced into one upstream
+/// fetch whose chunks are fanned out to every waiting client as they arrive,
+/// then the complete body is kept in a size-bounded LRU for later viewers.
+/// Only media responses with a `Content-Length` up to `max_entry_size` are
+/// shared, so playlists and other dynamic resources always go upstream.
+pub struct SegmentCache {
+    config: CacheConfig,
+    lru: Mutex<Lru>,
+    inflight: Mutex<InflightMap>,
+    disk_cache: Option<Arc<DiskCache>>,
+}
+
+impl SegmentCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            lru: Mutex::new(Lru::default()),
+            inflight: Mutex::new(HashMap::new()),
+            disk_cache: None,
+        }
+    }
+
+    /// Also write completed segments to `disk_cache`.
+    pub fn with_disk_cache(mut self, disk_cache: Arc<DiskCache>) -> Self {
+        self.disk_cache = Some(disk_cache);
+        self
+    }
+
+    /// Total size of the cached segment bodies.
+    pub fn size(&self) -> usize {
+        self.lru.lock().unwrap().size
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedSegment> {
+        self.lru.lock().unwrap().get(key)
+    }
+
+    /// Serve `key` from the cache, join an in-flight fetch of it, or start one with `fetch`.
+    pub async fn fetch<F, Fut>(
+        self: &Arc<Self>,
+        key: String,
+        fetch: F,
+    ) -> AppResult<(HeaderMap, ByteStream)>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = AppResult<Response>>,
+    {
+        Ok(match self.fetch_shared(key, fetch).await? {
+            SharedFetch::Shared(headers, stream) => (headers, stream),
+            SharedFetch::Unshared(response) => {
+                (response.headers().clone(), response_stream(response))
+            }
+        })
+    }
+
+    /// Like [`fetch`](Self::fetch), handing back the upstream response when
+    /// it is not shared, for the caller to relay it like any other.
+    pub async fn fetch_shared<F, Fut>(
+        self: &Arc<Self>,
+        key: String,
+        fetch: F,
+    ) -> AppResult<SharedFetch>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = AppResult<Response>>,
+    {
+        if let Some(segment) = self.get(&key) {
+            tracing::debug!(
+                "Segment cache hit for {}",
+                key.lines().next().unwrap_or_default()
+            );
+            let body = segment.body;
+            return Ok(SharedFetch::Shared(
+                segment.headers,
+                Box::pin(futures::stream::once(async move { Ok(body) })),
+            ));
+        }
+
+        let joined = {
+            let mut inflight = self.inflight.lock().unwrap();
+            match inflight.get(&key) {
+                Some(sender) => Err(sender.subscribe()),
+                None => {
+                    let sender = Arc::new(watch::Sender::new(Progress::default()));
+                    inflight.insert(key.clone(), sender.clone());
+                    Ok(sender)
+                }
+            }
+        };
+
+        let sender = match joined {
+            Ok(sender) => sender,
+            Err(mut receiver) => {
+                if let Some(headers) = wait_for_headers(&mut receiver).await {
+                    tracing::debug!(
+                        "Joined in-flight fetch for {}",
+                        key.lines().next().unwrap_or_default()
+                    );
+                    return Ok(SharedFetch::Shared(headers, follow(receiver)));
+                }
+                // The fetch we waited on failed or is not shared, so make our own
+                return fetch().await.map(SharedFetch::Unshared);
+            }
+        };
+
+        let guard = InflightGuard {
+            cache: self.clone(),
+            key,
+            sender,
+        };
+
+        let response = match fetch().await {
+            Ok(response) => response,
+            Err(e) => {
+                guard.finish(FetchState::Failed(e.to_string()));
+                return Err(e);
+            }
+        };
+        let headers = response.headers().clone();
+
+        let content_length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        let content_length = match content_length {
+            Some(length)
+                if response.status() == StatusCode::OK
+                    && length <= self.config.max_entry_size
+                    && is_media(&headers) =>
+            {
+                length
+            }
+            _ => {
+                guard.finish(FetchState::Unshared);
+                return Ok(SharedFetch::Unshared(response));
+            }
+        };
+
+        let receiver = guard.sender.subscribe();
+        guard
+            .sender
+            .send_modify(|progress| progress.headers = Some(headers.clone()));
+
+        let cache = self.clone();
+        tokio::spawn(async move {
+            let mut body = response.bytes_stream();
+            let mut received = 0;
+            while let Some(chunk) = body.next().await {
+                match chunk {
+                    Ok(chunk) => {
+                        received += chunk.len();
+                        metrics().record_bytes_in(chunk.len());
+                        guard
+                            .sender
+                            .send_modify(|progress| progress.chunks.push(chunk));
+                    }
+                    Err(e) => {
+                        guard.finish(FetchState::Failed(format!("Stream error: {}", e)));
+                        return;
+                    }
+                }
+            }
+
+            if received != content_length {
+                guard.finish(FetchState::Failed(format!(
+                    "Upstream sent {} of {} bytes",
+                    received, content_length
+                )));
+                return;
+            }
+
+            cache.store(&guard, content_length);
+            guard.finish(FetchState::Complete);
+        });
+
+        Ok(SharedFetch::Shared(headers, follow(receiver)))
+    }
+
+    /// Move a completed in-flight body into the LRU.
+    fn store(&self, guard: &InflightGuard, content_length: usize) {
+        let (headers, body) = {
+            let progress = guard.sender.borrow();
+            let Some(headers) = progress.headers.clone() else {
+                return;
+            };
+            let mut body = Vec::with_capacity(content_length);
+            for chunk in &progress.chunks {
+                body.extend_from_slice(chunk);
+            }
+            (headers, Bytes::from(body))
+        };
+
+        let cache_control = CacheControl::from_headers(&headers);
+        if cache_control.no_store {
+            return;
+        }
+        let ttl = Duration::from_secs(self.config.ttl)
+            .min(cache_control.max_age.unwrap_or(Duration::MAX));
+        if ttl.is_zero() {
+            return;
+        }
+
+        if let Some(disk_cache) = &self.disk_cache {
+            disk_cache.store(guard.key.clone(), StatusCode::OK, &headers, body.clone());
+        }
+
+        self.lru.lock().unwrap().insert(
+            guard.key.clone(),
+            CachedSegment { headers, body },
+            ttl,
+            self.config.max_size,
+        );
+    }
+}
+
+/// Whether a response is a media segment, by its `Content-Type`.
+///
+/// HLS playlists can be served as `audio/mpegurl`, so those are ruled out first.
+fn is_media(headers: &HeaderMap) -> bool {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    if content_type.contains("mpegurl") {
+        return false;
+    }
+    content_type.starts_with("video/")
+        || content_type.starts_with("audio/")
+        || MEDIA_CONTENT_TYPES
+            .iter()
+            .any(|media_type| content_type.starts_with(media_type))
+}
+
+/// Wait until the fetch being followed has headers, or `None` if it will not share them.
+async fn wait_for_headers(receiver: &mut watch::Receiver<Progress>) -> Option<HeaderMap> {
+    loop {
+        {
+            let progress = receiver.borrow_and_update();
+            if let Some(headers) = &progress.headers {
+                return Some(headers.clone());
+            }
+            if progress.state != FetchState::Pending {
+                return None;
+            }
+        }
+        if receiver.changed().await.is_err() {
+            return None;
+        }
+    }
+}
+
+/// Stream the chunks of an in-flight fetch from the beginning, as they arrive.
+fn follow(mut receiver: watch::Receiver<Progress>) -> ByteStream {
+    Box::pin(async_stream::stream! {
+        let mut position = 0;
+        loop {
+            let (chunks, state) = {
+                let progress = receiver.borrow_and_update();
+                (progress.chunks[position..].to_vec(), progress.state.clone())
+            };
+            position += chunks.len();
+            for chunk in chunks {
+                yield Ok(chunk);
+            }
+
+            match state {
+                FetchState::Pending => {
+                    if receiver.changed().await.is_err() {
+                        let aborted = {
+                            let progress = receiver.borrow();
+                            progress.state == FetchState::Pending && progress.chunks.len() == position
+                        };
+                        if aborted {
+                            yield Err(AppError::Proxy("Upstream fetch was aborted".to_string()));
+                            break;
+                        }
+                    }
+                }
+                FetchState::Failed(e) => {
+                    yield Err(AppError::Proxy(e));
+                    break;
+                }
+                FetchState::Complete | FetchState::Unshared => break,
+            }
+        }
+    })
+}
+
+/// The body of an upstream response, counted as received.
+pub fn response_stream(response: Response) -> ByteStream {
+    Box::pin(response.bytes_stream().map(|result| {
+        result
+            .inspect(|chunk| metrics().record_bytes_in(chunk.len()))
+            .map_err(|e| AppError::Proxy(format!("Stream error: {}", e)))
+    }))
+}
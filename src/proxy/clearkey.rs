@@ -0,0 +1,687 @@
+use actix_web::web::Bytes;
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::{
+    auth::encryption::ProxyData,
+    error::{AppError, AppResult},
+    proxy::{
+        mp4::{self, Mp4Box, Reader},
+        stream::SegmentProcessor,
+    },
+};
+
+const TFHD_BASE_DATA_OFFSET: u32 = 0x01;
+const TFHD_SAMPLE_DESCRIPTION_INDEX: u32 = 0x02;
+const TFHD_DEFAULT_SAMPLE_DURATION: u32 = 0x08;
+const TFHD_DEFAULT_SAMPLE_SIZE: u32 = 0x10;
+
+const TRUN_DATA_OFFSET: u32 = 0x01;
+const TRUN_FIRST_SAMPLE_FLAGS: u32 = 0x04;
+const TRUN_SAMPLE_DURATION: u32 = 0x100;
+const TRUN_SAMPLE_SIZE: u32 = 0x200;
+const TRUN_SAMPLE_FLAGS: u32 = 0x400;
+const TRUN_SAMPLE_CTO: u32 = 0x800;
+
+const SENC_USE_SUBSAMPLES: u32 = 0x02;
+
+/// ClearKey key pairs, given as hex `key_id`/`key` values (comma separated for several keys).
+#[derive(Debug, Clone)]
+pub struct ClearKeys {
+    keys: Vec<(Option<[u8; 16]>, [u8; 16])>,
+}
+
+impl ClearKeys {
+    pub fn parse(key_id: Option<&str>, key: &str) -> AppResult<Self> {
+        let keys = key
+            .split(',')
+            .map(|k| parse_hex_16(k.trim()))
+            .collect::<AppResult<Vec<_>>>()?;
+
+        let keys = match key_id {
+            Some(key_id) => {
+                let key_ids = key_id
+                    .split(',')
+                    .map(|k| parse_hex_16(k.trim()))
+                    .collect::<AppResult<Vec<_>>>()?;
+                if key_ids.len() != keys.len() {
+                    return Err(AppError::Proxy(
+                        "key_id and key must contain the same number of entries".to_string(),
+                    ));
+                }
+                key_ids.into_iter().map(Some).zip(keys).collect()
+            }
+            None => keys.into_iter().map(|k| (None, k)).collect(),
+        };
+
+        Ok(Self { keys })
+    }
+
+    /// Read `key_id`/`key` from the proxy data, falling back to the plain query string.
+    pub fn from_request(proxy_data: &ProxyData, query_string: &str) -> AppResult<Option<Self>> {
+        let from_proxy_data = |name: &str| {
+            proxy_data
+                .query_params
+                .as_ref()
+                .and_then(|params| params.get(name))
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        };
+        let query: HashMap<String, String> = url::form_urlencoded::parse(query_string.as_bytes())
+            .into_owned()
+            .collect();
+        let param = |name: &str| from_proxy_data(name).or_else(|| query.get(name).cloned());
+
+        match param("key") {
+            Some(key) if !key.is_empty() => Self::parse(param("key_id").as_deref(), &key).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Key for a KID; a key given without `key_id` matches any KID.
+    fn key_for(&self, kid: &[u8; 16]) -> Option<&[u8; 16]> {
+        self.keys
+            .iter()
+            .find(|(id, _)| id.as_ref() == Some(kid))
+            .or_else(|| self.keys.iter().find(|(id, _)| id.is_none()))
+            .map(|(_, key)| key)
+    }
+}
+
+/// Protection parameters of a track, from the `sinf` box of its sample entry.
+#[derive(Debug, Clone)]
+struct TrackProtection {
+    scheme: [u8; 4],
+    kid: [u8; 16],
+    iv_size: u8,
+    constant_iv: Option<Vec<u8>>,
+    crypt_blocks: u8,
+    skip_blocks: u8,
+}
+
+/// Decrypts CENC (`cenc`) and `cbcs` protected fragmented MP4 segments with
+/// ClearKey keys and strips the protection boxes, producing clear segments.
+///
+/// Media segments need the track's `tenc` parameters, so the init segment must
+/// be supplied unless it is part of the processed buffer.
+pub struct ClearKeyProcessor {
+    keys: ClearKeys,
+    tracks: HashMap<u32, TrackProtection>,
+    default_sample_sizes: HashMap<u32, u32>,
+}
+
+impl ClearKeyProcessor {
+    pub fn new(keys: ClearKeys, init_segment: Option<&[u8]>) -> AppResult<Self> {
+        let mut processor = Self {
+            keys,
+            tracks: HashMap::new(),
+            default_sample_sizes: HashMap::new(),
+        };
+        if let Some(init) = init_segment {
+            for moov in mp4::parse_boxes(init)?.iter().filter(|b| b.is(b"moov")) {
+                processor.read_moov(moov)?;
+            }
+        }
+        Ok(processor)
+    }
+
+    fn read_moov(&mut self, moov: &Mp4Box) -> AppResult<()> {
+        for trak in moov.children_of(b"trak") {
+            let Some(track_id) = trak.child(b"tkhd").map(tkhd_track_id).transpose()? else {
+                continue;
+            };
+            let Some(stsd) = trak.find(&[b"mdia", b"minf", b"stbl", b"stsd"]) else {
+                continue;
+            };
+            let protection = stsd
+                .children
+                .iter()
+                .filter(|entry| entry.is(b"encv") || entry.is(b"enca"))
+                .find_map(|entry| entry.child(b"sinf"))
+                .map(parse_sinf)
+                .transpose()?;
+            if let Some(protection) = protection {
+                self.tracks.insert(track_id, protection);
+            }
+        }
+
+        if let Some(mvex) = moov.child(b"mvex") {
+            for trex in mvex.children_of(b"trex") {
+                let mut reader = Reader::new(&trex.payload);
+                reader.skip(4)?;
+                let track_id = reader.u32()?;
+                reader.skip(8)?;
+                self.default_sample_sizes.insert(track_id, reader.u32()?);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn decrypt_fragment(
+        &self,
+        data: &mut [u8],
+        moof_offset: usize,
+        moof: &Mp4Box,
+    ) -> AppResult<()> {
+        for traf in moof.children_of(b"traf") {
+            let tfhd = traf
+                .child(b"tfhd")
+                .ok_or_else(|| AppError::Upstream("traf without tfhd".to_string()))?;
+            let tfhd = parse_tfhd(tfhd)?;
+
+            let Some(protection) = self.tracks.get(&tfhd.track_id) else {
+                continue;
+            };
+            let key = self.keys.key_for(&protection.kid).ok_or_else(|| {
+                AppError::Proxy(format!("No key for KID {}", to_hex(&protection.kid)))
+            })?;
+            let cipher = Aes128::new(GenericArray::from_slice(key));
+
+            let default_size = tfhd
+                .default_sample_size
+                .or_else(|| self.default_sample_sizes.get(&tfhd.track_id).copied());
+            let base = match tfhd.base_data_offset {
+                Some(offset) => usize::try_from(offset).map_err(|_| outside_of_segment())?,
+                None => moof_offset,
+            };
+            let samples = sample_ranges(traf, base, default_size)?;
+            let aux = sample_aux_info(traf, protection, data, base, samples.len())?;
+
+            for ((start, len), (iv, subsamples)) in samples.into_iter().zip(aux) {
+                let sample = start
+                    .checked_add(len)
+                    .and_then(|end| data.get_mut(start..end))
+                    .ok_or_else(outside_of_segment)?;
+                let iv = if iv.is_empty() {
+                    protection.constant_iv.clone().unwrap_or_default()
+                } else {
+                    iv
+                };
+                decrypt_sample(&cipher, protection, &iv, &subsamples, sample)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SegmentProcessor for ClearKeyProcessor {
+    fn process(&self, segment: Bytes) -> AppResult<Bytes> {
+        let mut data = segment.to_vec();
+        let headers = mp4::scan_boxes(&data)?;
+
+        // An init segment inside the buffer takes precedence over the one given upfront
+        let mut processor = Self {
+            keys: self.keys.clone(),
+            tracks: self.tracks.clone(),
+            default_sample_sizes: self.default_sample_sizes.clone(),
+        };
+        for header in headers.iter().filter(|h| &h.kind == b"moov") {
+            for moov in mp4::parse_boxes(&data[header.offset..header.offset + header.size])? {
+                processor.read_moov(&moov)?;
+            }
+        }
+
+        for header in headers.iter().filter(|h| &h.kind == b"moof") {
+            let moof = mp4::parse_boxes(&data[header.offset..header.offset + header.size])?
+                .into_iter()
+                .next()
+                .ok_or_else(|| AppError::Upstream("Empty moof".to_string()))?;
+            processor.decrypt_fragment(&mut data, header.offset, &moof)?;
+        }
+
+        let mut output = Vec::with_capacity(data.len());
+        let mut removed = 0u64;
+        for mut b in mp4::parse_boxes(&data)? {
+            // Segment indexes would describe the protected sizes
+            if b.is(b"sidx") || b.is(b"pssh") {
+                removed += b.size();
+                continue;
+            }
+            let original_size = b.size();
+            strip_protection(&mut b);
+            let delta = original_size - b.size();
+            removed += delta;
+            if b.is(b"moof") && delta > 0 {
+                patch_data_offsets(&mut b, delta, removed)?;
+            }
+            b.write_to(&mut output);
+        }
+
+        Ok(Bytes::from(output))
+    }
+}
+
+struct Tfhd {
+    track_id: u32,
+    base_data_offset: Option<u64>,
+    default_sample_size: Option<u32>,
+}
+
+fn parse_tfhd(tfhd: &Mp4Box) -> AppResult<Tfhd> {
+    let flags = tfhd.flags();
+    let mut reader = Reader::new(&tfhd.payload);
+    reader.skip(4)?;
+    let track_id = reader.u32()?;
+    let base_data_offset = if flags & TFHD_BASE_DATA_OFFSET != 0 {
+        Some(reader.u64()?)
+    } else {
+        None
+    };
+    if flags & TFHD_SAMPLE_DESCRIPTION_INDEX != 0 {
+        reader.skip(4)?;
+    }
+    if flags & TFHD_DEFAULT_SAMPLE_DURATION != 0 {
+        reader.skip(4)?;
+    }
+    let default_sample_size = if flags & TFHD_DEFAULT_SAMPLE_SIZE != 0 {
+        Some(reader.u32()?)
+    } else {
+        None
+    };
+
+    Ok(Tfhd {
+        track_id,
+        base_data_offset,
+        default_sample_size,
+    })
+}
+
+fn tkhd_track_id(tkhd: &Mp4Box) -> AppResult<u32> {
+    let mut reader = Reader::new(&tkhd.payload);
+    reader.skip(4)?;
+    reader.skip(if tkhd.version() == 1 { 16 } else { 8 })?;
+    reader.u32()
+}
+
+fn parse_sinf(sinf: &Mp4Box) -> AppResult<TrackProtection> {
+    let scheme = match sinf.child(b"schm") {
+        Some(schm) => {
+            let mut reader = Reader::new(&schm.payload);
+            reader.skip(4)?;
+            reader.fourcc()?
+        }
+        None => *b"cenc",
+    };
+    let tenc = sinf
+        .find(&[b"schi", b"tenc"])
+        .ok_or_else(|| AppError::Upstream("Protected track without tenc box".to_string()))?;
+
+    let mut reader = Reader::new(&tenc.payload);
+    reader.skip(5)?;
+    let pattern = reader.u8()?;
+    let (crypt_blocks, skip_blocks) = if tenc.version() > 0 {
+        (pattern >> 4, pattern & 0x0f)
+    } else {
+        (0, 0)
+    };
+    let is_protected = reader.u8()?;
+    let iv_size = reader.u8()?;
+    let kid: [u8; 16] = reader
+        .bytes(16)?
+        .try_into()
+        .map_err(|_| AppError::Upstream("Invalid KID in tenc".to_string()))?;
+    let constant_iv = if is_protected == 1 && iv_size == 0 {
+        let len = reader.u8()? as usize;
+        Some(reader.bytes(len)?.to_vec())
+    } else {
+        None
+    };
+
+    Ok(TrackProtection {
+        scheme,
+        kid,
+        iv_size,
+        constant_iv,
+        crypt_blocks,
+        skip_blocks,
+    })
+}
+
+fn outside_of_segment() -> AppError {
+    AppError::Upstream("Sample outside of segment".to_string())
+}
+
+/// Absolute `(offset, size)` of every sample described by the truns of a traf.
+fn sample_ranges(
+    traf: &Mp4Box,
+    base: usize,
+    default_size: Option<u32>,
+) -> AppResult<Vec<(usize, usize)>> {
+    let mut ranges = Vec::new();
+    let mut position = base;
+
+    for trun in traf.children_of(b"trun") {
+        let flags = trun.flags();
+        let mut reader = Reader::new(&trun.payload);
+        reader.skip(4)?;
+        let sample_count = reader.u32()?;
+        if flags & TRUN_DATA_OFFSET != 0 {
+            let data_offset = reader.u32()? as i32 as isize;
+            position = base
+                .checked_add_signed(data_offset)
+                .ok_or_else(outside_of_segment)?;
+        }
+        if flags & TRUN_FIRST_SAMPLE_FLAGS != 0 {
+            reader.skip(4)?;
+        }
+        for _ in 0..sample_count {
+            if flags & TRUN_SAMPLE_DURATION != 0 {
+                reader.skip(4)?;
+            }
+            let size = if flags & TRUN_SAMPLE_SIZE != 0 {
+                reader.u32()?
+            } else {
+                default_size.ok_or_else(|| AppError::Upstream("Missing sample size".to_string()))?
+            } as usize;
+            if flags & TRUN_SAMPLE_FLAGS != 0 {
+                reader.skip(4)?;
+            }
+            if flags & TRUN_SAMPLE_CTO != 0 {
+                reader.skip(4)?;
+            }
+            ranges.push((position, size));
+            position = position.checked_add(size).ok_or_else(outside_of_segment)?;
+        }
+    }
+
+    Ok(ranges)
+}
+
+type SampleAux = (Vec<u8>, Vec<(u16, u32)>);
+
+/// Per-sample IVs and subsample maps, from `senc` or from `saiz`/`saio` auxiliary info.
+fn sample_aux_info(
+    traf: &Mp4Box,
+    protection: &TrackProtection,
+    data: &[u8],
+    base: usize,
+    sample_count: usize,
+) -> AppResult<Vec<SampleAux>> {
+    let iv_size = protection.iv_size as usize;
+
+    if let Some(senc) = traf.child(b"senc") {
+        let use_subsamples = senc.flags() & SENC_USE_SUBSAMPLES != 0;
+        let mut reader = Reader::new(&senc.payload);
+        reader.skip(4)?;
+        let count = reader.u32()? as usize;
+        return (0..count)
+            .map(|_| read_aux_entry(&mut reader, iv_size, use_subsamples))
+            .collect();
+    }
+
+    let (Some(saiz), Some(saio)) = (traf.child(b"saiz"), traf.child(b"saio")) else {
+        // Without auxiliary info every sample is fully encrypted with the constant IV
+        return Ok(vec![(Vec::new(), Vec::new()); sample_count]);
+    };
+
+    let mut reader = Reader::new(&saiz.payload);
+    reader.skip(4)?;
+    if saiz.flags() & 1 != 0 {
+        reader.skip(8)?;
+    }
+    let default_info_size = reader.u8()?;
+    // Entries past the last sample are never used
+    let count = (reader.u32()? as usize).min(sample_count);
+    let sizes = if default_info_size == 0 {
+        reader.bytes(count)?.to_vec()
+    } else {
+        vec![default_info_size; count]
+    };
+
+    let mut reader = Reader::new(&saio.payload);
+    reader.skip(4)?;
+    if saio.flags() & 1 != 0 {
+        reader.skip(8)?;
+    }
+    if reader.u32()? == 0 {
+        return Err(AppError::Upstream("saio without offsets".to_string()));
+    }
+    let offset = if saio.version() == 0 {
+        reader.u32()? as u64
+    } else {
+        reader.u64()?
+    };
+
+    let outside = || AppError::Upstream("Auxiliary info outside of segment".to_string());
+    let mut position = usize::try_from(offset)
+        .ok()
+        .and_then(|offset| base.checked_add(offset))
+        .ok_or_else(outside)?;
+    let mut entries = Vec::with_capacity(count);
+    for size in sizes {
+        let size = size as usize;
+        let end = position.checked_add(size).ok_or_else(outside)?;
+        let info = data.get(position..end).ok_or_else(outside)?;
+        let mut reader = Reader::new(info);
+        entries.push(read_aux_entry(&mut reader, iv_size, size > iv_size)?);
+        position = end;
+    }
+
+    Ok(entries)
+}
+
+fn read_aux_entry(
+    reader: &mut Reader,
+    iv_size: usize,
+    use_subsamples: bool,
+) -> AppResult<SampleAux> {
+    let iv = reader.bytes(iv_size)?.to_vec();
+    let mut subsamples = Vec::new();
+    if use_subsamples {
+        let count = reader.u16()?;
+        for _ in 0..count {
+            subsamples.push((reader.u16()?, reader.u32()?));
+        }
+    }
+    Ok((iv, subsamples))
+}
+
+fn decrypt_sample(
+    cipher: &Aes128,
+    protection: &TrackProtection,
+    iv: &[u8],
+    subsamples: &[(u16, u32)],
+    sample: &mut [u8],
+) -> AppResult<()> {
+    let mut iv16 = [0u8; 16];
+    iv16[..iv.len().min(16)].copy_from_slice(&iv[..iv.len().min(16)]);
+
+    // (clear, protected) byte runs; no subsamples means the whole sample is protected
+    let runs: Vec<(usize, usize)> = if subsamples.is_empty() {
+        vec![(0, sample.len())]
+    } else {
+        subsamples
+            .iter()
+            .map(|(clear, protected)| (*clear as usize, *protected as usize))
+            .collect()
+    };
+
+    match &protection.scheme {
+        b"cenc" => {
+            let mut ctr = CtrState::new(cipher, iv16);
+            let mut position = 0;
+            for (clear, protected) in runs {
+                position += clear;
+                let run = sample
+                    .get_mut(position..position + protected)
+                    .ok_or_else(|| AppError::Upstream("Subsample exceeds sample".to_string()))?;
+                ctr.apply(run);
+                position += protected;
+            }
+        }
+        b"cbcs" => {
+            let mut position = 0;
+            for (clear, protected) in runs {
+                position += clear;
+                let run = sample
+                    .get_mut(position..position + protected)
+                    .ok_or_else(|| AppError::Upstream("Subsample exceeds sample".to_string()))?;
+                // The CBC chain restarts from the constant IV for every subsample
+                cbc_pattern_decrypt(
+                    cipher,
+                    iv16,
+                    run,
+                    protection.crypt_blocks as usize,
+                    protection.skip_blocks as usize,
+                );
+                position += protected;
+            }
+        }
+        scheme => {
+            return Err(AppError::Proxy(format!(
+                "Unsupported protection scheme '{}'",
+                mp4::fourcc_string(scheme)
+            )))
+        }
+    }
+
+    Ok(())
+}
+
+/// AES-CTR keystream that continues across the protected runs of a sample.
+struct CtrState<'a> {
+    cipher: &'a Aes128,
+    counter: u128,
+    keystream: [u8; 16],
+    used: usize,
+}
+
+impl<'a> CtrState<'a> {
+    fn new(cipher: &'a Aes128, iv: [u8; 16]) -> Self {
+        Self {
+            cipher,
+            counter: u128::from_be_bytes(iv),
+            keystream: [0; 16],
+            used: 16,
+        }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            if self.used == 16 {
+                let mut block = GenericArray::from(self.counter.to_be_bytes());
+                self.cipher.encrypt_block(&mut block);
+                self.keystream.copy_from_slice(&block);
+                self.counter = self.counter.wrapping_add(1);
+                self.used = 0;
+            }
+            *byte ^= self.keystream[self.used];
+            self.used += 1;
+        }
+    }
+}
+
+/// AES-CBC decryption of whole blocks following a crypt:skip block pattern.
+/// A trailing partial block is always left in the clear.
+fn cbc_pattern_decrypt(cipher: &Aes128, iv: [u8; 16], data: &mut [u8], crypt: usize, skip: usize) {
+    let (crypt, skip) = if crypt == 0 { (1, 0) } else { (crypt, skip) };
+    let blocks = data.len() / 16;
+    let mut chain = iv;
+    let mut index = 0;
+
+    while index < blocks {
+        for _ in 0..crypt {
+            if index >= blocks {
+                break;
+            }
+            let block = &mut data[index * 16..(index + 1) * 16];
+            let mut saved = [0u8; 16];
+            saved.copy_from_slice(block);
+            let ga = GenericArray::from_mut_slice(block);
+            cipher.decrypt_block(ga);
+            for (b, c) in block.iter_mut().zip(chain.iter()) {
+                *b ^= c;
+            }
+            chain = saved;
+            index += 1;
+        }
+        index += skip;
+    }
+}
+
+/// Remove protection boxes and restore the original sample entry formats.
+fn strip_protection(b: &mut Mp4Box) {
+    b.children.retain(|child| {
+        !(child.is(b"pssh")
+            || child.is(b"senc")
+            || child.is(b"saiz")
+            || child.is(b"saio")
+            || ((child.is(b"sbgp") || child.is(b"sgpd"))
+                && child.payload.get(4..8) == Some(b"seig")))
+    });
+
+    if b.is(b"encv") || b.is(b"enca") {
+        if let Some(format) = b
+            .find(&[b"sinf", b"frma"])
+            .and_then(|frma| frma.payload.get(0..4))
+            .and_then(|format| <[u8; 4]>::try_from(format).ok())
+        {
+            b.kind = format;
+        }
+        b.children.retain(|child| !child.is(b"sinf"));
+    }
+
+    for child in b.children.iter_mut() {
+        strip_protection(child);
+    }
+}
+
+/// Shift trun data offsets after the moof shrank by `delta` bytes; explicit
+/// base data offsets are shifted by every byte `removed` so far.
+fn patch_data_offsets(moof: &mut Mp4Box, delta: u64, removed: u64) -> AppResult<()> {
+    for traf in moof.children.iter_mut().filter(|b| b.is(b"traf")) {
+        let mut explicit_base = false;
+        for child in traf.children.iter_mut() {
+            if child.is(b"tfhd") && child.flags() & TFHD_BASE_DATA_OFFSET != 0 {
+                let field = child
+                    .payload
+                    .get_mut(8..16)
+                    .ok_or_else(|| AppError::Upstream("Truncated tfhd".to_string()))?;
+                let base = u64::from_be_bytes(field.try_into().unwrap_or_default());
+                field.copy_from_slice(&base.saturating_sub(removed).to_be_bytes());
+                explicit_base = true;
+            }
+        }
+        if explicit_base {
+            continue;
+        }
+        for trun in traf.children.iter_mut().filter(|b| b.is(b"trun")) {
+            if trun.flags() & TRUN_DATA_OFFSET != 0 {
+                let field = trun
+                    .payload
+                    .get_mut(8..12)
+                    .ok_or_else(|| AppError::Upstream("Truncated trun".to_string()))?;
+                let offset = i32::from_be_bytes(field.try_into().unwrap_or_default());
+                let offset = i32::try_from(delta)
+                    .ok()
+                    .and_then(|delta| offset.checked_sub(delta))
+                    .ok_or_else(|| AppError::Upstream("Invalid trun data offset".to_string()))?;
+                field.copy_from_slice(&offset.to_be_bytes());
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn parse_hex_16(value: &str) -> AppResult<[u8; 16]> {
+    let value = value.replace('-', "");
+    let invalid = || AppError::Proxy(format!("Invalid 128-bit hex value '{}'", value));
+    if value.len() != 32 {
+        return Err(invalid());
+    }
+    let mut output = [0u8; 16];
+    for (i, byte) in output.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(output)
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
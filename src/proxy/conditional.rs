@@ -0,0 +1,105 @@
+use actix_web::http::header::HttpDate;
+use reqwest::{
+    header::{
+        HeaderMap, HeaderValue, CACHE_CONTROL, ETAG, EXPIRES, IF_MATCH, IF_MODIFIED_SINCE,
+        IF_NONE_MATCH, LAST_MODIFIED,
+    },
+    StatusCode,
+};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+/// Request headers that make a request conditional on the validators of the stored response.
+pub const CONDITIONAL_HEADERS: [reqwest::header::HeaderName; 3] =
+    [IF_MATCH, IF_NONE_MATCH, IF_MODIFIED_SINCE];
+
+/// Response headers sent along with a 304, so clients can refresh their stored copy.
+const VALIDATOR_HEADERS: [reqwest::header::HeaderName; 4] =
+    [ETAG, LAST_MODIFIED, CACHE_CONTROL, EXPIRES];
+
+pub fn is_conditional(headers: &HeaderMap) -> bool {
+    CONDITIONAL_HEADERS
+        .iter()
+        .any(|name| headers.contains_key(name))
+}
+
+/// `headers` without the conditional headers, for requests that need the full body
+/// and for cache keys shared by conditional and unconditional requests.
+pub fn unconditional(headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+    for name in &CONDITIONAL_HEADERS {
+        headers.remove(name);
+    }
+    headers
+}
+
+/// Evaluate the conditional headers of a GET or HEAD request against a stored response.
+///
+/// Returns the status to answer with instead of the full response: 412 when `If-Match`
+/// fails, 304 when the client's copy is still valid. Follows the precedence of
+/// RFC 9110 section 13.2.2, so `If-Modified-Since` is ignored when `If-None-Match` is sent.
+pub fn evaluate(request: &HeaderMap, response: &HeaderMap) -> Option<StatusCode> {
+    let etag = response.get(ETAG).and_then(|v| v.to_str().ok());
+
+    if let Some(if_match) = request.get(IF_MATCH).and_then(|v| v.to_str().ok()) {
+        if !etag_matches(if_match, etag, true) {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    }
+
+    if let Some(if_none_match) = request.get(IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        return etag_matches(if_none_match, etag, false).then_some(StatusCode::NOT_MODIFIED);
+    }
+
+    let since = http_date(request.get(IF_MODIFIED_SINCE))?;
+    let last_modified = http_date(response.get(LAST_MODIFIED))?;
+    (last_modified <= since).then_some(StatusCode::NOT_MODIFIED)
+}
+
+/// The headers of a stored response that are repeated in a 304.
+pub fn validators(headers: &HeaderMap) -> HeaderMap {
+    let mut validators = HeaderMap::new();
+    for name in &VALIDATOR_HEADERS {
+        if let Some(value) = headers.get(name) {
+            validators.insert(name, value.clone());
+        }
+    }
+    validators
+}
+
+/// A strong ETag for content generated by the proxy, such as rewritten playlists.
+pub fn etag_for(body: &str) -> HeaderValue {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    HeaderValue::from_str(&format!("\"{:016x}\"", hasher.finish()))
+        .expect("hex digest is a valid header value")
+}
+
+/// Match an `If-Match`/`If-None-Match` list against the current ETag.
+///
+/// `If-Match` uses the strong comparison, where weak tags never match, and
+/// `If-None-Match` the weak one, which ignores the `W/` prefix.
+fn etag_matches(list: &str, etag: Option<&str>, strong: bool) -> bool {
+    let Some(etag) = etag else {
+        return false;
+    };
+    if list.trim() == "*" {
+        return true;
+    }
+    if strong && etag.starts_with("W/") {
+        return false;
+    }
+
+    let opaque = etag.trim_start_matches("W/");
+    list.split(',').map(str::trim).any(|candidate| {
+        if strong && candidate.starts_with("W/") {
+            return false;
+        }
+        candidate.trim_start_matches("W/") == opaque
+    })
+}
+
+fn http_date(value: Option<&HeaderValue>) -> Option<HttpDate> {
+    HttpDate::from_str(value?.to_str().ok()?).ok()
+}
@@ -0,0 +1,649 @@
+use actix_web::http::header::HttpDate;
+use actix_web::web::Bytes;
+use futures::StreamExt;
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG,
+    EXPIRES, LAST_MODIFIED,
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use crate::{
+    config::{DiskCacheConfig, EvictionPolicy},
+    error::AppError,
+    proxy::{cache::CacheControl, stream::ByteStream},
+};
+
+const META_EXTENSION: &str = "meta";
+const DATA_EXTENSION: &str = "data";
+const TEMP_EXTENSION: &str = "tmp";
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Headers that describe one response or connection rather than the stored entity.
+const UNSTORED_HEADERS: &[&str] = &[
+    "content-length",
+    "content-range",
+    "transfer-encoding",
+    "connection",
+    "keep-alive",
+    "date",
+    "age",
+    "set-cookie",
+];
+
+/// Persisted description of one cached entity and the byte ranges stored for it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EntryMeta {
+    key: String,
+    total_length: u64,
+    headers: Vec<(String, String)>,
+    /// Stored byte ranges, sorted, merged and end-exclusive
+    ranges: Vec<(u64, u64)>,
+    /// Timestamps from [`Index::tick`], in milliseconds
+    created: u64,
+    last_access: u64,
+    /// When the stored entity stops being fresh, in milliseconds
+    #[serde(default)]
+    expires: u64,
+    /// Stem of the data file, unique to this version of the entity
+    #[serde(default)]
+    file: String,
+    /// Changes whenever the entry is reset, so stale writers cannot record ranges
+    #[serde(skip)]
+    generation: u64,
+}
+
+impl EntryMeta {
+    fn header(&self, name: &HeaderName) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name.as_str())
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn covers(&self, start: u64, end: u64) -> bool {
+        self.ranges.iter().any(|(s, e)| *s <= start && end <= *e)
+    }
+
+    fn stored_size(&self) -> u64 {
+        self.ranges.iter().map(|(s, e)| e - s).sum()
+    }
+
+    /// Merge `[start, end)` into the stored ranges, returning the number of newly stored bytes.
+    fn add_range(&mut self, start: u64, end: u64) -> u64 {
+        let before = self.stored_size();
+        self.ranges.push((start, end));
+        self.ranges.sort_unstable();
+
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(self.ranges.len());
+        for (s, e) in self.ranges.drain(..) {
+            match merged.last_mut() {
+                Some(last) if s <= last.1 => last.1 = last.1.max(e),
+                _ => merged.push((s, e)),
+            }
+        }
+        self.ranges = merged;
+
+        self.stored_size() - before
+    }
+
+    /// Whether a response with these headers describes the same entity.
+    fn matches(&self, total_length: u64, headers: &HeaderMap) -> bool {
+        let same = |name: &HeaderName| match (self.header(name), headers.get(name)) {
+            (Some(stored), Some(value)) => value.to_str().is_ok_and(|v| v == stored),
+            _ => true,
+        };
+        self.total_length == total_length && same(&ETAG) && same(&LAST_MODIFIED)
+    }
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, EntryMeta>,
+    size: u64,
+    generation: u64,
+    clock: u64,
+}
+
+impl Index {
+    /// Current time in milliseconds, strictly increasing so eviction order has no ties.
+    fn tick(&mut self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        self.clock = now.max(self.clock + 1);
+        self.clock
+    }
+}
+
+/// A single byte range requested by a client, before the entity length is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestedRange {
+    /// `bytes=start-` or `bytes=start-end`, end inclusive
+    From(u64, Option<u64>),
+    /// `bytes=-length`
+    Suffix(u64),
+}
+
+impl RequestedRange {
+    /// Parse a `Range` header with a single byte range.
+    pub fn parse(value: &str) -> Option<Self> {
+        let spec = value.trim().strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+        let (start, end) = spec.split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+
+        if start.is_empty() {
+            return end.parse().ok().map(Self::Suffix);
+        }
+        let start = start.parse().ok()?;
+        if end.is_empty() {
+            Some(Self::From(start, None))
+        } else {
+            let end = end.parse().ok()?;
+            (end >= start).then_some(Self::From(start, Some(end)))
+        }
+    }
+
+    /// The end-exclusive byte span within an entity of `total_length` bytes.
+    pub fn resolve(&self, total_length: u64) -> Option<(u64, u64)> {
+        match *self {
+            Self::From(start, _) if start >= total_length => None,
+            Self::From(start, end) => Some((
+                start,
+                end.map_or(total_length, |end| (end + 1).min(total_length)),
+            )),
+            Self::Suffix(0) => None,
+            Self::Suffix(length) => Some((total_length.saturating_sub(length), total_length)),
+        }
+    }
+}
+
+/// On-disk cache tier for upstream bodies.
+///
+/// Each entity is kept as a sparse data file plus a JSON metadata file listing
+/// the byte ranges stored so far. Ranges fetched by different requests are
+/// merged over time, and a request is served from disk once every byte it
+/// asks for is present.
+pub struct DiskCache {
+    config: DiskCacheConfig,
+    index: Mutex<Index>,
+}
+
+impl DiskCache {
+    /// Open the cache directory, loading the entries stored by previous runs.
+    pub fn open(config: DiskCacheConfig) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&config.path)?;
+
+        let mut paths = Vec::new();
+        for dir_entry in std::fs::read_dir(&config.path)? {
+            paths.push(dir_entry?.path());
+        }
+        let extension = |path: &PathBuf| {
+            path.extension()
+                .and_then(|e| e.to_str())
+                .map(str::to_string)
+        };
+        let stem = |path: &PathBuf| {
+            path.file_stem()
+                .and_then(|s| s.to_str())
+                .map(str::to_string)
+                .unwrap_or_default()
+        };
+
+        let mut index = Index::default();
+        let now = index.tick();
+        for path in paths
+            .iter()
+            .filter(|path| extension(path).as_deref() == Some(META_EXTENSION))
+        {
+            let id = stem(path);
+            let meta = std::fs::read(path)
+                .ok()
+                .and_then(|data| serde_json::from_slice::<EntryMeta>(&data).ok());
+            match meta {
+                // Entries written under another naming scheme are dropped
+                Some(mut meta)
+                    if file_id(&meta.key) == id
+                        && meta.expires > now
+                        && !meta.file.is_empty()
+                        && config
+                            .path
+                            .join(format!("{}.{}", meta.file, DATA_EXTENSION))
+                            .exists() =>
+                {
+                    index.generation += 1;
+                    meta.generation = index.generation;
+                    index.clock = index.clock.max(meta.last_access);
+                    index.size += meta.stored_size();
+                    index.entries.insert(id, meta);
+                }
+                _ => {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+        }
+
+        let files: HashSet<&str> = index
+            .entries
+            .values()
+            .map(|meta| meta.file.as_str())
+            .collect();
+        for path in &paths {
+            let orphaned = match extension(path).as_deref() {
+                Some(DATA_EXTENSION) => !files.contains(stem(path).as_str()),
+                Some(TEMP_EXTENSION) => true,
+                _ => false,
+            };
+            if orphaned {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+
+        tracing::info!(
+            "Disk cache at {} holds {} entries ({} bytes)",
+            config.path.display(),
+            index.entries.len(),
+            index.size
+        );
+
+        let cache = Self {
+            config,
+            index: Mutex::new(index),
+        };
+        // The configured size may have shrunk since the last run
+        cache.evict(&mut cache.index.lock().unwrap(), None);
+        Ok(cache)
+    }
+
+    /// Total size of the stored byte ranges.
+    pub fn size(&self) -> u64 {
+        self.index.lock().unwrap().size
+    }
+
+    /// Serve a request from disk if every requested byte is stored.
+    ///
+    /// `range` and `if_range` are the client's `Range` and `If-Range` headers.
+    pub async fn read(
+        &self,
+        key: &str,
+        range: Option<&HeaderValue>,
+        if_range: Option<&HeaderValue>,
+    ) -> Option<(StatusCode, HeaderMap, ByteStream)> {
+        let id = file_id(key);
+        let (meta, start, end) = {
+            let mut index = self.index.lock().unwrap();
+            let timestamp = index.tick();
+            let meta = index
+                .entries
+                .get_mut(&id)
+                .filter(|m| m.key == key && m.expires > timestamp)?;
+
+            let requested = match range {
+                Some(range) => Some(RequestedRange::parse(range.to_str().ok()?)?),
+                None => None,
+            };
+            // A stale If-Range validator asks for the whole current entity, which only upstream knows
+            if let (Some(_), Some(if_range)) = (requested, if_range) {
+                let validator = if_range.to_str().ok()?;
+                if meta.header(&ETAG) != Some(validator)
+                    && meta.header(&LAST_MODIFIED) != Some(validator)
+                {
+                    return None;
+                }
+            }
+            let (start, end) = match requested {
+                Some(requested) => requested.resolve(meta.total_length)?,
+                None => (0, meta.total_length),
+            };
+            if !meta.covers(start, end) {
+                return None;
+            }
+
+            meta.last_access = timestamp;
+            (meta.clone(), start, end)
+        };
+
+        let mut file = tokio::fs::File::open(self.path(&meta.file, DATA_EXTENSION))
+            .await
+            .ok()?;
+        file.seek(SeekFrom::Start(start)).await.ok()?;
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in &meta.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(end - start));
+        if range.is_some() {
+            let content_range = format!("bytes {}-{}/{}", start, end - 1, meta.total_length);
+            if let Ok(value) = HeaderValue::from_str(&content_range) {
+                headers.insert(CONTENT_RANGE, value);
+            }
+        }
+
+        tracing::debug!("Disk cache hit for bytes {}-{} of {}", start, end, id);
+
+        let stream = async_stream::stream! {
+            let mut remaining = end - start;
+            let mut buffer = vec![0u8; READ_CHUNK_SIZE];
+            while remaining > 0 {
+                let len = buffer.len().min(remaining as usize);
+                match file.read(&mut buffer[..len]).await {
+                    Ok(0) => {
+                        yield Err(AppError::Internal("Disk cache entry is truncated".to_string()));
+                        break;
+                    }
+                    Ok(n) => {
+                        remaining -= n as u64;
+                        yield Ok(Bytes::copy_from_slice(&buffer[..n]));
+                    }
+                    Err(e) => {
+                        yield Err(AppError::Internal(format!("Failed to read disk cache: {}", e)));
+                        break;
+                    }
+                }
+            }
+        };
+
+        let status = if range.is_some() {
+            StatusCode::PARTIAL_CONTENT
+        } else {
+            StatusCode::OK
+        };
+        Some((status, headers, Box::pin(stream)))
+    }
+
+    /// Copy `stream` to disk as it is sent to the client, if the response can be stored.
+    ///
+    /// Whatever part of the body the client consumes is kept, so an interrupted
+    /// download still contributes the bytes it received.
+    pub fn tee(
+        self: &Arc<Self>,
+        key: String,
+        status: StatusCode,
+        headers: &HeaderMap,
+        stream: ByteStream,
+    ) -> ByteStream {
+        match self.writer(key, status, headers) {
+            Some(sender) => Box::pin(stream.inspect(move |chunk| {
+                if let Ok(chunk) = chunk {
+                    let _ = sender.send(chunk.clone());
+                }
+            })),
+            None => stream,
+        }
+    }
+
+    /// Store an already buffered response body.
+    pub fn store(
+        self: &Arc<Self>,
+        key: String,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: Bytes,
+    ) {
+        if let Some(sender) = self.writer(key, status, headers) {
+            let _ = sender.send(body);
+        }
+    }
+
+    /// Start a background writer for a response, returning the channel to feed it the body.
+    fn writer(
+        self: &Arc<Self>,
+        key: String,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> Option<mpsc::UnboundedSender<Bytes>> {
+        let (offset, total_length) = stored_span(status, headers)?;
+        if total_length == 0
+            || total_length > self.config.max_size
+            || CacheControl::from_headers(headers).no_store
+        {
+            return None;
+        }
+
+        let (generation, file) = self.prepare(&key, total_length, headers)?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(
+            self.clone()
+                .write(key, file, generation, offset, total_length, receiver),
+        );
+        Some(sender)
+    }
+
+    /// Find or create the entry for `key`, resetting it if it expired or
+    /// upstream now serves a different entity.
+    ///
+    /// Returns the generation and data file of the entry, or `None` if the
+    /// response is not fresh at all.
+    fn prepare(&self, key: &str, total_length: u64, headers: &HeaderMap) -> Option<(u64, String)> {
+        let ttl = freshness(headers).unwrap_or(Duration::from_secs(self.config.ttl));
+        if ttl.is_zero() {
+            return None;
+        }
+        let id = file_id(key);
+        let mut index = self.index.lock().unwrap();
+        let timestamp = index.tick();
+
+        if let Some(meta) = index.entries.get(&id) {
+            if meta.key == key && meta.expires > timestamp && meta.matches(total_length, headers) {
+                return Some((meta.generation, meta.file.clone()));
+            }
+            tracing::debug!("Resetting disk cache entry {}", id);
+            if let Some(stale) = self.remove_entry(&mut index, &id) {
+                // The new version writes to a file of its own, so this cannot race its writers
+                let path = self.path(&stale.file, DATA_EXTENSION);
+                tokio::spawn(async move {
+                    let _ = tokio::fs::remove_file(path).await;
+                });
+            }
+        }
+
+        index.generation += 1;
+        let meta = EntryMeta {
+            key: key.to_string(),
+            total_length,
+            headers: headers
+                .iter()
+                .filter(|(name, _)| !UNSTORED_HEADERS.contains(&name.as_str()))
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            ranges: Vec::new(),
+            created: timestamp,
+            last_access: timestamp,
+            expires: timestamp.saturating_add(ttl.as_millis() as u64),
+            file: format!("{}-{}", id, timestamp),
+            generation: index.generation,
+        };
+        let entry = (meta.generation, meta.file.clone());
+        index.entries.insert(id, meta);
+        Some(entry)
+    }
+
+    async fn write(
+        self: Arc<Self>,
+        key: String,
+        file: String,
+        generation: u64,
+        offset: u64,
+        total_length: u64,
+        mut receiver: mpsc::UnboundedReceiver<Bytes>,
+    ) {
+        let id = file_id(&key);
+        let path = self.path(&file, DATA_EXTENSION);
+
+        let result = async {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+
+            let mut written = 0u64;
+            while let Some(chunk) = receiver.recv().await {
+                file.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+            }
+            file.flush().await?;
+            Ok::<_, std::io::Error>(written)
+        }
+        .await;
+
+        match result {
+            Ok(0) => {}
+            Ok(written) => {
+                let end = (offset + written).min(total_length);
+                let cache = self.clone();
+                let _ = tokio::task::spawn_blocking(move || {
+                    cache.record(&id, &file, generation, offset, end)
+                })
+                .await;
+            }
+            Err(e) => tracing::warn!("Failed to write disk cache entry {}: {}", id, e),
+        }
+    }
+
+    /// Mark `[start, end)` as stored and persist the entry's metadata.
+    ///
+    /// Runs on a blocking thread.
+    fn record(&self, id: &str, file: &str, generation: u64, start: u64, end: u64) {
+        let mut index = self.index.lock().unwrap();
+        let Some(meta) = index
+            .entries
+            .get_mut(id)
+            .filter(|meta| meta.generation == generation)
+        else {
+            // Evicted or reset while the body was being written
+            let _ = std::fs::remove_file(self.path(file, DATA_EXTENSION));
+            return;
+        };
+
+        let added = meta.add_range(start, end);
+        let meta = meta.clone();
+        index.size += added;
+
+        let persisted = serde_json::to_vec(&meta)
+            .map_err(std::io::Error::other)
+            .and_then(|data| {
+                let temp = self.path(id, TEMP_EXTENSION);
+                std::fs::write(&temp, data)?;
+                std::fs::rename(&temp, self.path(id, META_EXTENSION))
+            });
+        if let Err(e) = persisted {
+            tracing::warn!("Failed to persist disk cache entry {}: {}", id, e);
+        }
+
+        self.evict(&mut index, Some(id));
+    }
+
+    /// Remove entries until the stored size fits, never evicting `keep`.
+    ///
+    /// Deletes their files right away, so it only runs at startup or on a blocking thread.
+    fn evict(&self, index: &mut Index, keep: Option<&str>) {
+        while index.size > self.config.max_size {
+            let victim = index
+                .entries
+                .iter()
+                .filter(|(id, _)| Some(id.as_str()) != keep)
+                .min_by_key(|(_, meta)| match self.config.eviction {
+                    EvictionPolicy::Lru => meta.last_access,
+                    EvictionPolicy::Fifo => meta.created,
+                })
+                .map(|(id, _)| id.clone());
+
+            match victim {
+                Some(id) => {
+                    tracing::debug!("Evicting disk cache entry {}", id);
+                    if let Some(meta) = self.remove_entry(index, &id) {
+                        let _ = std::fs::remove_file(self.path(&id, META_EXTENSION));
+                        let _ = std::fs::remove_file(self.path(&meta.file, DATA_EXTENSION));
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Drop an entry from the index, leaving its files to the caller.
+    fn remove_entry(&self, index: &mut Index, id: &str) -> Option<EntryMeta> {
+        let meta = index.entries.remove(id)?;
+        index.size -= meta.stored_size();
+        Some(meta)
+    }
+
+    fn path(&self, stem: &str, extension: &str) -> PathBuf {
+        self.config.path.join(format!("{}.{}", stem, extension))
+    }
+}
+
+/// Offset of the body within the entity, and the entity's total length.
+fn stored_span(status: StatusCode, headers: &HeaderMap) -> Option<(u64, u64)> {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+    };
+
+    match status {
+        StatusCode::OK => Some((0, header(CONTENT_LENGTH)?.parse().ok()?)),
+        StatusCode::PARTIAL_CONTENT => {
+            // bytes <start>-<end>/<total>
+            let (span, total) = header(CONTENT_RANGE)?
+                .strip_prefix("bytes ")?
+                .split_once('/')?;
+            let start = span.split_once('-')?.0.trim().parse().ok()?;
+            Some((start, total.trim().parse().ok()?))
+        }
+        _ => None,
+    }
+}
+
+/// How long a response stays fresh: its `max-age`, or else until its `Expires` date.
+fn freshness(headers: &HeaderMap) -> Option<Duration> {
+    if let Some(max_age) = CacheControl::from_headers(headers).max_age {
+        return Some(max_age);
+    }
+    let expires = headers.get(EXPIRES)?;
+    // A date in the past, or an invalid one such as `0`, means already expired
+    Some(
+        expires
+            .to_str()
+            .ok()
+            .and_then(|value| value.parse::<HttpDate>().ok())
+            .and_then(|date| {
+                SystemTime::from(date)
+                    .duration_since(SystemTime::now())
+                    .ok()
+            })
+            .unwrap_or_default(),
+    )
+}
+
+/// File name stem of an entry, stable across builds and restarts.
+fn file_id(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
@@ -1,27 +1,54 @@
 use actix_web::{
     body::SizedStream,
+    http::StatusCode,
     web::{self, Bytes},
-    HttpRequest, HttpResponse,
+    HttpMessage, HttpRequest, HttpResponse,
 };
-use futures::stream;
+use futures::{stream, Stream};
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use std::boxed::Box;
 use std::str::FromStr;
+use std::sync::Arc;
+use url::Url;
 
 use crate::{
-    auth::{encryption::ProxyData, EncryptionHandler},
+    auth::{
+        encryption::ProxyData,
+        keyring::{ApiKey, StreamQuota},
+        middleware::AuthMiddleware,
+        EncryptionHandler,
+    },
+    config::Endpoint,
     error::{AppError, AppResult},
-    models::request::{GenerateUrlRequest, SUPPORTED_REQUEST_HEADERS, SUPPORTED_RESPONSE_HEADERS},
-    proxy::stream::{ResponseStream, StreamManager},
+    metrics::metrics,
+    models::request::{
+        GenerateUrlRequest, HlsSegmentParams, MpdPlaylistParams, MpdSegmentParams,
+        RemuxSegmentParams, DECRYPT_PARAM, INIT_URL_PARAM, KEY_URL_PARAM, MIRROR_HEADER,
+        MIRROR_PARAM, REMUX_PARAM, SUPPORTED_REQUEST_HEADERS, SUPPORTED_RESPONSE_HEADERS,
+    },
+    proxy::{
+        aes128::{self, Aes128Decryptor},
+        cache::CachedManifest,
+        clearkey::{ClearKeyProcessor, ClearKeys},
+        conditional,
+        hls::{Playlist, VariantFilter, HLS_CONTENT_TYPE, STREAM_ENDPOINT},
+        mpd::{self, ByteRange, Mpd},
+        registry::StreamInfo,
+        remux::{TsRemuxer, FMP4_CONTENT_TYPE},
+        stream::{ByteStream, ResponseStream, StreamManager, UpstreamResponse},
+        url_builder::ProxyUrlBuilder,
+    },
 };
 
-async fn handle_proxy_request(
-    req: HttpRequest,
-    stream_manager: web::Data<StreamManager>,
-    proxy_data: web::ReqData<ProxyData>,
-    is_head: bool,
-) -> AppResult<HttpResponse> {
-    // Prepare headers
+/// Upstream headers that describe a response body.
+const BODY_HEADERS: &[&str] = &["content-length", "content-range", "transfer-encoding"];
+
+/// Size of the pieces processed segments are sent to the client in.
+const SEGMENT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Collect the upstream request headers: supported client headers plus the
+/// custom headers carried in the proxy data.
+fn build_request_headers(req: &HttpRequest, proxy_data: &ProxyData) -> AppResult<HeaderMap> {
     let mut request_headers = HeaderMap::new();
 
     // Add supported headers from original request
@@ -53,20 +80,237 @@ async fn handle_proxy_request(
         }
     }
 
+    Ok(request_headers)
+}
+
+/// Answer with an HLS playlist generated by the proxy.
+fn playlist_response(req: &HttpRequest, playlist: String, source: &[&str]) -> HttpResponse {
+    manifest_response(req, playlist, HLS_CONTENT_TYPE, source)
+}
+
+/// Answer with a manifest generated by the proxy, tagged with an ETag so that
+/// players can revalidate it without downloading it again.
+///
+/// Rewritten URLs carry freshly encrypted tokens every time, so the ETag covers
+/// what the manifest is generated from instead: the upstream `source` and the
+/// request options shaping the rewrite.
+fn manifest_response(
+    req: &HttpRequest,
+    manifest: String,
+    content_type: &str,
+    source: &[&str],
+) -> HttpResponse {
+    let etag = {
+        let connection_info = req.connection_info();
+        let mut input = format!(
+            "{}://{}{}",
+            connection_info.scheme(),
+            connection_info.host(),
+            req.uri()
+        );
+        for part in source {
+            input.push('\n');
+            input.push_str(part);
+        }
+        conditional::etag_for(&input)
+    };
+    let mut validators = HeaderMap::new();
+    validators.insert(reqwest::header::ETAG, etag.clone());
+
+    let mut conditions = HeaderMap::new();
+    for name in &conditional::CONDITIONAL_HEADERS {
+        if let Some(value) = req
+            .headers()
+            .get(name.as_str())
+            .and_then(|v| HeaderValue::from_bytes(v.as_bytes()).ok())
+        {
+            conditions.insert(name, value);
+        }
+    }
+
+    let etag = (
+        actix_web::http::header::ETAG,
+        etag.to_str().unwrap_or_default(),
+    );
+    match conditional::evaluate(&conditions, &validators) {
+        Some(status) => HttpResponse::build(
+            StatusCode::from_u16(status.as_u16()).unwrap_or(StatusCode::NOT_MODIFIED),
+        )
+        .insert_header(etag)
+        .finish(),
+        None => HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header(etag)
+            .body(manifest),
+    }
+}
+
+async fn handle_proxy_request(
+    req: HttpRequest,
+    stream_manager: web::Data<StreamManager>,
+    proxy_data: web::ReqData<ProxyData>,
+    is_head: bool,
+) -> AppResult<HttpResponse> {
+    let request_headers = build_request_headers(&req, &proxy_data)?;
     tracing::debug!("Request headers: {:?}", request_headers);
 
-    // Create the stream
-    let (upstream_headers, stream_opt) = stream_manager
-        .create_stream(proxy_data.destination.clone(), request_headers, is_head)
+    let quota = if is_head { None } else { stream_quota(&req)? };
+    if !is_head {
+        stream_manager.prefetch_after(&proxy_data.destination, &request_headers);
+    }
+    let (served_by, upstream) = stream_manager
+        .create_stream_with_mirrors(
+            proxy_data.destination.clone(),
+            &proxy_data.mirrors,
+            request_headers,
+            is_head,
+        )
         .await?;
+    if proxy_data.mirrors.is_empty() {
+        return relay_response(&req, &stream_manager, &proxy_data, upstream, quota, is_head).await;
+    }
+
+    // Redirects are resolved against, and streams listed under, the mirror that answered
+    let served = ProxyData {
+        destination: served_by,
+        mirrors: Vec::new(),
+        ..proxy_data.into_inner()
+    };
+    let mut response =
+        relay_response(&req, &stream_manager, &served, upstream, quota, is_head).await?;
+    if let Some(host) = Url::parse(&served.destination).ok().and_then(|url| {
+        url.host_str()
+            .and_then(|host| actix_web::http::header::HeaderValue::from_str(host).ok())
+    }) {
+        response.headers_mut().insert(
+            actix_web::http::header::HeaderName::from_static(MIRROR_HEADER),
+            host,
+        );
+    }
+    Ok(response)
+}
 
-    tracing::debug!("Upstream headers: {:?}", upstream_headers);
+/// Count a stream against the quotas of the API key that authenticated the request.
+fn stream_quota(req: &HttpRequest) -> AppResult<Option<StreamQuota>> {
+    req.extensions()
+        .get::<Arc<ApiKey>>()
+        .map(|api_key| api_key.open_stream())
+        .transpose()
+}
+
+/// Describe a stream relayed to the client for the admin API.
+fn stream_info(
+    req: &HttpRequest,
+    stream_manager: &StreamManager,
+    proxy_data: &ProxyData,
+) -> StreamInfo {
+    StreamInfo {
+        client_ip: req
+            .connection_info()
+            .realip_remote_addr()
+            .unwrap_or("unknown")
+            .to_string(),
+        destination_host: Url::parse(&proxy_data.destination)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default(),
+        route: stream_manager
+            .proxy_router()
+            .route_name(&proxy_data.destination)
+            .to_string(),
+        key: req
+            .extensions()
+            .get::<Arc<ApiKey>>()
+            .map(|api_key| api_key.label().to_string()),
+    }
+}
+
+/// The body sent to the client: `stream` holding the slot of `quota` until it
+/// ends, throttled to the bandwidth of its key and listed in the stream registry.
+async fn client_stream(
+    req: &HttpRequest,
+    stream_manager: &StreamManager,
+    proxy_data: &ProxyData,
+    stream: ByteStream,
+    quota: Option<StreamQuota>,
+) -> ResponseStream<impl Stream<Item = Result<Bytes, AppError>>> {
+    let throttles = quota.as_ref().and_then(StreamQuota::bandwidth);
+    let stream = match quota {
+        Some(quota) => quota.apply(stream),
+        None => stream,
+    };
+    let stream_with_progress = stream_manager
+        .stream_with_progress(stream, throttles.into_iter().collect())
+        .await;
+    let info = stream_info(req, stream_manager, proxy_data);
+    let registered = stream_manager
+        .registry()
+        .register(info, stream_with_progress);
+    ResponseStream::new(registered)
+}
+
+/// Serve a segment processed by the proxy, counted and throttled like relayed streams.
+async fn segment_response(
+    req: &HttpRequest,
+    stream_manager: &StreamManager,
+    proxy_data: &ProxyData,
+    content_type: &str,
+    body: Bytes,
+    quota: Option<StreamQuota>,
+) -> HttpResponse {
+    let length = body.len();
+    // Sent in pieces, so that throttles pace the segment instead of holding it back whole
+    let chunks = (0..length)
+        .step_by(SEGMENT_CHUNK_SIZE)
+        .map(move |start| Ok(body.slice(start..length.min(start + SEGMENT_CHUNK_SIZE))));
+    let stream = client_stream(
+        req,
+        stream_manager,
+        proxy_data,
+        Box::pin(stream::iter(chunks)),
+        quota,
+    )
+    .await;
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .no_chunking(length as u64)
+        .body(SizedStream::new(length as u64, stream))
+}
+
+/// Relay an upstream response to the client with its status, supported headers and body.
+async fn relay_response(
+    req: &HttpRequest,
+    stream_manager: &StreamManager,
+    proxy_data: &ProxyData,
+    upstream: UpstreamResponse,
+    quota: Option<StreamQuota>,
+    is_head: bool,
+) -> AppResult<HttpResponse> {
+    let UpstreamResponse {
+        status,
+        headers: upstream_headers,
+        body,
+    } = upstream;
+    tracing::debug!(
+        "Upstream status {}, headers: {:?}",
+        status,
+        upstream_headers
+    );
 
-    // Prepare response headers
-    let mut response = HttpResponse::Ok();
+    let status = StatusCode::from_u16(status.as_u16())
+        .map_err(|e| AppError::Internal(format!("Invalid status code: {}", e)))?;
+    let mut response = HttpResponse::build(status);
+    // Responses without content (304, 416, redirects) must not announce the upstream body
+    let has_body = is_head || body.is_some();
 
     // Add supported headers from upstream response
     for &header_name in SUPPORTED_RESPONSE_HEADERS {
+        // A 416 tells the client the entity length as `bytes */<length>`
+        let unsatisfiable_range =
+            status == StatusCode::RANGE_NOT_SATISFIABLE && header_name == "content-range";
+        if !has_body && BODY_HEADERS.contains(&header_name) && !unsatisfiable_range {
+            continue;
+        }
         if let Some(value) = upstream_headers.get(header_name) {
             if let Ok(converted_value) =
                 actix_web::http::header::HeaderValue::from_str(value.to_str().unwrap_or_default())
@@ -76,6 +320,23 @@ async fn handle_proxy_request(
         }
     }
 
+    // Redirects are followed through the proxy, with the same headers and expiry
+    if status.is_redirection() {
+        if let Some(location) = upstream_headers
+            .get(reqwest::header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+        {
+            let target = Url::parse(&proxy_data.destination)
+                .and_then(|base| base.join(location))
+                .map_err(|e| AppError::Upstream(format!("Invalid redirect location: {}", e)))?;
+            let url_builder = ProxyUrlBuilder::from_request(req, proxy_data);
+            response.insert_header((
+                actix_web::http::header::LOCATION,
+                url_builder.build(STREAM_ENDPOINT, target.as_str())?,
+            ));
+        }
+    }
+
     // Get content length from headers
     let content_length = upstream_headers
         .get("content-length")
@@ -106,9 +367,8 @@ async fn handle_proxy_request(
         Ok(response
             .no_chunking(content_length)
             .body(SizedStream::new(content_length, empty_stream)))
-    } else if let Some(stream) = stream_opt {
-        let stream_with_progress = stream_manager.stream_with_progress(stream).await;
-        let response_stream = ResponseStream::new(stream_with_progress);
+    } else if let Some(stream) = body {
+        let response_stream = client_stream(req, stream_manager, proxy_data, stream, quota).await;
         // If we have a content length, use SizedStream
         if content_length > 0 {
             Ok(response
@@ -119,7 +379,7 @@ async fn handle_proxy_request(
             Ok(response.streaming(response_stream))
         }
     } else {
-        Err(AppError::Internal("Stream not available".to_string()))
+        Ok(response.finish())
     }
 }
 
@@ -139,7 +399,427 @@ pub async fn proxy_stream_head(
     handle_proxy_request(req, stream_manager, proxy_data, true).await
 }
 
-pub async fn generate_url(req: web::Json<GenerateUrlRequest>) -> AppResult<HttpResponse> {
+pub async fn proxy_hls_manifest(
+    req: HttpRequest,
+    stream_manager: web::Data<StreamManager>,
+    proxy_data: web::ReqData<ProxyData>,
+) -> AppResult<HttpResponse> {
+    let request_headers = build_request_headers(&req, &proxy_data)?;
+
+    let manifest = stream_manager
+        .fetch_manifest(proxy_data.destination.clone(), request_headers.clone())
+        .await?;
+
+    let mut playlist = Playlist::parse(&manifest.body)?;
+    if !playlist.is_master {
+        stream_manager.prefetch_playlist(
+            playlist.segment_urls(&manifest.url)?,
+            !playlist.has_tag("#EXT-X-ENDLIST"),
+            &request_headers,
+        );
+    }
+    if let Some(filter) = VariantFilter::from_request(&proxy_data, req.query_string())? {
+        playlist.filter_variants(&filter);
+    }
+    let url_builder = ProxyUrlBuilder::from_request(&req, &proxy_data);
+    // Relative URIs are resolved against the final URL, after any redirects
+    let decrypt = decrypt_requested(&proxy_data, req.query_string());
+    if remux_requested(&proxy_data, req.query_string()) {
+        playlist.rewrite_remuxed(&manifest.url, &url_builder, decrypt)?;
+    } else if decrypt {
+        playlist.rewrite_decrypted(&manifest.url, &url_builder)?;
+    } else {
+        playlist.rewrite(&manifest.url, &url_builder)?;
+    }
+
+    Ok(playlist_response(
+        &req,
+        playlist.to_string(),
+        &[manifest.url.as_str(), &manifest.body],
+    ))
+}
+
+/// Whether the client asked for AES-128 segments to be decrypted by the proxy,
+/// in the proxy data or the plain query string.
+fn decrypt_requested(proxy_data: &ProxyData, query_string: &str) -> bool {
+    has_param(proxy_data, query_string, DECRYPT_PARAM, |value| {
+        matches!(value, "1" | "true" | "yes")
+    })
+}
+
+/// Whether the client asked for MPEG-TS segments to be remuxed into fMP4 by the proxy.
+fn remux_requested(proxy_data: &ProxyData, query_string: &str) -> bool {
+    has_param(proxy_data, query_string, REMUX_PARAM, |value| {
+        value == "fmp4"
+    })
+}
+
+/// Whether `name` is set to a value `accepts`, in the proxy data or the plain query string.
+fn has_param(
+    proxy_data: &ProxyData,
+    query_string: &str,
+    name: &str,
+    accepts: impl Fn(&str) -> bool,
+) -> bool {
+    proxy_data
+        .query_params
+        .as_ref()
+        .and_then(|params| params.get(name))
+        .and_then(|value| value.as_str())
+        .is_some_and(&accepts)
+        || url::form_urlencoded::parse(query_string.as_bytes())
+            .any(|(key, value)| key == name && accepts(&value))
+}
+
+/// Fetch the key of an AES-128 encrypted segment, through the same transport
+/// route and with the same headers as the playlist. Keys are shared by the
+/// segments of a playlist, and fetched once while cached.
+async fn fetch_decryptor(
+    stream_manager: &StreamManager,
+    key_url: &str,
+    iv: &str,
+    request_headers: HeaderMap,
+) -> AppResult<Aes128Decryptor> {
+    let iv = aes128::parse_iv(iv)?;
+    let key = stream_manager
+        .fetch_resource(key_url.to_string(), request_headers)
+        .await?;
+    Aes128Decryptor::new(&key, iv)
+}
+
+/// Fetch an AES-128 encrypted HLS segment, and its key, and serve it decrypted.
+pub async fn proxy_hls_segment(
+    req: HttpRequest,
+    stream_manager: web::Data<StreamManager>,
+    proxy_data: web::ReqData<ProxyData>,
+) -> AppResult<HttpResponse> {
+    let params = web::Query::<HlsSegmentParams>::from_query(req.query_string())
+        .map_err(|e| AppError::Proxy(format!("Invalid segment parameters: {}", e)))?;
+    let quota = stream_quota(&req)?;
+
+    // Decryption needs whole segments, never a client-selected part of one
+    let mut request_headers = build_request_headers(&req, &proxy_data)?;
+    request_headers.remove(reqwest::header::RANGE);
+
+    let key_url = proxy_data
+        .query_param(KEY_URL_PARAM)
+        .ok_or_else(|| AppError::Proxy("Missing segment key".to_string()))?;
+    let decryptor = fetch_decryptor(
+        &stream_manager,
+        key_url,
+        &params.iv,
+        request_headers.clone(),
+    )
+    .await?;
+    stream_manager.prefetch_after(&proxy_data.destination, &request_headers);
+
+    if let Some(range) = params.range.as_deref().and_then(ByteRange::parse) {
+        request_headers.insert(
+            reqwest::header::RANGE,
+            HeaderValue::from_str(&range.header_value())
+                .map_err(|e| AppError::Internal(format!("Invalid header value: {}", e)))?,
+        );
+    }
+    let (upstream_headers, body) = stream_manager
+        .fetch_segment(proxy_data.destination.clone(), request_headers, &decryptor)
+        .await?;
+
+    let content_type = upstream_headers
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("video/mp2t");
+
+    Ok(segment_response(
+        &req,
+        &stream_manager,
+        &proxy_data,
+        content_type,
+        body,
+        quota,
+    )
+    .await)
+}
+
+/// Fetch an MPEG-TS segment, decrypted first if it carries a key, and serve
+/// it remuxed into an fMP4 fragment, or the init segment built from it.
+async fn remux_segment(
+    req: HttpRequest,
+    stream_manager: web::Data<StreamManager>,
+    proxy_data: web::ReqData<ProxyData>,
+    is_init: bool,
+) -> AppResult<HttpResponse> {
+    let params = web::Query::<RemuxSegmentParams>::from_query(req.query_string())
+        .map_err(|e| AppError::Proxy(format!("Invalid segment parameters: {}", e)))?;
+    let quota = stream_quota(&req)?;
+
+    // Remuxing needs whole segments, never a client-selected part of one
+    let mut request_headers = build_request_headers(&req, &proxy_data)?;
+    request_headers.remove(reqwest::header::RANGE);
+
+    let decryptor = match (proxy_data.query_param(KEY_URL_PARAM), &params.iv) {
+        (Some(key_url), Some(iv)) => {
+            Some(fetch_decryptor(&stream_manager, key_url, iv, request_headers.clone()).await?)
+        }
+        _ => None,
+    };
+    if !is_init {
+        stream_manager.prefetch_after(&proxy_data.destination, &request_headers);
+    }
+
+    if let Some(range) = params.range.as_deref().and_then(ByteRange::parse) {
+        request_headers.insert(
+            reqwest::header::RANGE,
+            HeaderValue::from_str(&range.header_value())
+                .map_err(|e| AppError::Internal(format!("Invalid header value: {}", e)))?,
+        );
+    }
+    let remuxer = if is_init {
+        TsRemuxer::InitSegment
+    } else {
+        TsRemuxer::MediaSegment {
+            sequence: params.seq.unwrap_or(0) as u32,
+        }
+    };
+    let (_, body) = stream_manager
+        .fetch_segment(
+            proxy_data.destination.clone(),
+            request_headers,
+            &(decryptor, remuxer),
+        )
+        .await?;
+
+    Ok(segment_response(
+        &req,
+        &stream_manager,
+        &proxy_data,
+        FMP4_CONTENT_TYPE,
+        body,
+        quota,
+    )
+    .await)
+}
+
+/// Serve the fMP4 init segment of a remuxed MPEG-TS stream, built from one of its segments.
+pub async fn proxy_hls_fmp4_init(
+    req: HttpRequest,
+    stream_manager: web::Data<StreamManager>,
+    proxy_data: web::ReqData<ProxyData>,
+) -> AppResult<HttpResponse> {
+    remux_segment(req, stream_manager, proxy_data, true).await
+}
+
+/// Serve an MPEG-TS segment remuxed into an fMP4 fragment.
+pub async fn proxy_hls_fmp4_segment(
+    req: HttpRequest,
+    stream_manager: web::Data<StreamManager>,
+    proxy_data: web::ReqData<ProxyData>,
+) -> AppResult<HttpResponse> {
+    remux_segment(req, stream_manager, proxy_data, false).await
+}
+
+/// Fetch and parse the MPD referenced by the proxy data.
+async fn fetch_mpd(
+    req: &HttpRequest,
+    stream_manager: &StreamManager,
+    proxy_data: &ProxyData,
+) -> AppResult<(CachedManifest, Mpd)> {
+    let request_headers = build_request_headers(req, proxy_data)?;
+    let manifest = stream_manager
+        .fetch_manifest(proxy_data.destination.clone(), request_headers)
+        .await?;
+    let mpd = Mpd::parse(&manifest.body, &manifest.url)?;
+
+    Ok((manifest, mpd))
+}
+
+pub async fn proxy_mpd_manifest(
+    req: HttpRequest,
+    stream_manager: web::Data<StreamManager>,
+    proxy_data: web::ReqData<ProxyData>,
+) -> AppResult<HttpResponse> {
+    let (manifest, mpd) = fetch_mpd(&req, &stream_manager, &proxy_data).await?;
+    let url_builder = ProxyUrlBuilder::from_request(&req, &proxy_data);
+
+    Ok(playlist_response(
+        &req,
+        mpd::build_master_playlist(&mpd, &url_builder)?,
+        &[manifest.url.as_str(), &manifest.body],
+    ))
+}
+
+/// Serve the MPD itself with its URLs rewritten through the proxy, for native DASH players.
+pub async fn proxy_mpd_passthrough(
+    req: HttpRequest,
+    stream_manager: web::Data<StreamManager>,
+    proxy_data: web::ReqData<ProxyData>,
+) -> AppResult<HttpResponse> {
+    let request_headers = build_request_headers(&req, &proxy_data)?;
+    let manifest = stream_manager
+        .fetch_manifest(proxy_data.destination.clone(), request_headers)
+        .await?;
+    let url_builder = ProxyUrlBuilder::from_request(&req, &proxy_data);
+
+    Ok(manifest_response(
+        &req,
+        mpd::rewrite_manifest(&manifest.body, &manifest.url, &url_builder)?,
+        mpd::MPD_CONTENT_TYPE,
+        &[manifest.url.as_str(), &manifest.body],
+    ))
+}
+
+pub async fn proxy_mpd_playlist(
+    req: HttpRequest,
+    stream_manager: web::Data<StreamManager>,
+    proxy_data: web::ReqData<ProxyData>,
+) -> AppResult<HttpResponse> {
+    let params = web::Query::<MpdPlaylistParams>::from_query(req.query_string())
+        .map_err(|e| AppError::Proxy(format!("Invalid playlist parameters: {}", e)))?;
+    let (manifest, mpd) = fetch_mpd(&req, &stream_manager, &proxy_data).await?;
+
+    if mpd.representation(&params.profile_id).is_none() {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("Representation '{}' not found", params.profile_id)
+        })));
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64();
+    let first_active = mpd.periods.len() - mpd.active_periods().len();
+
+    let mut periods = Vec::new();
+    for (index, period) in mpd.periods.iter().enumerate().skip(first_active) {
+        let Some(rep) = period
+            .representations
+            .iter()
+            .find(|rep| rep.id == params.profile_id)
+        else {
+            continue;
+        };
+
+        let segments = match rep.index_range() {
+            Some(index_range) => {
+                // SegmentBase: the segment list lives in the sidx box of the resource
+                let mut headers = build_request_headers(&req, &proxy_data)?;
+                headers.insert(
+                    reqwest::header::RANGE,
+                    HeaderValue::from_str(&index_range.header_value())
+                        .map_err(|e| AppError::Internal(format!("Invalid header value: {}", e)))?,
+                );
+                let sidx = stream_manager
+                    .make_request(rep.base_url.to_string(), headers)
+                    .await?
+                    .bytes()
+                    .await
+                    .map_err(|e| AppError::Upstream(format!("Failed to read sidx: {}", e)))?;
+                metrics().record_bytes_in(sidx.len());
+                mpd::sidx_segments(&sidx, index_range, rep.base_url.as_str())?
+            }
+            None => mpd.segments(index, rep, now)?,
+        };
+        periods.push((rep.init_segment()?, segments));
+    }
+
+    let url_builder = ProxyUrlBuilder::from_request(&req, &proxy_data);
+    // Live segment windows move with the clock, not only with the MPD
+    let window: String = periods
+        .iter()
+        .filter_map(|(_, segments)| {
+            Some(format!("{}+{} ", segments.first()?.number, segments.len()))
+        })
+        .collect();
+
+    Ok(playlist_response(
+        &req,
+        mpd::build_media_playlist(&mpd, &periods, &url_builder)?,
+        &[manifest.url.as_str(), &manifest.body, &window],
+    ))
+}
+
+pub async fn proxy_mpd_segment(
+    req: HttpRequest,
+    stream_manager: web::Data<StreamManager>,
+    proxy_data: web::ReqData<ProxyData>,
+) -> AppResult<HttpResponse> {
+    let params = web::Query::<MpdSegmentParams>::from_query(req.query_string())
+        .map_err(|e| AppError::Proxy(format!("Invalid segment parameters: {}", e)))?;
+    let keys = ClearKeys::from_request(&proxy_data, req.query_string())?;
+    let mut request_headers = build_request_headers(&req, &proxy_data)?;
+
+    // Decryption needs whole segments, never a client-selected part of one
+    if keys.is_some() {
+        request_headers.remove(reqwest::header::RANGE);
+    }
+
+    // Byte-range addressed segments (SegmentBase/SegmentList) carry their range in the URL
+    if let Some(range) = params.range.as_deref().and_then(ByteRange::parse) {
+        request_headers.insert(
+            reqwest::header::RANGE,
+            HeaderValue::from_str(&range.header_value())
+                .map_err(|e| AppError::Internal(format!("Invalid header value: {}", e)))?,
+        );
+    }
+
+    let quota = stream_quota(&req)?;
+    let Some(keys) = keys else {
+        let mut upstream = stream_manager
+            .create_stream(proxy_data.destination.clone(), request_headers, false)
+            .await?;
+        // The client asked for the whole segment, which happens to be a byte range upstream
+        if params.range.is_some() && upstream.status == reqwest::StatusCode::PARTIAL_CONTENT {
+            upstream.status = reqwest::StatusCode::OK;
+            upstream.headers.remove(reqwest::header::CONTENT_RANGE);
+        }
+        return relay_response(&req, &stream_manager, &proxy_data, upstream, quota, false).await;
+    };
+
+    let init_segment = match proxy_data.query_param(INIT_URL_PARAM) {
+        Some(init_url) => {
+            let mut init_headers = build_request_headers(&req, &proxy_data)?;
+            init_headers.remove(reqwest::header::RANGE);
+            if let Some(range) = params.init_range.as_deref().and_then(ByteRange::parse) {
+                init_headers.insert(
+                    reqwest::header::RANGE,
+                    HeaderValue::from_str(&range.header_value())
+                        .map_err(|e| AppError::Internal(format!("Invalid header value: {}", e)))?,
+                );
+            }
+            Some(
+                stream_manager
+                    .fetch_resource(init_url.to_string(), init_headers)
+                    .await?,
+            )
+        }
+        None => None,
+    };
+
+    let processor = ClearKeyProcessor::new(keys, init_segment.as_deref())?;
+    let (upstream_headers, body) = stream_manager
+        .fetch_segment(proxy_data.destination.clone(), request_headers, &processor)
+        .await?;
+
+    let content_type = upstream_headers
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("video/mp4");
+
+    Ok(segment_response(
+        &req,
+        &stream_manager,
+        &proxy_data,
+        content_type,
+        body,
+        quota,
+    )
+    .await)
+}
+
+pub async fn generate_url(
+    req: web::Json<GenerateUrlRequest>,
+    auth: web::Data<AuthMiddleware>,
+) -> AppResult<HttpResponse> {
+    let keyring = auth.keyring();
     let mut url = req.mediaflow_proxy_url.clone();
 
     if let Some(endpoint) = &req.endpoint {
@@ -152,6 +832,16 @@ pub async fn generate_url(req: web::Json<GenerateUrlRequest>) -> AppResult<HttpR
 
     // If api_password is provided in the request body, encrypt the data
     if let Some(api_password) = &req.api_password {
+        // Only keys allowed to generate URLs for the destination may sign them
+        if !keyring.is_empty() {
+            let api_key = keyring
+                .find(api_password)
+                .ok_or_else(|| AppError::Auth("Invalid API password".to_string()))?;
+            for destination in std::iter::once(&req.destination_url).chain(&req.mirrors) {
+                api_key.authorize(Some(Endpoint::GenerateUrl), Some(destination))?;
+            }
+        }
+
         let encryption_handler = EncryptionHandler::new(api_password.as_bytes()).map_err(|e| {
             AppError::Internal(format!("Failed to create encryption handler: {}", e))
         })?;
@@ -175,6 +865,7 @@ pub async fn generate_url(req: web::Json<GenerateUrlRequest>) -> AppResult<HttpR
                     + e
             }),
             ip: req.ip.clone(),
+            mirrors: req.mirrors.clone(),
         };
 
         let token = encryption_handler.encrypt(&proxy_data)?;
@@ -194,7 +885,13 @@ pub async fn generate_url(req: web::Json<GenerateUrlRequest>) -> AppResult<HttpR
 
         let query_string = params
             .iter()
-            .map(|(k, v)| format!("{}={}", k, urlencoding::encode(&v.to_string())))
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .chain(
+                req.mirrors
+                    .iter()
+                    .map(|mirror| (MIRROR_PARAM, mirror.as_str())),
+            )
+            .map(|(k, v)| format!("{}={}", k, urlencoding::encode(v)))
             .collect::<Vec<_>>()
             .join("&");
 
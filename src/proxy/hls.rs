@@ -0,0 +1,842 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use url::Url;
+
+use crate::{
+    auth::encryption::ProxyData,
+    error::{AppError, AppResult},
+    models::request::KEY_URL_PARAM,
+    proxy::{aes128, mpd::ByteRange, url_builder::ProxyUrlBuilder},
+};
+
+pub const HLS_MANIFEST_ENDPOINT: &str = "/proxy/hls/manifest.m3u8";
+pub const HLS_SEGMENT_ENDPOINT: &str = "/proxy/hls/segment";
+pub const HLS_FMP4_INIT_ENDPOINT: &str = "/proxy/hls/fmp4/init.mp4";
+pub const HLS_FMP4_SEGMENT_ENDPOINT: &str = "/proxy/hls/fmp4/segment.m4s";
+pub const STREAM_ENDPOINT: &str = "/proxy/stream";
+pub const HLS_CONTENT_TYPE: &str = "application/vnd.apple.mpegurl";
+
+/// Playlist version introducing `#EXT-X-MAP` in media playlists.
+const MAP_VERSION: u32 = 6;
+
+/// Tags whose `URI` attribute references another playlist rather than a media resource.
+const PLAYLIST_URI_TAGS: &[&str] = &[
+    "#EXT-X-MEDIA",
+    "#EXT-X-I-FRAME-STREAM-INF",
+    "#EXT-X-RENDITION-REPORT",
+];
+
+/// Tags whose `URI` attribute references a key, init section or partial segment.
+const RESOURCE_URI_TAGS: &[&str] = &[
+    "#EXT-X-KEY",
+    "#EXT-X-SESSION-KEY",
+    "#EXT-X-MAP",
+    "#EXT-X-PART",
+    "#EXT-X-PRELOAD-HINT",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlaylistLine {
+    /// A tag such as `#EXT-X-KEY:METHOD=AES-128,URI="key.bin"`, split into name and value.
+    Tag { name: String, value: Option<String> },
+    /// A variant playlist or media segment URI.
+    Uri(String),
+    /// Blank lines and plain comments, kept verbatim.
+    Other(String),
+}
+
+impl fmt::Display for PlaylistLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlaylistLine::Tag { name, value: None } => write!(f, "{}", name),
+            PlaylistLine::Tag {
+                name,
+                value: Some(value),
+            } => write!(f, "{}:{}", name, value),
+            PlaylistLine::Uri(uri) => write!(f, "{}", uri),
+            PlaylistLine::Other(line) => write!(f, "{}", line),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Playlist {
+    pub lines: Vec<PlaylistLine>,
+    pub is_master: bool,
+}
+
+impl Playlist {
+    pub fn parse(content: &str) -> AppResult<Self> {
+        let content = content.trim_start_matches('\u{feff}');
+        if !content.trim_start().starts_with("#EXTM3U") {
+            return Err(AppError::Upstream(
+                "Upstream response is not an HLS playlist".to_string(),
+            ));
+        }
+
+        let mut lines = Vec::new();
+        let mut is_master = false;
+
+        for raw_line in content.lines() {
+            let line = raw_line.trim();
+            if line.starts_with("#EXT") {
+                let (name, value) = match line.split_once(':') {
+                    Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                    None => (line.to_string(), None),
+                };
+                if name == "#EXT-X-STREAM-INF" {
+                    is_master = true;
+                }
+                lines.push(PlaylistLine::Tag { name, value });
+            } else if line.is_empty() || line.starts_with('#') {
+                lines.push(PlaylistLine::Other(line.to_string()));
+            } else {
+                lines.push(PlaylistLine::Uri(line.to_string()));
+            }
+        }
+
+        Ok(Self { lines, is_master })
+    }
+
+    /// Value of a tag that appears once in the playlist, e.g. `#EXT-X-TARGETDURATION`.
+    pub fn tag_value(&self, tag: &str) -> Option<&str> {
+        self.lines.iter().find_map(|line| match line {
+            PlaylistLine::Tag { name, value } if name == tag => value.as_deref(),
+            _ => None,
+        })
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.lines
+            .iter()
+            .any(|line| matches!(line, PlaylistLine::Tag { name, .. } if name == tag))
+    }
+
+    /// Absolute URLs of the media segments, in order, leaving out byte ranges of a resource.
+    pub fn segment_urls(&self, base_url: &Url) -> AppResult<Vec<String>> {
+        let mut urls = Vec::new();
+        let mut byte_range = false;
+        for line in &self.lines {
+            match line {
+                PlaylistLine::Tag { name, .. } if name == "#EXT-X-BYTERANGE" => byte_range = true,
+                PlaylistLine::Uri(uri) => {
+                    if !byte_range {
+                        urls.push(resolve_uri(base_url, uri)?);
+                    }
+                    byte_range = false;
+                }
+                _ => {}
+            }
+        }
+        Ok(urls)
+    }
+
+    /// Drop and reorder the `#EXT-X-STREAM-INF` variants of a master playlist.
+    ///
+    /// Each variant keeps the lines between its tag and URI. Variants take the
+    /// slots of the original ones in their new order, so renditions and other
+    /// tags stay where they were. A filter that matches no variant is ignored.
+    pub fn filter_variants(&mut self, filter: &VariantFilter) {
+        if !self.is_master {
+            return;
+        }
+
+        enum Entry {
+            Line(PlaylistLine),
+            Variant,
+        }
+        let mut entries = Vec::with_capacity(self.lines.len());
+        let mut variants: Vec<(Variant, Vec<PlaylistLine>)> = Vec::new();
+        let mut pending: Option<(Variant, Vec<PlaylistLine>)> = None;
+
+        for line in std::mem::take(&mut self.lines) {
+            if let Some((_, lines)) = &mut pending {
+                let is_uri = matches!(line, PlaylistLine::Uri(_));
+                lines.push(line);
+                if is_uri {
+                    variants.extend(pending.take());
+                    entries.push(Entry::Variant);
+                }
+                continue;
+            }
+            match &line {
+                PlaylistLine::Tag { name, value } if name == "#EXT-X-STREAM-INF" => {
+                    let variant = Variant::parse(value.as_deref().unwrap_or_default());
+                    pending = Some((variant, vec![line]));
+                }
+                _ => entries.push(Entry::Line(line)),
+            }
+        }
+        // A variant tag without URI is kept as is
+        if let Some((_, lines)) = pending {
+            entries.extend(lines.into_iter().map(Entry::Line));
+        }
+
+        let count = variants.len();
+        let mut kept: Vec<_> = variants
+            .iter()
+            .enumerate()
+            .filter(|(_, (variant, _))| filter.accepts(variant))
+            .map(|(index, _)| index)
+            .collect();
+        if kept.is_empty() {
+            tracing::debug!("No variant matches the filter, keeping all {}", count);
+            kept = (0..count).collect();
+        }
+
+        let quality = |index: &usize| variants[*index].0.quality();
+        match filter.order {
+            Some(Quality::Highest) => kept.sort_by_key(|index| std::cmp::Reverse(quality(index))),
+            Some(Quality::Lowest) => kept.sort_by_key(quality),
+            None => {}
+        }
+        let single = match filter.single {
+            Some(Quality::Highest) => kept.iter().copied().max_by_key(quality),
+            Some(Quality::Lowest) => kept.iter().copied().min_by_key(quality),
+            None => None,
+        };
+        if let Some(single) = single {
+            kept = vec![single];
+        }
+
+        let mut variants: Vec<Option<Vec<PlaylistLine>>> =
+            variants.into_iter().map(|(_, lines)| Some(lines)).collect();
+        let mut kept = kept.into_iter();
+        for entry in entries {
+            match entry {
+                Entry::Line(line) => self.lines.push(line),
+                Entry::Variant => {
+                    if let Some(lines) = kept.next().and_then(|index| variants[index].take()) {
+                        self.lines.extend(lines);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Rewrite every URI in the playlist so it is fetched through the proxy.
+    ///
+    /// Variant and rendition playlists point back at the HLS manifest endpoint,
+    /// while segments, keys and init sections go through the stream endpoint.
+    pub fn rewrite(&mut self, base_url: &Url, url_builder: &ProxyUrlBuilder) -> AppResult<()> {
+        self.rewrite_lines(base_url, url_builder, false, false)
+    }
+
+    /// Like [`rewrite`](Self::rewrite), with segments encrypted with `METHOD=AES-128`
+    /// decrypted by the proxy.
+    ///
+    /// Their `#EXT-X-KEY` lines are removed and the segments, along with their
+    /// init sections, go through the HLS segment endpoint with the key URL and
+    /// IV. Byte ranges of encrypted segments move into the segment URL, since
+    /// each range is decrypted on its own.
+    pub fn rewrite_decrypted(
+        &mut self,
+        base_url: &Url,
+        url_builder: &ProxyUrlBuilder,
+    ) -> AppResult<()> {
+        self.rewrite_lines(base_url, url_builder, true, false)
+    }
+
+    /// Like [`rewrite`](Self::rewrite), with MPEG-TS segments remuxed into
+    /// fMP4 by the proxy.
+    ///
+    /// Segments go through the fMP4 segment endpoint, and an `#EXT-X-MAP`
+    /// pointing at the init segment endpoint is added before the first segment
+    /// and after every discontinuity, where the codec configuration may change.
+    /// AES-128 segments are decrypted on the way. Master playlists advertise
+    /// HEVC as `hvc1`, the sample entry of the remuxed segments.
+    ///
+    /// Playlists that already have init sections, or use `SAMPLE-AES`, are not
+    /// transport streams the proxy can remux; they are rewritten as with
+    /// [`rewrite`](Self::rewrite), or [`rewrite_decrypted`](Self::rewrite_decrypted)
+    /// if `decrypt` is set.
+    pub fn rewrite_remuxed(
+        &mut self,
+        base_url: &Url,
+        url_builder: &ProxyUrlBuilder,
+        decrypt: bool,
+    ) -> AppResult<()> {
+        if self.is_master {
+            self.rewrite_codecs();
+            return self.rewrite_lines(base_url, url_builder, decrypt, false);
+        }
+        let sample_aes = self.lines.iter().any(|line| {
+            matches!(line, PlaylistLine::Tag { name, value: Some(value) }
+                if name == "#EXT-X-KEY" && value.contains("METHOD=SAMPLE-AES"))
+        });
+        if self.has_tag("#EXT-X-MAP") || sample_aes {
+            return self.rewrite_lines(base_url, url_builder, decrypt, false);
+        }
+
+        self.rewrite_lines(base_url, url_builder, true, true)?;
+        self.require_version(MAP_VERSION);
+        Ok(())
+    }
+
+    /// Advertise `hev1` variants as `hvc1`.
+    fn rewrite_codecs(&mut self) {
+        for line in &mut self.lines {
+            let PlaylistLine::Tag {
+                name,
+                value: Some(value),
+            } = line
+            else {
+                continue;
+            };
+            if name != "#EXT-X-STREAM-INF" && name != "#EXT-X-I-FRAME-STREAM-INF" {
+                continue;
+            }
+            let mut attributes = parse_attributes(value);
+            let mut changed = false;
+            for (key, codecs) in attributes.iter_mut() {
+                if key == "CODECS" && codecs.contains("hev1.") {
+                    *codecs = codecs.replace("hev1.", "hvc1.");
+                    changed = true;
+                }
+            }
+            if changed {
+                *value = format_attributes(&attributes);
+            }
+        }
+    }
+
+    /// Raise `#EXT-X-VERSION` to at least `version`, adding it if missing.
+    fn require_version(&mut self, version: u32) {
+        for line in &mut self.lines {
+            if let PlaylistLine::Tag {
+                name,
+                value: Some(value),
+            } = line
+            {
+                if name == "#EXT-X-VERSION" {
+                    if value.trim().parse::<u32>().is_ok_and(|v| v < version) {
+                        *value = version.to_string();
+                    }
+                    return;
+                }
+            }
+        }
+        // Right after #EXTM3U
+        let index = 1.min(self.lines.len());
+        self.lines.insert(
+            index,
+            PlaylistLine::Tag {
+                name: "#EXT-X-VERSION".to_string(),
+                value: Some(version.to_string()),
+            },
+        );
+    }
+
+    fn rewrite_lines(
+        &mut self,
+        base_url: &Url,
+        url_builder: &ProxyUrlBuilder,
+        decrypt: bool,
+        remux: bool,
+    ) -> AppResult<()> {
+        let is_master = self.is_master;
+        let mut sequence: u64 = self
+            .tag_value("#EXT-X-MEDIA-SEQUENCE")
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(0);
+        let mut key: Option<SegmentKey> = None;
+        // Length and offset of the next encrypted segment, from its `#EXT-X-BYTERANGE`
+        let mut byte_range: Option<(u64, Option<u64>)> = None;
+        // Resource and end of the last sub-range, where a range without offset starts
+        let mut last_range: Option<(String, u64)> = None;
+        // Where the `#EXT-X-MAP` of the next segment goes, when remuxing
+        let mut map_needed = remux;
+        let mut map_index: Option<usize> = None;
+        let mut maps: Vec<(usize, PlaylistLine)> = Vec::new();
+
+        let mut lines = Vec::with_capacity(self.lines.len());
+        for mut line in std::mem::take(&mut self.lines) {
+            match &mut line {
+                PlaylistLine::Uri(uri) => {
+                    let destination = resolve_uri(base_url, uri)?;
+                    *uri = if is_master {
+                        url_builder.build(HLS_MANIFEST_ENDPOINT, &destination)?
+                    } else if key.is_some() || remux {
+                        let range = byte_range.take().map(|(length, offset)| {
+                            let start = match (offset, &last_range) {
+                                (Some(offset), _) => offset,
+                                (None, Some((resource, end))) if *resource == destination => *end,
+                                (None, _) => 0,
+                            };
+                            last_range = Some((destination.clone(), start + length));
+                            ByteRange {
+                                start,
+                                end: (start + length).saturating_sub(1),
+                            }
+                        });
+                        if let Some(index) = map_index.take() {
+                            let init = segment_url(
+                                url_builder,
+                                HLS_FMP4_INIT_ENDPOINT,
+                                &destination,
+                                key.as_ref(),
+                                sequence,
+                                range,
+                            )?;
+                            maps.push((
+                                index,
+                                PlaylistLine::Tag {
+                                    name: "#EXT-X-MAP".to_string(),
+                                    value: Some(format!("URI=\"{}\"", init)),
+                                },
+                            ));
+                        }
+                        let endpoint = if remux {
+                            HLS_FMP4_SEGMENT_ENDPOINT
+                        } else {
+                            HLS_SEGMENT_ENDPOINT
+                        };
+                        segment_url(
+                            url_builder,
+                            endpoint,
+                            &destination,
+                            key.as_ref(),
+                            sequence,
+                            range,
+                        )?
+                    } else {
+                        url_builder.build(STREAM_ENDPOINT, &destination)?
+                    };
+                    sequence += 1;
+                }
+                PlaylistLine::Tag { name, .. } if name == "#EXT-X-DISCONTINUITY" => {
+                    map_needed = remux;
+                }
+                PlaylistLine::Tag { name, .. } if name == "#EXTINF" && map_needed => {
+                    map_needed = false;
+                    map_index = Some(lines.len());
+                }
+                PlaylistLine::Tag {
+                    name,
+                    value: Some(value),
+                } => {
+                    if decrypt && (name == "#EXT-X-KEY" || name == "#EXT-X-SESSION-KEY") {
+                        let decrypted = SegmentKey::from_attributes(base_url, value)?;
+                        if name == "#EXT-X-KEY" {
+                            key = decrypted;
+                            if key.is_some() {
+                                continue;
+                            }
+                        } else if decrypted.is_some() {
+                            // Players have no key to preload
+                            continue;
+                        }
+                    } else if name == "#EXT-X-BYTERANGE" && (key.is_some() || remux) {
+                        byte_range = parse_byte_range(value);
+                        if byte_range.is_some() {
+                            continue;
+                        }
+                    } else if name == "#EXT-X-MAP" {
+                        if let Some(key) = &key {
+                            *value = key.map_value(base_url, url_builder, value, sequence)?;
+                            lines.push(line);
+                            continue;
+                        }
+                    }
+
+                    let endpoint = if PLAYLIST_URI_TAGS.contains(&name.as_str()) {
+                        HLS_MANIFEST_ENDPOINT
+                    } else if RESOURCE_URI_TAGS.contains(&name.as_str()) {
+                        STREAM_ENDPOINT
+                    } else {
+                        lines.push(line);
+                        continue;
+                    };
+                    rewrite_uri_attribute(value, |uri| {
+                        url_builder.build(endpoint, &resolve_uri(base_url, uri)?)
+                    })?;
+                }
+                _ => {}
+            }
+            lines.push(line);
+        }
+
+        for (index, map) in maps.into_iter().rev() {
+            lines.insert(index, map);
+        }
+        self.lines = lines;
+        Ok(())
+    }
+}
+
+/// The key of `METHOD=AES-128` segments, when the proxy decrypts them.
+struct SegmentKey {
+    url: String,
+    iv: Option<[u8; 16]>,
+}
+
+impl SegmentKey {
+    /// The key of an `#EXT-X-KEY` attribute list, if the proxy can decrypt with it.
+    fn from_attributes(base_url: &Url, value: &str) -> AppResult<Option<Self>> {
+        let attributes = parse_attributes(value);
+        let identity = attribute(&attributes, "KEYFORMAT").is_none_or(|f| f == "identity");
+        if attribute(&attributes, "METHOD") != Some("AES-128") || !identity {
+            return Ok(None);
+        }
+        let Some(uri) = attribute(&attributes, "URI") else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            url: resolve_uri(base_url, uri)?,
+            iv: attribute(&attributes, "IV")
+                .map(aes128::parse_iv)
+                .transpose()?,
+        }))
+    }
+
+    /// Rewrite an `#EXT-X-MAP` value so its init section is decrypted too.
+    fn map_value(
+        &self,
+        base_url: &Url,
+        url_builder: &ProxyUrlBuilder,
+        value: &str,
+        sequence: u64,
+    ) -> AppResult<String> {
+        let mut attributes = parse_attributes(value);
+        let range = attribute(&attributes, "BYTERANGE")
+            .and_then(parse_byte_range)
+            .map(|(length, offset)| {
+                let start = offset.unwrap_or(0);
+                ByteRange {
+                    start,
+                    end: (start + length).saturating_sub(1),
+                }
+            });
+        attributes.retain(|(key, _)| key != "BYTERANGE");
+
+        let mut value = format_attributes(&attributes);
+        rewrite_uri_attribute(&mut value, |uri| {
+            segment_url(
+                url_builder,
+                HLS_SEGMENT_ENDPOINT,
+                &resolve_uri(base_url, uri)?,
+                Some(self),
+                sequence,
+                range,
+            )
+        })?;
+        Ok(value)
+    }
+}
+
+/// The URL of a segment the proxy decrypts or remuxes, with its key and byte range.
+fn segment_url(
+    url_builder: &ProxyUrlBuilder,
+    endpoint: &str,
+    destination: &str,
+    key: Option<&SegmentKey>,
+    sequence: u64,
+    range: Option<ByteRange>,
+) -> AppResult<String> {
+    let iv =
+        key.map(|key| aes128::format_iv(&key.iv.unwrap_or_else(|| aes128::sequence_iv(sequence))));
+    let range = range.map(|range| range.to_string());
+    let sequence = sequence.to_string();
+
+    let mut params = Vec::new();
+    if let (Some(key), Some(iv)) = (key, &iv) {
+        params.push((KEY_URL_PARAM, key.url.as_str()));
+        params.push(("iv", iv.as_str()));
+    }
+    if let Some(range) = &range {
+        params.push(("range", range.as_str()));
+    }
+    if endpoint == HLS_FMP4_SEGMENT_ENDPOINT {
+        params.push(("seq", sequence.as_str()));
+    }
+    url_builder.build_with_params(endpoint, destination, &params)
+}
+
+/// Parse an `<n>[@<o>]` byte range into its length and offset.
+fn parse_byte_range(value: &str) -> Option<(u64, Option<u64>)> {
+    match value.trim().split_once('@') {
+        Some((length, offset)) => Some((length.parse().ok()?, Some(offset.parse().ok()?))),
+        None => Some((value.trim().parse().ok()?, None)),
+    }
+}
+
+/// Replace the `URI` attribute of a tag value with the result of `build`.
+fn rewrite_uri_attribute(
+    value: &mut String,
+    mut build: impl FnMut(&str) -> AppResult<String>,
+) -> AppResult<()> {
+    let mut attributes = parse_attributes(value);
+    let mut changed = false;
+    for (key, attr_value) in attributes.iter_mut() {
+        if key == "URI" {
+            let uri = unquote(attr_value);
+            // Sample-AES key formats such as skd:// are opaque to the proxy
+            if uri.starts_with("skd://") || uri.starts_with("data:") {
+                continue;
+            }
+            *attr_value = format!("\"{}\"", build(uri)?);
+            changed = true;
+        }
+    }
+    if changed {
+        *value = format_attributes(&attributes);
+    }
+    Ok(())
+}
+
+impl fmt::Display for Playlist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Which end of the quality range a variant option refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    Highest,
+    Lowest,
+}
+
+impl Quality {
+    fn parse(value: &str) -> AppResult<Self> {
+        match value {
+            "highest" | "highest_first" => Ok(Self::Highest),
+            "lowest" | "lowest_first" => Ok(Self::Lowest),
+            _ => Err(AppError::Proxy(format!(
+                "Invalid variant quality '{}', expected 'highest' or 'lowest'",
+                value
+            ))),
+        }
+    }
+}
+
+/// A resolution limit: `1280x720`, or a height alone such as `720` or `720p`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolution {
+    pub width: Option<u64>,
+    pub height: u64,
+}
+
+impl Resolution {
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        match value.split_once(['x', 'X']) {
+            Some((width, height)) => Some(Self {
+                width: Some(width.trim().parse().ok()?),
+                height: height.trim().parse().ok()?,
+            }),
+            None => Some(Self {
+                width: None,
+                height: value.trim_end_matches('p').parse().ok()?,
+            }),
+        }
+    }
+}
+
+/// The attributes of an `#EXT-X-STREAM-INF` the filter looks at.
+#[derive(Debug, Clone, Default)]
+struct Variant {
+    resolution: Option<(u64, u64)>,
+    bandwidth: Option<u64>,
+    codecs: Vec<String>,
+}
+
+impl Variant {
+    fn parse(value: &str) -> Self {
+        let attributes = parse_attributes(value);
+        Self {
+            resolution: attribute(&attributes, "RESOLUTION")
+                .and_then(Resolution::parse)
+                .and_then(|r| Some((r.width?, r.height))),
+            bandwidth: attribute(&attributes, "BANDWIDTH").and_then(|b| b.parse().ok()),
+            codecs: attribute(&attributes, "CODECS")
+                .map(|codecs| codecs.split(',').map(|c| c.trim().to_string()).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Pixels first, then bitrate.
+    fn quality(&self) -> (u64, u64) {
+        (
+            self.resolution.map_or(0, |(w, h)| w * h),
+            self.bandwidth.unwrap_or(0),
+        )
+    }
+}
+
+/// Which variants of a master playlist players get, and in what order.
+///
+/// Read from the `max_resolution`, `min_resolution`, `max_bandwidth`,
+/// `codecs`, `variant` and `order` parameters of the request or its token.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VariantFilter {
+    pub max_resolution: Option<Resolution>,
+    pub min_resolution: Option<Resolution>,
+    /// Highest `BANDWIDTH`, in bits per second
+    pub max_bandwidth: Option<u64>,
+    /// Allowed codec prefixes, e.g. `avc1` and `mp4a`
+    pub codecs: Vec<String>,
+    /// Keep only the highest or lowest quality variant
+    pub single: Option<Quality>,
+    /// Sort the variants, highest or lowest quality first
+    pub order: Option<Quality>,
+}
+
+impl VariantFilter {
+    /// Read the filter from the proxy data, falling back to the plain query string.
+    ///
+    /// Returns `None` when no variant option is set.
+    pub fn from_request(proxy_data: &ProxyData, query_string: &str) -> AppResult<Option<Self>> {
+        let from_proxy_data = |name: &str| {
+            proxy_data
+                .query_params
+                .as_ref()
+                .and_then(|params| params.get(name))
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        };
+        let query: HashMap<String, String> = url::form_urlencoded::parse(query_string.as_bytes())
+            .into_owned()
+            .collect();
+        let param = |name: &str| {
+            from_proxy_data(name)
+                .or_else(|| query.get(name).cloned())
+                .filter(|value| !value.is_empty())
+        };
+        let resolution = |name: &str| {
+            param(name)
+                .map(|value| {
+                    Resolution::parse(&value)
+                        .ok_or_else(|| AppError::Proxy(format!("Invalid {} '{}'", name, value)))
+                })
+                .transpose()
+        };
+
+        let filter = Self {
+            max_resolution: resolution("max_resolution")?,
+            min_resolution: resolution("min_resolution")?,
+            max_bandwidth: param("max_bandwidth")
+                .map(|value| {
+                    value
+                        .parse()
+                        .map_err(|_| AppError::Proxy(format!("Invalid max_bandwidth '{}'", value)))
+                })
+                .transpose()?,
+            codecs: param("codecs")
+                .map(|codecs| {
+                    codecs
+                        .split(',')
+                        .map(|c| c.trim().to_string())
+                        .filter(|c| !c.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            single: param("variant")
+                .as_deref()
+                .map(Quality::parse)
+                .transpose()?,
+            order: param("order").as_deref().map(Quality::parse).transpose()?,
+        };
+
+        Ok((filter != Self::default()).then_some(filter))
+    }
+
+    fn accepts(&self, variant: &Variant) -> bool {
+        if let Some((width, height)) = variant.resolution {
+            if let Some(max) = self.max_resolution {
+                if height > max.height || max.width.is_some_and(|w| width > w) {
+                    return false;
+                }
+            }
+            if let Some(min) = self.min_resolution {
+                if height < min.height || min.width.is_some_and(|w| width < w) {
+                    return false;
+                }
+            }
+        }
+        if let (Some(max), Some(bandwidth)) = (self.max_bandwidth, variant.bandwidth) {
+            if bandwidth > max {
+                return false;
+            }
+        }
+        self.codecs.is_empty()
+            || variant.codecs.iter().all(|codec| {
+                self.codecs
+                    .iter()
+                    .any(|allowed| codec.starts_with(allowed.as_str()))
+            })
+    }
+}
+
+/// Split an HLS attribute list into `(key, raw value)` pairs, keeping quotes intact.
+pub fn parse_attributes(value: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    let mut push = |item: &str| {
+        if let Some((key, value)) = item.split_once('=') {
+            attributes.push((key.trim().to_string(), value.trim().to_string()));
+        } else if !item.trim().is_empty() {
+            attributes.push((item.trim().to_string(), String::new()));
+        }
+    };
+
+    for c in value.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                current.push(c);
+            }
+            ',' if !in_quotes => {
+                push(&current);
+                current.clear();
+            }
+            _ => current.push(c),
+        }
+    }
+    push(&current);
+
+    attributes
+}
+
+pub fn format_attributes(attributes: &[(String, String)]) -> String {
+    attributes
+        .iter()
+        .map(|(key, value)| {
+            if value.is_empty() {
+                key.clone()
+            } else {
+                format!("{}={}", key, value)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Look up an attribute by name, with surrounding quotes removed.
+pub fn attribute<'a>(attributes: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| unquote(value))
+}
+
+pub fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+pub fn resolve_uri(base_url: &Url, uri: &str) -> AppResult<String> {
+    base_url
+        .join(uri)
+        .map(|url| url.to_string())
+        .map_err(|e| AppError::Upstream(format!("Invalid URI '{}' in playlist: {}", uri, e)))
+}
@@ -1,2 +1,19 @@
+pub mod admin;
+pub mod aes128;
+pub mod cache;
+pub mod clearkey;
+pub mod conditional;
+pub mod disk_cache;
 pub mod handler;
+pub mod hls;
+pub mod mp4;
+pub mod mpd;
+pub mod pool;
+pub mod prefetch;
+pub mod rate_limit;
+pub mod registry;
+pub mod remux;
 pub mod stream;
+pub mod throttle;
+pub mod ts;
+pub mod url_builder;
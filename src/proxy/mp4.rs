@@ -0,0 +1,252 @@
+use crate::error::{AppError, AppResult};
+
+/// Boxes whose payload is only a sequence of child boxes.
+const CONTAINER_BOXES: &[&[u8; 4]] = &[
+    b"moov", b"trak", b"mdia", b"minf", b"stbl", b"moof", b"traf", b"mvex", b"edts", b"dinf",
+    b"sinf", b"schi", b"udta", b"mfra",
+];
+
+/// Protected sample entries that carry a `sinf` child after their fixed fields.
+const VISUAL_SAMPLE_ENTRY_FIELDS: usize = 78;
+const AUDIO_SAMPLE_ENTRY_FIELDS: usize = 28;
+
+/// An ISO BMFF box.
+///
+/// Containers keep their fixed fields in `payload` and their parsed children
+/// in `children`; leaf boxes keep their whole payload in `payload`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mp4Box {
+    pub kind: [u8; 4],
+    pub payload: Vec<u8>,
+    pub children: Vec<Mp4Box>,
+}
+
+impl Mp4Box {
+    pub fn leaf(kind: &[u8; 4], payload: Vec<u8>) -> Self {
+        Self {
+            kind: *kind,
+            payload,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn is(&self, kind: &[u8; 4]) -> bool {
+        &self.kind == kind
+    }
+
+    pub fn child(&self, kind: &[u8; 4]) -> Option<&Mp4Box> {
+        self.children.iter().find(|b| b.is(kind))
+    }
+
+    pub fn children_of<'a>(&'a self, kind: &'a [u8; 4]) -> impl Iterator<Item = &'a Mp4Box> {
+        self.children.iter().filter(move |b| b.is(kind))
+    }
+
+    /// Follow a path of nested boxes, e.g. `[b"mdia", b"minf", b"stbl"]`.
+    pub fn find(&self, path: &[&[u8; 4]]) -> Option<&Mp4Box> {
+        path.iter()
+            .try_fold(self, |current, kind| current.child(kind))
+    }
+
+    pub fn size(&self) -> u64 {
+        let body = self.payload.len() as u64 + self.children.iter().map(Mp4Box::size).sum::<u64>();
+        if body + 8 > u32::MAX as u64 {
+            body + 16
+        } else {
+            body + 8
+        }
+    }
+
+    pub fn write_to(&self, output: &mut Vec<u8>) {
+        let size = self.size();
+        if size > u32::MAX as u64 {
+            output.extend_from_slice(&1u32.to_be_bytes());
+            output.extend_from_slice(&self.kind);
+            output.extend_from_slice(&size.to_be_bytes());
+        } else {
+            output.extend_from_slice(&(size as u32).to_be_bytes());
+            output.extend_from_slice(&self.kind);
+        }
+        output.extend_from_slice(&self.payload);
+        for child in &self.children {
+            child.write_to(output);
+        }
+    }
+
+    /// Full-box version byte.
+    pub fn version(&self) -> u8 {
+        self.payload.first().copied().unwrap_or(0)
+    }
+
+    /// Full-box 24-bit flags.
+    pub fn flags(&self) -> u32 {
+        match self.payload.get(1..4) {
+            Some(f) => u32::from_be_bytes([0, f[0], f[1], f[2]]),
+            None => 0,
+        }
+    }
+}
+
+/// A located box header inside a buffer, used where absolute offsets matter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoxHeader {
+    pub kind: [u8; 4],
+    pub offset: usize,
+    pub header_size: usize,
+    pub size: usize,
+}
+
+impl BoxHeader {
+    pub fn payload_range(&self) -> std::ops::Range<usize> {
+        self.offset + self.header_size..self.offset + self.size
+    }
+}
+
+/// Scan the top-level box headers of `data`.
+pub fn scan_boxes(data: &[u8]) -> AppResult<Vec<BoxHeader>> {
+    let mut headers = Vec::new();
+    let mut offset = 0;
+
+    while offset + 8 <= data.len() {
+        let mut reader = Reader::new(&data[offset..]);
+        let size32 = reader.u32()?;
+        let kind = reader.fourcc()?;
+        let (size, header_size) = match size32 {
+            0 => (data.len() - offset, 8),
+            1 => (
+                usize::try_from(reader.u64()?)
+                    .map_err(|_| invalid(&kind, "box size exceeds buffer"))?,
+                16,
+            ),
+            size => (size as usize, 8),
+        };
+        if size < header_size || offset.checked_add(size).is_none_or(|end| end > data.len()) {
+            return Err(invalid(&kind, "box size exceeds buffer"));
+        }
+        headers.push(BoxHeader {
+            kind,
+            offset,
+            header_size,
+            size,
+        });
+        offset += size;
+    }
+
+    Ok(headers)
+}
+
+/// Parse a sequence of boxes, descending into containers and protected sample entries.
+pub fn parse_boxes(data: &[u8]) -> AppResult<Vec<Mp4Box>> {
+    scan_boxes(data)?
+        .into_iter()
+        .map(|header| parse_box(header.kind, &data[header.payload_range()]))
+        .collect()
+}
+
+fn parse_box(kind: [u8; 4], payload: &[u8]) -> AppResult<Mp4Box> {
+    let fields_len = if CONTAINER_BOXES.contains(&&kind) {
+        Some(0)
+    } else {
+        match &kind {
+            b"stsd" => Some(8),
+            b"encv" => Some(VISUAL_SAMPLE_ENTRY_FIELDS),
+            b"enca" => {
+                // QuickTime sound sample entry versions 1 and 2 carry extra fields
+                let version = payload.get(8..10).map(|v| u16::from_be_bytes([v[0], v[1]]));
+                Some(
+                    AUDIO_SAMPLE_ENTRY_FIELDS
+                        + match version {
+                            Some(1) => 16,
+                            Some(2) => 36,
+                            _ => 0,
+                        },
+                )
+            }
+            _ => None,
+        }
+    };
+
+    match fields_len {
+        Some(fields_len) if payload.len() >= fields_len => Ok(Mp4Box {
+            kind,
+            payload: payload[..fields_len].to_vec(),
+            children: parse_boxes(&payload[fields_len..])?,
+        }),
+        Some(_) => Err(invalid(&kind, "box too short")),
+        None => Ok(Mp4Box::leaf(&kind, payload.to_vec())),
+    }
+}
+
+pub fn write_boxes(boxes: &[Mp4Box]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(boxes.iter().map(|b| b.size() as usize).sum());
+    for b in boxes {
+        b.write_to(&mut output);
+    }
+    output
+}
+
+pub fn fourcc_string(kind: &[u8; 4]) -> String {
+    String::from_utf8_lossy(kind).into_owned()
+}
+
+fn invalid(kind: &[u8; 4], reason: &str) -> AppError {
+    AppError::Upstream(format!(
+        "Invalid MP4 '{}' box: {}",
+        fourcc_string(kind),
+        reason
+    ))
+}
+
+/// Big-endian cursor over box payloads.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn bytes(&mut self, len: usize) -> AppResult<&'a [u8]> {
+        let end = self
+            .position
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| AppError::Upstream("Truncated MP4 box".to_string()))?;
+        let slice = &self.data[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+
+    pub fn skip(&mut self, len: usize) -> AppResult<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    pub fn u8(&mut self) -> AppResult<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> AppResult<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> AppResult<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> AppResult<u64> {
+        Ok(((self.u32()? as u64) << 32) | self.u32()? as u64)
+    }
+
+    pub fn fourcc(&mut self) -> AppResult<[u8; 4]> {
+        let b = self.bytes(4)?;
+        Ok([b[0], b[1], b[2], b[3]])
+    }
+}
@@ -0,0 +1,1166 @@
+use roxmltree::{Document, Node};
+use std::fmt::Write as _;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use url::Url;
+
+use crate::{
+    error::{AppError, AppResult},
+    models::request::INIT_URL_PARAM,
+    proxy::{hls::STREAM_ENDPOINT, url_builder::ProxyUrlBuilder},
+};
+
+pub const MPD_MANIFEST_ENDPOINT: &str = "/proxy/mpd/manifest.m3u8";
+pub const MPD_PLAYLIST_ENDPOINT: &str = "/proxy/mpd/playlist.m3u8";
+pub const MPD_SEGMENT_ENDPOINT: &str = "/proxy/mpd/segment";
+pub const MPD_PASSTHROUGH_ENDPOINT: &str = "/proxy/mpd/manifest.mpd";
+pub const MPD_CONTENT_TYPE: &str = "application/dash+xml";
+
+/// Live window used when the MPD does not declare `timeShiftBufferDepth`.
+const DEFAULT_LIVE_WINDOW_SECS: f64 = 60.0;
+/// Most segments a representation may list in one period.
+const MAX_SEGMENTS: u64 = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    /// Inclusive end offset, as in HTTP `Range` headers.
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (start, end) = value.trim().split_once('-')?;
+        Some(Self {
+            start: start.trim().parse().ok()?,
+            end: end.trim().parse().ok()?,
+        })
+    }
+
+    pub fn header_value(&self) -> String {
+        format!("bytes={}-{}", self.start, self.end)
+    }
+}
+
+impl std::fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    Video,
+    Audio,
+    Text,
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimelineEntry {
+    pub t: Option<u64>,
+    pub d: u64,
+    pub r: i64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SegmentTemplate {
+    pub media: Option<String>,
+    pub initialization: Option<String>,
+    pub timescale: Option<u64>,
+    pub duration: Option<u64>,
+    pub start_number: Option<u64>,
+    pub presentation_time_offset: Option<u64>,
+    pub timeline: Vec<TimelineEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentUrl {
+    pub media: Option<String>,
+    pub media_range: Option<ByteRange>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SegmentAddressing {
+    Template(SegmentTemplate),
+    List {
+        timescale: u64,
+        duration: Option<u64>,
+        start_number: u64,
+        initialization: Option<(Option<String>, Option<ByteRange>)>,
+        segments: Vec<SegmentUrl>,
+    },
+    Base {
+        initialization: Option<ByteRange>,
+        index_range: Option<ByteRange>,
+    },
+    /// Only a `BaseURL`: the whole resource is one segment.
+    Single,
+}
+
+#[derive(Debug, Clone)]
+pub struct Representation {
+    pub id: String,
+    pub bandwidth: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub codecs: Option<String>,
+    pub mime_type: String,
+    pub frame_rate: Option<String>,
+    pub lang: Option<String>,
+    pub kind: MediaKind,
+    pub base_url: Url,
+    pub addressing: SegmentAddressing,
+}
+
+#[derive(Debug, Clone)]
+pub struct Period {
+    pub id: Option<String>,
+    pub start: f64,
+    pub duration: Option<f64>,
+    pub representations: Vec<Representation>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mpd {
+    pub is_live: bool,
+    pub duration: Option<f64>,
+    pub min_update_period: Option<f64>,
+    pub availability_start_time: Option<f64>,
+    pub time_shift_buffer_depth: Option<f64>,
+    pub periods: Vec<Period>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaSegment {
+    pub url: String,
+    pub duration: f64,
+    pub number: u64,
+    pub byte_range: Option<ByteRange>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InitSegment {
+    pub url: String,
+    pub byte_range: Option<ByteRange>,
+}
+
+impl Mpd {
+    /// Parse an MPD document; relative `BaseURL`s are resolved against `mpd_url`.
+    pub fn parse(content: &str, mpd_url: &Url) -> AppResult<Self> {
+        let document = Document::parse(content)
+            .map_err(|e| AppError::Upstream(format!("Invalid MPD document: {}", e)))?;
+        let root = document.root_element();
+        if root.tag_name().name() != "MPD" {
+            return Err(AppError::Upstream(
+                "Upstream response is not a DASH manifest".to_string(),
+            ));
+        }
+
+        let is_live = root.attribute("type") == Some("dynamic");
+        let duration = root
+            .attribute("mediaPresentationDuration")
+            .and_then(parse_duration);
+        let mpd_base = resolve_base_url(mpd_url, root)?;
+
+        let mut periods = Vec::new();
+        let mut next_start = 0.0;
+        for period_node in children(root, "Period") {
+            let start = period_node
+                .attribute("start")
+                .and_then(parse_duration)
+                .unwrap_or(next_start);
+            let period_duration = period_node.attribute("duration").and_then(parse_duration);
+            next_start = start + period_duration.unwrap_or(0.0);
+
+            let period_base = resolve_base_url(&mpd_base, period_node)?;
+            let mut representations = Vec::new();
+
+            for set_node in children(period_node, "AdaptationSet") {
+                let set_base = resolve_base_url(&period_base, set_node)?;
+                for rep_node in children(set_node, "Representation") {
+                    representations.push(parse_representation(
+                        period_node,
+                        set_node,
+                        rep_node,
+                        &set_base,
+                    )?);
+                }
+            }
+
+            periods.push(Period {
+                id: period_node.attribute("id").map(str::to_string),
+                start,
+                duration: period_duration,
+                representations,
+            });
+        }
+
+        Ok(Self {
+            is_live,
+            duration,
+            min_update_period: root
+                .attribute("minimumUpdatePeriod")
+                .and_then(parse_duration),
+            availability_start_time: root
+                .attribute("availabilityStartTime")
+                .and_then(parse_date_time),
+            time_shift_buffer_depth: root
+                .attribute("timeShiftBufferDepth")
+                .and_then(parse_duration),
+            periods,
+        })
+    }
+
+    /// Periods to expose: every period for VOD, only the current one for live streams.
+    pub fn active_periods(&self) -> &[Period] {
+        if self.is_live && !self.periods.is_empty() {
+            &self.periods[self.periods.len() - 1..]
+        } else {
+            &self.periods
+        }
+    }
+
+    /// Find a representation by id in the first active period that has it.
+    pub fn representation(&self, profile_id: &str) -> Option<&Representation> {
+        self.active_periods()
+            .iter()
+            .flat_map(|period| period.representations.iter())
+            .find(|rep| rep.id == profile_id)
+    }
+
+    fn period_duration(&self, index: usize) -> Option<f64> {
+        let period = &self.periods[index];
+        period.duration.or_else(|| {
+            match self.periods.get(index + 1) {
+                Some(next) => Some(next.start - period.start),
+                None => self.duration.map(|d| d - period.start),
+            }
+            .filter(|d| *d > 0.0)
+        })
+    }
+
+    /// Segments of `rep` within the period at `period_index`.
+    ///
+    /// `now` is the wall clock in seconds since the Unix epoch and is only used
+    /// for live streams that address segments by number.
+    pub fn segments(
+        &self,
+        period_index: usize,
+        rep: &Representation,
+        now: f64,
+    ) -> AppResult<Vec<MediaSegment>> {
+        let period = &self.periods[period_index];
+        let period_duration = self.period_duration(period_index);
+
+        match &rep.addressing {
+            SegmentAddressing::Template(template) => {
+                self.template_segments(period, period_duration, rep, template, now)
+            }
+            SegmentAddressing::List {
+                timescale,
+                duration,
+                start_number,
+                segments,
+                ..
+            } => {
+                let segment_duration = duration
+                    .map(|d| d as f64 / *timescale as f64)
+                    .or(period_duration.map(|d| d / segments.len().max(1) as f64))
+                    .unwrap_or(0.0);
+                segments
+                    .iter()
+                    .enumerate()
+                    .map(|(index, segment)| {
+                        let url = match &segment.media {
+                            Some(media) => resolve(&rep.base_url, media)?,
+                            None => rep.base_url.to_string(),
+                        };
+                        Ok(MediaSegment {
+                            url,
+                            duration: segment_duration,
+                            number: start_number + index as u64,
+                            byte_range: segment.media_range,
+                        })
+                    })
+                    .collect()
+            }
+            SegmentAddressing::Base { .. } | SegmentAddressing::Single => Ok(vec![MediaSegment {
+                url: rep.base_url.to_string(),
+                duration: period_duration.unwrap_or(0.0),
+                number: 0,
+                byte_range: None,
+            }]),
+        }
+    }
+
+    fn template_segments(
+        &self,
+        period: &Period,
+        period_duration: Option<f64>,
+        rep: &Representation,
+        template: &SegmentTemplate,
+        now: f64,
+    ) -> AppResult<Vec<MediaSegment>> {
+        let media = template.media.as_deref().ok_or_else(|| {
+            AppError::Upstream(format!(
+                "SegmentTemplate without media for representation {}",
+                rep.id
+            ))
+        })?;
+        let timescale = template.timescale.unwrap_or(1).max(1);
+        let start_number = template.start_number.unwrap_or(1);
+        let too_many = || {
+            AppError::Upstream(format!(
+                "SegmentTemplate of representation {} lists more than {} segments",
+                rep.id, MAX_SEGMENTS
+            ))
+        };
+        let mut segments = Vec::new();
+
+        if !template.timeline.is_empty() {
+            let mut number = start_number;
+            let mut time = 0u64;
+            for (index, entry) in template.timeline.iter().enumerate() {
+                if let Some(t) = entry.t {
+                    time = t;
+                }
+                let repeat = if entry.r < 0 {
+                    // Negative repeat: until the next entry or the end of the period
+                    let end = match template.timeline.get(index + 1).and_then(|next| next.t) {
+                        Some(next_t) => next_t,
+                        None => period_duration
+                            .map(|d| {
+                                template.presentation_time_offset.unwrap_or(0)
+                                    + (d * timescale as f64) as u64
+                            })
+                            .unwrap_or(time + entry.d),
+                    };
+                    end.saturating_sub(time)
+                        .div_ceil(entry.d.max(1))
+                        .saturating_sub(1)
+                } else {
+                    entry.r as u64
+                };
+                if repeat >= MAX_SEGMENTS - segments.len() as u64 {
+                    return Err(too_many());
+                }
+                for _ in 0..=repeat {
+                    let url =
+                        fill_template(media, &rep.id, rep.bandwidth, Some(number), Some(time));
+                    segments.push(MediaSegment {
+                        url: resolve(&rep.base_url, &url)?,
+                        duration: entry.d as f64 / timescale as f64,
+                        number,
+                        byte_range: None,
+                    });
+                    number += 1;
+                    time = time.saturating_add(entry.d);
+                }
+            }
+
+            if self.is_live {
+                let window = self
+                    .time_shift_buffer_depth
+                    .unwrap_or(DEFAULT_LIVE_WINDOW_SECS);
+                let total: f64 = segments.iter().map(|s| s.duration).sum();
+                let mut skipped = 0.0;
+                segments.retain(|segment| {
+                    let keep = total - skipped <= window;
+                    skipped += segment.duration;
+                    keep
+                });
+            }
+            return Ok(segments);
+        }
+
+        let duration = template.duration.filter(|d| *d > 0).ok_or_else(|| {
+            AppError::Upstream(format!(
+                "SegmentTemplate without duration or timeline for representation {}",
+                rep.id
+            ))
+        })?;
+        let segment_duration = duration as f64 / timescale as f64;
+
+        let (first, count) = if self.is_live {
+            let available_since = self.availability_start_time.unwrap_or(0.0) + period.start;
+            let elapsed = (now - available_since).max(0.0);
+            let window = self
+                .time_shift_buffer_depth
+                .unwrap_or(DEFAULT_LIVE_WINDOW_SECS);
+            let latest = (elapsed / segment_duration).floor() as u64;
+            let window_count = ((window / segment_duration).ceil() as u64).max(1);
+            let first = latest.saturating_sub(window_count);
+            (start_number + first, latest - first)
+        } else {
+            let total = period_duration.unwrap_or(0.0);
+            (
+                start_number,
+                (total / segment_duration).ceil().max(1.0) as u64,
+            )
+        };
+        if count > MAX_SEGMENTS {
+            return Err(too_many());
+        }
+
+        for number in first..first + count {
+            let time =
+                (number - start_number) * duration + template.presentation_time_offset.unwrap_or(0);
+            let url = fill_template(media, &rep.id, rep.bandwidth, Some(number), Some(time));
+            segments.push(MediaSegment {
+                url: resolve(&rep.base_url, &url)?,
+                duration: segment_duration,
+                number,
+                byte_range: None,
+            });
+        }
+
+        Ok(segments)
+    }
+}
+
+impl Representation {
+    pub fn init_segment(&self) -> AppResult<Option<InitSegment>> {
+        match &self.addressing {
+            SegmentAddressing::Template(template) => template
+                .initialization
+                .as_ref()
+                .map(|init| {
+                    let url = fill_template(init, &self.id, self.bandwidth, None, None);
+                    Ok(InitSegment {
+                        url: resolve(&self.base_url, &url)?,
+                        byte_range: None,
+                    })
+                })
+                .transpose(),
+            SegmentAddressing::List {
+                initialization: Some((source, range)),
+                ..
+            } => Ok(Some(InitSegment {
+                url: match source {
+                    Some(source) => resolve(&self.base_url, source)?,
+                    None => self.base_url.to_string(),
+                },
+                byte_range: *range,
+            })),
+            SegmentAddressing::Base {
+                initialization: Some(range),
+                ..
+            } => Ok(Some(InitSegment {
+                url: self.base_url.to_string(),
+                byte_range: Some(*range),
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    /// Byte range of the `sidx` box for `SegmentBase` addressing.
+    pub fn index_range(&self) -> Option<ByteRange> {
+        match &self.addressing {
+            SegmentAddressing::Base { index_range, .. } => *index_range,
+            _ => None,
+        }
+    }
+}
+
+/// Build the segment list of a `SegmentBase` representation from its `sidx` box.
+///
+/// `data` must start at `index_range.start` of the resource.
+pub fn sidx_segments(
+    data: &[u8],
+    index_range: ByteRange,
+    url: &str,
+) -> AppResult<Vec<MediaSegment>> {
+    let invalid = || AppError::Upstream("Invalid sidx box".to_string());
+    let read_u32 = |offset: usize| -> AppResult<u32> {
+        data.get(offset..offset + 4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(invalid)
+    };
+    let read_u64 = |offset: usize| -> AppResult<u64> {
+        Ok(((read_u32(offset)? as u64) << 32) | read_u32(offset + 4)? as u64)
+    };
+
+    if data.get(4..8) != Some(b"sidx") {
+        return Err(invalid());
+    }
+    let box_size = read_u32(0)? as u64;
+    let version = *data.get(8).ok_or_else(invalid)?;
+    let timescale = read_u32(16)?.max(1) as f64;
+    let (first_offset, mut offset) = if version == 0 {
+        (read_u32(24)? as u64, 28)
+    } else {
+        (read_u64(28)?, 36)
+    };
+    let reference_count = u16::from_be_bytes(
+        data.get(offset + 2..offset + 4)
+            .ok_or_else(invalid)?
+            .try_into()
+            .map_err(|_| invalid())?,
+    );
+    offset += 4;
+
+    // Offsets are relative to the first byte after the sidx box
+    let mut position = index_range
+        .start
+        .checked_add(box_size)
+        .and_then(|position| position.checked_add(first_offset))
+        .ok_or_else(invalid)?;
+    let mut segments = Vec::with_capacity(reference_count as usize);
+    for number in 0..reference_count as u64 {
+        let referenced_size = (read_u32(offset)? & 0x7fff_ffff) as u64;
+        let subsegment_duration = read_u32(offset + 4)? as f64;
+        let next = position
+            .checked_add(referenced_size)
+            .filter(|next| *next > position)
+            .ok_or_else(invalid)?;
+        segments.push(MediaSegment {
+            url: url.to_string(),
+            duration: subsegment_duration / timescale,
+            number,
+            byte_range: Some(ByteRange {
+                start: position,
+                end: next - 1,
+            }),
+        });
+        position = next;
+        offset += 12;
+    }
+
+    Ok(segments)
+}
+
+/// Build the HLS master playlist for an MPD.
+///
+/// Each variant points back at the MPD playlist endpoint with a `profile_id`
+/// selecting the representation.
+pub fn build_master_playlist(mpd: &Mpd, url_builder: &ProxyUrlBuilder) -> AppResult<String> {
+    let destination = &url_builder.proxy_data().destination;
+    let mut output = String::from("#EXTM3U\n#EXT-X-VERSION:6\n#EXT-X-INDEPENDENT-SEGMENTS\n");
+
+    let mut video = Vec::new();
+    let mut audio = Vec::new();
+    for rep in mpd
+        .active_periods()
+        .first()
+        .map(|p| p.representations.iter())
+        .into_iter()
+        .flatten()
+    {
+        match rep.kind {
+            MediaKind::Video => video.push(rep),
+            MediaKind::Audio => audio.push(rep),
+            _ => {}
+        }
+    }
+
+    if video.is_empty() && audio.is_empty() {
+        return Err(AppError::Upstream(
+            "MPD contains no audio or video representations".to_string(),
+        ));
+    }
+
+    let max_audio_bandwidth = audio.iter().map(|rep| rep.bandwidth).max().unwrap_or(0);
+    let audio_codec = audio.iter().find_map(|rep| rep.codecs.clone());
+
+    if !video.is_empty() {
+        for (index, rep) in audio.iter().enumerate() {
+            let uri = url_builder.build_with_params(
+                MPD_PLAYLIST_ENDPOINT,
+                destination,
+                &[("profile_id", &rep.id)],
+            )?;
+            let name = rep.lang.clone().unwrap_or_else(|| rep.id.clone());
+            let _ = write!(
+                output,
+                "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",NAME=\"{}\",DEFAULT={},AUTOSELECT=YES",
+                name,
+                if index == 0 { "YES" } else { "NO" }
+            );
+            if let Some(lang) = &rep.lang {
+                let _ = write!(output, ",LANGUAGE=\"{}\"", lang);
+            }
+            let _ = writeln!(output, ",URI=\"{}\"", uri);
+        }
+    }
+
+    let variants = if video.is_empty() { &audio } else { &video };
+    for rep in variants {
+        let is_video = rep.kind == MediaKind::Video;
+        let bandwidth = rep.bandwidth + if is_video { max_audio_bandwidth } else { 0 };
+        let _ = write!(output, "#EXT-X-STREAM-INF:BANDWIDTH={}", bandwidth);
+        if let (Some(width), Some(height)) = (rep.width, rep.height) {
+            let _ = write!(output, ",RESOLUTION={}x{}", width, height);
+        }
+
+        let mut codecs: Vec<String> = rep.codecs.iter().cloned().collect();
+        if is_video {
+            codecs.extend(audio_codec.iter().cloned());
+        }
+        if !codecs.is_empty() {
+            let _ = write!(output, ",CODECS=\"{}\"", codecs.join(","));
+        }
+        if let Some(frame_rate) = rep.frame_rate.as_deref().and_then(parse_frame_rate) {
+            let _ = write!(output, ",FRAME-RATE={:.3}", frame_rate);
+        }
+        if is_video && !audio.is_empty() {
+            output.push_str(",AUDIO=\"audio\"");
+        }
+        output.push('\n');
+
+        let uri = url_builder.build_with_params(
+            MPD_PLAYLIST_ENDPOINT,
+            destination,
+            &[("profile_id", &rep.id)],
+        )?;
+        let _ = writeln!(output, "{}", uri);
+    }
+
+    Ok(output)
+}
+
+/// Build the HLS media playlist for one representation.
+///
+/// `periods` holds the init segment and segment list of the representation in
+/// each active period; period boundaries become discontinuities.
+pub fn build_media_playlist(
+    mpd: &Mpd,
+    periods: &[(Option<InitSegment>, Vec<MediaSegment>)],
+    url_builder: &ProxyUrlBuilder,
+) -> AppResult<String> {
+    let target_duration = periods
+        .iter()
+        .flat_map(|(_, segments)| segments.iter())
+        .map(|segment| segment.duration.ceil() as u64)
+        .max()
+        .unwrap_or(1)
+        .max(1);
+    let media_sequence = periods
+        .iter()
+        .find_map(|(_, segments)| segments.first())
+        .map(|segment| segment.number)
+        .unwrap_or(0);
+
+    let mut output = String::from("#EXTM3U\n#EXT-X-VERSION:6\n");
+    let _ = writeln!(output, "#EXT-X-TARGETDURATION:{}", target_duration);
+    let _ = writeln!(output, "#EXT-X-MEDIA-SEQUENCE:{}", media_sequence);
+    if !mpd.is_live {
+        output.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n");
+    }
+    output.push_str("#EXT-X-INDEPENDENT-SEGMENTS\n");
+
+    // With ClearKey keys every media segment needs its init segment to be decrypted
+    let decrypt = url_builder
+        .proxy_data()
+        .query_params
+        .as_ref()
+        .and_then(|params| params.get("key"))
+        .is_some();
+
+    for (index, (init, segments)) in periods.iter().enumerate() {
+        if index > 0 {
+            output.push_str("#EXT-X-DISCONTINUITY\n");
+        }
+        if let Some(init) = init {
+            let uri = segment_uri(url_builder, &init.url, init.byte_range, None)?;
+            let _ = writeln!(output, "#EXT-X-MAP:URI=\"{}\"", uri);
+        }
+        let segment_init = init.as_ref().filter(|_| decrypt);
+        for segment in segments {
+            let _ = writeln!(output, "#EXTINF:{:.3},", segment.duration);
+            let _ = writeln!(
+                output,
+                "{}",
+                segment_uri(url_builder, &segment.url, segment.byte_range, segment_init)?
+            );
+        }
+    }
+
+    if !mpd.is_live {
+        output.push_str("#EXT-X-ENDLIST\n");
+    }
+
+    Ok(output)
+}
+
+fn segment_uri(
+    url_builder: &ProxyUrlBuilder,
+    url: &str,
+    byte_range: Option<ByteRange>,
+    init: Option<&InitSegment>,
+) -> AppResult<String> {
+    let range = byte_range.map(|range| range.to_string());
+    let init_range = init.and_then(|init| init.byte_range).map(|r| r.to_string());
+
+    let mut params = Vec::new();
+    if let Some(range) = &range {
+        params.push(("range", range.as_str()));
+    }
+    if let Some(init) = init {
+        params.push((INIT_URL_PARAM, init.url.as_str()));
+    }
+    if let Some(init_range) = &init_range {
+        params.push(("init_range", init_range.as_str()));
+    }
+
+    url_builder.build_with_params(MPD_SEGMENT_ENDPOINT, url, &params)
+}
+
+fn parse_representation(
+    period: Node,
+    adaptation_set: Node,
+    rep: Node,
+    set_base: &Url,
+) -> AppResult<Representation> {
+    let attr = |name: &str| {
+        rep.attribute(name)
+            .or_else(|| adaptation_set.attribute(name))
+            .map(str::to_string)
+    };
+
+    let mime_type = attr("mimeType").unwrap_or_default();
+    let content_type = attr("contentType").unwrap_or_default();
+    let codecs = attr("codecs");
+    let kind = if mime_type.starts_with("video") || content_type == "video" {
+        MediaKind::Video
+    } else if mime_type.starts_with("audio") || content_type == "audio" {
+        MediaKind::Audio
+    } else if mime_type.starts_with("text")
+        || content_type == "text"
+        || mime_type == "application/ttml+xml"
+        || codecs
+            .as_deref()
+            .is_some_and(|c| c == "wvtt" || c == "stpp")
+    {
+        MediaKind::Text
+    } else {
+        MediaKind::Other
+    };
+
+    let base_url = resolve_base_url(set_base, rep)?;
+    let levels = [period, adaptation_set, rep];
+
+    let addressing = if levels
+        .iter()
+        .any(|n| child(*n, "SegmentTemplate").is_some())
+    {
+        SegmentAddressing::Template(merge_templates(&levels))
+    } else if let Some(list) =
+        child(rep, "SegmentList").or_else(|| child(adaptation_set, "SegmentList"))
+    {
+        let initialization = child(list, "Initialization").map(|init| {
+            (
+                init.attribute("sourceURL").map(str::to_string),
+                init.attribute("range").and_then(ByteRange::parse),
+            )
+        });
+        SegmentAddressing::List {
+            timescale: list
+                .attribute("timescale")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1)
+                .max(1),
+            duration: list.attribute("duration").and_then(|v| v.parse().ok()),
+            start_number: list
+                .attribute("startNumber")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1),
+            initialization,
+            segments: children(list, "SegmentURL")
+                .map(|segment| SegmentUrl {
+                    media: segment.attribute("media").map(str::to_string),
+                    media_range: segment.attribute("mediaRange").and_then(ByteRange::parse),
+                })
+                .collect(),
+        }
+    } else if let Some(base) =
+        child(rep, "SegmentBase").or_else(|| child(adaptation_set, "SegmentBase"))
+    {
+        SegmentAddressing::Base {
+            initialization: child(base, "Initialization")
+                .and_then(|init| init.attribute("range"))
+                .and_then(ByteRange::parse),
+            index_range: base.attribute("indexRange").and_then(ByteRange::parse),
+        }
+    } else {
+        SegmentAddressing::Single
+    };
+
+    Ok(Representation {
+        id: rep.attribute("id").unwrap_or_default().to_string(),
+        bandwidth: rep
+            .attribute("bandwidth")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0),
+        width: attr("width").and_then(|v| v.parse().ok()),
+        height: attr("height").and_then(|v| v.parse().ok()),
+        codecs,
+        mime_type,
+        frame_rate: attr("frameRate"),
+        lang: adaptation_set.attribute("lang").map(str::to_string),
+        kind,
+        base_url,
+        addressing,
+    })
+}
+
+/// Merge `SegmentTemplate` elements from outer to inner levels; inner attributes win.
+fn merge_templates(levels: &[Node]) -> SegmentTemplate {
+    let mut template = SegmentTemplate::default();
+
+    for node in levels
+        .iter()
+        .filter_map(|level| child(*level, "SegmentTemplate"))
+    {
+        let number = |name: &str| node.attribute(name).and_then(|v| v.parse().ok());
+        if let Some(media) = node.attribute("media") {
+            template.media = Some(media.to_string());
+        }
+        if let Some(init) = node.attribute("initialization") {
+            template.initialization = Some(init.to_string());
+        }
+        template.timescale = number("timescale").or(template.timescale);
+        template.duration = number("duration").or(template.duration);
+        template.start_number = number("startNumber").or(template.start_number);
+        template.presentation_time_offset =
+            number("presentationTimeOffset").or(template.presentation_time_offset);
+
+        if let Some(timeline) = child(node, "SegmentTimeline") {
+            template.timeline = children(timeline, "S")
+                .map(|s| TimelineEntry {
+                    t: s.attribute("t").and_then(|v| v.parse().ok()),
+                    d: s.attribute("d").and_then(|v| v.parse().ok()).unwrap_or(0),
+                    r: s.attribute("r").and_then(|v| v.parse().ok()).unwrap_or(0),
+                })
+                .collect();
+        }
+    }
+
+    template
+}
+
+/// Substitute `$RepresentationID$`, `$Number$`, `$Bandwidth$` and `$Time$`
+/// identifiers, including `%0Nd` width specifiers.
+pub fn fill_template(
+    template: &str,
+    representation_id: &str,
+    bandwidth: u64,
+    number: Option<u64>,
+    time: Option<u64>,
+) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('$') else {
+            output.push_str(&rest[start..]);
+            return output;
+        };
+
+        let identifier = &after[..end];
+        let (name, format) = match identifier.split_once('%') {
+            Some((name, format)) => (name, Some(format)),
+            None => (identifier, None),
+        };
+        let width = format
+            .and_then(|f| f.strip_prefix('0'))
+            .and_then(|f| f.strip_suffix('d'))
+            .and_then(|w| w.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        let value = match name {
+            "" => Some("$".to_string()),
+            "RepresentationID" => Some(representation_id.to_string()),
+            "Number" => number.map(|n| format!("{:0width$}", n, width = width)),
+            "Bandwidth" => Some(format!("{:0width$}", bandwidth, width = width)),
+            "Time" => time.map(|t| format!("{:0width$}", t, width = width)),
+            _ => None,
+        };
+        match value {
+            Some(value) => output.push_str(&value),
+            None => {
+                output.push('$');
+                output.push_str(identifier);
+                output.push('$');
+            }
+        }
+        rest = &after[end + 1..];
+    }
+
+    output.push_str(rest);
+    output
+}
+
+/// Parse an ISO 8601 duration such as `PT1H2M3.5S` into seconds.
+pub fn parse_duration(value: &str) -> Option<f64> {
+    let value = value.trim().strip_prefix('P')?;
+    let (date_part, time_part) = value.split_once('T').unwrap_or((value, ""));
+
+    let mut total = 0.0;
+    for (part, units) in [
+        (
+            date_part,
+            &[
+                ('Y', 31_536_000.0),
+                ('M', 2_592_000.0),
+                ('W', 604_800.0),
+                ('D', 86_400.0),
+            ][..],
+        ),
+        (time_part, &[('H', 3_600.0), ('M', 60.0), ('S', 1.0)][..]),
+    ] {
+        let mut number = String::new();
+        for c in part.chars() {
+            if c.is_ascii_digit() || c == '.' {
+                number.push(c);
+            } else {
+                let (_, multiplier) = units.iter().find(|(unit, _)| *unit == c)?;
+                total += number.parse::<f64>().ok()? * multiplier;
+                number.clear();
+            }
+        }
+        if !number.is_empty() {
+            return None;
+        }
+    }
+
+    Some(total)
+}
+
+/// Parse an `xs:dateTime` into seconds since the Unix epoch; UTC is assumed without an offset.
+pub fn parse_date_time(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let has_offset = value.ends_with('Z')
+        || value
+            .rsplit_once('T')
+            .is_some_and(|(_, time)| time.contains('+') || time.contains('-'));
+    let value = if has_offset {
+        value.to_string()
+    } else {
+        format!("{}Z", value)
+    };
+
+    OffsetDateTime::parse(&value, &Rfc3339)
+        .ok()
+        .map(|dt| dt.unix_timestamp_nanos() as f64 / 1_000_000_000.0)
+}
+
+fn parse_frame_rate(value: &str) -> Option<f64> {
+    match value.split_once('/') {
+        Some((num, den)) => {
+            let den = den.parse::<f64>().ok()?;
+            (den > 0.0).then(|| num.parse::<f64>().ok().map(|n| n / den))?
+        }
+        None => value.parse().ok(),
+    }
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn resolve_base_url(parent: &Url, node: Node) -> AppResult<Url> {
+    match child(node, "BaseURL").and_then(|n| n.text()).map(str::trim) {
+        Some(base) if !base.is_empty() => parent
+            .join(base)
+            .map_err(|e| AppError::Upstream(format!("Invalid BaseURL '{}': {}", base, e))),
+        _ => Ok(parent.clone()),
+    }
+}
+
+fn resolve(base_url: &Url, uri: &str) -> AppResult<String> {
+    base_url
+        .join(uri)
+        .map(|url| url.to_string())
+        .map_err(|e| AppError::Upstream(format!("Invalid segment URI '{}': {}", uri, e)))
+}
+
+/// Query parameter prefix carrying the value of the n-th identifier of a segment template.
+pub const TEMPLATE_PARAM_PREFIX: &str = "tpl";
+
+/// Serve the MPD itself, with every URL in it going through the proxy.
+///
+/// `BaseURL`s and segment URLs become absolute proxied URLs carrying the same
+/// proxy data, and `Location` points back at this endpoint. Segment templates
+/// are resolved against the base URL of the element declaring them. Their
+/// identifiers, such as `$Number$`, are left for the player to fill in as plain
+/// `tpl0`, `tpl1`… parameters, since the destination may be inside a token.
+pub fn rewrite_manifest(
+    content: &str,
+    mpd_url: &Url,
+    url_builder: &ProxyUrlBuilder,
+) -> AppResult<String> {
+    let document = Document::parse(content)
+        .map_err(|e| AppError::Upstream(format!("Invalid MPD document: {}", e)))?;
+    let root = document.root_element();
+    if root.tag_name().name() != "MPD" {
+        return Err(AppError::Upstream(
+            "Upstream response is not a DASH manifest".to_string(),
+        ));
+    }
+
+    let mut edits = Vec::new();
+    for location in children(root, "Location") {
+        if let Some(text) = location.first_child().filter(|n| n.is_text()) {
+            let destination = resolve(mpd_url, text.text().unwrap_or_default().trim())?;
+            edits.push((
+                text.range(),
+                url_builder.build(MPD_PASSTHROUGH_ENDPOINT, &destination)?,
+            ));
+        }
+    }
+    rewrite_urls(root, mpd_url, url_builder, &mut edits)?;
+
+    edits.sort_by_key(|(range, _)| range.start);
+    let mut output = String::with_capacity(content.len() * 2);
+    let mut position = 0;
+    for (range, replacement) in edits {
+        output.push_str(&content[position..range.start]);
+        output.push_str(&escape_xml(&replacement));
+        position = range.end;
+    }
+    output.push_str(&content[position..]);
+
+    Ok(output)
+}
+
+/// Collect the replacements for the URLs in `node` and its descendants.
+fn rewrite_urls(
+    node: Node,
+    parent_base: &Url,
+    url_builder: &ProxyUrlBuilder,
+    edits: &mut Vec<(std::ops::Range<usize>, String)>,
+) -> AppResult<()> {
+    let base = resolve_base_url(parent_base, node)?;
+
+    let attributes: &[&str] = match node.tag_name().name() {
+        "SegmentTemplate" => &["media", "initialization", "index"],
+        "SegmentURL" => &["media", "index"],
+        "Initialization" | "RepresentationIndex" => &["sourceURL"],
+        _ => &[],
+    };
+    for attribute in node.attributes().filter(|a| attributes.contains(&a.name())) {
+        edits.push((
+            attribute.range_value(),
+            template_url(url_builder, &base, attribute.value())?,
+        ));
+    }
+
+    for element in node.children().filter(|n| n.is_element()) {
+        match element.tag_name().name() {
+            // Alternative BaseURLs are all resolved against the parent element
+            "BaseURL" => {
+                if let Some(text) = element.first_child().filter(|n| n.is_text()) {
+                    let destination = resolve(parent_base, text.text().unwrap_or_default().trim())?;
+                    edits.push((
+                        text.range(),
+                        url_builder.build(STREAM_ENDPOINT, &destination)?,
+                    ));
+                }
+            }
+            "Location" => {}
+            _ => rewrite_urls(element, &base, url_builder, edits)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Proxied URL of a segment template, with its identifiers as plain parameters
+/// holding the identifier itself, for the player to substitute.
+fn template_url(url_builder: &ProxyUrlBuilder, base: &Url, template: &str) -> AppResult<String> {
+    let destination = resolve(base, template)?;
+    let identifiers = template_identifiers(template);
+    if identifiers.is_empty() {
+        return url_builder.build(STREAM_ENDPOINT, &destination);
+    }
+
+    // The identifiers must reach the player unencoded, so placeholders stand in for them
+    let names: Vec<String> = (0..identifiers.len())
+        .map(|i| format!("{}{}", TEMPLATE_PARAM_PREFIX, i))
+        .collect();
+    let placeholders: Vec<String> = (0..identifiers.len())
+        .map(|i| format!("__mediaflow_template_{}__", i))
+        .collect();
+    let params: Vec<(&str, &str)> = names
+        .iter()
+        .zip(&placeholders)
+        .map(|(name, placeholder)| (name.as_str(), placeholder.as_str()))
+        .collect();
+
+    let mut url = url_builder.build_with_params(STREAM_ENDPOINT, &destination, &params)?;
+    for (placeholder, (_, identifier)) in placeholders.iter().zip(&identifiers) {
+        url = url.replace(placeholder, identifier);
+    }
+    Ok(url)
+}
+
+/// The `$…$` identifiers of a segment template with their byte ranges; `$$` is a literal `$`.
+fn template_identifiers(template: &str) -> Vec<(std::ops::Range<usize>, &str)> {
+    let mut identifiers = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = template[offset..].find('$').map(|i| offset + i) {
+        let Some(end) = template[start + 1..].find('$').map(|i| start + 1 + i) else {
+            break;
+        };
+        if end > start + 1 {
+            identifiers.push((start..end + 1, &template[start..end + 1]));
+        }
+        offset = end + 1;
+    }
+    identifiers
+}
+
+/// Fill the identifiers of a segment template `destination` with `values`, in order.
+///
+/// Values are restricted to URL-safe characters, so that they cannot change
+/// the host a destination was authorized for.
+pub fn expand_template(destination: &str, values: &[&str]) -> AppResult<String> {
+    let identifiers = template_identifiers(destination);
+    if identifiers.len() != values.len() {
+        return Err(AppError::Forbidden(format!(
+            "Expected {} template values, got {}",
+            identifiers.len(),
+            values.len()
+        )));
+    }
+
+    let mut expanded = String::with_capacity(destination.len());
+    let mut position = 0;
+    for ((range, _), value) in identifiers.into_iter().zip(values) {
+        let safe = !value.is_empty()
+            && value
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~'));
+        if !safe {
+            return Err(AppError::Forbidden(format!(
+                "Invalid template value '{}'",
+                value
+            )));
+        }
+        expanded.push_str(&destination[position..range.start].replace("$$", "$"));
+        expanded.push_str(value);
+        position = range.end;
+    }
+    expanded.push_str(&destination[position..].replace("$$", "$"));
+
+    Ok(expanded)
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// Log the connection reuse ratio every this many upstream requests.
+const REPORT_INTERVAL: u64 = 1000;
+
+/// Counts upstream requests, the connections opened to serve them and the
+/// transport routes they went through.
+#[derive(Debug, Default)]
+pub struct PoolStats {
+    requests: AtomicU64,
+    connections: AtomicU64,
+    routes: Mutex<BTreeMap<String, u64>>,
+}
+
+impl PoolStats {
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Share of requests served over an already open connection.
+    pub fn reuse_ratio(&self) -> f64 {
+        let requests = self.requests();
+        if requests == 0 {
+            return 0.0;
+        }
+        1.0 - (self.connections().min(requests) as f64 / requests as f64)
+    }
+
+    /// Upstream requests by transport route pattern.
+    pub fn route_usage(&self) -> Vec<(String, u64)> {
+        let routes = self.routes.lock().unwrap();
+        routes
+            .iter()
+            .map(|(route, count)| (route.clone(), *count))
+            .collect()
+    }
+
+    pub fn record_route(&self, route: &str) {
+        let mut routes = self.routes.lock().unwrap();
+        match routes.get_mut(route) {
+            Some(count) => *count += 1,
+            None => {
+                routes.insert(route.to_string(), 1);
+            }
+        }
+    }
+
+    pub fn record_request(&self) {
+        let requests = self.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        if requests.is_multiple_of(REPORT_INTERVAL) {
+            tracing::info!(
+                "Upstream connection reuse {:.1}% ({} connections for {} requests)",
+                self.reuse_ratio() * 100.0,
+                self.connections(),
+                requests
+            );
+        }
+    }
+}
+
+/// Connector layer that counts every new upstream connection.
+#[derive(Debug, Clone)]
+pub struct CountConnectionsLayer {
+    stats: Arc<PoolStats>,
+}
+
+impl CountConnectionsLayer {
+    pub fn new(stats: Arc<PoolStats>) -> Self {
+        Self { stats }
+    }
+}
+
+impl<S> Layer<S> for CountConnectionsLayer {
+    type Service = CountConnections<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CountConnections {
+            inner,
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CountConnections<S> {
+    inner: S,
+    stats: Arc<PoolStats>,
+}
+
+impl<S, R> Service<R> for CountConnections<S>
+where
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        self.stats.connections.fetch_add(1, Ordering::Relaxed);
+        self.inner.call(request)
+    }
+}
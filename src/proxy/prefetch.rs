@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Above this many segments, the oldest playlists are forgotten.
+const MAX_TRACKED_SEGMENTS: usize = 16384;
+
+/// Where each segment of the media playlists served recently sits in its playlist.
+struct Position {
+    segments: Arc<[String]>,
+    index: usize,
+}
+
+/// Tracks the order of segments in the media playlists served, so the
+/// segments a player will ask for next can be fetched ahead of time.
+#[derive(Default)]
+pub struct Prefetcher {
+    positions: Mutex<HashMap<String, Position>>,
+}
+
+impl Prefetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the segments of a media playlist, in order, and return the
+    /// ones a player starts with: the first for VOD, the newest for live.
+    pub fn playlist(&self, segments: Vec<String>, live: bool, depth: usize) -> Vec<String> {
+        let start = if live {
+            segments.len().saturating_sub(depth)
+        } else {
+            0
+        };
+        let first = segments.iter().skip(start).take(depth).cloned().collect();
+
+        let segments: Arc<[String]> = segments.into();
+        let mut positions = self.positions.lock().unwrap();
+        if positions.len() + segments.len() > MAX_TRACKED_SEGMENTS {
+            positions.clear();
+        }
+        for (index, url) in segments.iter().enumerate() {
+            positions.insert(
+                url.clone(),
+                Position {
+                    segments: segments.clone(),
+                    index,
+                },
+            );
+        }
+
+        first
+    }
+
+    /// The `depth` segments following `url` in the last playlist that listed it.
+    pub fn following(&self, url: &str, depth: usize) -> Vec<String> {
+        let positions = self.positions.lock().unwrap();
+        match positions.get(url) {
+            Some(position) => position
+                .segments
+                .iter()
+                .skip(position.index + 1)
+                .take(depth)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Number of segments tracked.
+    pub fn len(&self) -> usize {
+        self.positions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
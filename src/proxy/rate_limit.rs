@@ -0,0 +1,338 @@
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    web::Bytes,
+    Error, HttpMessage,
+};
+use futures::future::LocalBoxFuture;
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use tokio::time::{sleep, Duration, Instant, Sleep};
+
+use crate::{
+    auth::{encryption::ProxyData, keyring::ApiKey, EncryptionHandler},
+    config::{RateLimitConfig, RateLimitKey},
+    error::AppError,
+    proxy::throttle::Throttle,
+};
+
+const UNLIMITED_ENDPOINTS: &[&str] = &["/health", "/metrics"];
+
+/// How often clients without activity are forgotten.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The limits state of one client.
+struct Client {
+    requests: Option<Throttle>,
+    bandwidth: Option<Arc<Throttle>>,
+    /// Shared with the client it replaces on reload, as the streams stay open
+    streams: Arc<AtomicUsize>,
+}
+
+impl Client {
+    fn is_idle(&self) -> bool {
+        self.streams.load(Ordering::SeqCst) == 0
+            && self.requests.as_ref().is_none_or(Throttle::is_idle)
+            && self.bandwidth.as_deref().is_none_or(Throttle::is_idle)
+    }
+}
+
+struct Clients {
+    clients: HashMap<String, Arc<Client>>,
+    swept: Instant,
+}
+
+/// Middleware limiting the request rate, open streams and bandwidth of each client.
+///
+/// It needs the API key of the request when keying by key, so it has to be
+/// wrapped inside the [`AuthMiddleware`](crate::auth::middleware::AuthMiddleware),
+/// with the [`before_auth`](Self::before_auth) limiter outside of it.
+/// Rejected requests get a `429 Too Many Requests` with `Retry-After`.
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: Arc<RwLock<Arc<RateLimitConfig>>>,
+    clients: Arc<Mutex<Clients>>,
+    /// Only limits the request rate, by client IP
+    before_auth: bool,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config: Arc::new(RwLock::new(Arc::new(config))),
+            clients: Arc::new(Mutex::new(Clients {
+                clients: HashMap::new(),
+                swept: Instant::now(),
+            })),
+            before_auth: false,
+        }
+    }
+
+    /// The request rate limit keyed by client IP, to wrap outside the
+    /// `AuthMiddleware` so that requests failing authentication count as well.
+    /// It follows the reloads of this limiter.
+    pub fn before_auth(&self) -> Self {
+        Self {
+            before_auth: true,
+            ..self.clone()
+        }
+    }
+
+    /// Apply updated limits from now on.
+    ///
+    /// Clients get fresh request and bandwidth allowances under the new limits.
+    /// The streams they have open still count against the new `max_streams`,
+    /// and finish at the bandwidth they started with.
+    pub fn reload(&self, config: RateLimitConfig) {
+        let mut clients = self.clients.lock().unwrap();
+        let config = Arc::new(config);
+        *self.config.write().unwrap() = config.clone();
+        clients.clients = clients
+            .clients
+            .drain()
+            .filter(|(_, client)| client.streams.load(Ordering::SeqCst) > 0)
+            .map(|(id, client)| {
+                let client = self.new_client(&config, client.streams.clone());
+                (id, Arc::new(client))
+            })
+            .collect();
+    }
+
+    fn config(&self) -> Arc<RateLimitConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    fn is_enabled(&self) -> bool {
+        let config = self.config();
+        config.requests_per_second.is_some()
+            || !self.before_auth && (config.max_streams.is_some() || config.max_bandwidth.is_some())
+    }
+
+    fn client_id(&self, req: &ServiceRequest) -> String {
+        let ip = || {
+            req.connection_info()
+                .realip_remote_addr()
+                .unwrap_or("unknown")
+                .to_string()
+        };
+        if self.before_auth {
+            return format!("before-auth:{}", ip());
+        }
+
+        let keyed = {
+            let extensions = req.extensions();
+            let api_key = extensions.get::<Arc<ApiKey>>();
+            match self.config().key_by {
+                RateLimitKey::Ip => None,
+                RateLimitKey::ApiKey => api_key.map(|api_key| format!("key:{}", api_key.label())),
+                // Every URL rewritten from a token gets a fresh one, with the same subject
+                RateLimitKey::Token => extensions
+                    .get::<Arc<EncryptionHandler>>()
+                    .and(extensions.get::<ProxyData>())
+                    .and_then(|proxy_data| match (&proxy_data.ip, api_key) {
+                        (Some(ip), _) => Some(format!("token-ip:{}", ip)),
+                        (None, Some(api_key)) => Some(format!("token-key:{}", api_key.label())),
+                        (None, None) => None,
+                    }),
+            }
+        };
+
+        keyed.unwrap_or_else(|| format!("ip:{}", ip()))
+    }
+
+    fn client(&self, id: String) -> Arc<Client> {
+        let mut clients = self.clients.lock().unwrap();
+        let config = self.config();
+        if clients.swept.elapsed() >= SWEEP_INTERVAL {
+            clients
+                .clients
+                .retain(|_, client| Arc::strong_count(client) > 1 || !client.is_idle());
+            clients.swept = Instant::now();
+        }
+
+        clients
+            .clients
+            .entry(id)
+            .or_insert_with(|| Arc::new(self.new_client(&config, Arc::default())))
+            .clone()
+    }
+
+    fn new_client(&self, config: &RateLimitConfig, streams: Arc<AtomicUsize>) -> Client {
+        Client {
+            requests: config.requests_per_second.map(|rate| {
+                let burst = config.burst.unwrap_or(rate);
+                Throttle::with_burst(rate as u64, burst.max(1) as u64)
+            }),
+            bandwidth: config
+                .max_bandwidth
+                .filter(|_| !self.before_auth)
+                .map(|rate| Arc::new(Throttle::new(rate))),
+            streams,
+        }
+    }
+
+    /// Admit a request from `client`, holding one of its streams until the guard
+    /// is dropped. Streams are only counted after authentication.
+    fn admit(&self, id: &str, client: &Arc<Client>) -> Result<Option<StreamGuard>, AppError> {
+        if let Some(requests) = &client.requests {
+            if let Err(wait) = requests.try_acquire(1) {
+                tracing::debug!("Rate limiting {}: too many requests", id);
+                return Err(AppError::RateLimited {
+                    message: "Too many requests".to_string(),
+                    retry_after: wait.as_secs_f64().ceil().max(1.0) as u64,
+                });
+            }
+        }
+
+        if self.before_auth {
+            return Ok(None);
+        }
+
+        let open = client.streams.fetch_add(1, Ordering::SeqCst);
+        let guard = StreamGuard {
+            client: client.clone(),
+        };
+        if self
+            .config()
+            .max_streams
+            .is_some_and(|max_streams| open >= max_streams)
+        {
+            tracing::debug!("Rate limiting {}: {} streams already open", id, open);
+            return Err(AppError::RateLimited {
+                message: format!("Too many open streams ({})", open),
+                retry_after: 1,
+            });
+        }
+        Ok(Some(guard))
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<LimitedBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimiterService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimiterService {
+            service: Rc::new(service),
+            limiter: self.clone(),
+        }))
+    }
+}
+
+pub struct RateLimiterService<S> {
+    service: Rc<S>,
+    limiter: RateLimiter,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimiterService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<LimitedBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limiter = self.limiter.clone();
+
+        Box::pin(async move {
+            if !limiter.is_enabled() || UNLIMITED_ENDPOINTS.contains(&req.path()) {
+                let res = service.call(req).await?;
+                return Ok(res.map_body(|_, body| LimitedBody::new(body, None)));
+            }
+
+            let id = limiter.client_id(&req);
+            let client = limiter.client(id.clone());
+            let guard = limiter.admit(&id, &client)?;
+
+            // The stream stays open, and counted, until the body has been sent
+            let res = service.call(req).await?;
+            Ok(res.map_body(|_, body| LimitedBody::new(body, guard)))
+        })
+    }
+}
+
+/// An open stream of a client, counted until dropped.
+pub struct StreamGuard {
+    client: Arc<Client>,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.client.streams.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A response body sent at the bandwidth of its client.
+pub struct LimitedBody<B> {
+    inner: Pin<Box<B>>,
+    guard: Option<StreamGuard>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<B> LimitedBody<B> {
+    fn new(inner: B, guard: Option<StreamGuard>) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            guard,
+            delay: None,
+        }
+    }
+}
+
+impl<B: MessageBody> MessageBody for LimitedBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        // Wait out what the previous chunk cost before sending the next one
+        if let Some(delay) = self.delay.as_mut() {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.delay = None;
+        }
+
+        let poll = self.inner.as_mut().poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let wait = self
+                    .guard
+                    .as_ref()
+                    .and_then(|guard| guard.client.bandwidth.as_ref())
+                    .and_then(|bandwidth| bandwidth.reserve(chunk.len()));
+                self.delay = wait.map(|wait| Box::pin(sleep(wait)));
+            }
+            Poll::Ready(None) | Poll::Ready(Some(Err(_))) => {
+                // Release the stream as soon as the body is done
+                self.guard = None;
+            }
+            Poll::Pending => {}
+        }
+        poll
+    }
+}
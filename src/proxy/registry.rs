@@ -0,0 +1,157 @@
+use actix_web::web::Bytes;
+use futures::stream::{AbortHandle, Abortable};
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::error::AppError;
+
+/// Who a relayed stream is for and where it comes from.
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    pub client_ip: String,
+    pub destination_host: String,
+    /// The transport route the destination matched
+    pub route: String,
+    /// Label of the API key that opened the stream
+    pub key: Option<String>,
+}
+
+struct Entry {
+    info: StreamInfo,
+    started_at: u64,
+    started: Instant,
+    bytes: AtomicU64,
+    abort: AbortHandle,
+}
+
+/// A stream as listed by the admin API.
+#[derive(Debug, Serialize)]
+pub struct StreamSnapshot {
+    pub id: u64,
+    pub client_ip: String,
+    pub destination_host: String,
+    pub route: String,
+    pub key: Option<String>,
+    /// Unix time the stream started at
+    pub started_at: u64,
+    pub duration_secs: f64,
+    pub bytes: u64,
+    /// Average bytes per second since the start
+    pub throughput: f64,
+}
+
+/// The streams being relayed to clients, so they can be inspected and killed.
+#[derive(Default)]
+pub struct StreamRegistry {
+    next_id: AtomicU64,
+    streams: Mutex<BTreeMap<u64, Arc<Entry>>>,
+}
+
+impl StreamRegistry {
+    /// Track `stream` until it ends or is dropped.
+    pub fn register<S>(
+        self: &Arc<Self>,
+        info: StreamInfo,
+        stream: S,
+    ) -> impl Stream<Item = Result<Bytes, AppError>>
+    where
+        S: Stream<Item = Result<Bytes, AppError>>,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (abort, abort_registration) = AbortHandle::new_pair();
+        let entry = Arc::new(Entry {
+            info,
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            started: Instant::now(),
+            bytes: AtomicU64::new(0),
+            abort,
+        });
+        self.streams.lock().unwrap().insert(id, entry.clone());
+
+        let registered = Registered {
+            registry: self.clone(),
+            id,
+        };
+        Abortable::new(stream, abort_registration).map(move |chunk| {
+            // Listed until the stream is dropped
+            let _registered = &registered;
+            if let Ok(bytes) = &chunk {
+                entry.bytes.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+            }
+            chunk
+        })
+    }
+
+    /// The active streams, oldest first.
+    pub fn list(&self) -> Vec<StreamSnapshot> {
+        let streams = self.streams.lock().unwrap();
+        streams
+            .iter()
+            .map(|(id, entry)| {
+                let duration = entry.started.elapsed().as_secs_f64();
+                let bytes = entry.bytes.load(Ordering::Relaxed);
+                StreamSnapshot {
+                    id: *id,
+                    client_ip: entry.info.client_ip.clone(),
+                    destination_host: entry.info.destination_host.clone(),
+                    route: entry.info.route.clone(),
+                    key: entry.info.key.clone(),
+                    started_at: entry.started_at,
+                    duration_secs: duration,
+                    bytes,
+                    throughput: if duration > 0.0 {
+                        bytes as f64 / duration
+                    } else {
+                        0.0
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// End the stream `id`; its client sees the body cut short.
+    ///
+    /// Returns whether the stream was found.
+    pub fn kill(&self, id: u64) -> bool {
+        match self.streams.lock().unwrap().remove(&id) {
+            Some(entry) => {
+                tracing::info!(
+                    "Killing stream {} from {} to {}",
+                    id,
+                    entry.info.client_ip,
+                    entry.info.destination_host
+                );
+                entry.abort.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Removes a stream from the registry when dropped.
+struct Registered {
+    registry: Arc<StreamRegistry>,
+    id: u64,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.registry.streams.lock().unwrap().remove(&self.id);
+    }
+}
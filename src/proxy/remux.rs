@@ -0,0 +1,942 @@
+use actix_web::web::Bytes;
+
+use crate::{
+    error::{AppError, AppResult},
+    proxy::{
+        mp4::{self, Mp4Box},
+        stream::SegmentProcessor,
+        ts::{self, ElementaryStream, STREAM_TYPE_AAC, STREAM_TYPE_H264, STREAM_TYPE_H265},
+    },
+};
+
+pub const FMP4_CONTENT_TYPE: &str = "video/mp4";
+
+/// Timescale of the video tracks: the 90 kHz clock of MPEG-TS timestamps.
+const VIDEO_TIMESCALE: u32 = 90_000;
+/// Duration of a frame when a segment holds a single one, 30 frames per second.
+const DEFAULT_FRAME_DURATION: u32 = VIDEO_TIMESCALE / 30;
+const AAC_FRAME_SAMPLES: u32 = 1024;
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+/// MPEG-TS timestamps wrap around after 33 bits.
+const TIMESTAMP_MASK: u64 = (1 << 33) - 1;
+
+const TFHD_DEFAULT_BASE_IS_MOOF: u32 = 0x020000;
+const TRUN_DATA_OFFSET: u32 = 0x01;
+const TRUN_SAMPLE_DURATION: u32 = 0x100;
+const TRUN_SAMPLE_SIZE: u32 = 0x200;
+const TRUN_SAMPLE_FLAGS: u32 = 0x400;
+const TRUN_SAMPLE_CTO: u32 = 0x800;
+
+/// `sample_depends_on` 2: decodable on its own.
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+/// `sample_depends_on` 1 and `sample_is_non_sync_sample`.
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+
+const UNITY_MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// H.264 profiles whose SPS carries the chroma format and bit depths.
+const H264_HIGH_PROFILES: &[u8] = &[100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135];
+
+/// Remuxes MPEG-TS segments with H.264, H.265 and AAC streams into CMAF
+/// fragmented MP4, or builds the init segment from one of them.
+///
+/// Each supported stream becomes a track, numbered in program map table
+/// order. Fragments keep the timestamps of the transport stream, so
+/// consecutive segments line up without the proxy keeping any state. Video
+/// PES packets are expected to hold one access unit each, as they do in HLS.
+pub enum TsRemuxer {
+    InitSegment,
+    MediaSegment { sequence: u32 },
+}
+
+impl SegmentProcessor for TsRemuxer {
+    fn process(&self, segment: Bytes) -> AppResult<Bytes> {
+        let streams = ts::demux(&segment)?;
+        let output = match self {
+            TsRemuxer::InitSegment => init_segment(&streams)?,
+            TsRemuxer::MediaSegment { sequence } => media_segment(&streams, *sequence)?,
+        };
+        Ok(Bytes::from(output))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VideoCodec {
+    H264,
+    H265,
+}
+
+impl VideoCodec {
+    fn nal_type(self, nal: &[u8]) -> u8 {
+        match self {
+            VideoCodec::H264 => nal[0] & 0x1f,
+            VideoCodec::H265 => (nal[0] >> 1) & 0x3f,
+        }
+    }
+
+    /// Parameter set NAL unit types, in the order of the decoder configuration record.
+    fn parameter_sets(self) -> &'static [u8] {
+        match self {
+            VideoCodec::H264 => &[7, 8],
+            VideoCodec::H265 => &[32, 33, 34],
+        }
+    }
+
+    fn is_access_unit_delimiter(self, nal_type: u8) -> bool {
+        match self {
+            VideoCodec::H264 => nal_type == 9,
+            VideoCodec::H265 => nal_type == 35,
+        }
+    }
+
+    /// IDR pictures for H.264, IRAP pictures for H.265.
+    fn is_sync(self, nal_type: u8) -> bool {
+        match self {
+            VideoCodec::H264 => nal_type == 5,
+            VideoCodec::H265 => (16..=23).contains(&nal_type),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamKind {
+    Video(VideoCodec),
+    Audio,
+}
+
+impl StreamKind {
+    fn of(stream: &ElementaryStream) -> Option<Self> {
+        match stream.stream_type {
+            STREAM_TYPE_H264 => Some(StreamKind::Video(VideoCodec::H264)),
+            STREAM_TYPE_H265 => Some(StreamKind::Video(VideoCodec::H265)),
+            STREAM_TYPE_AAC => Some(StreamKind::Audio),
+            _ => None,
+        }
+    }
+}
+
+/// The streams that become tracks, with their kind and track ID.
+fn tracks(
+    streams: &[ElementaryStream],
+) -> impl Iterator<Item = (u32, StreamKind, &ElementaryStream)> {
+    streams
+        .iter()
+        .filter_map(|stream| StreamKind::of(stream).map(|kind| (kind, stream)))
+        .zip(1..)
+        .map(|((kind, stream), id)| (id, kind, stream))
+}
+
+/// Build the `ftyp` and `moov` of the fMP4 variant of a segment.
+fn init_segment(streams: &[ElementaryStream]) -> AppResult<Vec<u8>> {
+    let mut traks = Vec::new();
+    let mut trexs = Vec::new();
+    for (id, kind, stream) in tracks(streams) {
+        traks.push(match kind {
+            StreamKind::Video(codec) => video_trak(id, &VideoConfig::from_stream(codec, stream)?),
+            StreamKind::Audio => audio_trak(id, &Adts::from_stream(stream)?),
+        });
+        trexs.push(full_box(b"trex", 0, 0, &u32_fields(&[id, 1, 0, 0, 0])));
+    }
+    if traks.is_empty() {
+        return Err(invalid("no H.264, H.265 or AAC stream"));
+    }
+
+    let mut mvhd = u32_fields(&[0, 0, VIDEO_TIMESCALE, 0, 0x0001_0000]);
+    mvhd.extend_from_slice(&[0x01, 0x00]);
+    mvhd.extend_from_slice(&[0; 10]);
+    mvhd.extend_from_slice(&u32_fields(&UNITY_MATRIX));
+    mvhd.extend_from_slice(&[0; 24]);
+    mvhd.extend_from_slice(&(traks.len() as u32 + 1).to_be_bytes());
+
+    let mut moov = vec![full_box(b"mvhd", 0, 0, &mvhd)];
+    moov.extend(traks);
+    moov.push(container(b"mvex", trexs));
+
+    let mut ftyp = b"iso6".to_vec();
+    ftyp.extend_from_slice(&0u32.to_be_bytes());
+    ftyp.extend_from_slice(b"iso6cmfcmp41");
+
+    Ok(mp4::write_boxes(&[
+        Mp4Box::leaf(b"ftyp", ftyp),
+        container(b"moov", moov),
+    ]))
+}
+
+/// Build the `moof` and `mdat` of a segment, one `traf` per track with samples.
+fn media_segment(streams: &[ElementaryStream], sequence: u32) -> AppResult<Vec<u8>> {
+    let fragments = tracks(streams)
+        .map(|(id, kind, stream)| {
+            let fragment = match kind {
+                StreamKind::Video(codec) => Fragment::video(codec, stream),
+                StreamKind::Audio => Fragment::audio(stream)?,
+            };
+            Ok((id, fragment))
+        })
+        .filter(|fragment| !matches!(fragment, Ok((_, f)) if f.samples.is_empty()))
+        .collect::<AppResult<Vec<_>>>()?;
+    if fragments.is_empty() {
+        return Err(invalid("no H.264, H.265 or AAC samples"));
+    }
+
+    // Data offsets are relative to the moof, whose size does not depend on them
+    let moof = |data_offsets: &[u32]| {
+        let mut children = vec![full_box(b"mfhd", 0, 0, &sequence.to_be_bytes())];
+        for ((id, fragment), data_offset) in fragments.iter().zip(data_offsets) {
+            children.push(fragment.traf(*id, *data_offset));
+        }
+        container(b"moof", children)
+    };
+    let moof_size = moof(&vec![0; fragments.len()]).size() as u32;
+
+    let mut data_offsets = Vec::with_capacity(fragments.len());
+    let mut mdat = Vec::new();
+    for (_, fragment) in &fragments {
+        data_offsets.push(moof_size + 8 + mdat.len() as u32);
+        for sample in &fragment.samples {
+            mdat.extend_from_slice(&sample.data);
+        }
+    }
+
+    Ok(mp4::write_boxes(&[
+        moof(&data_offsets),
+        Mp4Box::leaf(b"mdat", mdat),
+    ]))
+}
+
+struct Sample {
+    duration: u32,
+    composition_offset: i32,
+    sync: bool,
+    data: Vec<u8>,
+}
+
+/// The samples of one track in a segment.
+struct Fragment {
+    base_decode_time: u64,
+    samples: Vec<Sample>,
+}
+
+impl Fragment {
+    /// One sample per PES packet, with the access unit delimiters and parameter
+    /// sets left out since the sample entry carries the latter.
+    fn video(codec: VideoCodec, stream: &ElementaryStream) -> Self {
+        let mut timestamps = Vec::new();
+        let mut samples = Vec::new();
+        for pes in &stream.packets {
+            let mut data = Vec::with_capacity(pes.data.len());
+            let mut sync = false;
+            for nal in nal_units(&pes.data) {
+                let nal_type = codec.nal_type(nal);
+                if codec.is_access_unit_delimiter(nal_type)
+                    || codec.parameter_sets().contains(&nal_type)
+                {
+                    continue;
+                }
+                sync |= codec.is_sync(nal_type);
+                data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                data.extend_from_slice(nal);
+            }
+            if data.is_empty() {
+                continue;
+            }
+            timestamps.push(pes.dts);
+            samples.push(Sample {
+                duration: 0,
+                composition_offset: timestamp_delta(pes.dts, pes.pts) as i32,
+                sync,
+                data,
+            });
+        }
+
+        // Each sample lasts until the next one is decoded, the last one as long as the one before
+        let mut last_duration = DEFAULT_FRAME_DURATION;
+        for (index, sample) in samples.iter_mut().enumerate() {
+            if let Some(next) = timestamps.get(index + 1) {
+                last_duration = timestamp_delta(timestamps[index], *next).max(0) as u32;
+            }
+            sample.duration = last_duration;
+        }
+
+        Self {
+            base_decode_time: timestamps.first().copied().unwrap_or(0),
+            samples,
+        }
+    }
+
+    /// One sample per ADTS frame, in the timescale of the sample rate.
+    fn audio(stream: &ElementaryStream) -> AppResult<Self> {
+        let mut base_decode_time = None;
+        let mut samples = Vec::new();
+        for pes in &stream.packets {
+            let mut frames = &pes.data[..];
+            while let Some(adts) = Adts::parse(frames) {
+                let frame = frames
+                    .get(adts.header_length..adts.frame_length)
+                    .ok_or_else(|| invalid("truncated ADTS frame"))?;
+                base_decode_time.get_or_insert(pes.pts * adts.sample_rate() as u64 / 90_000);
+                samples.push(Sample {
+                    duration: AAC_FRAME_SAMPLES,
+                    composition_offset: 0,
+                    sync: true,
+                    data: frame.to_vec(),
+                });
+                frames = &frames[adts.frame_length..];
+            }
+        }
+
+        Ok(Self {
+            base_decode_time: base_decode_time.unwrap_or(0),
+            samples,
+        })
+    }
+
+    fn traf(&self, track_id: u32, data_offset: u32) -> Mp4Box {
+        let mut trun = u32_fields(&[self.samples.len() as u32, data_offset]);
+        for sample in &self.samples {
+            let flags = if sample.sync {
+                SYNC_SAMPLE_FLAGS
+            } else {
+                NON_SYNC_SAMPLE_FLAGS
+            };
+            trun.extend_from_slice(&u32_fields(&[
+                sample.duration,
+                sample.data.len() as u32,
+                flags,
+                sample.composition_offset as u32,
+            ]));
+        }
+
+        container(
+            b"traf",
+            vec![
+                full_box(
+                    b"tfhd",
+                    0,
+                    TFHD_DEFAULT_BASE_IS_MOOF,
+                    &track_id.to_be_bytes(),
+                ),
+                full_box(b"tfdt", 1, 0, &self.base_decode_time.to_be_bytes()),
+                // Version 1 for signed composition offsets
+                full_box(
+                    b"trun",
+                    1,
+                    TRUN_DATA_OFFSET
+                        | TRUN_SAMPLE_DURATION
+                        | TRUN_SAMPLE_SIZE
+                        | TRUN_SAMPLE_FLAGS
+                        | TRUN_SAMPLE_CTO,
+                    &trun,
+                ),
+            ],
+        )
+    }
+}
+
+/// The sample entry of a video track, from the parameter sets of a segment.
+struct VideoConfig {
+    codec: VideoCodec,
+    width: u32,
+    height: u32,
+    /// `avcC` or `hvcC` box
+    record: Mp4Box,
+}
+
+impl VideoConfig {
+    fn from_stream(codec: VideoCodec, stream: &ElementaryStream) -> AppResult<Self> {
+        // Distinct parameter sets of each type, in order of appearance
+        let mut sets: Vec<Vec<&[u8]>> = vec![Vec::new(); codec.parameter_sets().len()];
+        for nal in stream.packets.iter().flat_map(|pes| nal_units(&pes.data)) {
+            let nal_type = codec.nal_type(nal);
+            if let Some(index) = codec.parameter_sets().iter().position(|t| *t == nal_type) {
+                if !sets[index].contains(&nal) {
+                    sets[index].push(nal);
+                }
+            }
+        }
+        if sets.iter().any(Vec::is_empty) {
+            return Err(invalid("no video parameter sets"));
+        }
+
+        match codec {
+            VideoCodec::H264 => Self::avc(&sets[0], &sets[1]),
+            VideoCodec::H265 => Self::hevc(&sets),
+        }
+    }
+
+    fn avc(sps: &[&[u8]], pps: &[&[u8]]) -> AppResult<Self> {
+        let info = H264Sps::parse(sps[0])?;
+
+        let mut record = vec![1, info.profile, info.constraints, info.level, 0xff];
+        record.push(0xe0 | sps.len() as u8);
+        push_units(&mut record, sps);
+        record.push(pps.len() as u8);
+        push_units(&mut record, pps);
+        if H264_HIGH_PROFILES.contains(&info.profile) {
+            record.extend_from_slice(&[
+                0xfc | info.chroma_format,
+                0xf8 | info.bit_depth_luma_minus8,
+                0xf8 | info.bit_depth_chroma_minus8,
+                0,
+            ]);
+        }
+
+        Ok(Self {
+            codec: VideoCodec::H264,
+            width: info.width,
+            height: info.height,
+            record: Mp4Box::leaf(b"avcC", record),
+        })
+    }
+
+    fn hevc(sets: &[Vec<&[u8]>]) -> AppResult<Self> {
+        let info = H265Sps::parse(sets[1][0])?;
+
+        let mut record = vec![1];
+        record.extend_from_slice(&info.profile_tier_level);
+        record.extend_from_slice(&[
+            0xf0,
+            0x00,
+            0xfc,
+            0xfc | info.chroma_format,
+            0xf8 | info.bit_depth_luma_minus8,
+            0xf8 | info.bit_depth_chroma_minus8,
+            0,
+            0,
+            // Temporal layers, nesting and 4-byte NAL unit lengths
+            (info.max_sub_layers << 3) | ((info.temporal_id_nesting as u8) << 2) | 0x03,
+        ]);
+        record.push(sets.len() as u8);
+        for (nal_type, units) in VideoCodec::H265.parameter_sets().iter().zip(sets) {
+            // Complete arrays: the parameter sets are not repeated in the samples
+            record.push(0x80 | nal_type);
+            record.extend_from_slice(&(units.len() as u16).to_be_bytes());
+            push_units(&mut record, units);
+        }
+
+        Ok(Self {
+            codec: VideoCodec::H265,
+            width: info.width,
+            height: info.height,
+            record: Mp4Box::leaf(b"hvcC", record),
+        })
+    }
+}
+
+fn push_units(record: &mut Vec<u8>, units: &[&[u8]]) {
+    for unit in units {
+        record.extend_from_slice(&(unit.len() as u16).to_be_bytes());
+        record.extend_from_slice(unit);
+    }
+}
+
+/// The fields of an H.264 sequence parameter set the sample entry needs.
+struct H264Sps {
+    profile: u8,
+    constraints: u8,
+    level: u8,
+    chroma_format: u8,
+    bit_depth_luma_minus8: u8,
+    bit_depth_chroma_minus8: u8,
+    width: u32,
+    height: u32,
+}
+
+impl H264Sps {
+    fn parse(nal: &[u8]) -> AppResult<Self> {
+        let mut reader = BitReader::new(nal.get(1..).unwrap_or_default());
+        let profile = reader.bits(8)? as u8;
+        let constraints = reader.bits(8)? as u8;
+        let level = reader.bits(8)? as u8;
+        reader.ue()?; // seq_parameter_set_id
+
+        let mut chroma_format = 1;
+        let mut separate_colour_plane = false;
+        let mut bit_depth_luma_minus8 = 0;
+        let mut bit_depth_chroma_minus8 = 0;
+        if H264_HIGH_PROFILES.contains(&profile) {
+            chroma_format = reader.ue()?;
+            if chroma_format == 3 {
+                separate_colour_plane = reader.bit()?;
+            }
+            bit_depth_luma_minus8 = reader.ue()?;
+            bit_depth_chroma_minus8 = reader.ue()?;
+            reader.skip(1)?; // qpprime_y_zero_transform_bypass_flag
+            if reader.bit()? {
+                let lists = if chroma_format == 3 { 12 } else { 8 };
+                for list in 0..lists {
+                    if reader.bit()? {
+                        reader.skip_scaling_list(if list < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+
+        reader.ue()?; // log2_max_frame_num_minus4
+        match reader.ue()? {
+            0 => {
+                reader.ue()?; // log2_max_pic_order_cnt_lsb_minus4
+            }
+            1 => {
+                reader.skip(1)?; // delta_pic_order_always_zero_flag
+                reader.se()?;
+                reader.se()?;
+                for _ in 0..reader.ue()? {
+                    reader.se()?;
+                }
+            }
+            _ => {}
+        }
+        reader.ue()?; // max_num_ref_frames
+        reader.skip(1)?; // gaps_in_frame_num_value_allowed_flag
+        let width_in_mbs = reader.ue()? + 1;
+        let height_in_map_units = reader.ue()? + 1;
+        let frame_mbs_only = reader.bit()?;
+        if !frame_mbs_only {
+            reader.skip(1)?; // mb_adaptive_frame_field_flag
+        }
+        reader.skip(1)?; // direct_8x8_inference_flag
+        let (mut left, mut right, mut top, mut bottom) = (0, 0, 0, 0);
+        if reader.bit()? {
+            left = reader.ue()?;
+            right = reader.ue()?;
+            top = reader.ue()?;
+            bottom = reader.ue()?;
+        }
+
+        let field_factor = if frame_mbs_only { 1 } else { 2 };
+        let (crop_x, crop_y) = match (chroma_format, separate_colour_plane) {
+            (0, _) | (3, true) => (1, field_factor),
+            (1, _) => (2, 2 * field_factor),
+            (2, _) => (2, field_factor),
+            _ => (1, field_factor),
+        };
+
+        Ok(Self {
+            profile,
+            constraints,
+            level,
+            chroma_format: chroma_format as u8 & 0x03,
+            bit_depth_luma_minus8: bit_depth_luma_minus8 as u8 & 0x07,
+            bit_depth_chroma_minus8: bit_depth_chroma_minus8 as u8 & 0x07,
+            width: (width_in_mbs * 16).saturating_sub(crop_x * (left + right)),
+            height: (field_factor * height_in_map_units * 16)
+                .saturating_sub(crop_y * (top + bottom)),
+        })
+    }
+}
+
+/// The fields of an H.265 sequence parameter set the sample entry needs.
+struct H265Sps {
+    /// General profile space, tier, profile, compatibility and constraint flags and level
+    profile_tier_level: [u8; 12],
+    max_sub_layers: u8,
+    temporal_id_nesting: bool,
+    chroma_format: u8,
+    bit_depth_luma_minus8: u8,
+    bit_depth_chroma_minus8: u8,
+    width: u32,
+    height: u32,
+}
+
+impl H265Sps {
+    fn parse(nal: &[u8]) -> AppResult<Self> {
+        let mut reader = BitReader::new(nal.get(2..).unwrap_or_default());
+        reader.skip(4)?; // sps_video_parameter_set_id
+        let max_sub_layers_minus1 = reader.bits(3)?;
+        let temporal_id_nesting = reader.bit()?;
+
+        let mut profile_tier_level = [0u8; 12];
+        for byte in profile_tier_level.iter_mut() {
+            *byte = reader.bits(8)? as u8;
+        }
+        let mut sub_layers = Vec::new();
+        for _ in 0..max_sub_layers_minus1 {
+            sub_layers.push((reader.bit()?, reader.bit()?));
+        }
+        if max_sub_layers_minus1 > 0 {
+            reader.skip(2 * (8 - max_sub_layers_minus1))?;
+        }
+        for (profile_present, level_present) in sub_layers {
+            if profile_present {
+                reader.skip(88)?;
+            }
+            if level_present {
+                reader.skip(8)?;
+            }
+        }
+
+        reader.ue()?; // sps_seq_parameter_set_id
+        let chroma_format = reader.ue()?;
+        if chroma_format == 3 {
+            reader.skip(1)?; // separate_colour_plane_flag
+        }
+        let width = reader.ue()?;
+        let height = reader.ue()?;
+        let (mut left, mut right, mut top, mut bottom) = (0, 0, 0, 0);
+        if reader.bit()? {
+            left = reader.ue()?;
+            right = reader.ue()?;
+            top = reader.ue()?;
+            bottom = reader.ue()?;
+        }
+        let bit_depth_luma_minus8 = reader.ue()?;
+        let bit_depth_chroma_minus8 = reader.ue()?;
+
+        let (sub_width, sub_height) = match chroma_format {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+
+        Ok(Self {
+            profile_tier_level,
+            max_sub_layers: max_sub_layers_minus1 as u8 + 1,
+            temporal_id_nesting,
+            chroma_format: chroma_format as u8 & 0x03,
+            bit_depth_luma_minus8: bit_depth_luma_minus8 as u8 & 0x07,
+            bit_depth_chroma_minus8: bit_depth_chroma_minus8 as u8 & 0x07,
+            width: width.saturating_sub(sub_width * (left + right)),
+            height: height.saturating_sub(sub_height * (top + bottom)),
+        })
+    }
+}
+
+/// Reads the fields of a parameter set, with its emulation prevention bytes removed.
+struct BitReader {
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl BitReader {
+    fn new(payload: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(payload.len());
+        let mut zeros = 0;
+        for &byte in payload {
+            if zeros >= 2 && byte == 0x03 {
+                zeros = 0;
+                continue;
+            }
+            zeros = if byte == 0 { zeros + 1 } else { 0 };
+            data.push(byte);
+        }
+        Self { data, position: 0 }
+    }
+
+    fn bit(&mut self) -> AppResult<bool> {
+        let byte = self
+            .data
+            .get(self.position / 8)
+            .ok_or_else(|| invalid("truncated parameter set"))?;
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Ok(bit == 1)
+    }
+
+    fn bits(&mut self, count: u32) -> AppResult<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.bit()? as u32;
+        }
+        Ok(value)
+    }
+
+    fn skip(&mut self, count: u32) -> AppResult<()> {
+        self.position += count as usize;
+        if self.position > self.data.len() * 8 {
+            return Err(invalid("truncated parameter set"));
+        }
+        Ok(())
+    }
+
+    /// Unsigned Exp-Golomb code.
+    fn ue(&mut self) -> AppResult<u32> {
+        let mut leading_zeros = 0;
+        while !self.bit()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return Err(invalid("invalid Exp-Golomb code"));
+            }
+        }
+        Ok(((1u64 << leading_zeros) - 1 + self.bits(leading_zeros)? as u64) as u32)
+    }
+
+    /// Signed Exp-Golomb code.
+    fn se(&mut self) -> AppResult<i32> {
+        let value = self.ue()? as i64;
+        Ok(if value % 2 == 1 {
+            ((value + 1) / 2) as i32
+        } else {
+            (-(value / 2)) as i32
+        })
+    }
+
+    fn skip_scaling_list(&mut self, size: usize) -> AppResult<()> {
+        let mut last_scale = 8;
+        let mut next_scale = 8;
+        for _ in 0..size {
+            if next_scale != 0 {
+                next_scale = (last_scale + self.se()? + 256) % 256;
+            }
+            if next_scale != 0 {
+                last_scale = next_scale;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An ADTS frame header.
+#[derive(Debug, Clone, Copy)]
+struct Adts {
+    object_type: u8,
+    frequency_index: u8,
+    channels: u8,
+    header_length: usize,
+    frame_length: usize,
+}
+
+impl Adts {
+    fn parse(data: &[u8]) -> Option<Self> {
+        // Sync word, and layer 0
+        if data.len() < 7 || data[0] != 0xff || data[1] & 0xf6 != 0xf0 {
+            return None;
+        }
+        let header_length = if data[1] & 0x01 == 1 { 7 } else { 9 };
+        let frequency_index = (data[2] >> 2) & 0x0f;
+        let frame_length =
+            ((data[3] as usize & 0x03) << 11) | ((data[4] as usize) << 3) | (data[5] as usize >> 5);
+        if frequency_index as usize >= AAC_SAMPLE_RATES.len() || frame_length < header_length {
+            return None;
+        }
+
+        Some(Self {
+            object_type: (data[2] >> 6) + 1,
+            frequency_index,
+            channels: ((data[2] & 0x01) << 2) | (data[3] >> 6),
+            header_length,
+            frame_length,
+        })
+    }
+
+    /// The header of the first frame of a stream.
+    fn from_stream(stream: &ElementaryStream) -> AppResult<Self> {
+        stream
+            .packets
+            .iter()
+            .find_map(|pes| Self::parse(&pes.data))
+            .ok_or_else(|| invalid("no ADTS frame"))
+    }
+
+    fn sample_rate(&self) -> u32 {
+        AAC_SAMPLE_RATES[self.frequency_index as usize]
+    }
+
+    fn audio_specific_config(&self) -> [u8; 2] {
+        [
+            (self.object_type << 3) | (self.frequency_index >> 1),
+            ((self.frequency_index & 0x01) << 7) | (self.channels << 3),
+        ]
+    }
+}
+
+fn video_trak(id: u32, config: &VideoConfig) -> Mp4Box {
+    let mut entry = vec![0; 6];
+    entry.extend_from_slice(&1u16.to_be_bytes()); // data_reference_index
+    entry.extend_from_slice(&[0; 16]);
+    entry.extend_from_slice(&(config.width as u16).to_be_bytes());
+    entry.extend_from_slice(&(config.height as u16).to_be_bytes());
+    entry.extend_from_slice(&u32_fields(&[0x0048_0000, 0x0048_0000, 0]));
+    entry.extend_from_slice(&1u16.to_be_bytes()); // frame_count
+    entry.extend_from_slice(&[0; 32]); // compressorname
+    entry.extend_from_slice(&[0x00, 0x18, 0xff, 0xff]);
+    let kind = match config.codec {
+        VideoCodec::H264 => b"avc1",
+        // Apple players only accept HEVC tagged hvc1, parameter sets in the sample entry
+        VideoCodec::H265 => b"hvc1",
+    };
+    let entry = Mp4Box {
+        kind: *kind,
+        payload: entry,
+        children: vec![config.record.clone()],
+    };
+
+    let media_header = full_box(b"vmhd", 0, 1, &[0; 8]);
+    trak(
+        id,
+        VIDEO_TIMESCALE,
+        (config.width, config.height),
+        b"vide",
+        media_header,
+        entry,
+    )
+}
+
+fn audio_trak(id: u32, adts: &Adts) -> Mp4Box {
+    // Channel configuration 0 is defined in the stream; stereo is the usual case
+    let channels = if adts.channels == 0 { 2 } else { adts.channels };
+    let mut entry = vec![0; 6];
+    entry.extend_from_slice(&1u16.to_be_bytes()); // data_reference_index
+    entry.extend_from_slice(&[0; 8]);
+    entry.extend_from_slice(&(channels as u16).to_be_bytes());
+    entry.extend_from_slice(&16u16.to_be_bytes()); // samplesize
+    entry.extend_from_slice(&[0; 4]);
+    entry.extend_from_slice(&(adts.sample_rate().min(u16::MAX as u32) << 16).to_be_bytes());
+
+    let config = adts.audio_specific_config();
+    let mut decoder_config = vec![0x40, 0x15, 0, 0, 0];
+    decoder_config.extend_from_slice(&u32_fields(&[0, 0]));
+    decoder_config.extend(descriptor(0x05, &config));
+    let mut es = vec![0, 0, 0];
+    es.extend(descriptor(0x04, &decoder_config));
+    es.extend(descriptor(0x06, &[0x02]));
+    let esds = full_box(b"esds", 0, 0, &descriptor(0x03, &es));
+
+    let entry = Mp4Box {
+        kind: *b"mp4a",
+        payload: entry,
+        children: vec![esds],
+    };
+    let media_header = full_box(b"smhd", 0, 0, &[0; 4]);
+    trak(id, adts.sample_rate(), (0, 0), b"soun", media_header, entry)
+}
+
+/// An MPEG-4 descriptor of the `esds` box.
+fn descriptor(tag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut output = vec![tag, payload.len() as u8];
+    output.extend_from_slice(payload);
+    output
+}
+
+fn trak(
+    id: u32,
+    timescale: u32,
+    (width, height): (u32, u32),
+    handler: &[u8; 4],
+    media_header: Mp4Box,
+    sample_entry: Mp4Box,
+) -> Mp4Box {
+    let mut tkhd = u32_fields(&[0, 0, id, 0, 0, 0, 0]);
+    tkhd.extend_from_slice(&[0, 0, 0, 0]); // layer, alternate_group
+    tkhd.extend_from_slice(if handler == b"soun" {
+        &[0x01, 0x00]
+    } else {
+        &[0, 0]
+    });
+    tkhd.extend_from_slice(&[0, 0]);
+    tkhd.extend_from_slice(&u32_fields(&UNITY_MATRIX));
+    tkhd.extend_from_slice(&u32_fields(&[width << 16, height << 16]));
+
+    let mut mdhd = u32_fields(&[0, 0, timescale, 0]);
+    mdhd.extend_from_slice(&[0x55, 0xc4, 0, 0]); // "und"
+
+    let mut hdlr = vec![0; 4];
+    hdlr.extend_from_slice(handler);
+    hdlr.extend_from_slice(&[0; 12]);
+    hdlr.extend_from_slice(b"MediaFlow\0");
+
+    let mut stsd = Mp4Box {
+        kind: *b"stsd",
+        payload: vec![0; 8],
+        children: vec![sample_entry],
+    };
+    stsd.payload[7] = 1;
+
+    let stbl = container(
+        b"stbl",
+        vec![
+            stsd,
+            full_box(b"stts", 0, 0, &[0; 4]),
+            full_box(b"stsc", 0, 0, &[0; 4]),
+            full_box(b"stsz", 0, 0, &[0; 8]),
+            full_box(b"stco", 0, 0, &[0; 4]),
+        ],
+    );
+    let dinf = container(
+        b"dinf",
+        vec![Mp4Box {
+            kind: *b"dref",
+            payload: vec![0, 0, 0, 0, 0, 0, 0, 1],
+            // Self-contained: the media is in the same file
+            children: vec![full_box(b"url ", 0, 1, &[])],
+        }],
+    );
+
+    container(
+        b"trak",
+        vec![
+            full_box(b"tkhd", 0, 0x03, &tkhd),
+            container(
+                b"mdia",
+                vec![
+                    full_box(b"mdhd", 0, 0, &mdhd),
+                    full_box(b"hdlr", 0, 0, &hdlr),
+                    container(b"minf", vec![media_header, dinf, stbl]),
+                ],
+            ),
+        ],
+    )
+}
+
+fn container(kind: &[u8; 4], children: Vec<Mp4Box>) -> Mp4Box {
+    Mp4Box {
+        kind: *kind,
+        payload: Vec::new(),
+        children,
+    }
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, fields: &[u8]) -> Mp4Box {
+    let mut payload = ((version as u32) << 24 | flags).to_be_bytes().to_vec();
+    payload.extend_from_slice(fields);
+    Mp4Box::leaf(kind, payload)
+}
+
+fn u32_fields(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+/// The NAL units of an Annex B byte stream, without start codes or trailing zeros.
+fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut units = Vec::new();
+    let mut start = None;
+    let mut position = 0;
+    while position + 3 <= data.len() {
+        if data[position..position + 3] == [0, 0, 1] {
+            if let Some(start) = start {
+                units.push(&data[start..position]);
+            }
+            position += 3;
+            start = Some(position);
+        } else {
+            position += 1;
+        }
+    }
+    if let Some(start) = start {
+        units.push(&data[start..]);
+    }
+
+    units
+        .into_iter()
+        .map(|unit| {
+            let end = unit.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+            &unit[..end]
+        })
+        .filter(|unit| !unit.is_empty())
+        .collect()
+}
+
+/// `to - from` for 33-bit timestamps, across a wrap around.
+fn timestamp_delta(from: u64, to: u64) -> i64 {
+    let delta = to.wrapping_sub(from) & TIMESTAMP_MASK;
+    if delta > TIMESTAMP_MASK / 2 {
+        delta as i64 - (TIMESTAMP_MASK as i64 + 1)
+    } else {
+        delta as i64
+    }
+}
+
+fn invalid(reason: &str) -> AppError {
+    AppError::Upstream(format!("Cannot remux MPEG-TS segment: {}", reason))
+}
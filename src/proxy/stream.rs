@@ -1,166 +1,853 @@
 use actix_web::web::Bytes;
 use futures::{Stream, StreamExt};
-use reqwest::{Client, Proxy, Response};
+use reqwest::{
+    header::{
+        HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED,
+        RANGE,
+    },
+    Method, Response, StatusCode,
+};
 use std::pin::Pin;
-use tokio::time::{timeout, Duration};
+use std::sync::{Arc, RwLock};
+use tokio::time::{timeout, Duration, Instant};
 use tracing::{error, info};
 
 use crate::{
-    config::{ProxyConfig, ProxyRouter},
+    config::{BandwidthConfig, CacheConfig, ProxyConfig, ProxyRouter},
     error::{AppError, AppResult},
+    metrics::metrics,
+    proxy::{
+        cache::{self, CachedManifest, FetchCache, ManifestCache, SegmentCache, SharedFetch},
+        conditional,
+        disk_cache::DiskCache,
+        prefetch::Prefetcher,
+        registry::StreamRegistry,
+        throttle::{Shaper, Throttle},
+    },
 };
 
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, AppError>> + Send>>;
+
+/// An upstream response to relay to the client.
+pub struct UpstreamResponse {
+    pub status: StatusCode,
+    pub headers: reqwest::header::HeaderMap,
+    pub body: Option<ByteStream>,
+}
+
+impl UpstreamResponse {
+    fn without_body(response: &Response) -> Self {
+        Self {
+            status: response.status(),
+            headers: response.headers().clone(),
+            body: None,
+        }
+    }
+}
+
+/// Statuses relayed as they are on the stream route; everything else becomes an error.
+fn is_passthrough(status: StatusCode) -> bool {
+    status.is_success() || status.is_redirection() || status == StatusCode::RANGE_NOT_SATISFIABLE
+}
+
+/// Errors another mirror may not run into: connection failures, timeouts and server errors.
+fn is_failover(error: &AppError) -> bool {
+    match error {
+        AppError::Proxy(_) => true,
+        AppError::UpstreamStatus { status, .. } => *status >= 500,
+        _ => false,
+    }
+}
+
+fn upstream_error(status: StatusCode) -> AppError {
+    AppError::UpstreamStatus {
+        status: status.as_u16(),
+        message: format!("Upstream returned error status: {}", status),
+    }
+}
+
+/// Where an upstream body is, to request the rest of it if it breaks off.
+struct Resume {
+    /// The destination that served the body, then its mirrors
+    urls: Vec<String>,
+    /// The request headers, without range and conditions
+    headers: reqwest::header::HeaderMap,
+    /// `If-Range` value making sure the rest belongs to the same entity
+    validator: HeaderValue,
+    /// Position of the next byte in the entity
+    offset: u64,
+    /// Last byte requested, for range requests
+    end: Option<u64>,
+}
+
+impl Resume {
+    /// Resume information for a response, unless it cannot be resumed safely:
+    /// without a strong validator, there is no telling whether the rest
+    /// fetched later belongs to the same entity.
+    fn new(
+        urls: Vec<String>,
+        headers: &reqwest::header::HeaderMap,
+        status: StatusCode,
+        response_headers: &reqwest::header::HeaderMap,
+    ) -> Option<Self> {
+        if response_headers
+            .get(ACCEPT_RANGES)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"none"))
+        {
+            return None;
+        }
+        let validator = response_headers
+            .get(ETAG)
+            .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+            .or_else(|| response_headers.get(LAST_MODIFIED))?
+            .clone();
+        let (offset, end) = match status {
+            StatusCode::OK => (0, None),
+            StatusCode::PARTIAL_CONTENT => {
+                let (start, end) = content_range(response_headers)?;
+                (start, Some(end))
+            }
+            _ => return None,
+        };
+
+        let mut headers = headers.clone();
+        headers.remove(RANGE);
+        headers.remove(IF_RANGE);
+        Some(Self {
+            urls,
+            headers,
+            validator,
+            offset,
+            end,
+        })
+    }
+}
+
+/// First and last byte of a `Content-Range: bytes <first>-<last>/<total>` header.
+fn content_range(headers: &reqwest::header::HeaderMap) -> Option<(u64, u64)> {
+    let range = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    let (first, last) = range
+        .strip_prefix("bytes ")?
+        .split_once('/')?
+        .0
+        .split_once('-')?;
+    Some((first.trim().parse().ok()?, last.trim().parse().ok()?))
+}
+
+/// Transforms a complete upstream segment before it is sent to the client,
+/// e.g. to decrypt it.
+pub trait SegmentProcessor {
+    fn process(&self, segment: Bytes) -> AppResult<Bytes>;
+}
+
+/// Two processors run one after the other, e.g. decryption then remuxing.
+impl<A: SegmentProcessor, B: SegmentProcessor> SegmentProcessor for (A, B) {
+    fn process(&self, segment: Bytes) -> AppResult<Bytes> {
+        self.1.process(self.0.process(segment)?)
+    }
+}
+
+/// An optional step, leaving segments as they are when absent.
+impl<P: SegmentProcessor> SegmentProcessor for Option<P> {
+    fn process(&self, segment: Bytes) -> AppResult<Bytes> {
+        match self {
+            Some(processor) => processor.process(segment),
+            None => Ok(segment),
+        }
+    }
+}
+
+/// The part of the stream manager swapped when the configuration is reloaded.
+///
+/// Requests take a snapshot of it, so streams started before a reload keep
+/// their upstream connection and bandwidth limits.
+struct Upstream {
+    config: ProxyConfig,
+    proxy_router: Arc<ProxyRouter>,
+    shaper: Arc<Shaper>,
+}
+
 #[derive(Clone)]
 pub struct StreamManager {
-    client: Client,
-    config: ProxyConfig,
-    proxy_router: ProxyRouter,
+    upstream: Arc<RwLock<Arc<Upstream>>>,
+    manifest_cache: Option<Arc<ManifestCache>>,
+    resource_cache: Option<Arc<FetchCache<Bytes>>>,
+    segment_cache: Option<Arc<SegmentCache>>,
+    disk_cache: Option<Arc<DiskCache>>,
+    registry: Arc<StreamRegistry>,
+    prefetcher: Arc<Prefetcher>,
 }
 
 impl StreamManager {
-    pub fn new(config: ProxyConfig) -> Self {
-        let proxy_router = ProxyRouter::from_config(&config);
-        let client = Self::create_client(&config, &proxy_router);
+    pub fn new(config: ProxyConfig, cache_config: CacheConfig) -> Self {
+        let proxy_router = Arc::new(ProxyRouter::from_config(&config));
+        let manifest_cache = config
+            .manifest_cache
+            .then(|| Arc::new(ManifestCache::new()));
+        let resource_cache = config.manifest_cache.then(|| Arc::new(FetchCache::new()));
+        let disk_cache = cache_config.disk.clone().and_then(|disk_config| {
+            let path = disk_config.path.clone();
+            match DiskCache::open(disk_config) {
+                Ok(disk_cache) => Some(Arc::new(disk_cache)),
+                Err(e) => {
+                    error!("Failed to open disk cache at {}: {}", path.display(), e);
+                    None
+                }
+            }
+        });
+        let segment_cache = cache_config.enabled.then(|| {
+            let segment_cache = SegmentCache::new(cache_config);
+            Arc::new(match &disk_cache {
+                Some(disk_cache) => segment_cache.with_disk_cache(disk_cache.clone()),
+                None => segment_cache,
+            })
+        });
 
         Self {
-            client,
-            config,
-            proxy_router,
-        }
-    }
-
-    fn create_client(config: &ProxyConfig, proxy_router: &ProxyRouter) -> Client {
-        let follow_redirects = config.follow_redirects;
-        let mut builder = Client::builder()
-            .connect_timeout(Duration::from_secs(config.connect_timeout))
-            // Remove the overall timeout to prevent stream interruption
-            .pool_idle_timeout(Duration::from_secs(90))
-            .pool_max_idle_per_host(0) // Disable connection pooling
-            .redirect(reqwest::redirect::Policy::custom(move |attempt| {
-                if follow_redirects {
-                    attempt.follow()
-                } else {
-                    attempt.stop()
+            upstream: Arc::new(RwLock::new(Arc::new(Upstream {
+                config,
+                proxy_router,
+                shaper: Arc::default(),
+            }))),
+            manifest_cache,
+            resource_cache,
+            segment_cache,
+            disk_cache,
+            registry: Arc::default(),
+            prefetcher: Arc::default(),
+        }
+    }
+
+    /// Shape the bandwidth of the streamed responses.
+    pub fn with_bandwidth(self, config: &BandwidthConfig) -> Self {
+        let upstream = self.upstream();
+        *self.upstream.write().unwrap() = Arc::new(Upstream {
+            config: upstream.config.clone(),
+            proxy_router: upstream.proxy_router.clone(),
+            shaper: Arc::new(Shaper::new(config)),
+        });
+        self
+    }
+
+    /// Swap the upstream clients, transport routes, timeouts and bandwidth
+    /// limits, leaving the streams in progress untouched.
+    ///
+    /// The caches are kept as they are.
+    pub fn reload(&self, config: &ProxyConfig, bandwidth: &BandwidthConfig) {
+        let upstream = self.upstream();
+        let proxy_router = upstream.proxy_router.reload(config);
+        *self.upstream.write().unwrap() = Arc::new(Upstream {
+            config: config.clone(),
+            proxy_router: Arc::new(proxy_router),
+            shaper: Arc::new(Shaper::new(bandwidth)),
+        });
+    }
+
+    fn upstream(&self) -> Arc<Upstream> {
+        self.upstream.read().unwrap().clone()
+    }
+
+    pub fn proxy_router(&self) -> Arc<ProxyRouter> {
+        self.upstream().proxy_router.clone()
+    }
+
+    /// The streams being relayed to clients.
+    pub fn registry(&self) -> &Arc<StreamRegistry> {
+        &self.registry
+    }
+
+    /// GET `url`, failing unless upstream answers with a success status.
+    ///
+    /// Conditional headers are dropped, since callers need the body.
+    pub async fn make_request(
+        &self,
+        url: String,
+        headers: reqwest::header::HeaderMap,
+    ) -> AppResult<Response> {
+        let headers = conditional::unconditional(&headers);
+        let response = self.send_request(Method::GET, url, headers).await?;
+        if !response.status().is_success() {
+            return Err(upstream_error(response.status()));
+        }
+
+        Ok(response)
+    }
+
+    async fn send_request(
+        &self,
+        method: Method,
+        url: String,
+        headers: reqwest::header::HeaderMap,
+    ) -> AppResult<Response> {
+        let upstream = self.upstream();
+        let client = upstream.proxy_router.get_client(&url)?;
+        upstream.proxy_router.pool_stats().record_request();
+
+        let started = Instant::now();
+        let response = timeout(
+            Duration::from_secs(upstream.config.connect_timeout),
+            client.request(method, &url).headers(headers).send(),
+        )
+        .await
+        .map_err(|e| AppError::Proxy(format!("Connection timeout: {}", e)))?
+        .map_err(|e| AppError::Proxy(format!("Failed to connect to upstream: {}", e)))?;
+        metrics().record_upstream_latency(started.elapsed());
+
+        Ok(response)
+    }
+
+    /// Fetch the upstream status and headers with a HEAD request.
+    ///
+    /// Origins that reject HEAD with a 405 or 501 are retried, if configured,
+    /// with a GET for the first byte only; its `Content-Range` total becomes
+    /// the `Content-Length`.
+    pub async fn fetch_headers(
+        &self,
+        url: String,
+        headers: reqwest::header::HeaderMap,
+    ) -> AppResult<UpstreamResponse> {
+        let response = self
+            .send_request(Method::HEAD, url.clone(), headers.clone())
+            .await?;
+        if is_passthrough(response.status()) {
+            return Ok(UpstreamResponse::without_body(&response));
+        }
+        let unsupported = matches!(
+            response.status(),
+            StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+        );
+        if !unsupported || !self.upstream().config.head_fallback {
+            return Err(upstream_error(response.status()));
+        }
+
+        tracing::debug!(
+            "HEAD failed for {} ({}), retrying as a ranged GET",
+            url,
+            response.status()
+        );
+        let client_range = headers.contains_key(RANGE);
+        let mut headers = headers;
+        if !client_range {
+            headers.insert(RANGE, HeaderValue::from_static("bytes=0-0"));
+        }
+
+        // The body is never read; dropping the response discards it
+        let response = self.send_request(Method::GET, url, headers).await?;
+        if !is_passthrough(response.status()) {
+            return Err(upstream_error(response.status()));
+        }
+        let mut upstream = UpstreamResponse::without_body(&response);
+        if !client_range && upstream.status == StatusCode::PARTIAL_CONTENT {
+            let total_length = upstream
+                .headers
+                .get(CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.rsplit_once('/'))
+                .and_then(|(_, total)| total.trim().parse::<u64>().ok());
+            upstream.status = StatusCode::OK;
+            upstream.headers.remove(CONTENT_RANGE);
+            match total_length {
+                Some(total_length) => {
+                    upstream
+                        .headers
+                        .insert(CONTENT_LENGTH, HeaderValue::from(total_length));
+                }
+                None => {
+                    upstream.headers.remove(CONTENT_LENGTH);
                 }
-            }));
+            }
+        }
+
+        Ok(upstream)
+    }
 
-        if let Some(default_proxy) = proxy_router.default_proxy() {
-            if let Ok(proxy) = Proxy::all(default_proxy) {
-                builder = builder.proxy(proxy);
+    /// Fetch an HLS playlist or MPD, served from the shared manifest cache when fresh.
+    pub async fn fetch_manifest(
+        &self,
+        url: String,
+        headers: reqwest::header::HeaderMap,
+    ) -> AppResult<CachedManifest> {
+        let headers = conditional::unconditional(&headers);
+        let fetch = || async {
+            let response = self.make_request(url.clone(), headers.clone()).await?;
+            let final_url = response.url().clone();
+            let response_headers = response.headers().clone();
+            let body = response
+                .text()
+                .await
+                .map_err(|e| AppError::Upstream(format!("Failed to read manifest: {}", e)))?;
+            metrics().record_bytes_in(body.len());
+            let ttl = cache::manifest_ttl(&body, &response_headers);
+
+            Ok((
+                CachedManifest {
+                    url: final_url,
+                    body: body.into(),
+                },
+                ttl,
+            ))
+        };
+
+        match &self.manifest_cache {
+            Some(manifest_cache) => {
+                let key = ManifestCache::key(&url, &headers);
+                manifest_cache.get_or_fetch(key, fetch).await
             }
+            None => fetch().await.map(|(manifest, _)| manifest),
         }
+    }
 
-        builder.build().expect("Failed to create HTTP client")
+    /// Fetch a segment key or init segment, shared by every segment of a
+    /// stream and kept with the manifests while fresh.
+    pub async fn fetch_resource(
+        &self,
+        url: String,
+        headers: reqwest::header::HeaderMap,
+    ) -> AppResult<Bytes> {
+        let fetch = || async {
+            let response = self.make_request(url.clone(), headers.clone()).await?;
+            let ttl = cache::resource_ttl(response.headers());
+            let body = response
+                .bytes()
+                .await
+                .map_err(|e| AppError::Upstream(format!("Failed to read {}: {}", url, e)))?;
+            metrics().record_bytes_in(body.len());
+            Ok((body, ttl))
+        };
+
+        match &self.resource_cache {
+            Some(resource_cache) => {
+                let key = FetchCache::<Bytes>::key(&url, &headers);
+                resource_cache.get_or_fetch(key, fetch).await
+            }
+            None => fetch().await.map(|(body, _)| body),
+        }
     }
 
-    pub async fn make_request(
+    /// Like [`create_stream`](Self::create_stream), falling back to the
+    /// `mirrors` in order when `url` cannot be reached, times out or fails
+    /// with a server error.
+    ///
+    /// Returns the destination that answered along with its response. A body
+    /// that breaks off is resumed from any of the destinations.
+    pub async fn create_stream_with_mirrors(
         &self,
         url: String,
+        mirrors: &[String],
         headers: reqwest::header::HeaderMap,
-    ) -> AppResult<Response> {
-        let proxy_config = self.proxy_router.get_proxy_config(&url);
-
-        let client = if let Some(config) = proxy_config {
-            let mut builder = Client::builder()
-                .connect_timeout(Duration::from_secs(self.config.connect_timeout))
-                .pool_idle_timeout(Duration::from_secs(90))
-                .pool_max_idle_per_host(0);
-
-            if config.proxy {
-                if let Some(proxy_url) = config.proxy_url.as_ref() {
-                    match Proxy::all(proxy_url) {
-                        Ok(proxy) => {
-                            info!("Using proxy {} for {}", proxy_url, url);
-                            builder = builder.proxy(proxy);
-                        }
-                        Err(e) => {
-                            error!("Failed to create proxy for {}: {}", proxy_url, e);
-                            return Err(AppError::Internal(format!(
-                                "Failed to create proxy: {}",
-                                e
-                            )));
-                        }
+        is_head: bool,
+    ) -> AppResult<(String, UpstreamResponse)> {
+        let destinations: Vec<String> = std::iter::once(url).chain(mirrors.to_vec()).collect();
+
+        let mut last_error = None;
+        for (index, destination) in destinations.iter().enumerate() {
+            // The others remain available to resume from
+            let mut others = destinations.clone();
+            others.remove(index);
+
+            match self
+                .create_stream_from(destination.clone(), others, headers.clone(), is_head)
+                .await
+            {
+                Ok(response) => return Ok((destination.clone(), response)),
+                Err(e) if is_failover(&e) => {
+                    tracing::warn!(
+                        "Destination {} failed ({}), trying the next mirror",
+                        destination,
+                        e
+                    );
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| AppError::Internal("No destination".to_string())))
+    }
+
+    /// Relay `url` to a client: the upstream status, headers and, unless
+    /// `is_head` or the status has no content to relay, the body.
+    pub async fn create_stream(
+        &self,
+        url: String,
+        headers: reqwest::header::HeaderMap,
+        is_head: bool,
+    ) -> AppResult<UpstreamResponse> {
+        self.create_stream_from(url, Vec::new(), headers, is_head)
+            .await
+    }
+
+    /// Relay `url`, resuming a broken body from `url` or the `mirrors`.
+    async fn create_stream_from(
+        &self,
+        url: String,
+        mirrors: Vec<String>,
+        headers: reqwest::header::HeaderMap,
+        is_head: bool,
+    ) -> AppResult<UpstreamResponse> {
+        if is_head {
+            return self.fetch_headers(url, headers).await;
+        }
+
+        // Cached copies are looked up without the conditions and validated locally
+        let unconditional = conditional::unconditional(&headers);
+        let entity_key = self.entity_key(&url, &unconditional);
+        if let Some(cached) = self
+            .read_disk_cache(entity_key.as_deref(), &unconditional)
+            .await
+        {
+            return Ok(Self::validate(&headers, cached));
+        }
+        let mut unshared = None;
+        if let Some(segment_cache) = self.shared_cache(&unconditional) {
+            let key = cache::request_key(&url, &unconditional);
+            let cached = segment_cache.get(&key);
+            if let Some(segment) = &cached {
+                if let Some(status) = conditional::evaluate(&headers, &segment.headers) {
+                    return Ok(UpstreamResponse {
+                        status,
+                        headers: conditional::validators(&segment.headers),
+                        body: None,
+                    });
+                }
+            }
+            // A conditional miss goes upstream as is, its 304 is not worth sharing
+            if cached.is_some() || !conditional::is_conditional(&headers) {
+                let fetch = || async {
+                    let response = self
+                        .send_request(Method::GET, url.clone(), unconditional.clone())
+                        .await?;
+                    if !is_passthrough(response.status()) {
+                        return Err(upstream_error(response.status()));
+                    }
+                    Ok(response)
+                };
+                match segment_cache.fetch_shared(key, fetch).await? {
+                    SharedFetch::Shared(response_headers, stream) => {
+                        return Ok(UpstreamResponse {
+                            status: StatusCode::OK,
+                            headers: response_headers,
+                            body: Some(stream),
+                        });
                     }
+                    // Relayed like any other response, resumable and written to disk
+                    SharedFetch::Unshared(response) => unshared = Some(response),
                 }
             }
+        }
 
-            if !config.verify_ssl {
-                tracing::warn!("SSL verification disabled for {}", url);
-                builder = builder.danger_accept_invalid_certs(true);
+        let response = match unshared {
+            Some(response) => response,
+            None => {
+                let response = self.send_request(Method::GET, url.clone(), headers).await?;
+                if !is_passthrough(response.status()) {
+                    return Err(upstream_error(response.status()));
+                }
+                response
             }
+        };
+        let status = response.status();
+        if !status.is_success() {
+            // 304, 416 and redirects are relayed without their body
+            return Ok(UpstreamResponse::without_body(&response));
+        }
+        let response_headers = response.headers().clone();
 
-            builder
-                .build()
-                .map_err(|e| AppError::Internal(format!("Failed to create client: {}", e)))?
-        } else {
-            self.client.clone()
+        let stream = cache::response_stream(response);
+        let urls = std::iter::once(url).chain(mirrors).collect();
+        let stream = match Resume::new(urls, &unconditional, status, &response_headers) {
+            Some(resume) => self.resumable(resume, stream),
+            None => stream,
+        };
+        let stream = match (&self.disk_cache, entity_key) {
+            (Some(disk_cache), Some(key)) => disk_cache.tee(key, status, &response_headers, stream),
+            _ => stream,
         };
 
-        let response = timeout(
-            Duration::from_secs(self.config.connect_timeout),
-            client.get(&url).headers(headers).send(),
-        )
-        .await
-        .map_err(|e| AppError::Proxy(format!("Connection timeout: {}", e)))?
-        .map_err(|e| AppError::Proxy(format!("Failed to connect to upstream: {}", e)))?;
+        Ok(UpstreamResponse {
+            status,
+            headers: response_headers,
+            body: Some(stream),
+        })
+    }
 
-        if !response.status().is_success() {
+    /// Continue `stream` where it broke off with range requests for the rest,
+    /// so that the client sees one uninterrupted body.
+    fn resumable(&self, mut resume: Resume, mut stream: ByteStream) -> ByteStream {
+        let manager = self.clone();
+        let upstream = self.upstream();
+        let retries = upstream.config.resume_retries;
+        let backoff = Duration::from_millis(upstream.config.resume_backoff_ms);
+
+        Box::pin(async_stream::stream! {
+            loop {
+                let error = match stream.next().await {
+                    Some(Ok(chunk)) => {
+                        resume.offset += chunk.len() as u64;
+                        yield Ok(chunk);
+                        continue;
+                    }
+                    Some(Err(e)) => e,
+                    None => break,
+                };
+
+                // Every break gets the full number of attempts, as long as bytes come through
+                let mut last_error = error;
+                let mut resumed = None;
+                for attempt in 0..retries {
+                    tokio::time::sleep(backoff * 2u32.saturating_pow(attempt)).await;
+                    tracing::warn!(
+                        "Upstream stream broke at byte {} ({}), resuming (attempt {}/{})",
+                        resume.offset,
+                        last_error,
+                        attempt + 1,
+                        retries
+                    );
+                    match manager.resume(&resume, attempt as usize).await {
+                        Ok(rest) => {
+                            resumed = Some(rest);
+                            break;
+                        }
+                        Err(e) => last_error = e,
+                    }
+                }
+
+                match resumed {
+                    Some(rest) => stream = rest,
+                    None => {
+                        yield Err(last_error);
+                        break;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Request the bytes of `resume` from its offset on, if upstream still has the same entity.
+    ///
+    /// Successive attempts go to the next destination in turn.
+    async fn resume(&self, resume: &Resume, attempt: usize) -> AppResult<ByteStream> {
+        let url = &resume.urls[attempt % resume.urls.len()];
+        let mut headers = resume.headers.clone();
+        let range = match resume.end {
+            Some(end) => format!("bytes={}-{}", resume.offset, end),
+            None => format!("bytes={}-", resume.offset),
+        };
+        headers.insert(
+            RANGE,
+            HeaderValue::from_str(&range)
+                .map_err(|e| AppError::Internal(format!("Invalid header value: {}", e)))?,
+        );
+        headers.insert(IF_RANGE, resume.validator.clone());
+
+        let response = self.send_request(Method::GET, url.clone(), headers).await?;
+        // A 200 means the entity changed, or ranges are not supported after all
+        let start = (response.status() == StatusCode::PARTIAL_CONTENT)
+            .then(|| content_range(response.headers()))
+            .flatten()
+            .map(|(start, _)| start);
+        if start != Some(resume.offset) {
             return Err(AppError::Upstream(format!(
-                "Upstream returned error status: {}",
+                "Cannot resume upstream stream at byte {} (status {})",
+                resume.offset,
                 response.status()
             )));
         }
 
-        Ok(response)
+        Ok(cache::response_stream(response))
     }
 
-    pub async fn create_stream(
+    /// Answer a conditional request from a cached response, when its conditions allow it.
+    fn validate(
+        conditions: &reqwest::header::HeaderMap,
+        cached: UpstreamResponse,
+    ) -> UpstreamResponse {
+        match conditional::evaluate(conditions, &cached.headers) {
+            Some(status) => UpstreamResponse {
+                status,
+                headers: conditional::validators(&cached.headers),
+                body: None,
+            },
+            None => cached,
+        }
+    }
+
+    /// Disk cache key for the entity behind a request, if the disk cache is enabled.
+    ///
+    /// Range headers are left out so that every byte range of a file maps to one entry.
+    fn entity_key(&self, url: &str, headers: &reqwest::header::HeaderMap) -> Option<String> {
+        self.disk_cache.as_ref()?;
+        let mut headers = headers.clone();
+        headers.remove(RANGE);
+        headers.remove(IF_RANGE);
+        Some(cache::request_key(url, &headers))
+    }
+
+    async fn read_disk_cache(
+        &self,
+        entity_key: Option<&str>,
+        headers: &reqwest::header::HeaderMap,
+    ) -> Option<UpstreamResponse> {
+        self.disk_cache
+            .as_ref()?
+            .read(entity_key?, headers.get(RANGE), headers.get(IF_RANGE))
+            .await
+            .map(|(status, headers, stream)| UpstreamResponse {
+                status,
+                headers,
+                body: Some(stream),
+            })
+    }
+
+    /// The segment cache, if enabled and usable for a request with these headers.
+    fn shared_cache(&self, headers: &reqwest::header::HeaderMap) -> Option<&Arc<SegmentCache>> {
+        // Range requests are passed through untouched
+        self.segment_cache
+            .as_ref()
+            .filter(|_| !headers.contains_key(RANGE))
+    }
+
+    /// Remember the order of the segments of a media playlist, and prefetch
+    /// the ones a player starts with up to the depth of their transport route.
+    pub fn prefetch_playlist(
+        &self,
+        segments: Vec<String>,
+        live: bool,
+        headers: &reqwest::header::HeaderMap,
+    ) {
+        let Some(first) = segments.first() else {
+            return;
+        };
+        let depth = self.proxy_router().prefetch_depth(first);
+        if depth == 0 {
+            return;
+        }
+        let urls = self.prefetcher.playlist(segments, live, depth);
+        self.prefetch(urls, headers);
+    }
+
+    /// Prefetch the segments following `url` in the last playlist listing it.
+    pub fn prefetch_after(&self, url: &str, headers: &reqwest::header::HeaderMap) {
+        let depth = self.proxy_router().prefetch_depth(url);
+        if depth == 0 {
+            return;
+        }
+        let urls = self.prefetcher.following(url, depth);
+        self.prefetch(urls, headers);
+    }
+
+    /// Fetch `urls` into the segment cache in the background, so the player's
+    /// requests for them join the fetch or hit the cache.
+    fn prefetch(&self, urls: Vec<String>, headers: &reqwest::header::HeaderMap) {
+        let headers = conditional::unconditional(headers);
+        let Some(segment_cache) = self.shared_cache(&headers) else {
+            return;
+        };
+
+        for url in urls {
+            let key = cache::request_key(&url, &headers);
+            if segment_cache.get(&key).is_some() {
+                continue;
+            }
+            let manager = self.clone();
+            let segment_cache = segment_cache.clone();
+            let headers = headers.clone();
+            tokio::spawn(async move {
+                tracing::debug!("Prefetching {}", url);
+                // Shared bodies are read to the end by the cache, the stream is not needed
+                let fetched = segment_cache
+                    .fetch(key, || manager.make_request(url.clone(), headers))
+                    .await;
+                if let Err(e) = fetched {
+                    tracing::debug!("Failed to prefetch {}: {}", url, e);
+                }
+            });
+        }
+    }
+
+    /// Fetch a whole segment and run it through `processor`.
+    ///
+    /// Segment processors need the complete body, so unlike [`create_stream`](Self::create_stream)
+    /// this buffers the upstream response in memory.
+    pub async fn fetch_segment(
         &self,
         url: String,
         headers: reqwest::header::HeaderMap,
-        is_head: bool,
-    ) -> AppResult<(
-        reqwest::header::HeaderMap,
-        Option<impl Stream<Item = Result<Bytes, AppError>>>,
-    )> {
-        // Always make a GET request but don't read the body for HEAD
+        processor: &dyn SegmentProcessor,
+    ) -> AppResult<(reqwest::header::HeaderMap, Bytes)> {
+        let headers = conditional::unconditional(&headers);
+        let entity_key = self.entity_key(&url, &headers);
+        let cached = match self.read_disk_cache(entity_key.as_deref(), &headers).await {
+            Some(UpstreamResponse {
+                headers: response_headers,
+                body: Some(stream),
+                ..
+            }) => Some((response_headers, stream)),
+            _ => match self.shared_cache(&headers) {
+                Some(segment_cache) => {
+                    let key = cache::request_key(&url, &headers);
+                    Some(
+                        segment_cache
+                            .fetch(key, || self.make_request(url.clone(), headers.clone()))
+                            .await?,
+                    )
+                }
+                None => None,
+            },
+        };
+
+        if let Some((response_headers, mut stream)) = cached {
+            let mut body = Vec::new();
+            while let Some(chunk) = stream.next().await {
+                body.extend_from_slice(&chunk?);
+            }
+            return Ok((response_headers, processor.process(Bytes::from(body))?));
+        }
+
         let response = self.make_request(url, headers).await?;
+        let status = response.status();
         let response_headers = response.headers().clone();
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| AppError::Proxy(format!("Failed to read segment: {}", e)))?;
+        metrics().record_bytes_in(body.len());
 
-        if is_head {
-            // For HEAD requests, return only headers
-            Ok((response_headers, None))
-        } else {
-            // For GET requests, return headers and stream
-            let stream = response
-                .bytes_stream()
-                .map(|result| result.map_err(|e| AppError::Proxy(format!("Stream error: {}", e))));
-
-            Ok((response_headers, Some(stream)))
+        if let (Some(disk_cache), Some(key)) = (&self.disk_cache, entity_key) {
+            disk_cache.store(key, status, &response_headers, body.clone());
         }
+
+        Ok((response_headers, processor.process(body)?))
     }
 
-    pub async fn stream_with_progress<S>(
+    /// Log the progress of `stream`, count it in the metrics and pace it
+    /// under the bandwidth limits, plus the `throttles` it shares with other streams.
+    pub async fn stream_with_progress(
         &self,
-        stream: S,
-    ) -> impl Stream<Item = Result<Bytes, AppError>>
-    where
-        S: Stream<Item = Result<Bytes, AppError>> + 'static,
-    {
-        let buffer_size = self.config.buffer_size;
+        stream: ByteStream,
+        throttles: Vec<Arc<Throttle>>,
+    ) -> impl Stream<Item = Result<Bytes, AppError>> {
+        let upstream = self.upstream();
+        let buffer_size = upstream.config.buffer_size;
         let mut total_bytes = 0usize;
+        let active = metrics().open_stream();
 
-        Box::pin(stream.map(move |chunk| match chunk {
-            Ok(bytes) => {
-                total_bytes += bytes.len();
-                if total_bytes % (buffer_size * 10) == 0 {
-                    info!("Streamed {} bytes", total_bytes);
+        Box::pin(upstream.shaper.shape(stream, throttles).map(move |chunk| {
+            // Counted as active until the stream is dropped
+            let _active = &active;
+            match chunk {
+                Ok(bytes) => {
+                    total_bytes += bytes.len();
+                    if total_bytes.is_multiple_of(buffer_size * 10) {
+                        info!("Streamed {} bytes", total_bytes);
+                    }
+                    Ok(bytes)
+                }
+                Err(e) => {
+                    error!("Streaming error after {} bytes: {}", total_bytes, e);
+                    metrics().record_error(&e);
+                    Err(e)
                 }
-                Ok(bytes)
-            }
-            Err(e) => {
-                error!("Streaming error after {} bytes: {}", total_bytes, e);
-                Err(e)
             }
         }))
     }
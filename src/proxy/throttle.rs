@@ -0,0 +1,186 @@
+use futures::StreamExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::{config::BandwidthConfig, proxy::stream::ByteStream};
+
+/// A token bucket limiting throughput to `rate` bytes per second.
+///
+/// Bytes are taken before they are sent and the bucket may go into debt, so a
+/// chunk larger than the bucket is delayed rather than refused. Concurrent
+/// streams sharing a throttle split the rate between them.
+pub struct Throttle {
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Throttle {
+    /// A throttle allowing bursts of up to one second worth of bytes.
+    pub fn new(rate: u64) -> Self {
+        Self::with_burst(rate, rate)
+    }
+
+    pub fn with_burst(rate: u64, burst: u64) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate: rate.max(1) as f64,
+                burst: burst as f64,
+                tokens: burst as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().rate as u64
+    }
+
+    /// Change the rate from now on; bytes already taken keep their cost.
+    pub fn set_rate(&self, rate: u64) {
+        self.refill().rate = rate.max(1) as f64;
+    }
+
+    /// Take `bytes` from the bucket, waiting until they are paid for.
+    pub async fn acquire(&self, bytes: usize) {
+        if let Some(wait) = self.reserve(bytes) {
+            sleep(wait).await;
+        }
+    }
+
+    /// Take `bytes` from the bucket, returning how long to wait before sending them.
+    pub fn reserve(&self, bytes: usize) -> Option<Duration> {
+        let mut bucket = self.refill();
+        bucket.tokens -= bytes as f64;
+        (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / bucket.rate))
+    }
+
+    /// Take `amount` from the bucket only if it holds enough, otherwise
+    /// return how long until it will.
+    pub fn try_acquire(&self, amount: usize) -> Result<(), Duration> {
+        let mut bucket = self.refill();
+        let missing = amount as f64 - bucket.tokens;
+        if missing > 0.0 {
+            return Err(Duration::from_secs_f64(missing / bucket.rate));
+        }
+        bucket.tokens -= amount as f64;
+        Ok(())
+    }
+
+    /// Whether the bucket is full again, i.e. the throttle has been idle for a while.
+    pub fn is_idle(&self) -> bool {
+        let bucket = self.refill();
+        bucket.tokens >= bucket.burst
+    }
+
+    fn refill(&self) -> MutexGuard<'_, Bucket> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * bucket.rate).min(bucket.burst);
+        bucket.updated = now;
+        bucket
+    }
+}
+
+/// Shapes the bandwidth of the responses streamed by the process.
+///
+/// Each response is capped on its own, by any throttle it shares with other
+/// responses (such as its API key's), and by the global limit. While several
+/// responses are waiting on the global limit, each is also paced at its fair
+/// share of it, so a single large download cannot take the whole uplink from
+/// live viewers. Responses idling between chunks leave their share to the others.
+#[derive(Default)]
+pub struct Shaper {
+    per_stream: Option<u64>,
+    global: Option<Arc<Throttle>>,
+    burst: Option<u64>,
+    active: Arc<AtomicUsize>,
+    /// Responses with a chunk waiting on the global limit.
+    contending: Arc<AtomicUsize>,
+}
+
+impl Shaper {
+    pub fn new(config: &BandwidthConfig) -> Self {
+        Self {
+            per_stream: config.max_stream_bandwidth,
+            global: config
+                .max_total_bandwidth
+                .map(|rate| Arc::new(Throttle::with_burst(rate, config.burst.unwrap_or(rate)))),
+            burst: config.burst,
+            active: Arc::default(),
+            contending: Arc::default(),
+        }
+    }
+
+    /// Responses being shaped right now.
+    pub fn active_streams(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Pace `stream` under the limits, plus the `shared` throttles.
+    pub fn shape(&self, mut stream: ByteStream, shared: Vec<Arc<Throttle>>) -> ByteStream {
+        if self.per_stream.is_none() && self.global.is_none() && shared.is_empty() {
+            return stream;
+        }
+
+        let throttle = |rate: u64| Throttle::with_burst(rate, self.burst.unwrap_or(rate));
+        let own = self.per_stream.map(throttle);
+        let fair = self.global.as_ref().map(|global| throttle(global.rate()));
+        let global = self.global.clone();
+        let contending = self.contending.clone();
+        let active = ActiveStream::new(self.active.clone());
+
+        Box::pin(async_stream::stream! {
+            let _active = active;
+            while let Some(chunk) = stream.next().await {
+                if let Ok(bytes) = &chunk {
+                    if let Some(own) = &own {
+                        own.acquire(bytes.len()).await;
+                    }
+                    for throttle in &shared {
+                        throttle.acquire(bytes.len()).await;
+                    }
+                    if let (Some(fair), Some(global)) = (&fair, &global) {
+                        let waiting = ActiveStream::new(contending.clone());
+                        let count = waiting.count();
+                        if count > 1 {
+                            fair.set_rate(global.rate() / count as u64);
+                            fair.acquire(bytes.len()).await;
+                        }
+                        global.acquire(bytes.len()).await;
+                    }
+                }
+                yield chunk;
+            }
+        })
+    }
+}
+
+/// A response counted among the active, or contending, ones until dropped.
+struct ActiveStream {
+    active: Arc<AtomicUsize>,
+}
+
+impl ActiveStream {
+    fn new(active: Arc<AtomicUsize>) -> Self {
+        active.fetch_add(1, Ordering::SeqCst);
+        Self { active }
+    }
+
+    fn count(&self) -> usize {
+        self.active.load(Ordering::SeqCst).max(1)
+    }
+}
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+
+use crate::error::{AppError, AppResult};
+
+const PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0;
+
+/// MPEG-TS stream types the remuxer understands.
+pub const STREAM_TYPE_AAC: u8 = 0x0f;
+pub const STREAM_TYPE_H264: u8 = 0x1b;
+pub const STREAM_TYPE_H265: u8 = 0x24;
+
+/// One PES packet: an access unit for video, one or more ADTS frames for audio.
+#[derive(Debug, Clone)]
+pub struct Pes {
+    /// 90 kHz presentation timestamp
+    pub pts: u64,
+    /// 90 kHz decode timestamp, the PTS when absent
+    pub dts: u64,
+    pub data: Vec<u8>,
+}
+
+/// The packets of one elementary stream of the program.
+#[derive(Debug, Clone)]
+pub struct ElementaryStream {
+    pub pid: u16,
+    pub stream_type: u8,
+    pub packets: Vec<Pes>,
+}
+
+/// Demultiplex the elementary streams of the first program of a transport
+/// stream segment, in PMT order.
+///
+/// Sections are expected to fit in one packet, as they do in HLS segments.
+pub fn demux(data: &[u8]) -> AppResult<Vec<ElementaryStream>> {
+    let start = data
+        .iter()
+        .position(|&b| b == SYNC_BYTE)
+        .filter(|&start| data.len() >= start + PACKET_SIZE)
+        .ok_or_else(|| invalid("no transport stream packets"))?;
+
+    let mut pmt_pid = None;
+    let mut streams: Vec<ElementaryStream> = Vec::new();
+    let mut pending: HashMap<u16, Vec<u8>> = HashMap::new();
+
+    for packet in data[start..].chunks_exact(PACKET_SIZE) {
+        if packet[0] != SYNC_BYTE {
+            return Err(invalid("lost packet sync"));
+        }
+        let unit_start = packet[1] & 0x40 != 0;
+        let pid = u16::from_be_bytes([packet[1] & 0x1f, packet[2]]);
+        let adaptation = (packet[3] >> 4) & 0x03;
+
+        let mut offset = 4;
+        if adaptation & 0x02 != 0 {
+            offset += 1 + packet[4] as usize;
+        }
+        if adaptation & 0x01 == 0 || offset >= PACKET_SIZE {
+            continue;
+        }
+        let payload = &packet[offset..];
+
+        if pid == PAT_PID {
+            if unit_start && pmt_pid.is_none() {
+                pmt_pid = parse_pat(section(payload)?)?;
+            }
+        } else if Some(pid) == pmt_pid {
+            if unit_start && streams.is_empty() {
+                streams = parse_pmt(section(payload)?)?;
+            }
+        } else if let Some(stream) = streams.iter_mut().find(|s| s.pid == pid) {
+            if unit_start {
+                if let Some(previous) = pending.remove(&pid) {
+                    stream.packets.extend(parse_pes(&previous)?);
+                }
+                pending.insert(pid, payload.to_vec());
+            } else if let Some(buffer) = pending.get_mut(&pid) {
+                buffer.extend_from_slice(payload);
+            }
+        }
+    }
+
+    for stream in streams.iter_mut() {
+        if let Some(previous) = pending.remove(&stream.pid) {
+            stream.packets.extend(parse_pes(&previous)?);
+        }
+    }
+    if streams.is_empty() {
+        return Err(invalid("no program map table"));
+    }
+
+    Ok(streams)
+}
+
+/// The section following the pointer field of a PSI payload, up to its CRC.
+fn section(payload: &[u8]) -> AppResult<&[u8]> {
+    let pointer = *payload.first().ok_or_else(|| invalid("empty section"))? as usize;
+    let section = payload
+        .get(1 + pointer..)
+        .filter(|s| s.len() >= 3)
+        .ok_or_else(|| invalid("truncated section"))?;
+    let length = (u16::from_be_bytes([section[1] & 0x0f, section[2]])) as usize;
+    section
+        .get(..3 + length)
+        .filter(|_| length >= 4)
+        .map(|s| &s[..s.len() - 4])
+        .ok_or_else(|| invalid("section spans several packets"))
+}
+
+fn parse_pat(section: &[u8]) -> AppResult<Option<u16>> {
+    let programs = section.get(8..).ok_or_else(|| invalid("truncated PAT"))?;
+    Ok(programs
+        .chunks_exact(4)
+        .find(|entry| u16::from_be_bytes([entry[0], entry[1]]) != 0)
+        .map(|entry| u16::from_be_bytes([entry[2] & 0x1f, entry[3]])))
+}
+
+fn parse_pmt(section: &[u8]) -> AppResult<Vec<ElementaryStream>> {
+    if section.len() < 12 {
+        return Err(invalid("truncated PMT"));
+    }
+    let program_info_length = u16::from_be_bytes([section[10] & 0x0f, section[11]]) as usize;
+    let mut entries = section
+        .get(12 + program_info_length..)
+        .ok_or_else(|| invalid("truncated PMT"))?;
+
+    let mut streams = Vec::new();
+    while entries.len() >= 5 {
+        let es_info_length = u16::from_be_bytes([entries[3] & 0x0f, entries[4]]) as usize;
+        streams.push(ElementaryStream {
+            pid: u16::from_be_bytes([entries[1] & 0x1f, entries[2]]),
+            stream_type: entries[0],
+            packets: Vec::new(),
+        });
+        entries = entries.get(5 + es_info_length..).unwrap_or_default();
+    }
+    Ok(streams)
+}
+
+/// Parse a PES packet; packets without a timestamp or payload are dropped.
+fn parse_pes(data: &[u8]) -> AppResult<Option<Pes>> {
+    if data.len() < 9 || data[..3] != [0, 0, 1] {
+        return Err(invalid("PES packet without start code"));
+    }
+    let flags = data[7];
+    let header_end = 9 + data[8] as usize;
+    if data.len() < header_end {
+        return Err(invalid("truncated PES header"));
+    }
+
+    let pts = match flags & 0x80 {
+        0 => return Ok(None),
+        _ => timestamp(&data[9..]),
+    };
+    let dts = match flags & 0x40 {
+        0 => pts,
+        _ => timestamp(data.get(14..).unwrap_or_default()),
+    };
+    // Unbounded video packets have a zero length, the rest of the buffer is theirs
+    let length = u16::from_be_bytes([data[4], data[5]]) as usize;
+    let end = if length == 0 {
+        data.len()
+    } else {
+        (6 + length).min(data.len())
+    };
+
+    Ok((end > header_end).then(|| Pes {
+        pts,
+        dts,
+        data: data[header_end..end].to_vec(),
+    }))
+}
+
+/// A 33-bit timestamp from the five bytes of a PES header field.
+fn timestamp(field: &[u8]) -> u64 {
+    match field {
+        [a, b, c, d, e, ..] => {
+            (((*a as u64) >> 1) & 0x07) << 30
+                | (*b as u64) << 22
+                | ((*c as u64) >> 1) << 15
+                | (*d as u64) << 7
+                | (*e as u64) >> 1
+        }
+        _ => 0,
+    }
+}
+
+fn invalid(reason: &str) -> AppError {
+    AppError::Upstream(format!("Invalid MPEG-TS segment: {}", reason))
+}
@@ -0,0 +1,147 @@
+use actix_web::{HttpMessage, HttpRequest};
+use serde_json::Value;
+use std::sync::Arc;
+
+use crate::{
+    auth::{encryption::ProxyData, EncryptionHandler},
+    error::AppResult,
+    models::request::{CARRIED_PARAMS, FETCH_URL_PARAMS, MIRROR_PARAM},
+};
+
+/// Builds proxied URLs that carry the same authentication and headers as the
+/// incoming request, so that follow-up requests issued by players go through
+/// the proxy as well.
+#[derive(Clone)]
+pub struct ProxyUrlBuilder {
+    base_url: String,
+    proxy_data: ProxyData,
+    encryption_handler: Option<Arc<EncryptionHandler>>,
+}
+
+impl ProxyUrlBuilder {
+    pub fn new(
+        base_url: String,
+        proxy_data: ProxyData,
+        encryption_handler: Option<Arc<EncryptionHandler>>,
+    ) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            proxy_data,
+            encryption_handler,
+        }
+    }
+
+    pub fn from_request(req: &HttpRequest, proxy_data: &ProxyData) -> Self {
+        let connection_info = req.connection_info();
+        let base_url = format!("{}://{}", connection_info.scheme(), connection_info.host());
+        // Only present when the request was authenticated with an encrypted token
+        let encryption_handler = req.extensions().get::<Arc<EncryptionHandler>>().cloned();
+
+        let mut proxy_data = proxy_data.clone();
+        if encryption_handler.is_some() {
+            // Options given next to the token hold for the URLs rewritten from it
+            let query_params = proxy_data
+                .query_params
+                .get_or_insert_with(|| Value::Object(Default::default()));
+            if let Value::Object(query_params) = query_params {
+                for (key, value) in url::form_urlencoded::parse(req.query_string().as_bytes()) {
+                    if CARRIED_PARAMS.contains(&key.as_ref()) && !query_params.contains_key(&*key) {
+                        query_params.insert(key.into_owned(), Value::String(value.into_owned()));
+                    }
+                }
+            }
+        }
+
+        Self::new(base_url, proxy_data, encryption_handler)
+    }
+
+    pub fn proxy_data(&self) -> &ProxyData {
+        &self.proxy_data
+    }
+
+    /// Build a URL for `endpoint` (e.g. `/proxy/stream`) pointing at `destination`.
+    pub fn build(&self, endpoint: &str, destination: &str) -> AppResult<String> {
+        self.build_with_params(endpoint, destination, &[])
+    }
+
+    /// Like [`build`](Self::build), with additional query parameters appended.
+    /// Upstream URLs fetched next to the destination ([`FETCH_URL_PARAMS`]) go
+    /// inside the token, so that they can't be swapped for other hosts.
+    pub fn build_with_params(
+        &self,
+        endpoint: &str,
+        destination: &str,
+        extra_params: &[(&str, &str)],
+    ) -> AppResult<String> {
+        let mut params: Vec<(String, String)> = Vec::new();
+        let mut extra_params = extra_params.to_vec();
+
+        if let Some(handler) = &self.encryption_handler {
+            let mut proxy_data = self.proxy_data.clone();
+            proxy_data.destination = destination.to_string();
+            // Mirrors and fetched URLs belong to the original destination only
+            proxy_data.mirrors.clear();
+            let query_params = proxy_data
+                .query_params
+                .get_or_insert_with(|| Value::Object(Default::default()));
+            if let Value::Object(query_params) = query_params {
+                query_params.retain(|key, _| !FETCH_URL_PARAMS.contains(&key.as_str()));
+                for (key, value) in &extra_params {
+                    if FETCH_URL_PARAMS.contains(key) {
+                        query_params.insert(key.to_string(), Value::String(value.to_string()));
+                    }
+                }
+                extra_params.retain(|(key, _)| !FETCH_URL_PARAMS.contains(key));
+            }
+            params.push(("token".to_string(), handler.encrypt(&proxy_data)?));
+        } else {
+            // Keep every original parameter (e.g. api_password) except the ones we re-emit
+            if let Some(Value::Object(query_params)) = &self.proxy_data.query_params {
+                for (key, value) in query_params {
+                    if key == "d"
+                        || key == MIRROR_PARAM
+                        || FETCH_URL_PARAMS.contains(&key.as_str())
+                        || key.starts_with("h_")
+                        || key.starts_with("r_")
+                    {
+                        continue;
+                    }
+                    if let Some(value) = value.as_str() {
+                        params.push((key.clone(), value.to_string()));
+                    }
+                }
+            }
+
+            params.push(("d".to_string(), destination.to_string()));
+            Self::push_prefixed(&mut params, "h_", &self.proxy_data.request_headers);
+            Self::push_prefixed(&mut params, "r_", &self.proxy_data.response_headers);
+        }
+
+        for (key, value) in &extra_params {
+            params.push((key.to_string(), value.to_string()));
+        }
+
+        let query_string = params
+            .iter()
+            .map(|(k, v)| format!("{}={}", urlencoding::encode(k), urlencoding::encode(v)))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        Ok(format!(
+            "{}/{}?{}",
+            self.base_url,
+            endpoint.trim_start_matches('/'),
+            query_string
+        ))
+    }
+
+    fn push_prefixed(params: &mut Vec<(String, String)>, prefix: &str, headers: &Option<Value>) {
+        if let Some(Value::Object(headers)) = headers {
+            for (key, value) in headers {
+                if let Some(value) = value.as_str() {
+                    params.push((format!("{}{}", prefix, key), value.to_string()));
+                }
+            }
+        }
+    }
+}
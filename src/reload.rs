@@ -0,0 +1,119 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+use crate::{
+    auth::middleware::AuthMiddleware,
+    config::Config,
+    error::{AppError, AppResult},
+    proxy::{rate_limit::RateLimiter, stream::StreamManager},
+};
+
+/// How often the configuration file is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Applies configuration changes to the running server.
+///
+/// The upstream clients and transport routes, the bandwidth limits, the API
+/// keys and the rate limits are swapped without touching the streams in
+/// progress. The server address, workers and caches need a restart.
+#[derive(Clone)]
+pub struct ConfigReloader {
+    stream_manager: StreamManager,
+    auth: AuthMiddleware,
+    rate_limiter: RateLimiter,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        stream_manager: StreamManager,
+        auth: AuthMiddleware,
+        rate_limiter: RateLimiter,
+    ) -> Self {
+        Self {
+            stream_manager,
+            auth,
+            rate_limiter,
+        }
+    }
+
+    /// Load the configuration again, from `CONFIG_PATH` and the environment, and apply it.
+    pub fn reload(&self) -> AppResult<()> {
+        let config = Config::from_env()
+            .map_err(|e| AppError::Internal(format!("Invalid configuration: {}", e)))?;
+        self.apply(&config)
+    }
+
+    /// Apply `config`, or nothing of it if it is invalid.
+    pub fn apply(&self, config: &Config) -> AppResult<()> {
+        self.auth.reload(&config.auth)?;
+        self.stream_manager.reload(&config.proxy, &config.bandwidth);
+        self.rate_limiter.reload(config.rate_limit.clone());
+        Ok(())
+    }
+
+    /// Reload whenever the file at `path` changes or the process receives `SIGHUP`.
+    pub fn spawn(self, path: Option<PathBuf>) {
+        tokio::spawn(async move {
+            let mut modified = path.as_deref().and_then(modified_time);
+            let mut poll = interval(POLL_INTERVAL);
+            poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut hangup = Hangup::new();
+
+            loop {
+                tokio::select! {
+                    _ = poll.tick() => {
+                        let current = path.as_deref().and_then(modified_time);
+                        if current == modified {
+                            continue;
+                        }
+                        modified = current;
+                        tracing::info!("Configuration file changed, reloading");
+                    }
+                    _ = hangup.recv() => {
+                        tracing::info!("Received SIGHUP, reloading configuration");
+                    }
+                }
+
+                match self.reload() {
+                    Ok(()) => tracing::info!("Configuration reloaded"),
+                    Err(e) => tracing::error!(
+                        "Failed to reload configuration, keeping the current one: {}",
+                        e
+                    ),
+                }
+            }
+        });
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// `SIGHUP`, where the platform has it.
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+    fn new() -> Self {
+        Self {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .inspect_err(|e| tracing::warn!("Cannot listen for SIGHUP: {}", e))
+                .ok(),
+        }
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            if signal.recv().await.is_some() {
+                return;
+            }
+        }
+        std::future::pending::<()>().await
+    }
+}
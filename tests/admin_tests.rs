@@ -0,0 +1,159 @@
+use actix_web::{web, App};
+use futures::{stream, StreamExt};
+use mediaflow_proxy_light::auth::middleware::AuthMiddleware;
+use mediaflow_proxy_light::config::{ApiKeyConfig, AuthConfig, CacheConfig, Endpoint, ProxyConfig};
+use mediaflow_proxy_light::proxy::admin;
+use mediaflow_proxy_light::proxy::registry::{StreamInfo, StreamRegistry};
+use mediaflow_proxy_light::proxy::stream::StreamManager;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+fn info(client_ip: &str) -> StreamInfo {
+    StreamInfo {
+        client_ip: client_ip.to_string(),
+        destination_host: "cdn.example.com".to_string(),
+        route: "default".to_string(),
+        key: None,
+    }
+}
+
+fn auth_config(api_password: &str, keys: Vec<ApiKeyConfig>) -> AuthConfig {
+    AuthConfig {
+        api_password: api_password.to_string(),
+        previous_passwords: Vec::new(),
+        legacy_tokens: true,
+        python_tokens: false,
+        keys,
+    }
+}
+
+fn key(label: &str, endpoints: Vec<Endpoint>) -> ApiKeyConfig {
+    ApiKeyConfig {
+        label: label.to_string(),
+        key: format!("{}-secret", label),
+        endpoints,
+        hosts: Vec::new(),
+        expires_at: None,
+        max_streams: None,
+        max_bandwidth: None,
+    }
+}
+
+fn stream_manager() -> StreamManager {
+    StreamManager::new(
+        ProxyConfig {
+            connect_timeout: 5,
+            buffer_size: 8192,
+            follow_redirects: true,
+            proxy_url: None,
+            all_proxy: false,
+            transport_routes: HashMap::new(),
+            manifest_cache: true,
+            pool_max_idle_per_host: 4,
+            pool_idle_timeout: 30,
+            head_fallback: true,
+            resume_retries: 3,
+            resume_backoff_ms: 500,
+        },
+        CacheConfig::default(),
+    )
+}
+
+#[tokio::test]
+async fn test_registry_tracks_and_kills_streams() {
+    let registry = Arc::new(StreamRegistry::default());
+    let chunks = vec![Ok(web::Bytes::from_static(b"0123456789"))];
+    let first = registry.register(
+        info("10.0.0.1"),
+        stream::iter(chunks).chain(stream::pending()),
+    );
+    let mut first = Box::pin(first);
+    let second = registry.register(info("10.0.0.2"), stream::pending());
+
+    assert_eq!(first.next().await.unwrap().unwrap().len(), 10);
+    let streams = registry.list();
+    assert_eq!(streams.len(), 2);
+    assert_eq!(streams[0].client_ip, "10.0.0.1");
+    assert_eq!(streams[0].bytes, 10);
+    assert_eq!(streams[1].bytes, 0);
+
+    // A killed stream ends and is no longer listed
+    assert!(registry.kill(streams[0].id));
+    assert!(first.next().await.is_none());
+    assert!(!registry.kill(streams[0].id));
+    assert_eq!(registry.len(), 1);
+
+    // Dropped streams are unlisted too
+    drop(second);
+    assert!(registry.is_empty());
+}
+
+#[actix_web::test]
+async fn test_admin_api_requires_admin_key() {
+    let auth = auth_config(
+        "admin",
+        vec![key("viewer", Vec::new()), key("ops", vec![Endpoint::Admin])],
+    );
+    let stream_manager = stream_manager();
+    let registry = stream_manager.registry().clone();
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(AuthMiddleware::new(&auth))
+            .app_data(web::Data::new(stream_manager))
+            .route("/admin/streams", web::get().to(admin::list_streams))
+            .route("/admin/streams/{id}", web::delete().to(admin::kill_stream)),
+    )
+    .await;
+
+    let _stream = registry.register(info("10.0.0.1"), stream::pending());
+
+    // Keys without endpoint scopes do not get the admin API
+    let request = actix_web::test::TestRequest::get()
+        .uri("/admin/streams?api_password=viewer-secret")
+        .to_request();
+    let error = actix_web::test::try_call_service(&app, request)
+        .await
+        .unwrap_err();
+    assert_eq!(error.error_response().status(), 403);
+
+    for password in ["admin", "ops-secret"] {
+        let request = actix_web::test::TestRequest::get()
+            .uri(&format!("/admin/streams?api_password={}", password))
+            .to_request();
+        let streams: serde_json::Value =
+            actix_web::test::call_and_read_body_json(&app, request).await;
+        assert_eq!(streams[0]["client_ip"], "10.0.0.1");
+        assert_eq!(streams[0]["destination_host"], "cdn.example.com");
+    }
+
+    let id = registry.list()[0].id;
+    let request = actix_web::test::TestRequest::delete()
+        .uri(&format!("/admin/streams/{}?api_password=admin", id))
+        .to_request();
+    let res = actix_web::test::call_service(&app, request).await;
+    assert_eq!(res.status(), 204);
+    assert!(registry.is_empty());
+
+    let request = actix_web::test::TestRequest::delete()
+        .uri(&format!("/admin/streams/{}?api_password=admin", id))
+        .to_request();
+    let res = actix_web::test::call_service(&app, request).await;
+    assert_eq!(res.status(), 404);
+}
+
+#[actix_web::test]
+async fn test_admin_api_disabled_without_auth() {
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(AuthMiddleware::new(&auth_config("", Vec::new())))
+            .app_data(web::Data::new(stream_manager()))
+            .route("/admin/streams", web::get().to(admin::list_streams)),
+    )
+    .await;
+
+    let request = actix_web::test::TestRequest::get()
+        .uri("/admin/streams")
+        .to_request();
+    let res = actix_web::test::call_service(&app, request).await;
+    assert_eq!(res.status(), 403);
+}
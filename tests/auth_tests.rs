@@ -1,4 +1,7 @@
-use mediaflow_proxy_light::auth::encryption::{EncryptionHandler, ProxyData};
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use aes::Aes256;
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use mediaflow_proxy_light::auth::encryption::{EncryptionHandler, ProxyData, PythonTokenCodec};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 #[tokio::test]
@@ -19,6 +22,7 @@ async fn test_encryption_handler() {
         response_headers: None,
         exp: Some(future_timestamp),
         ip: None,
+        mirrors: Vec::new(),
     };
 
     let token = handler.encrypt(&proxy_data).unwrap();
@@ -39,6 +43,7 @@ async fn test_token_expiration() {
         response_headers: None,
         exp: Some(0), // Expired timestamp
         ip: None,
+        mirrors: Vec::new(),
     };
 
     let token = handler.encrypt(&proxy_data).unwrap();
@@ -46,3 +51,180 @@ async fn test_token_expiration() {
 
     assert!(result.is_err());
 }
+
+fn proxy_data() -> ProxyData {
+    ProxyData {
+        destination: "https://example.com/video.mp4".to_string(),
+        query_params: None,
+        request_headers: None,
+        response_headers: None,
+        exp: None,
+        ip: None,
+        mirrors: Vec::new(),
+    }
+}
+
+/// A token in the original format: a random IV followed by independently encrypted blocks.
+fn legacy_token(password: &[u8], data: &ProxyData) -> String {
+    let mut key = [0u8; 32];
+    key[..password.len()].copy_from_slice(password);
+    let cipher = Aes256::new(&key.into());
+
+    let mut plaintext = serde_json::to_vec(data).unwrap();
+    let padding = 16 - plaintext.len() % 16;
+    plaintext.extend(std::iter::repeat_n(padding as u8, padding));
+
+    let mut token = vec![7u8; 16];
+    for chunk in plaintext.chunks_mut(16) {
+        cipher.encrypt_block(GenericArray::from_mut_slice(chunk));
+        token.extend_from_slice(chunk);
+    }
+    URL_SAFE.encode(token)
+}
+
+#[tokio::test]
+async fn test_token_is_versioned_and_authenticated() {
+    let handler = EncryptionHandler::new(b"test_password").unwrap();
+    let token = handler.encrypt(&proxy_data()).unwrap();
+
+    let mut bytes = URL_SAFE.decode(&token).unwrap();
+    assert_eq!(bytes[0], 1);
+
+    let last = bytes.len() - 1;
+    bytes[last] ^= 1;
+    assert!(handler.decrypt(&URL_SAFE.encode(&bytes), None).is_err());
+
+    let other = EncryptionHandler::new(b"other_password").unwrap();
+    assert!(other.decrypt(&token, None).is_err());
+}
+
+#[tokio::test]
+async fn test_key_rotation() {
+    let old_handler = EncryptionHandler::new(b"old_password").unwrap();
+    let old_token = old_handler.encrypt(&proxy_data()).unwrap();
+
+    let handler = EncryptionHandler::new(b"new_password")
+        .unwrap()
+        .with_previous_keys(&["old_password"])
+        .unwrap();
+    assert_eq!(
+        handler.decrypt(&old_token, None).unwrap().destination,
+        "https://example.com/video.mp4"
+    );
+
+    // New tokens are issued with the current password only
+    let new_token = handler.encrypt(&proxy_data()).unwrap();
+    assert!(old_handler.decrypt(&new_token, None).is_err());
+}
+
+#[tokio::test]
+async fn test_legacy_tokens() {
+    let token = legacy_token(b"test_password", &proxy_data());
+
+    let handler = EncryptionHandler::new(b"test_password").unwrap();
+    assert_eq!(
+        handler.decrypt(&token, None).unwrap().destination,
+        "https://example.com/video.mp4"
+    );
+
+    let rotated = EncryptionHandler::new(b"new_password")
+        .unwrap()
+        .with_previous_keys(&["test_password"])
+        .unwrap();
+    assert!(rotated.decrypt(&token, None).is_ok());
+
+    assert!(handler
+        .with_legacy_tokens(false)
+        .decrypt(&token, None)
+        .is_err());
+}
+
+/// Tokens from the Python MediaFlow Proxy's `EncryptionHandler.encrypt_data`, as
+/// `(api_password, json.dumps(params), iv, token)`, generated with:
+///
+/// ```python
+/// key = secret.encode("utf-8").ljust(32)[:32]
+/// padder = padding.PKCS7(128).padder()
+/// padded = padder.update(json.dumps(data).encode("utf-8")) + padder.finalize()
+/// encryptor = Cipher(algorithms.AES(key), modes.CBC(iv)).encryptor()
+/// token = base64.urlsafe_b64encode(iv + encryptor.update(padded) + encryptor.finalize())
+/// ```
+const PYTHON_VECTORS: &[(&str, &str, &str, &str)] = &[
+    (
+        "test_password",
+        r#"{"api_password": "test_password", "d": "https://example.com/video.mp4"}"#,
+        "000102030405060708090a0b0c0d0e0f",
+        "AAECAwQFBgcICQoLDA0OD4oTkCvykVJbFVylhz-Uy3pTI3ZEK00lIYbLL5NfntMue32iJUqTYWZ1Cdt6-yQ-4hhCgY6FlyoQ-Df3-z95XzcRmxj7iMGhaV2-QnIuDPOy",
+    ),
+    (
+        "a-secret-that-is-longer-than-thirty-two-bytes",
+        r#"{"api_password": "a-secret-that-is-longer-than-thirty-two-bytes", "d": "https://cdn.example.com/live/master.m3u8?token=abc&x=1", "exp": 4102444800, "h_referer": "https://example.com/", "h_user-agent": "Mozilla/5.0", "r_content-type": "application/vnd.apple.mpegurl"}"#,
+        "a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5",
+        "paWlpaWlpaWlpaWlpaWlpTVZDSYp51oF_UvR25Un9Zv7ONNGCfr45QVzC-Ozrd0TPCqk6S3qlEz-bkueNNbYj7Dmc4DsXwy76BBv5Vu5Zj-YEaUxxosoOuW7BcH_3PvdHumQVpclqvGPXurQIoN0UD6sIpOE_rnP9Vh3FRFEk0R7el7_iJIJ3XOKvLYF0B9_f9tBIv9RI6LLCy_RQ9rCJXCaWLjTpJtDE_i8rZPjKEERNFfiiVy_wlBycycVC04iCTF3NYQEjrBNIe7HbO3V1VxsmvzdIRwQdOj1z4jzQX8WDgSwef1vtJNnceVX-zihf5_lfPtYK5POs-mS1V2-y6ReEY7wjTUc1Eq9fypBq1y751CB3ItpCmX_kzmCX4g2",
+    ),
+    (
+        "päss",
+        r#"{"api_password": "päss", "d": "https://example.com/café/épisode 1.mkv", "ip": "203.0.113.7"}"#,
+        "30313233343536373839616263646566",
+        "MDEyMzQ1Njc4OWFiY2RlZs4HwowFE3BTeYSgKsoLRK3j9GNLoxs7LlrwPmvtAXb9ndmoFE57m1wjHU0Q4CrkD3XQ472ckm8dcG1i89crOSHoH4YaGkeA3Mzx7JhFsadifB1mgO-OhwU3ZX_MCRkw7i8Eh8a0b_Dc8Fozfbq3Gek=",
+    ),
+];
+
+fn hex_iv(hex: &str) -> [u8; 16] {
+    let mut iv = [0u8; 16];
+    for (i, byte) in iv.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    iv
+}
+
+#[tokio::test]
+async fn test_python_token_vectors() {
+    for (password, json, iv, token) in PYTHON_VECTORS {
+        let codec = PythonTokenCodec::new(password.as_bytes());
+        let params: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(json).unwrap();
+
+        assert_eq!(codec.decode(token).unwrap(), params);
+        assert_eq!(&codec.encode_with_iv(&params, hex_iv(iv)).unwrap(), token);
+        assert_eq!(
+            codec.decode(&codec.encode(&params).unwrap()).unwrap(),
+            params
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_python_tokens_are_accepted_when_enabled() {
+    let (password, _, _, token) = PYTHON_VECTORS[1];
+
+    let handler = EncryptionHandler::new(password.as_bytes()).unwrap();
+    assert!(handler.decrypt(token, None).is_err());
+
+    let proxy_data = handler
+        .with_python_tokens(true)
+        .decrypt(token, None)
+        .unwrap();
+    assert_eq!(
+        proxy_data.destination,
+        "https://cdn.example.com/live/master.m3u8?token=abc&x=1"
+    );
+    assert_eq!(proxy_data.exp, Some(4102444800));
+    assert_eq!(
+        proxy_data.request_headers.unwrap()["user-agent"],
+        "Mozilla/5.0"
+    );
+    assert_eq!(
+        proxy_data.response_headers.unwrap()["content-type"],
+        "application/vnd.apple.mpegurl"
+    );
+    assert_eq!(proxy_data.query_params.unwrap()["api_password"], password);
+
+    // The client IP is checked like for native tokens
+    let (password, _, _, token) = PYTHON_VECTORS[2];
+    let handler = EncryptionHandler::new(password.as_bytes())
+        .unwrap()
+        .with_python_tokens(true);
+    assert!(handler.decrypt(token, Some("203.0.113.7")).is_ok());
+    assert!(handler.decrypt(token, Some("198.51.100.1")).is_err());
+}
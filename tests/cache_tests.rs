@@ -0,0 +1,327 @@
+use actix_web::web::Bytes;
+use futures::StreamExt;
+use mediaflow_proxy_light::config::CacheConfig;
+use mediaflow_proxy_light::error::{AppError, AppResult};
+use mediaflow_proxy_light::proxy::cache::{
+    manifest_ttl, resource_ttl, CachedManifest, ManifestCache, SegmentCache,
+};
+use mediaflow_proxy_light::proxy::stream::ByteStream;
+use reqwest::header::{HeaderMap, HeaderValue, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+const LIVE_PLAYLIST: &str = "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.0,\nseg.ts\n";
+const VOD_PLAYLIST: &str =
+    "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.0,\nseg.ts\n#EXT-X-ENDLIST\n";
+
+fn cache_control(value: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_str(value).unwrap());
+    headers
+}
+
+#[test]
+fn test_manifest_ttl() {
+    let none = HeaderMap::new();
+    assert_eq!(manifest_ttl(LIVE_PLAYLIST, &none), Duration::from_secs(6));
+    assert_eq!(
+        manifest_ttl(LIVE_PLAYLIST, &cache_control("public, max-age=2")),
+        Duration::from_secs(2)
+    );
+    assert_eq!(
+        manifest_ttl(LIVE_PLAYLIST, &cache_control("no-cache")),
+        Duration::ZERO
+    );
+    assert_eq!(manifest_ttl(VOD_PLAYLIST, &none), Duration::from_secs(60));
+    assert_eq!(
+        manifest_ttl(VOD_PLAYLIST, &cache_control("max-age=3600")),
+        Duration::from_secs(3600)
+    );
+
+    let live_mpd = r#"<MPD type="dynamic" minimumUpdatePeriod="PT4S"></MPD>"#;
+    assert_eq!(manifest_ttl(live_mpd, &none), Duration::from_secs(4));
+}
+
+#[test]
+fn test_resource_ttl() {
+    assert_eq!(resource_ttl(&HeaderMap::new()), Duration::from_secs(300));
+    assert_eq!(
+        resource_ttl(&cache_control("max-age=30")),
+        Duration::from_secs(30)
+    );
+    assert_eq!(resource_ttl(&cache_control("no-store")), Duration::ZERO);
+}
+
+#[test]
+fn test_cache_key_ignores_header_order() {
+    let mut a = HeaderMap::new();
+    a.insert("referer", HeaderValue::from_static("https://a"));
+    a.insert("origin", HeaderValue::from_static("https://b"));
+    let mut b = HeaderMap::new();
+    b.insert("origin", HeaderValue::from_static("https://b"));
+    b.insert("referer", HeaderValue::from_static("https://a"));
+
+    assert_eq!(
+        ManifestCache::key("https://x/y.m3u8", &a),
+        ManifestCache::key("https://x/y.m3u8", &b)
+    );
+    assert_ne!(
+        ManifestCache::key("https://x/y.m3u8", &a),
+        ManifestCache::key("https://x/y.m3u8", &HeaderMap::new())
+    );
+}
+
+#[tokio::test]
+async fn test_concurrent_requests_share_one_fetch() {
+    let cache = Arc::new(ManifestCache::new());
+    let fetches = Arc::new(AtomicUsize::new(0));
+
+    let tasks = (0..10).map(|_| {
+        let cache = cache.clone();
+        let fetches = fetches.clone();
+        tokio::spawn(async move {
+            cache
+                .get_or_fetch("live".to_string(), || async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    Ok((
+                        CachedManifest {
+                            url: Url::parse("https://cdn.example.com/live.m3u8").unwrap(),
+                            body: LIVE_PLAYLIST.into(),
+                        },
+                        Duration::from_secs(6),
+                    ))
+                })
+                .await
+                .unwrap()
+        })
+    });
+
+    for task in tasks.collect::<Vec<_>>() {
+        assert_eq!(&*task.await.unwrap().body, LIVE_PLAYLIST);
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_uncacheable_manifest_is_refetched() {
+    let cache = ManifestCache::new();
+    let fetches = AtomicUsize::new(0);
+
+    for _ in 0..3 {
+        cache
+            .get_or_fetch("no-store".to_string(), || async {
+                fetches.fetch_add(1, Ordering::SeqCst);
+                Ok((
+                    CachedManifest {
+                        url: Url::parse("https://cdn.example.com/live.m3u8").unwrap(),
+                        body: LIVE_PLAYLIST.into(),
+                    },
+                    Duration::ZERO,
+                ))
+            })
+            .await
+            .unwrap();
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_concurrent_requests_share_uncacheable_fetch() {
+    let cache = Arc::new(ManifestCache::new());
+    let fetches = Arc::new(AtomicUsize::new(0));
+
+    let tasks = (0..10).map(|_| {
+        let cache = cache.clone();
+        let fetches = fetches.clone();
+        tokio::spawn(async move {
+            cache
+                .get_or_fetch("no-store".to_string(), || async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    Ok((
+                        CachedManifest {
+                            url: Url::parse("https://cdn.example.com/live.m3u8").unwrap(),
+                            body: LIVE_PLAYLIST.into(),
+                        },
+                        Duration::ZERO,
+                    ))
+                })
+                .await
+                .unwrap()
+        })
+    });
+
+    for task in tasks.collect::<Vec<_>>() {
+        assert_eq!(&*task.await.unwrap().body, LIVE_PLAYLIST);
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_failed_fetch_is_not_shared() {
+    let cache = Arc::new(ManifestCache::new());
+    let fetches = Arc::new(AtomicUsize::new(0));
+
+    let tasks = (0..3).map(|_| {
+        let cache = cache.clone();
+        let fetches = fetches.clone();
+        tokio::spawn(async move {
+            cache
+                .get_or_fetch("failing".to_string(), || async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    Err(AppError::Upstream("unavailable".to_string()))
+                })
+                .await
+        })
+    });
+
+    for task in tasks.collect::<Vec<_>>() {
+        assert!(task.await.unwrap().is_err());
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 3);
+}
+
+fn segment_cache(max_size: usize) -> Arc<SegmentCache> {
+    Arc::new(SegmentCache::new(CacheConfig {
+        enabled: true,
+        max_size,
+        max_entry_size: 1024,
+        ttl: 60,
+        ..Default::default()
+    }))
+}
+
+/// Upstream response whose body arrives in small chunks with a delay between them.
+fn slow_response(body: &'static [u8], content_length: Option<usize>) -> reqwest::Response {
+    let chunks = futures::stream::iter(body.chunks(4)).then(|chunk| async move {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        Ok::<_, std::io::Error>(Bytes::from_static(chunk))
+    });
+    let mut response = http::Response::builder().header(CONTENT_TYPE, "video/mp2t");
+    if let Some(length) = content_length {
+        response = response.header(CONTENT_LENGTH, length);
+    }
+    response
+        .body(reqwest::Body::wrap_stream(chunks))
+        .unwrap()
+        .into()
+}
+
+async fn collect(stream: ByteStream) -> AppResult<Vec<u8>> {
+    let chunks: Vec<_> = stream.collect().await;
+    let mut body = Vec::new();
+    for chunk in chunks {
+        body.extend_from_slice(&chunk?);
+    }
+    Ok(body)
+}
+
+const SEGMENT: &[u8] = b"segment-data-shared-between-many-viewers";
+
+#[tokio::test]
+async fn test_concurrent_segment_requests_are_coalesced() {
+    let cache = segment_cache(4096);
+    let fetches = Arc::new(AtomicUsize::new(0));
+
+    let tasks: Vec<_> = (0..8)
+        .map(|_| {
+            let cache = cache.clone();
+            let fetches = fetches.clone();
+            tokio::spawn(async move {
+                let (_, stream) = cache
+                    .fetch("segment".to_string(), || async move {
+                        fetches.fetch_add(1, Ordering::SeqCst);
+                        Ok(slow_response(SEGMENT, Some(SEGMENT.len())))
+                    })
+                    .await
+                    .unwrap();
+                collect(stream).await.unwrap()
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        assert_eq!(task.await.unwrap(), SEGMENT);
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+    // Later viewers are served from the cache
+    let (headers, stream) = cache
+        .fetch("segment".to_string(), || async {
+            panic!("segment should be cached")
+        })
+        .await
+        .unwrap();
+    assert_eq!(headers.get(CONTENT_LENGTH).unwrap(), "40");
+    assert_eq!(collect(stream).await.unwrap(), SEGMENT);
+    assert_eq!(cache.size(), SEGMENT.len());
+}
+
+#[tokio::test]
+async fn test_responses_without_length_are_not_shared() {
+    let cache = segment_cache(4096);
+    let fetches = AtomicUsize::new(0);
+
+    for _ in 0..2 {
+        let (_, stream) = cache
+            .fetch("unsized".to_string(), || async {
+                fetches.fetch_add(1, Ordering::SeqCst);
+                Ok(slow_response(SEGMENT, None))
+            })
+            .await
+            .unwrap();
+        assert_eq!(collect(stream).await.unwrap(), SEGMENT);
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    assert_eq!(cache.size(), 0);
+}
+
+#[tokio::test]
+async fn test_playlists_are_not_shared() {
+    let cache = segment_cache(4096);
+    let fetches = AtomicUsize::new(0);
+
+    for _ in 0..2 {
+        let (_, stream) = cache
+            .fetch("live.m3u8".to_string(), || async {
+                fetches.fetch_add(1, Ordering::SeqCst);
+                let response: http::Response<_> =
+                    slow_response(LIVE_PLAYLIST.as_bytes(), Some(LIVE_PLAYLIST.len())).into();
+                let (mut parts, body) = response.into_parts();
+                parts.headers.insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("application/vnd.apple.mpegurl"),
+                );
+                Ok(http::Response::from_parts(parts, body).into())
+            })
+            .await
+            .unwrap();
+        assert_eq!(collect(stream).await.unwrap(), LIVE_PLAYLIST.as_bytes());
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    assert_eq!(cache.size(), 0);
+}
+
+#[tokio::test]
+async fn test_least_recently_used_segment_is_evicted() {
+    let cache = segment_cache(SEGMENT.len() * 2);
+
+    for key in ["a", "b", "a", "c"] {
+        let (_, stream) = cache
+            .fetch(key.to_string(), || async {
+                Ok(slow_response(SEGMENT, Some(SEGMENT.len())))
+            })
+            .await
+            .unwrap();
+        collect(stream).await.unwrap();
+    }
+
+    assert!(cache.get("a").is_some());
+    assert!(cache.get("b").is_none());
+    assert!(cache.get("c").is_some());
+    assert_eq!(cache.size(), SEGMENT.len() * 2);
+}
@@ -0,0 +1,317 @@
+use actix_web::web::Bytes;
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use mediaflow_proxy_light::proxy::clearkey::{ClearKeyProcessor, ClearKeys};
+use mediaflow_proxy_light::proxy::mp4::{self, Mp4Box};
+use mediaflow_proxy_light::proxy::stream::SegmentProcessor;
+
+const KID: [u8; 16] = [0x11; 16];
+const KEY: [u8; 16] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+];
+
+fn container(kind: &[u8; 4], children: Vec<Mp4Box>) -> Mp4Box {
+    Mp4Box {
+        kind: *kind,
+        payload: Vec::new(),
+        children,
+    }
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, body: &[u8]) -> Mp4Box {
+    let mut payload = vec![version];
+    payload.extend_from_slice(&flags.to_be_bytes()[1..]);
+    payload.extend_from_slice(body);
+    Mp4Box::leaf(kind, payload)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Init segment with one `encv` track protected by `scheme`.
+fn init_segment(scheme: &[u8; 4], tenc_version: u8, tenc_body: &[u8]) -> Vec<u8> {
+    let mut tkhd = vec![0u8; 8];
+    tkhd.extend_from_slice(&1u32.to_be_bytes());
+    tkhd.extend_from_slice(&[0u8; 68]);
+
+    let mut schm = scheme.to_vec();
+    schm.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+
+    let sinf = container(
+        b"sinf",
+        vec![
+            Mp4Box::leaf(b"frma", b"avc1".to_vec()),
+            full_box(b"schm", 0, 0, &schm),
+            container(b"schi", vec![full_box(b"tenc", tenc_version, 0, tenc_body)]),
+        ],
+    );
+    let encv = Mp4Box {
+        kind: *b"encv",
+        payload: vec![0u8; 78],
+        children: vec![Mp4Box::leaf(b"avcC", vec![1, 2, 3]), sinf],
+    };
+    let mut stsd_fields = vec![0u8; 4];
+    stsd_fields.extend_from_slice(&1u32.to_be_bytes());
+    let stsd = Mp4Box {
+        kind: *b"stsd",
+        payload: stsd_fields,
+        children: vec![encv],
+    };
+
+    let trak = container(
+        b"trak",
+        vec![
+            full_box(b"tkhd", 0, 3, &tkhd),
+            container(
+                b"mdia",
+                vec![container(b"minf", vec![container(b"stbl", vec![stsd])])],
+            ),
+        ],
+    );
+    let moov = container(b"moov", vec![trak, full_box(b"pssh", 0, 0, &[0u8; 20])]);
+
+    mp4::write_boxes(&[moov])
+}
+
+/// Per-sample IV and (clear, protected) subsample sizes.
+type SencEntry = (Vec<u8>, Vec<(u16, u32)>);
+
+/// Media segment with the given samples and per-sample `senc` entries.
+fn media_segment(samples: &[Vec<u8>], senc_entries: &[SencEntry]) -> Vec<u8> {
+    let mut tfhd = Vec::new();
+    tfhd.extend_from_slice(&1u32.to_be_bytes());
+
+    let mut trun = Vec::new();
+    trun.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+    trun.extend_from_slice(&0i32.to_be_bytes()); // patched below
+    for sample in samples {
+        trun.extend_from_slice(&(sample.len() as u32).to_be_bytes());
+    }
+
+    let mut senc = Vec::new();
+    senc.extend_from_slice(&(senc_entries.len() as u32).to_be_bytes());
+    for (iv, subsamples) in senc_entries {
+        senc.extend_from_slice(iv);
+        senc.extend_from_slice(&(subsamples.len() as u16).to_be_bytes());
+        for (clear, protected) in subsamples {
+            senc.extend_from_slice(&clear.to_be_bytes());
+            senc.extend_from_slice(&protected.to_be_bytes());
+        }
+    }
+
+    let build_moof = |data_offset: i32| {
+        let mut trun = trun.clone();
+        trun[4..8].copy_from_slice(&data_offset.to_be_bytes());
+        container(
+            b"moof",
+            vec![
+                full_box(b"mfhd", 0, 0, &1u32.to_be_bytes()),
+                container(
+                    b"traf",
+                    vec![
+                        full_box(b"tfhd", 0, 0x020000, &tfhd),
+                        full_box(b"trun", 0, 0x000201, &trun),
+                        full_box(b"senc", 0, 0x02, &senc),
+                    ],
+                ),
+            ],
+        )
+    };
+
+    let moof_size = build_moof(0).size() as i32;
+    let mdat = Mp4Box::leaf(b"mdat", samples.concat());
+    mp4::write_boxes(&[build_moof(moof_size + 8), mdat])
+}
+
+fn ctr_encrypt(iv: &[u8], data: &mut [u8]) {
+    let cipher = Aes128::new(GenericArray::from_slice(&KEY));
+    let mut counter_bytes = [0u8; 16];
+    counter_bytes[..iv.len()].copy_from_slice(iv);
+    let counter = u128::from_be_bytes(counter_bytes);
+    for (counter, chunk) in (counter..).zip(data.chunks_mut(16)) {
+        let mut block = GenericArray::from(counter.to_be_bytes());
+        cipher.encrypt_block(&mut block);
+        for (b, k) in chunk.iter_mut().zip(block.iter()) {
+            *b ^= k;
+        }
+    }
+}
+
+fn cbc_pattern_encrypt(iv: &[u8; 16], data: &mut [u8], crypt: usize, skip: usize) {
+    let cipher = Aes128::new(GenericArray::from_slice(&KEY));
+    let blocks = data.len() / 16;
+    let mut chain = *iv;
+    let mut index = 0;
+    while index < blocks {
+        for _ in 0..crypt {
+            if index >= blocks {
+                break;
+            }
+            let block = &mut data[index * 16..(index + 1) * 16];
+            for (b, c) in block.iter_mut().zip(chain.iter()) {
+                *b ^= c;
+            }
+            cipher.encrypt_block(GenericArray::from_mut_slice(block));
+            chain.copy_from_slice(block);
+            index += 1;
+        }
+        index += skip;
+    }
+}
+
+fn mdat_payload(segment: &[u8]) -> Vec<u8> {
+    let boxes = mp4::parse_boxes(segment).unwrap();
+    boxes
+        .iter()
+        .find(|b| b.is(b"mdat"))
+        .unwrap()
+        .payload
+        .clone()
+}
+
+fn assert_clear_fragment(output: &[u8], expected_samples: &[Vec<u8>]) {
+    let boxes = mp4::parse_boxes(output).unwrap();
+    let moof = boxes.iter().find(|b| b.is(b"moof")).unwrap();
+    let traf = moof.child(b"traf").unwrap();
+    assert!(traf.child(b"senc").is_none());
+
+    // The trun data offset must still point at the first mdat payload byte
+    let trun = traf.child(b"trun").unwrap();
+    let data_offset = i32::from_be_bytes(trun.payload[8..12].try_into().unwrap());
+    assert_eq!(data_offset as u64, moof.size() + 8);
+
+    assert_eq!(mdat_payload(output), expected_samples.concat());
+}
+
+#[test]
+fn test_parse_keys() {
+    assert!(ClearKeys::parse(Some(&hex(&KID)), &hex(&KEY)).is_ok());
+    assert!(ClearKeys::parse(None, "abcd").is_err());
+    assert!(ClearKeys::parse(Some(&format!("{},{}", hex(&KID), hex(&KID))), &hex(&KEY)).is_err());
+}
+
+#[test]
+fn test_cenc_decryption() {
+    let mut tenc = vec![0, 0, 1, 8];
+    tenc.extend_from_slice(&KID);
+    let init = init_segment(b"cenc", 0, &tenc);
+
+    let clear_samples = vec![(0u8..100).collect::<Vec<u8>>(), (100u8..160).collect()];
+    let iv1 = vec![1, 2, 3, 4, 5, 6, 7, 8];
+    let iv2 = vec![8, 7, 6, 5, 4, 3, 2, 1];
+
+    // Sample 1: 10 clear bytes then 90 protected; sample 2 fully protected via two subsamples
+    let mut encrypted = clear_samples.clone();
+    ctr_encrypt(&iv1, &mut encrypted[0][10..]);
+    let mut protected: Vec<u8> = encrypted[1][5..25]
+        .iter()
+        .chain(encrypted[1][30..].iter())
+        .copied()
+        .collect();
+    ctr_encrypt(&iv2, &mut protected);
+    encrypted[1][5..25].copy_from_slice(&protected[..20]);
+    encrypted[1][30..].copy_from_slice(&protected[20..]);
+
+    let segment = media_segment(
+        &encrypted,
+        &[(iv1, vec![(10, 90)]), (iv2, vec![(5, 20), (5, 30)])],
+    );
+
+    let keys = ClearKeys::parse(Some(&hex(&KID)), &hex(&KEY)).unwrap();
+    let processor = ClearKeyProcessor::new(keys, Some(&init)).unwrap();
+    let output = processor.process(Bytes::from(segment)).unwrap();
+
+    assert_clear_fragment(&output, &clear_samples);
+}
+
+#[test]
+fn test_cbcs_decryption() {
+    let constant_iv = [0x42u8; 16];
+    // tenc v1: crypt 1, skip 9, per-sample IV size 0 with a constant IV
+    let mut tenc = vec![0, 0x19, 1, 0];
+    tenc.extend_from_slice(&KID);
+    tenc.push(16);
+    tenc.extend_from_slice(&constant_iv);
+    let init = init_segment(b"cbcs", 1, &tenc);
+
+    let clear_sample: Vec<u8> = (0..400u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut encrypted = clear_sample.clone();
+    // 32 clear header bytes, then pattern encryption over 368 protected bytes (partial block stays clear)
+    cbc_pattern_encrypt(&constant_iv, &mut encrypted[32..], 1, 9);
+
+    let segment = media_segment(
+        std::slice::from_ref(&encrypted),
+        &[(Vec::new(), vec![(32, 368)])],
+    );
+
+    let keys = ClearKeys::parse(None, &hex(&KEY)).unwrap();
+    let processor = ClearKeyProcessor::new(keys, Some(&init)).unwrap();
+    let output = processor.process(Bytes::from(segment)).unwrap();
+
+    assert_clear_fragment(&output, &[clear_sample]);
+}
+
+#[test]
+fn test_init_segment_is_cleaned() {
+    let mut tenc = vec![0, 0, 1, 8];
+    tenc.extend_from_slice(&KID);
+    let init = init_segment(b"cenc", 0, &tenc);
+
+    let keys = ClearKeys::parse(None, &hex(&KEY)).unwrap();
+    let processor = ClearKeyProcessor::new(keys, None).unwrap();
+    let output = processor.process(Bytes::from(init)).unwrap();
+
+    let boxes = mp4::parse_boxes(&output).unwrap();
+    let moov = &boxes[0];
+    assert!(moov.child(b"pssh").is_none());
+
+    let stsd = moov
+        .find(&[b"trak", b"mdia", b"minf", b"stbl", b"stsd"])
+        .unwrap();
+    let raw = mp4::write_boxes(std::slice::from_ref(stsd));
+    assert!(raw.windows(4).any(|w| w == b"avc1"));
+    assert!(!raw.windows(4).any(|w| w == b"sinf" || w == b"encv"));
+}
+
+#[test]
+fn test_missing_key_is_rejected() {
+    let mut tenc = vec![0, 0, 1, 8];
+    tenc.extend_from_slice(&KID);
+    let init = init_segment(b"cenc", 0, &tenc);
+    let segment = media_segment(&[vec![0u8; 32]], &[(vec![0u8; 8], vec![(0, 32)])]);
+
+    let keys = ClearKeys::parse(Some(&hex(&[0x22; 16])), &hex(&KEY)).unwrap();
+    let processor = ClearKeyProcessor::new(keys, Some(&init)).unwrap();
+    assert!(processor.process(Bytes::from(segment)).is_err());
+}
+
+#[test]
+fn test_oversized_boxes_are_rejected() {
+    let mut largesize = vec![0, 0, 0, 1];
+    largesize.extend_from_slice(b"mdat");
+    largesize.extend_from_slice(&u64::MAX.to_be_bytes());
+    assert!(mp4::scan_boxes(&largesize).is_err());
+
+    let mut past_end = 64u32.to_be_bytes().to_vec();
+    past_end.extend_from_slice(b"free");
+    assert!(mp4::scan_boxes(&past_end).is_err());
+}
+
+#[test]
+fn test_negative_data_offset_is_rejected() {
+    let mut tenc = vec![0, 0, 1, 8];
+    tenc.extend_from_slice(&KID);
+    let init = init_segment(b"cenc", 0, &tenc);
+    let mut segment = media_segment(&[vec![0u8; 32]], &[(vec![0u8; 8], vec![(0, 32)])]);
+
+    // Point the samples far before the start of the segment
+    let trun = segment.windows(4).position(|w| w == b"trun").unwrap();
+    segment[trun + 12..trun + 16].copy_from_slice(&i32::MIN.to_be_bytes());
+
+    let keys = ClearKeys::parse(None, &hex(&KEY)).unwrap();
+    let processor = ClearKeyProcessor::new(keys, Some(&init)).unwrap();
+    assert!(processor.process(Bytes::from(segment)).is_err());
+}
@@ -0,0 +1,112 @@
+use mediaflow_proxy_light::proxy::conditional::{self, evaluate};
+use reqwest::header::{
+    HeaderMap, HeaderValue, CONTENT_LENGTH, ETAG, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+    LAST_MODIFIED,
+};
+use reqwest::StatusCode;
+
+fn headers(pairs: &[(reqwest::header::HeaderName, &'static str)]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+        headers.insert(name, HeaderValue::from_static(value));
+    }
+    headers
+}
+
+#[test]
+fn test_if_none_match() {
+    let response = headers(&[(ETAG, "W/\"v2\"")]);
+
+    assert_eq!(
+        evaluate(&headers(&[(IF_NONE_MATCH, "\"v1\", \"v2\"")]), &response),
+        Some(StatusCode::NOT_MODIFIED)
+    );
+    assert_eq!(
+        evaluate(&headers(&[(IF_NONE_MATCH, "*")]), &response),
+        Some(StatusCode::NOT_MODIFIED)
+    );
+    assert_eq!(
+        evaluate(&headers(&[(IF_NONE_MATCH, "\"v1\"")]), &response),
+        None
+    );
+    assert_eq!(evaluate(&HeaderMap::new(), &response), None);
+}
+
+#[test]
+fn test_if_modified_since() {
+    let response = headers(&[
+        (ETAG, "\"v1\""),
+        (LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT"),
+    ]);
+
+    assert_eq!(
+        evaluate(
+            &headers(&[(IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT")]),
+            &response
+        ),
+        Some(StatusCode::NOT_MODIFIED)
+    );
+    assert_eq!(
+        evaluate(
+            &headers(&[(IF_MODIFIED_SINCE, "Tue, 20 Oct 2015 07:28:00 GMT")]),
+            &response
+        ),
+        None
+    );
+    // If-None-Match takes precedence over the date
+    assert_eq!(
+        evaluate(
+            &headers(&[
+                (IF_NONE_MATCH, "\"v0\""),
+                (IF_MODIFIED_SINCE, "Thu, 22 Oct 2015 07:28:00 GMT"),
+            ]),
+            &response
+        ),
+        None
+    );
+}
+
+#[test]
+fn test_if_match_uses_strong_comparison() {
+    assert_eq!(
+        evaluate(
+            &headers(&[(IF_MATCH, "\"v1\"")]),
+            &headers(&[(ETAG, "\"v1\"")])
+        ),
+        None
+    );
+    assert_eq!(
+        evaluate(
+            &headers(&[(IF_MATCH, "\"v1\"")]),
+            &headers(&[(ETAG, "W/\"v1\"")])
+        ),
+        Some(StatusCode::PRECONDITION_FAILED)
+    );
+    assert_eq!(
+        evaluate(&headers(&[(IF_MATCH, "*")]), &HeaderMap::new()),
+        Some(StatusCode::PRECONDITION_FAILED)
+    );
+}
+
+#[test]
+fn test_validators_and_unconditional() {
+    let response = headers(&[(ETAG, "\"v1\""), (CONTENT_LENGTH, "10")]);
+    let validators = conditional::validators(&response);
+    assert_eq!(validators.get(ETAG).unwrap(), "\"v1\"");
+    assert!(validators.get(CONTENT_LENGTH).is_none());
+
+    let request = headers(&[(IF_NONE_MATCH, "\"v1\""), (IF_MATCH, "\"v1\"")]);
+    assert!(conditional::is_conditional(&request));
+    assert!(!conditional::is_conditional(&conditional::unconditional(
+        &request
+    )));
+
+    assert_eq!(
+        conditional::etag_for("#EXTM3U"),
+        conditional::etag_for("#EXTM3U")
+    );
+    assert_ne!(
+        conditional::etag_for("#EXTM3U"),
+        conditional::etag_for("#EXTM3U\n")
+    );
+}
@@ -1,17 +1,23 @@
 use mediaflow_proxy_light::config::Config;
+use std::sync::{Mutex, MutexGuard, PoisonError};
 use std::{env, fs};
 
-fn setup() {
+/// Serializes the tests, which share the process environment.
+static ENV: Mutex<()> = Mutex::new(());
+
+fn setup() -> MutexGuard<'static, ()> {
+    let guard = ENV.lock().unwrap_or_else(PoisonError::into_inner);
     env::remove_var("APP__SERVER__HOST");
     env::remove_var("APP__SERVER__PORT");
     env::remove_var("APP__AUTH__API_PASSWORD");
     env::remove_var("APP__PROXY__BUFFER_SIZE");
     env::remove_var("APP__PROXY__TRANSPORT_ROUTES");
+    guard
 }
 
 #[test]
 fn test_config_from_env() {
-    setup();
+    let _env = setup();
 
     // Set environment variables
     env::set_var("APP__SERVER__HOST", "127.0.0.1");
@@ -30,7 +36,7 @@ fn test_config_from_env() {
 
 #[test]
 fn test_transport_routes_config() {
-    setup();
+    let _env = setup();
 
     // Modify the JSON string to be a single line with escaped quotes
     let routes_json = r#"{"all://*.streaming.com":{"proxy":true,"proxy_url":"socks5://test-proxy:1080","verify_ssl":true}}"#;
@@ -67,9 +73,25 @@ fn test_transport_routes_config() {
     assert!(route.verify_ssl, "SSL verification should be enabled");
 }
 
+#[test]
+fn test_transport_route_prefetch_from_env() {
+    let _env = setup();
+
+    env::set_var(
+        "APP__PROXY__TRANSPORT_ROUTES",
+        r#"{"all://live.example.com":{"prefetch":3},"all://vod.example.com":{"proxy":true}}"#,
+    );
+    let config = Config::from_env().unwrap();
+    env::remove_var("APP__PROXY__TRANSPORT_ROUTES");
+
+    let routes = config.proxy.transport_routes;
+    assert_eq!(routes["all://live.example.com"].prefetch, 3);
+    assert_eq!(routes["all://vod.example.com"].prefetch, 0);
+}
+
 #[test]
 fn test_transport_routes_from_toml() {
-    setup();
+    let _env = setup();
 
     let config_content = r#"
 [server]
@@ -125,3 +147,23 @@ verify_ssl = true
     );
     assert!(route.verify_ssl, "SSL verification should be enabled");
 }
+
+#[test]
+fn test_previous_passwords_from_env() {
+    let _env = setup();
+    env::set_var(
+        "APP__AUTH__PREVIOUS_PASSWORDS",
+        "old_password, older_password",
+    );
+    env::set_var("APP__AUTH__LEGACY_TOKENS", "false");
+
+    let config = Config::from_env().unwrap();
+    env::remove_var("APP__AUTH__PREVIOUS_PASSWORDS");
+    env::remove_var("APP__AUTH__LEGACY_TOKENS");
+
+    assert_eq!(
+        config.auth.previous_passwords,
+        vec!["old_password".to_string(), "older_password".to_string()]
+    );
+    assert!(!config.auth.legacy_tokens);
+}
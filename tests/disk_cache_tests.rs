@@ -0,0 +1,227 @@
+use actix_web::web::Bytes;
+use futures::StreamExt;
+use mediaflow_proxy_light::config::{DiskCacheConfig, EvictionPolicy};
+use mediaflow_proxy_light::proxy::disk_cache::{DiskCache, RequestedRange};
+use mediaflow_proxy_light::proxy::stream::ByteStream;
+use reqwest::header::{
+    HeaderMap, HeaderValue, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, ETAG, EXPIRES,
+};
+use reqwest::StatusCode;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+const FILE: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+fn open(path: &Path, max_size: u64) -> Arc<DiskCache> {
+    Arc::new(
+        DiskCache::open(DiskCacheConfig {
+            path: path.to_path_buf(),
+            max_size,
+            eviction: EvictionPolicy::Lru,
+            ttl: 3600,
+        })
+        .unwrap(),
+    )
+}
+
+fn partial_headers(start: usize, end: usize, etag: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(end - start));
+    headers.insert(
+        CONTENT_RANGE,
+        HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end - 1, FILE.len())).unwrap(),
+    );
+    headers.insert(ETAG, HeaderValue::from_str(etag).unwrap());
+    headers
+}
+
+/// Pass `FILE[start..end]` through the cache as a 206 response and consume it like a client.
+async fn fetch_range(cache: &Arc<DiskCache>, key: &str, start: usize, end: usize, etag: &str) {
+    fetch_range_with(cache, key, start, end, partial_headers(start, end, etag)).await
+}
+
+async fn fetch_range_with(
+    cache: &Arc<DiskCache>,
+    key: &str,
+    start: usize,
+    end: usize,
+    headers: HeaderMap,
+) {
+    let stream: ByteStream = Box::pin(futures::stream::iter(
+        FILE[start..end]
+            .chunks(5)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>(),
+    ));
+    let stream = cache.tee(
+        key.to_string(),
+        StatusCode::PARTIAL_CONTENT,
+        &headers,
+        stream,
+    );
+    stream.for_each(|_| async {}).await;
+}
+
+/// Wait for the background writer to record its range.
+async fn wait_for_size(cache: &DiskCache, size: u64) {
+    for _ in 0..200 {
+        if cache.size() == size {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    panic!(
+        "disk cache size stayed at {}, expected {}",
+        cache.size(),
+        size
+    );
+}
+
+async fn read(cache: &DiskCache, key: &str, range: &str) -> Option<(HeaderMap, Vec<u8>)> {
+    let range = HeaderValue::from_str(range).unwrap();
+    let (_, headers, stream) = cache.read(key, Some(&range), None).await?;
+    let chunks: Vec<_> = stream.collect().await;
+    let body = chunks
+        .into_iter()
+        .flat_map(|chunk| chunk.unwrap().to_vec())
+        .collect();
+    Some((headers, body))
+}
+
+#[test]
+fn test_requested_range() {
+    assert_eq!(
+        RequestedRange::parse("bytes=10-19"),
+        Some(RequestedRange::From(10, Some(19)))
+    );
+    assert_eq!(
+        RequestedRange::parse("bytes=10-"),
+        Some(RequestedRange::From(10, None))
+    );
+    assert_eq!(
+        RequestedRange::parse("bytes=-5"),
+        Some(RequestedRange::Suffix(5))
+    );
+    assert_eq!(RequestedRange::parse("bytes=0-1,5-6"), None);
+    assert_eq!(RequestedRange::parse("bytes=9-1"), None);
+
+    assert_eq!(
+        RequestedRange::From(10, Some(100)).resolve(36),
+        Some((10, 36))
+    );
+    assert_eq!(RequestedRange::Suffix(6).resolve(36), Some((30, 36)));
+    assert_eq!(RequestedRange::From(36, None).resolve(36), None);
+}
+
+#[tokio::test]
+async fn test_partial_ranges_are_stitched_together() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = open(dir.path(), 1024);
+
+    fetch_range(&cache, "movie", 0, 20, "\"v1\"").await;
+    wait_for_size(&cache, 20).await;
+    assert!(read(&cache, "movie", "bytes=10-29").await.is_none());
+
+    fetch_range(&cache, "movie", 15, 36, "\"v1\"").await;
+    wait_for_size(&cache, 36).await;
+
+    let (headers, body) = read(&cache, "movie", "bytes=10-29").await.unwrap();
+    assert_eq!(body, &FILE[10..30]);
+    assert_eq!(headers.get(CONTENT_RANGE).unwrap(), "bytes 10-29/36");
+    assert_eq!(headers.get(CONTENT_LENGTH).unwrap(), "20");
+    assert_eq!(headers.get(ETAG).unwrap(), "\"v1\"");
+
+    let (_, body) = read(&cache, "movie", "bytes=-6").await.unwrap();
+    assert_eq!(body, &FILE[30..]);
+
+    // Entries survive a restart
+    drop(cache);
+    let cache = open(dir.path(), 1024);
+    assert_eq!(cache.size(), 36);
+    let (_, body) = read(&cache, "movie", "bytes=0-").await.unwrap();
+    assert_eq!(body, FILE);
+}
+
+#[tokio::test]
+async fn test_changed_entity_resets_entry() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = open(dir.path(), 1024);
+
+    fetch_range(&cache, "movie", 0, 20, "\"v1\"").await;
+    wait_for_size(&cache, 20).await;
+
+    fetch_range(&cache, "movie", 20, 36, "\"v2\"").await;
+    wait_for_size(&cache, 16).await;
+    assert!(read(&cache, "movie", "bytes=0-9").await.is_none());
+    assert!(read(&cache, "movie", "bytes=20-35").await.is_some());
+}
+
+#[tokio::test]
+async fn test_least_recently_used_entry_is_evicted() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = open(dir.path(), 50);
+
+    fetch_range(&cache, "first", 0, 20, "\"a\"").await;
+    wait_for_size(&cache, 20).await;
+    fetch_range(&cache, "second", 0, 20, "\"b\"").await;
+    wait_for_size(&cache, 40).await;
+
+    fetch_range(&cache, "third", 0, 20, "\"c\"").await;
+    for _ in 0..200 {
+        if read(&cache, "third", "bytes=0-19").await.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    assert_eq!(cache.size(), 40);
+    assert!(read(&cache, "first", "bytes=0-19").await.is_none());
+    assert!(read(&cache, "second", "bytes=0-19").await.is_some());
+}
+
+#[tokio::test]
+async fn test_expired_entry_is_a_miss() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = open(dir.path(), 1024);
+
+    let mut headers = partial_headers(0, 36, "\"v1\"");
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=1"));
+    fetch_range_with(&cache, "movie", 0, 36, headers).await;
+    wait_for_size(&cache, 36).await;
+    assert!(read(&cache, "movie", "bytes=0-9").await.is_some());
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert!(read(&cache, "movie", "bytes=0-9").await.is_none());
+
+    // Expired entries do not come back after a restart
+    drop(cache);
+    let cache = open(dir.path(), 1024);
+    assert_eq!(cache.size(), 0);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn test_stale_responses_are_not_stored() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = open(dir.path(), 1024);
+
+    let mut headers = partial_headers(0, 36, "\"v1\"");
+    headers.insert(EXPIRES, HeaderValue::from_static("0"));
+    fetch_range_with(&cache, "movie", 0, 36, headers).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(cache.size(), 0);
+    assert!(read(&cache, "movie", "bytes=0-9").await.is_none());
+}
+
+#[tokio::test]
+async fn test_entries_are_named_by_key_digest() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = open(dir.path(), 1024);
+
+    fetch_range(&cache, "movie", 0, 36, "\"v1\"").await;
+    wait_for_size(&cache, 36).await;
+
+    // SHA-256 of "movie", the same in every build
+    let digest = "8a6ba32c9bed6ce703f999f9af6ec23686d44e144e4da572d94c8daca4a9cbab";
+    assert!(dir.path().join(format!("{}.meta", digest)).exists());
+}
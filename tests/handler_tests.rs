@@ -0,0 +1,526 @@
+use actix_web::{web, App};
+use mediaflow_proxy_light::auth::encryption::{EncryptionHandler, ProxyData};
+use mediaflow_proxy_light::auth::middleware::AuthMiddleware;
+use mediaflow_proxy_light::config::{ApiKeyConfig, AuthConfig, CacheConfig, ProxyConfig};
+use mediaflow_proxy_light::proxy::handler;
+use mediaflow_proxy_light::proxy::stream::StreamManager;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const MEDIA_PLAYLIST: &str = "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:1\n\
+#EXTINF:6.0,\nseg-1.ts\n#EXTINF:6.0,\nseg-2.ts\n";
+
+const ENCRYPTED_PLAYLIST: &str = "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:1\n\
+#EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\"\n#EXTINF:6.0,\nseg-1.ts\n";
+
+const MASTER_PLAYLIST: &str = "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1000000\nlive.m3u8\n";
+
+const PROTECTED_MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT8S">
+  <Period>
+    <AdaptationSet mimeType="video/mp4">
+      <SegmentTemplate timescale="1" duration="4" startNumber="1"
+        initialization="init.mp4" media="seg-$Number$.m4s"/>
+      <Representation id="v1" bandwidth="1000000" codecs="avc1.64001f"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+const CLEAR_KEY: &str = "00112233445566778899aabbccddeeff";
+
+/// A local host the upstream is not reachable as, for keys restricted to `127.0.0.1`.
+const FORBIDDEN_URL: &str = "http://localhost:1/key.bin";
+
+/// Serve `(path, content type, body)` resources, closing the connection after each response.
+async fn serve(resources: Vec<(&'static str, &'static str, Vec<u8>)>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let resources = Arc::new(resources);
+
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let resources = resources.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = vec![0u8; 4096];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match socket.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_string();
+                let path = request.split(' ').nth(1).unwrap_or_default();
+                let response = match resources.iter().find(|(p, _, _)| *p == path) {
+                    Some((_, content_type, body)) => {
+                        let mut response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            content_type,
+                            body.len()
+                        )
+                        .into_bytes();
+                        response.extend_from_slice(body);
+                        response
+                    }
+                    None => {
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                            .to_vec()
+                    }
+                };
+                let _ = socket.write_all(&response).await;
+            });
+        }
+    });
+
+    format!("http://{}", address)
+}
+
+fn stream_manager() -> StreamManager {
+    StreamManager::new(
+        ProxyConfig {
+            connect_timeout: 5,
+            buffer_size: 8192,
+            follow_redirects: true,
+            proxy_url: None,
+            all_proxy: false,
+            transport_routes: HashMap::new(),
+            manifest_cache: false,
+            pool_max_idle_per_host: 4,
+            pool_idle_timeout: 30,
+            head_fallback: true,
+            resume_retries: 0,
+            resume_backoff_ms: 0,
+        },
+        CacheConfig {
+            enabled: false,
+            ..Default::default()
+        },
+    )
+}
+
+fn auth_config() -> AuthConfig {
+    AuthConfig {
+        api_password: "secret".to_string(),
+        previous_passwords: Vec::new(),
+        legacy_tokens: false,
+        python_tokens: false,
+        keys: Vec::new(),
+    }
+}
+
+/// Only allowed to proxy `127.0.0.1`, where the mock upstream listens.
+fn restricted_auth_config() -> AuthConfig {
+    AuthConfig {
+        keys: vec![ApiKeyConfig {
+            label: "cdn".to_string(),
+            key: "cdn-secret".to_string(),
+            endpoints: Vec::new(),
+            hosts: vec!["127.0.0.1".to_string()],
+            expires_at: None,
+            max_streams: None,
+            max_bandwidth: None,
+        }],
+        ..auth_config()
+    }
+}
+
+fn token(destination: &str, query_params: serde_json::Value) -> String {
+    let mut params = json!({ "api_password": "secret" });
+    params
+        .as_object_mut()
+        .unwrap()
+        .extend(query_params.as_object().unwrap().clone());
+    EncryptionHandler::new(b"secret")
+        .unwrap()
+        .encrypt(&ProxyData {
+            destination: destination.to_string(),
+            query_params: Some(params),
+            request_headers: None,
+            response_headers: None,
+            exp: None,
+            ip: None,
+            mirrors: Vec::new(),
+        })
+        .unwrap()
+}
+
+macro_rules! app {
+    ($auth:expr) => {
+        app!($auth, web::Data::new(stream_manager()))
+    };
+    ($auth:expr, $stream_manager:expr) => {
+        actix_web::test::init_service(
+            App::new()
+                .wrap(AuthMiddleware::new(&$auth))
+                .app_data($stream_manager)
+                .route(
+                    "/proxy/hls/manifest.m3u8",
+                    web::get().to(handler::proxy_hls_manifest),
+                )
+                .route(
+                    "/proxy/hls/segment",
+                    web::get().to(handler::proxy_hls_segment),
+                )
+                .route(
+                    "/proxy/mpd/playlist.m3u8",
+                    web::get().to(handler::proxy_mpd_playlist),
+                )
+                .route(
+                    "/proxy/mpd/segment",
+                    web::get().to(handler::proxy_mpd_segment),
+                ),
+        )
+        .await
+    };
+}
+
+#[actix_web::test]
+async fn test_playlist_etag_survives_fresh_tokens() {
+    let upstream = serve(vec![(
+        "/live.m3u8",
+        "application/vnd.apple.mpegurl",
+        MEDIA_PLAYLIST.as_bytes().to_vec(),
+    )])
+    .await;
+    let app = app!(auth_config());
+    let uri = format!(
+        "/proxy/hls/manifest.m3u8?token={}",
+        token(&format!("{}/live.m3u8", upstream), json!({}))
+    );
+
+    let mut etags = Vec::new();
+    let mut bodies = Vec::new();
+    for _ in 0..2 {
+        let request = actix_web::test::TestRequest::get().uri(&uri).to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), 200);
+        etags.push(response.headers().get("etag").unwrap().clone());
+        bodies.push(actix_web::test::read_body(response).await);
+    }
+    // The segment URLs carry new tokens, the ETag stays the same
+    assert_ne!(bodies[0], bodies[1]);
+    assert_eq!(etags[0], etags[1]);
+
+    let request = actix_web::test::TestRequest::get()
+        .uri(&uri)
+        .insert_header(("if-none-match", etags[0].to_str().unwrap()))
+        .to_request();
+    let response = actix_web::test::call_service(&app, request).await;
+    assert_eq!(response.status(), 304);
+}
+
+#[actix_web::test]
+async fn test_key_urls_travel_inside_tokens() {
+    let upstream = serve(vec![(
+        "/live.m3u8",
+        "application/vnd.apple.mpegurl",
+        ENCRYPTED_PLAYLIST.as_bytes().to_vec(),
+    )])
+    .await;
+    let app = app!(auth_config());
+
+    let uri = format!(
+        "/proxy/hls/manifest.m3u8?token={}",
+        token(
+            &format!("{}/live.m3u8", upstream),
+            json!({ "decrypt": "1" })
+        )
+    );
+    let request = actix_web::test::TestRequest::get().uri(&uri).to_request();
+    let body = actix_web::test::call_and_read_body(&app, request).await;
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("/proxy/hls/segment?token="), "{}", body);
+    assert!(!body.contains("key_url="), "{}", body);
+
+    let uri = format!(
+        "/proxy/hls/manifest.m3u8?api_password=secret&decrypt=1&d={}",
+        urlencoding::encode(&format!("{}/live.m3u8", upstream))
+    );
+    let request = actix_web::test::TestRequest::get().uri(&uri).to_request();
+    let body = actix_web::test::call_and_read_body(&app, request).await;
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        body.contains(&format!(
+            "key_url={}",
+            urlencoding::encode(&format!("{}/key.bin", upstream))
+        )),
+        "{}",
+        body
+    );
+}
+
+#[actix_web::test]
+async fn test_fetch_urls_are_authorized() {
+    let upstream = serve(Vec::new()).await;
+    let app = app!(restricted_auth_config());
+    let segment = format!("{}/seg-1.ts", upstream);
+
+    let uris = [
+        format!(
+            "/proxy/hls/segment?iv=00&token={}",
+            token(
+                &segment,
+                json!({ "api_password": "cdn-secret", "key_url": FORBIDDEN_URL })
+            )
+        ),
+        format!(
+            "/proxy/hls/segment?api_password=cdn-secret&iv=00&d={}&key_url={}",
+            urlencoding::encode(&segment),
+            urlencoding::encode(FORBIDDEN_URL)
+        ),
+        format!(
+            "/proxy/mpd/segment?api_password=cdn-secret&key=00&d={}&init_url={}",
+            urlencoding::encode(&segment),
+            urlencoding::encode(FORBIDDEN_URL)
+        ),
+    ];
+    for uri in uris {
+        let request = actix_web::test::TestRequest::get().uri(&uri).to_request();
+        let error = actix_web::test::try_call_service(&app, request)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.error_response().status(), 403, "{}", uri);
+    }
+
+    // A key URL next to a token is not the token's to vouch for
+    let uri = format!(
+        "/proxy/hls/segment?iv=00&key_url={}&token={}",
+        urlencoding::encode(FORBIDDEN_URL),
+        token(&segment, json!({ "api_password": "cdn-secret" }))
+    );
+    let request = actix_web::test::TestRequest::get().uri(&uri).to_request();
+    let response = actix_web::test::call_service(&app, request).await;
+    assert_eq!(response.status(), 502);
+    let body = actix_web::test::read_body(response).await;
+    assert!(String::from_utf8_lossy(&body).contains("Missing segment key"));
+}
+
+/// The proxy data inside the token of a rewritten URL.
+fn token_data(url: &str) -> ProxyData {
+    let query = url.split_once('?').unwrap().1;
+    let token = url::form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == "token")
+        .unwrap()
+        .1;
+    EncryptionHandler::new(b"secret")
+        .unwrap()
+        .decrypt(&token, None)
+        .unwrap()
+}
+
+#[actix_web::test]
+async fn test_keys_next_to_tokens_reach_segments() {
+    let upstream = serve(vec![(
+        "/manifest.mpd",
+        "application/dash+xml",
+        PROTECTED_MPD.as_bytes().to_vec(),
+    )])
+    .await;
+    let app = app!(auth_config());
+
+    let uri = format!(
+        "/proxy/mpd/playlist.m3u8?profile_id=v1&key={}&token={}",
+        CLEAR_KEY,
+        token(&format!("{}/manifest.mpd", upstream), json!({}))
+    );
+    let request = actix_web::test::TestRequest::get().uri(&uri).to_request();
+    let body = actix_web::test::call_and_read_body(&app, request).await;
+    let body = String::from_utf8(body.to_vec()).unwrap();
+
+    let segment = body
+        .lines()
+        .find(|line| line.contains("/proxy/mpd/segment?") && !line.starts_with('#'))
+        .unwrap();
+    let proxy_data = token_data(segment);
+    assert_eq!(proxy_data.destination, format!("{}/seg-1.m4s", upstream));
+    assert_eq!(proxy_data.query_param("key"), Some(CLEAR_KEY));
+    assert_eq!(
+        proxy_data.query_param("init_url"),
+        Some(format!("{}/init.mp4", upstream).as_str())
+    );
+}
+
+/// Path and query of a rewritten URL, to request it from the test app.
+fn local_uri(url: &str) -> &str {
+    &url[url.find("/proxy/").unwrap()..]
+}
+
+#[actix_web::test]
+async fn test_decrypt_flag_reaches_variants() {
+    let upstream = serve(vec![
+        (
+            "/master.m3u8",
+            "application/vnd.apple.mpegurl",
+            MASTER_PLAYLIST.as_bytes().to_vec(),
+        ),
+        (
+            "/live.m3u8",
+            "application/vnd.apple.mpegurl",
+            ENCRYPTED_PLAYLIST.as_bytes().to_vec(),
+        ),
+    ])
+    .await;
+    let app = app!(auth_config());
+
+    let uri = format!(
+        "/proxy/hls/manifest.m3u8?token={}&decrypt=1",
+        token(&format!("{}/master.m3u8", upstream), json!({}))
+    );
+    let request = actix_web::test::TestRequest::get().uri(&uri).to_request();
+    let body = actix_web::test::call_and_read_body(&app, request).await;
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    let variant = body.lines().find(|line| !line.starts_with('#')).unwrap();
+    assert_eq!(token_data(variant).query_param("decrypt"), Some("1"));
+
+    let request = actix_web::test::TestRequest::get()
+        .uri(local_uri(variant))
+        .to_request();
+    let body = actix_web::test::call_and_read_body(&app, request).await;
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(!body.contains("#EXT-X-KEY"), "{}", body);
+    let segment = body.lines().find(|line| !line.starts_with('#')).unwrap();
+    assert!(segment.contains("/proxy/hls/segment?token="), "{}", body);
+    assert_eq!(
+        token_data(segment).query_param("key_url"),
+        Some(format!("{}/key.bin", upstream).as_str())
+    );
+}
+
+#[actix_web::test]
+async fn test_remux_flag_reaches_variants() {
+    let upstream = serve(vec![
+        (
+            "/master.m3u8",
+            "application/vnd.apple.mpegurl",
+            MASTER_PLAYLIST.as_bytes().to_vec(),
+        ),
+        (
+            "/live.m3u8",
+            "application/vnd.apple.mpegurl",
+            MEDIA_PLAYLIST.as_bytes().to_vec(),
+        ),
+    ])
+    .await;
+    let app = app!(auth_config());
+
+    let uri = format!(
+        "/proxy/hls/manifest.m3u8?token={}&remux=fmp4",
+        token(&format!("{}/master.m3u8", upstream), json!({}))
+    );
+    let request = actix_web::test::TestRequest::get().uri(&uri).to_request();
+    let body = actix_web::test::call_and_read_body(&app, request).await;
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    let variant = body.lines().find(|line| !line.starts_with('#')).unwrap();
+    assert_eq!(token_data(variant).query_param("remux"), Some("fmp4"));
+
+    let request = actix_web::test::TestRequest::get()
+        .uri(local_uri(variant))
+        .to_request();
+    let body = actix_web::test::call_and_read_body(&app, request).await;
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("#EXT-X-MAP:URI="), "{}", body);
+    let segment = body.lines().find(|line| !line.starts_with('#')).unwrap();
+    assert!(
+        segment.contains("/proxy/hls/fmp4/segment.m4s?token="),
+        "{}",
+        body
+    );
+}
+
+const SEGMENT_KEY: [u8; 16] = [0x2b; 16];
+
+/// `plaintext` encrypted with AES-128-CBC and PKCS#7 padding under [`SEGMENT_KEY`] and a zero IV.
+fn encrypt_segment(plaintext: &[u8]) -> Vec<u8> {
+    use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+
+    let cipher = aes::Aes128::new_from_slice(&SEGMENT_KEY).unwrap();
+    let padding = 16 - plaintext.len() % 16;
+    let mut data = plaintext.to_vec();
+    data.extend(std::iter::repeat_n(padding as u8, padding));
+    let mut chain = [0u8; 16];
+    for block in data.chunks_exact_mut(16) {
+        for (b, c) in block.iter_mut().zip(chain.iter()) {
+            *b ^= c;
+        }
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        chain.copy_from_slice(block);
+    }
+    data
+}
+
+#[actix_web::test]
+async fn test_decrypted_segments_count_against_key_quotas() {
+    let upstream = serve(vec![
+        ("/key.bin", "application/octet-stream", SEGMENT_KEY.to_vec()),
+        (
+            "/seg-1.ts",
+            "video/mp2t",
+            encrypt_segment(b"\x47 transport stream packet payload"),
+        ),
+    ])
+    .await;
+    let mut auth = restricted_auth_config();
+    auth.keys[0].max_streams = Some(1);
+    let app = app!(auth);
+
+    let uri = format!(
+        "/proxy/hls/segment?iv=0&token={}",
+        token(
+            &format!("{}/seg-1.ts", upstream),
+            json!({ "api_password": "cdn-secret", "key_url": format!("{}/key.bin", upstream) })
+        )
+    );
+    let request = || actix_web::test::TestRequest::get().uri(&uri).to_request();
+    let first = actix_web::test::call_service(&app, request()).await;
+    assert_eq!(first.status(), 200);
+
+    // The segment holds the key's only stream until it has been sent
+    let second = actix_web::test::call_service(&app, request()).await;
+    assert_eq!(second.status(), 429);
+
+    drop(first);
+    let body = actix_web::test::call_and_read_body(&app, request()).await;
+    assert_eq!(&body[..], b"\x47 transport stream packet payload");
+}
+
+#[actix_web::test]
+async fn test_decrypted_segments_are_registered() {
+    let upstream = serve(vec![
+        ("/key.bin", "application/octet-stream", SEGMENT_KEY.to_vec()),
+        (
+            "/seg-1.ts",
+            "video/mp2t",
+            encrypt_segment(b"\x47 transport stream packet payload"),
+        ),
+    ])
+    .await;
+    let stream_manager = web::Data::new(stream_manager());
+    let app = app!(restricted_auth_config(), stream_manager.clone());
+
+    let uri = format!(
+        "/proxy/hls/segment?iv=0&token={}",
+        token(
+            &format!("{}/seg-1.ts", upstream),
+            json!({ "api_password": "cdn-secret", "key_url": format!("{}/key.bin", upstream) })
+        )
+    );
+    let request = actix_web::test::TestRequest::get().uri(&uri).to_request();
+    let response = actix_web::test::call_service(&app, request).await;
+    assert_eq!(response.status(), 200);
+
+    let registry = stream_manager.registry();
+    let streams = registry.list();
+    assert_eq!(streams.len(), 1);
+    assert_eq!(streams[0].destination_host, "127.0.0.1");
+    assert_eq!(streams[0].key.as_deref(), Some("cdn"));
+
+    // The segment can be killed like any relayed stream
+    assert!(registry.kill(streams[0].id));
+    assert!(registry.is_empty());
+    drop(response);
+}
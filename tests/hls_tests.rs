@@ -0,0 +1,443 @@
+use mediaflow_proxy_light::auth::encryption::{EncryptionHandler, ProxyData};
+use mediaflow_proxy_light::proxy::hls::{
+    Playlist, PlaylistLine, Quality, Resolution, VariantFilter,
+};
+use mediaflow_proxy_light::proxy::url_builder::ProxyUrlBuilder;
+use serde_json::json;
+use std::sync::Arc;
+use url::Url;
+
+fn proxy_data() -> ProxyData {
+    ProxyData {
+        destination: "https://cdn.example.com/live/master.m3u8".to_string(),
+        query_params: Some(json!({
+            "api_password": "secret",
+            "d": "https://cdn.example.com/live/master.m3u8",
+            "h_referer": "https://example.com"
+        })),
+        request_headers: Some(json!({ "referer": "https://example.com" })),
+        response_headers: None,
+        exp: None,
+        ip: None,
+        mirrors: Vec::new(),
+    }
+}
+
+fn query_param(url: &str, name: &str) -> Option<String> {
+    Url::parse(url)
+        .unwrap()
+        .query_pairs()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.into_owned())
+}
+
+#[test]
+fn test_master_playlist_rewrite() {
+    let content = "#EXTM3U\n\
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aud\",NAME=\"English\",URI=\"audio/en.m3u8\"\n\
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,RESOLUTION=1280x720,CODECS=\"avc1.4d401f,mp4a.40.2\"\n\
+720p/index.m3u8\n";
+
+    let mut playlist = Playlist::parse(content).unwrap();
+    assert!(playlist.is_master);
+
+    let builder = ProxyUrlBuilder::new("http://proxy:8888".to_string(), proxy_data(), None);
+    let base_url = Url::parse("https://cdn.example.com/live/master.m3u8").unwrap();
+    playlist.rewrite(&base_url, &builder).unwrap();
+
+    let output = playlist.to_string();
+    let lines: Vec<&str> = output.lines().collect();
+
+    // Codec list with commas inside quotes must survive untouched
+    assert!(lines[2].contains("CODECS=\"avc1.4d401f,mp4a.40.2\""));
+
+    let variant = lines[3];
+    assert!(variant.starts_with("http://proxy:8888/proxy/hls/manifest.m3u8?"));
+    assert_eq!(
+        query_param(variant, "d").as_deref(),
+        Some("https://cdn.example.com/live/720p/index.m3u8")
+    );
+    assert_eq!(
+        query_param(variant, "api_password").as_deref(),
+        Some("secret")
+    );
+    assert_eq!(
+        query_param(variant, "h_referer").as_deref(),
+        Some("https://example.com")
+    );
+
+    let media_uri = lines[1]
+        .split("URI=\"")
+        .nth(1)
+        .unwrap()
+        .trim_end_matches('"');
+    assert!(media_uri.starts_with("http://proxy:8888/proxy/hls/manifest.m3u8?"));
+    assert_eq!(
+        query_param(media_uri, "d").as_deref(),
+        Some("https://cdn.example.com/live/audio/en.m3u8")
+    );
+}
+
+#[test]
+fn test_media_playlist_rewrite() {
+    let content = "#EXTM3U\n\
+#EXT-X-TARGETDURATION:6\n\
+#EXT-X-KEY:METHOD=AES-128,URI=\"https://keys.example.com/key?id=1\",IV=0x00000000000000000000000000000001\n\
+#EXT-X-MAP:URI=\"init.mp4\"\n\
+#EXTINF:6.0,\n\
+seg-1.m4s\n\
+#EXT-X-ENDLIST\n";
+
+    let mut playlist = Playlist::parse(content).unwrap();
+    assert!(!playlist.is_master);
+    assert_eq!(playlist.tag_value("#EXT-X-TARGETDURATION"), Some("6"));
+
+    let builder = ProxyUrlBuilder::new("http://proxy:8888/".to_string(), proxy_data(), None);
+    let base_url = Url::parse("https://cdn.example.com/live/720p/index.m3u8").unwrap();
+    playlist.rewrite(&base_url, &builder).unwrap();
+
+    let output = playlist.to_string();
+    let lines: Vec<&str> = output.lines().collect();
+
+    assert!(lines[2].starts_with("#EXT-X-KEY:METHOD=AES-128,URI=\"http://proxy:8888/proxy/stream?"));
+    assert!(lines[2].ends_with(",IV=0x00000000000000000000000000000001"));
+    let key_uri = lines[2]
+        .split("URI=\"")
+        .nth(1)
+        .unwrap()
+        .split('"')
+        .next()
+        .unwrap();
+    assert_eq!(
+        query_param(key_uri, "d").as_deref(),
+        Some("https://keys.example.com/key?id=1")
+    );
+
+    let map_uri = lines[3]
+        .split("URI=\"")
+        .nth(1)
+        .unwrap()
+        .trim_end_matches('"');
+    assert_eq!(
+        query_param(map_uri, "d").as_deref(),
+        Some("https://cdn.example.com/live/720p/init.mp4")
+    );
+
+    assert!(lines[5].starts_with("http://proxy:8888/proxy/stream?"));
+    assert_eq!(
+        query_param(lines[5], "d").as_deref(),
+        Some("https://cdn.example.com/live/720p/seg-1.m4s")
+    );
+    assert_eq!(lines[6], "#EXT-X-ENDLIST");
+}
+
+#[test]
+fn test_rewrite_with_token() {
+    let handler = Arc::new(EncryptionHandler::new(b"secret").unwrap());
+    let content = "#EXTM3U\n#EXTINF:4.0,\nhttps://other.example.com/seg.ts\n";
+
+    let mut playlist = Playlist::parse(content).unwrap();
+    let builder = ProxyUrlBuilder::new(
+        "http://proxy:8888".to_string(),
+        proxy_data(),
+        Some(handler.clone()),
+    );
+    let base_url = Url::parse("https://cdn.example.com/index.m3u8").unwrap();
+    playlist.rewrite(&base_url, &builder).unwrap();
+
+    let output = playlist.to_string();
+    let segment = output.lines().nth(2).unwrap();
+    assert_eq!(query_param(segment, "d"), None);
+
+    let token = query_param(segment, "token").unwrap();
+    let decrypted = handler.decrypt(&token, None).unwrap();
+    assert_eq!(decrypted.destination, "https://other.example.com/seg.ts");
+    assert_eq!(decrypted.request_headers, proxy_data().request_headers);
+}
+
+#[test]
+fn test_rejects_non_playlist() {
+    assert!(Playlist::parse("<html></html>").is_err());
+}
+
+#[test]
+fn test_media_playlist_rewrite_decrypted() {
+    let content = "#EXTM3U\n\
+#EXT-X-MEDIA-SEQUENCE:7\n\
+#EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\"\n\
+#EXTINF:6.0,\n\
+seg-7.ts\n\
+#EXT-X-KEY:METHOD=AES-128,URI=\"https://keys.example.com/k2\",IV=0x0102\n\
+#EXT-X-BYTERANGE:1000@0\n\
+#EXTINF:6.0,\n\
+all.ts\n\
+#EXT-X-BYTERANGE:500\n\
+#EXTINF:6.0,\n\
+all.ts\n\
+#EXT-X-KEY:METHOD=NONE\n\
+#EXTINF:6.0,\n\
+clear.ts\n";
+
+    let mut playlist = Playlist::parse(content).unwrap();
+    let builder = ProxyUrlBuilder::new("http://proxy:8888".to_string(), proxy_data(), None);
+    let base_url = Url::parse("https://cdn.example.com/live/720p/index.m3u8").unwrap();
+    playlist.rewrite_decrypted(&base_url, &builder).unwrap();
+
+    let output = playlist.to_string();
+    let lines: Vec<&str> = output.lines().collect();
+    // Keys the proxy decrypts with, and the byte ranges of encrypted segments, are gone
+    assert!(!output.contains("METHOD=AES-128"));
+    assert!(!output.contains("#EXT-X-BYTERANGE"));
+    assert_eq!(lines.len(), 11);
+
+    let first = lines[3];
+    assert!(first.starts_with("http://proxy:8888/proxy/hls/segment?"));
+    assert_eq!(
+        query_param(first, "d").as_deref(),
+        Some("https://cdn.example.com/live/720p/seg-7.ts")
+    );
+    assert_eq!(
+        query_param(first, "key_url").as_deref(),
+        Some("https://cdn.example.com/live/720p/key.bin")
+    );
+    // Without an IV attribute, the media sequence number is the IV
+    assert_eq!(
+        query_param(first, "iv").as_deref(),
+        Some("00000000000000000000000000000007")
+    );
+    assert_eq!(query_param(first, "range"), None);
+
+    assert_eq!(
+        query_param(lines[5], "iv").as_deref(),
+        Some("00000000000000000000000000000102")
+    );
+    assert_eq!(query_param(lines[5], "range").as_deref(), Some("0-999"));
+    // A sub-range without offset follows the previous one
+    assert_eq!(query_param(lines[7], "range").as_deref(), Some("1000-1499"));
+
+    assert_eq!(lines[8], "#EXT-X-KEY:METHOD=NONE");
+    assert!(lines[10].starts_with("http://proxy:8888/proxy/stream?"));
+}
+
+#[test]
+fn test_media_playlist_rewrite_remuxed() {
+    let content = "#EXTM3U\n\
+#EXT-X-VERSION:3\n\
+#EXT-X-MEDIA-SEQUENCE:20\n\
+#EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\"\n\
+#EXTINF:6.0,\n\
+seg-20.ts\n\
+#EXT-X-KEY:METHOD=NONE\n\
+#EXTINF:6.0,\n\
+seg-21.ts\n\
+#EXT-X-DISCONTINUITY\n\
+#EXT-X-BYTERANGE:1000@500\n\
+#EXTINF:4.0,\n\
+ad.ts\n";
+
+    let mut playlist = Playlist::parse(content).unwrap();
+    let builder = ProxyUrlBuilder::new("http://proxy:8888".to_string(), proxy_data(), None);
+    let base_url = Url::parse("https://cdn.example.com/live/720p/index.m3u8").unwrap();
+    playlist
+        .rewrite_remuxed(&base_url, &builder, false)
+        .unwrap();
+
+    let output = playlist.to_string();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines[1], "#EXT-X-VERSION:6");
+    // Remuxed segments are decrypted, whether or not decryption was asked for
+    assert!(!output.contains("METHOD=AES-128"));
+    assert!(!output.contains("#EXT-X-BYTERANGE"));
+
+    // An init section before the first segment, built from it
+    let map = lines[3]
+        .strip_prefix("#EXT-X-MAP:URI=\"")
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap();
+    assert!(map.starts_with("http://proxy:8888/proxy/hls/fmp4/init.mp4?"));
+    assert_eq!(
+        query_param(map, "d").as_deref(),
+        Some("https://cdn.example.com/live/720p/seg-20.ts")
+    );
+    assert!(query_param(map, "key_url").is_some());
+    assert_eq!(query_param(map, "seq"), None);
+
+    let first = lines[5];
+    assert!(first.starts_with("http://proxy:8888/proxy/hls/fmp4/segment.m4s?"));
+    assert_eq!(query_param(first, "seq").as_deref(), Some("20"));
+    assert_eq!(
+        query_param(first, "iv").as_deref(),
+        Some("00000000000000000000000000000014")
+    );
+
+    let second = lines[8];
+    assert_eq!(query_param(second, "seq").as_deref(), Some("21"));
+    assert_eq!(query_param(second, "key_url"), None);
+
+    // And another one after the discontinuity, with the byte range of its segment
+    assert_eq!(lines[9], "#EXT-X-DISCONTINUITY");
+    assert!(lines[10].starts_with("#EXT-X-MAP:URI=\"http://proxy:8888/proxy/hls/fmp4/init.mp4?"));
+    assert_eq!(query_param(lines[12], "range").as_deref(), Some("500-1499"));
+    assert_eq!(lines.len(), 13);
+
+    // Playlists that already use fMP4 are left to the player
+    let fmp4 = "#EXTM3U\n#EXT-X-MAP:URI=\"init.mp4\"\n#EXTINF:6.0,\nseg.m4s\n";
+    let mut playlist = Playlist::parse(fmp4).unwrap();
+    playlist
+        .rewrite_remuxed(&base_url, &builder, false)
+        .unwrap();
+    let output = playlist.to_string();
+    assert!(!output.contains("fmp4"));
+    assert!(!output.contains("#EXT-X-VERSION"));
+
+    // Master playlists advertise HEVC as hvc1
+    let master = "#EXTM3U\n\
+#EXT-X-STREAM-INF:BANDWIDTH=5000000,CODECS=\"hev1.1.6.L120.90,mp4a.40.2\"\n\
+1080p.m3u8\n";
+    let mut playlist = Playlist::parse(master).unwrap();
+    playlist
+        .rewrite_remuxed(&base_url, &builder, false)
+        .unwrap();
+    let output = playlist.to_string();
+    assert!(output.contains("CODECS=\"hvc1.1.6.L120.90,mp4a.40.2\""));
+    assert!(output.contains("/proxy/hls/manifest.m3u8?"));
+}
+
+#[test]
+fn test_aes128_segment_decryption() {
+    use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+    use mediaflow_proxy_light::proxy::aes128::{sequence_iv, Aes128Decryptor};
+    use mediaflow_proxy_light::proxy::stream::SegmentProcessor;
+
+    let key = [0x2bu8; 16];
+    let iv = sequence_iv(42);
+    let plaintext = b"\x47 transport stream packet payload".to_vec();
+
+    // AES-128-CBC with PKCS#7 padding
+    let cipher = aes::Aes128::new_from_slice(&key).unwrap();
+    let padding = 16 - plaintext.len() % 16;
+    let mut data = plaintext.clone();
+    data.extend(std::iter::repeat_n(padding as u8, padding));
+    let mut chain = iv;
+    for block in data.chunks_exact_mut(16) {
+        for (b, c) in block.iter_mut().zip(chain.iter()) {
+            *b ^= c;
+        }
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        chain.copy_from_slice(block);
+    }
+
+    let decryptor = Aes128Decryptor::new(&key, iv).unwrap();
+    let decrypted = decryptor.process(data.clone().into()).unwrap();
+    assert_eq!(&decrypted[..], &plaintext[..]);
+
+    // The wrong key breaks the padding; partial blocks and short keys are rejected
+    let wrong_key = Aes128Decryptor::new(&[0u8; 16], iv).unwrap();
+    assert!(wrong_key.process(data.clone().into()).is_err());
+    assert!(decryptor.process(data[..20].to_vec().into()).is_err());
+    assert!(Aes128Decryptor::new(&key[..8], iv).is_err());
+}
+
+const LADDER: &str = "#EXTM3U\n\
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aud\",NAME=\"English\",URI=\"audio.m3u8\"\n\
+#EXT-X-STREAM-INF:BANDWIDTH=20000000,RESOLUTION=3840x2160,CODECS=\"hvc1.2.4.L153,mp4a.40.2\"\n\
+2160p.m3u8\n\
+#EXT-X-STREAM-INF:BANDWIDTH=5000000,RESOLUTION=1920x1080,CODECS=\"avc1.640028,mp4a.40.2\"\n\
+1080p.m3u8\n\
+#EXT-X-STREAM-INF:BANDWIDTH=800000,RESOLUTION=640x360,CODECS=\"avc1.4d401e,mp4a.40.2\"\n\
+360p.m3u8\n\
+#EXT-X-STREAM-INF:BANDWIDTH=2500000,RESOLUTION=1280x720,CODECS=\"avc1.4d401f,mp4a.40.2\"\n\
+720p.m3u8\n";
+
+fn variants(filter: &VariantFilter) -> Vec<String> {
+    let mut playlist = Playlist::parse(LADDER).unwrap();
+    playlist.filter_variants(filter);
+    // Renditions stay in place
+    assert!(playlist
+        .to_string()
+        .lines()
+        .nth(1)
+        .unwrap()
+        .starts_with("#EXT-X-MEDIA:"));
+    playlist
+        .lines
+        .iter()
+        .filter_map(|line| match line {
+            PlaylistLine::Uri(uri) => Some(uri.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_variant_filter() {
+    assert_eq!(
+        variants(&VariantFilter {
+            max_resolution: Resolution::parse("1080p"),
+            min_resolution: Resolution::parse("1280x720"),
+            ..Default::default()
+        }),
+        ["1080p.m3u8", "720p.m3u8"]
+    );
+    assert_eq!(
+        variants(&VariantFilter {
+            max_bandwidth: Some(3_000_000),
+            order: Some(Quality::Highest),
+            ..Default::default()
+        }),
+        ["720p.m3u8", "360p.m3u8"]
+    );
+    // HEVC dropped by the codec allow-list, lowest quality first
+    assert_eq!(
+        variants(&VariantFilter {
+            codecs: vec!["avc1".to_string(), "mp4a".to_string()],
+            order: Some(Quality::Lowest),
+            ..Default::default()
+        }),
+        ["360p.m3u8", "720p.m3u8", "1080p.m3u8"]
+    );
+    assert_eq!(
+        variants(&VariantFilter {
+            max_resolution: Resolution::parse("1280x720"),
+            single: Some(Quality::Highest),
+            ..Default::default()
+        }),
+        ["720p.m3u8"]
+    );
+    // A filter matching nothing leaves the playlist playable
+    assert_eq!(
+        variants(&VariantFilter {
+            max_bandwidth: Some(1000),
+            ..Default::default()
+        })
+        .len(),
+        4
+    );
+}
+
+#[test]
+fn test_variant_filter_from_request() {
+    assert_eq!(
+        VariantFilter::from_request(&proxy_data(), "d=x&api_password=secret").unwrap(),
+        None
+    );
+
+    let mut data = proxy_data();
+    data.query_params = Some(json!({ "max_resolution": "720p", "order": "highest_first" }));
+    let filter = VariantFilter::from_request(&data, "codecs=avc1,mp4a&order=lowest")
+        .unwrap()
+        .unwrap();
+    // Token parameters take precedence over the plain query string
+    assert_eq!(filter.order, Some(Quality::Highest));
+    assert_eq!(
+        filter.max_resolution,
+        Some(Resolution {
+            width: None,
+            height: 720
+        })
+    );
+    assert_eq!(filter.codecs, ["avc1", "mp4a"]);
+
+    assert!(VariantFilter::from_request(&proxy_data(), "variant=best").is_err());
+    assert!(VariantFilter::from_request(&proxy_data(), "max_resolution=hd").is_err());
+}
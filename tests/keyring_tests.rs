@@ -0,0 +1,275 @@
+use actix_web::{web, App, HttpMessage, HttpRequest, HttpResponse};
+use futures::{stream, StreamExt};
+use mediaflow_proxy_light::auth::keyring::{ApiKey, Keyring};
+use mediaflow_proxy_light::auth::middleware::AuthMiddleware;
+use mediaflow_proxy_light::config::{ApiKeyConfig, AuthConfig, Endpoint};
+use mediaflow_proxy_light::error::AppError;
+use mediaflow_proxy_light::proxy::throttle::{Shaper, Throttle};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn auth_config(keys: Vec<ApiKeyConfig>) -> AuthConfig {
+    AuthConfig {
+        api_password: "admin".to_string(),
+        previous_passwords: Vec::new(),
+        legacy_tokens: true,
+        python_tokens: false,
+        keys,
+    }
+}
+
+fn key(label: &str) -> ApiKeyConfig {
+    ApiKeyConfig {
+        label: label.to_string(),
+        key: format!("{}-secret", label),
+        endpoints: Vec::new(),
+        hosts: Vec::new(),
+        expires_at: None,
+        max_streams: None,
+        max_bandwidth: None,
+    }
+}
+
+#[test]
+fn test_endpoint_from_path() {
+    assert_eq!(Endpoint::from_path("/proxy/stream"), Some(Endpoint::Stream));
+    assert_eq!(
+        Endpoint::from_path("/proxy/hls/manifest.m3u8"),
+        Some(Endpoint::Hls)
+    );
+    assert_eq!(
+        Endpoint::from_path("/proxy/mpd/segment"),
+        Some(Endpoint::Mpd)
+    );
+    assert_eq!(
+        Endpoint::from_path("/proxy/generate_url"),
+        Some(Endpoint::GenerateUrl)
+    );
+    assert_eq!(
+        Endpoint::from_path("/admin/streams/1"),
+        Some(Endpoint::Admin)
+    );
+    assert_eq!(Endpoint::from_path("/health"), None);
+}
+
+#[test]
+fn test_key_scopes() {
+    let keyring = Keyring::from_config(&auth_config(vec![
+        ApiKeyConfig {
+            endpoints: vec![Endpoint::Hls],
+            hosts: vec!["*.example.com".to_string()],
+            ..key("player")
+        },
+        ApiKeyConfig {
+            expires_at: Some(1),
+            ..key("revoked")
+        },
+    ]));
+
+    let admin = keyring.find("admin").unwrap();
+    assert_eq!(admin.label(), "default");
+    assert!(admin
+        .authorize(Some(Endpoint::Ip), Some("https://anywhere.net/"))
+        .is_ok());
+
+    let player = keyring.find("player-secret").unwrap();
+    assert!(player
+        .authorize(
+            Some(Endpoint::Hls),
+            Some("https://cdn.EXAMPLE.com/live.m3u8")
+        )
+        .is_ok());
+    assert!(matches!(
+        player.authorize(
+            Some(Endpoint::Stream),
+            Some("https://cdn.example.com/a.mp4")
+        ),
+        Err(AppError::Forbidden(_))
+    ));
+    assert!(matches!(
+        player.authorize(Some(Endpoint::Hls), Some("https://example.org/live.m3u8")),
+        Err(AppError::Forbidden(_))
+    ));
+
+    let revoked = keyring.find("revoked-secret").unwrap();
+    assert!(revoked.is_expired());
+    assert!(matches!(
+        revoked.authorize(None, None),
+        Err(AppError::Auth(_))
+    ));
+
+    assert!(keyring.find("unknown").is_none());
+}
+
+#[test]
+fn test_concurrent_stream_quota() {
+    let keyring = Keyring::from_config(&auth_config(vec![ApiKeyConfig {
+        max_streams: Some(1),
+        ..key("app")
+    }]));
+    let app = keyring.find("app-secret").unwrap();
+
+    let first = app.open_stream().unwrap();
+    assert!(matches!(app.open_stream(), Err(AppError::QuotaExceeded(_))));
+    assert_eq!(app.active_streams(), 1);
+
+    // The slot is held until the stream it was applied to is dropped
+    let body = first.apply(Box::pin(stream::iter(vec![Ok(
+        actix_web::web::Bytes::from_static(b"data"),
+    )])));
+    assert!(app.open_stream().is_err());
+    drop(body);
+    assert_eq!(app.active_streams(), 0);
+    assert!(app.open_stream().is_ok());
+}
+
+#[tokio::test]
+async fn test_key_bandwidth() {
+    let keyring = Keyring::from_config(&auth_config(vec![ApiKeyConfig {
+        max_bandwidth: Some(10_000),
+        ..key("slow")
+    }]));
+    let slow = keyring.find("slow-secret").unwrap();
+
+    let chunks = (0..3).map(|_| Ok(actix_web::web::Bytes::from(vec![0u8; 5_000])));
+    let quota = slow.open_stream().unwrap();
+    let throttles = quota.bandwidth().into_iter().collect();
+    let mut body = Shaper::default().shape(quota.apply(Box::pin(stream::iter(chunks))), throttles);
+
+    // One second of burst, then 5000 bytes at 10000 bytes/s
+    let started = Instant::now();
+    while let Some(chunk) = body.next().await {
+        chunk.unwrap();
+    }
+    assert!(started.elapsed() >= Duration::from_millis(450));
+}
+
+#[tokio::test]
+async fn test_throttle_burst() {
+    let throttle = Throttle::with_burst(1_000_000, 64 * 1024);
+    let started = Instant::now();
+    throttle.acquire(64 * 1024).await;
+    assert!(started.elapsed() < Duration::from_millis(50));
+    assert_eq!(throttle.rate(), 1_000_000);
+}
+
+async fn whoami(req: HttpRequest) -> HttpResponse {
+    let label = req
+        .extensions()
+        .get::<Arc<ApiKey>>()
+        .map(|key| key.label().to_string())
+        .unwrap_or_default();
+    HttpResponse::Ok().body(label)
+}
+
+#[actix_web::test]
+async fn test_middleware_attaches_key() {
+    let auth = auth_config(vec![ApiKeyConfig {
+        endpoints: vec![Endpoint::Stream],
+        ..key("app")
+    }]);
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(AuthMiddleware::new(&auth))
+            .route("/proxy/stream", web::get().to(whoami))
+            .route("/proxy/ip", web::get().to(whoami)),
+    )
+    .await;
+
+    let request = actix_web::test::TestRequest::get()
+        .uri("/proxy/stream?d=https://example.com/a.mp4&api_password=app-secret")
+        .to_request();
+    let body = actix_web::test::call_and_read_body(&app, request).await;
+    assert_eq!(body, "app");
+
+    let request = actix_web::test::TestRequest::get()
+        .uri("/proxy/ip?api_password=app-secret")
+        .to_request();
+    let error = actix_web::test::try_call_service(&app, request)
+        .await
+        .unwrap_err();
+    assert_eq!(error.error_response().status(), 403);
+
+    let request = actix_web::test::TestRequest::get()
+        .uri("/proxy/ip?api_password=wrong")
+        .to_request();
+    let error = actix_web::test::try_call_service(&app, request)
+        .await
+        .unwrap_err();
+    assert_eq!(error.error_response().status(), 401);
+}
+
+#[actix_web::test]
+async fn test_mirrors_are_scoped() {
+    let auth = auth_config(vec![ApiKeyConfig {
+        hosts: vec!["*.example.com".to_string()],
+        ..key("app")
+    }]);
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(AuthMiddleware::new(&auth))
+            .route("/proxy/stream", web::get().to(whoami)),
+    )
+    .await;
+
+    let request = actix_web::test::TestRequest::get()
+        .uri("/proxy/stream?d=https://a.example.com/v.mp4&mirror=https%3A%2F%2Fb.example.com%2Fv.mp4&api_password=app-secret")
+        .to_request();
+    let body = actix_web::test::call_and_read_body(&app, request).await;
+    assert_eq!(body, "app");
+
+    let request = actix_web::test::TestRequest::get()
+        .uri("/proxy/stream?d=https://a.example.com/v.mp4&mirror=https%3A%2F%2Fother.net%2Fv.mp4&api_password=app-secret")
+        .to_request();
+    let error = actix_web::test::try_call_service(&app, request)
+        .await
+        .unwrap_err();
+    assert_eq!(error.error_response().status(), 403);
+}
+
+#[actix_web::test]
+async fn test_template_destination_is_expanded_before_scoping() {
+    let auth = auth_config(vec![ApiKeyConfig {
+        hosts: vec!["cdn.example.com".to_string()],
+        ..key("app")
+    }]);
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(AuthMiddleware::new(&auth))
+            .route("/proxy/stream", web::get().to(whoami)),
+    )
+    .await;
+
+    let request = actix_web::test::TestRequest::get()
+        .uri("/proxy/stream?d=https%3A%2F%2Fcdn.example.com%2F%24RepresentationID%24%2F%24Number%24.m4s&tpl0=v1&tpl1=42&api_password=app-secret")
+        .to_request();
+    let body = actix_web::test::call_and_read_body(&app, request).await;
+    assert_eq!(body, "app");
+
+    let request = actix_web::test::TestRequest::get()
+        .uri("/proxy/stream?d=https%3A%2F%2Fcdn.example.com%2F%24RepresentationID%24%2F%24Number%24.m4s&tpl0=v1&tpl1=%2F%2Fother.net&api_password=app-secret")
+        .to_request();
+    let error = actix_web::test::try_call_service(&app, request)
+        .await
+        .unwrap_err();
+    assert_eq!(error.error_response().status(), 403);
+}
+
+#[test]
+fn test_open_streams_survive_reload() {
+    let auth = auth_config(vec![ApiKeyConfig {
+        max_streams: Some(1),
+        ..key("app")
+    }]);
+    let keyring = Keyring::from_config(&auth);
+    let stream = keyring.find("app-secret").unwrap().open_stream().unwrap();
+
+    let reloaded = Keyring::reloaded(&auth, &keyring);
+    let app = reloaded.find("app-secret").unwrap();
+    assert_eq!(app.active_streams(), 1);
+    assert!(matches!(app.open_stream(), Err(AppError::QuotaExceeded(_))));
+
+    drop(stream);
+    assert_eq!(app.active_streams(), 0);
+    assert!(app.open_stream().is_ok());
+}
@@ -0,0 +1,119 @@
+use actix_web::{web, App, HttpResponse};
+use mediaflow_proxy_light::config::{ProxyConfig, ProxyRouteConfig, ProxyRouter};
+use mediaflow_proxy_light::error::AppError;
+use mediaflow_proxy_light::metrics::{metrics, RequestMetrics};
+use std::collections::HashMap;
+use std::time::Duration;
+
+async fn ok() -> HttpResponse {
+    HttpResponse::Ok().body("0123456789")
+}
+
+async fn forbidden() -> Result<HttpResponse, AppError> {
+    Err(AppError::Forbidden("no".to_string()))
+}
+
+#[actix_web::test]
+async fn test_request_metrics() {
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(RequestMetrics)
+            .route("/metrics-test/ok/{id}", web::get().to(ok))
+            .route("/metrics-test/forbidden", web::get().to(forbidden)),
+    )
+    .await;
+    let forbidden_errors = metrics().errors("forbidden");
+
+    for id in 0..2 {
+        let request = actix_web::test::TestRequest::get()
+            .uri(&format!("/metrics-test/ok/{}", id))
+            .to_request();
+        let body = actix_web::test::call_and_read_body(&app, request).await;
+        assert_eq!(body.len(), 10);
+    }
+    let request = actix_web::test::TestRequest::get()
+        .uri("/metrics-test/forbidden")
+        .to_request();
+    let res = actix_web::test::call_service(&app, request).await;
+    assert_eq!(res.status(), 403);
+
+    // Requests are labelled by route pattern rather than path
+    assert_eq!(metrics().requests("/metrics-test/ok/{id}", 200), 2);
+    assert_eq!(metrics().requests("/metrics-test/forbidden", 403), 1);
+    assert_eq!(metrics().errors("forbidden"), forbidden_errors + 1);
+
+    let rendered = metrics().render(&Default::default());
+    assert!(rendered
+        .contains("mediaflow_requests_total{route=\"/metrics-test/ok/{id}\",status=\"200\"} 2\n"));
+    assert!(rendered.contains("# TYPE mediaflow_client_bytes_total counter\n"));
+}
+
+#[test]
+fn test_upstream_latency_histogram() {
+    metrics().record_upstream_latency(Duration::from_millis(30));
+    let rendered = metrics().render(&Default::default());
+
+    let bucket = |le: &str| -> u64 {
+        let prefix = format!(
+            "mediaflow_upstream_latency_seconds_bucket{{le=\"{}\"}} ",
+            le
+        );
+        let line = rendered
+            .lines()
+            .find(|line| line.starts_with(&prefix))
+            .unwrap();
+        line[prefix.len()..].parse().unwrap()
+    };
+    // Buckets are cumulative
+    assert!(bucket("0.05") >= 1);
+    assert!(bucket("0.05") <= bucket("0.1"));
+    assert!(bucket("10") <= bucket("+Inf"));
+    assert!(rendered.contains("# TYPE mediaflow_upstream_latency_seconds histogram\n"));
+}
+
+#[test]
+fn test_transport_route_usage() {
+    let config = ProxyConfig {
+        connect_timeout: 5,
+        buffer_size: 8192,
+        follow_redirects: true,
+        proxy_url: None,
+        all_proxy: false,
+        transport_routes: HashMap::from([(
+            "all://*.example.com".to_string(),
+            ProxyRouteConfig {
+                proxy: false,
+                proxy_url: None,
+                verify_ssl: false,
+                prefetch: 0,
+            },
+        )]),
+        manifest_cache: true,
+        pool_max_idle_per_host: 4,
+        pool_idle_timeout: 30,
+        head_fallback: true,
+        resume_retries: 3,
+        resume_backoff_ms: 500,
+    };
+    let router = ProxyRouter::from_config(&config);
+
+    for url in [
+        "https://cdn.example.com/a.ts",
+        "https://cdn.example.com/b.ts",
+        "https://other.host/a.ts",
+    ] {
+        router.get_client(url).unwrap();
+    }
+
+    let usage = router.pool_stats().route_usage();
+    assert_eq!(
+        usage,
+        vec![
+            ("all://*.example.com".to_string(), 2),
+            ("default".to_string(), 1)
+        ]
+    );
+    let rendered = metrics().render(router.pool_stats());
+    assert!(rendered
+        .contains("mediaflow_transport_route_requests_total{route=\"all://*.example.com\"} 2\n"));
+}
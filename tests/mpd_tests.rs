@@ -0,0 +1,402 @@
+use mediaflow_proxy_light::auth::encryption::ProxyData;
+use mediaflow_proxy_light::proxy::mpd::{
+    self, fill_template, parse_duration, sidx_segments, ByteRange, MediaKind, Mpd,
+};
+use mediaflow_proxy_light::proxy::url_builder::ProxyUrlBuilder;
+use serde_json::json;
+use url::Url;
+
+const VOD_MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT0H0M20.000S">
+  <BaseURL>media/</BaseURL>
+  <Period id="p0">
+    <AdaptationSet mimeType="video/mp4" segmentAlignment="true">
+      <SegmentTemplate timescale="1000" duration="4000" startNumber="1"
+        initialization="$RepresentationID$/init.mp4" media="$RepresentationID$/seg-$Number%05d$.m4s"/>
+      <Representation id="v720" bandwidth="2000000" width="1280" height="720" codecs="avc1.64001f" frameRate="30000/1001"/>
+      <Representation id="v1080" bandwidth="5000000" width="1920" height="1080" codecs="avc1.640028"/>
+    </AdaptationSet>
+    <AdaptationSet mimeType="audio/mp4" lang="en">
+      <Representation id="a1" bandwidth="128000" codecs="mp4a.40.2">
+        <SegmentTemplate timescale="48000" initialization="audio/init.mp4" media="audio/$Time$.m4s">
+          <SegmentTimeline>
+            <S t="0" d="192000" r="3"/>
+            <S d="96000"/>
+          </SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+fn mpd_url() -> Url {
+    Url::parse("https://cdn.example.com/vod/manifest.mpd").unwrap()
+}
+
+fn url_builder() -> ProxyUrlBuilder {
+    ProxyUrlBuilder::new(
+        "http://proxy:8888".to_string(),
+        ProxyData {
+            destination: mpd_url().to_string(),
+            query_params: Some(json!({ "api_password": "secret" })),
+            request_headers: None,
+            response_headers: None,
+            exp: None,
+            ip: None,
+            mirrors: Vec::new(),
+        },
+        None,
+    )
+}
+
+fn query_param(url: &str, name: &str) -> Option<String> {
+    Url::parse(url)
+        .unwrap()
+        .query_pairs()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.into_owned())
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("PT0H0M20.000S"), Some(20.0));
+    assert_eq!(parse_duration("PT1H2M3.5S"), Some(3723.5));
+    assert_eq!(parse_duration("P1DT1S"), Some(86401.0));
+    assert_eq!(parse_duration("1H"), None);
+}
+
+#[test]
+fn test_fill_template() {
+    assert_eq!(
+        fill_template(
+            "$RepresentationID$/$Number%05d$.m4s",
+            "v1",
+            0,
+            Some(42),
+            None
+        ),
+        "v1/00042.m4s"
+    );
+    assert_eq!(
+        fill_template("$Bandwidth$/$Time$.m4s?x=$$", "v1", 800, None, Some(9000)),
+        "800/9000.m4s?x=$"
+    );
+}
+
+#[test]
+fn test_template_number_segments() {
+    let mpd = Mpd::parse(VOD_MPD, &mpd_url()).unwrap();
+    assert!(!mpd.is_live);
+
+    let rep = mpd.representation("v720").unwrap();
+    assert_eq!(rep.kind, MediaKind::Video);
+
+    let init = rep.init_segment().unwrap().unwrap();
+    assert_eq!(init.url, "https://cdn.example.com/vod/media/v720/init.mp4");
+
+    let segments = mpd.segments(0, rep, 0.0).unwrap();
+    assert_eq!(segments.len(), 5);
+    assert_eq!(
+        segments[0].url,
+        "https://cdn.example.com/vod/media/v720/seg-00001.m4s"
+    );
+    assert_eq!(segments[4].number, 5);
+    assert_eq!(segments[0].duration, 4.0);
+}
+
+#[test]
+fn test_template_timeline_segments() {
+    let mpd = Mpd::parse(VOD_MPD, &mpd_url()).unwrap();
+    let rep = mpd.representation("a1").unwrap();
+    assert_eq!(rep.kind, MediaKind::Audio);
+
+    let segments = mpd.segments(0, rep, 0.0).unwrap();
+    assert_eq!(segments.len(), 5);
+    assert_eq!(
+        segments[1].url,
+        "https://cdn.example.com/vod/media/audio/192000.m4s"
+    );
+    assert_eq!(segments[4].duration, 2.0);
+}
+
+#[test]
+fn test_master_playlist() {
+    let mpd = Mpd::parse(VOD_MPD, &mpd_url()).unwrap();
+    let playlist = mpd::build_master_playlist(&mpd, &url_builder()).unwrap();
+    let lines: Vec<&str> = playlist.lines().collect();
+
+    assert!(lines[3].starts_with("#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\""));
+    assert!(lines[3].contains("LANGUAGE=\"en\""));
+    assert_eq!(
+        lines[4],
+        "#EXT-X-STREAM-INF:BANDWIDTH=2128000,RESOLUTION=1280x720,\
+CODECS=\"avc1.64001f,mp4a.40.2\",FRAME-RATE=29.970,AUDIO=\"audio\""
+    );
+    assert!(lines[5].starts_with("http://proxy:8888/proxy/mpd/playlist.m3u8?"));
+    assert_eq!(query_param(lines[5], "profile_id").as_deref(), Some("v720"));
+    assert_eq!(
+        query_param(lines[5], "d").as_deref(),
+        Some("https://cdn.example.com/vod/manifest.mpd")
+    );
+}
+
+#[test]
+fn test_media_playlist() {
+    let mpd = Mpd::parse(VOD_MPD, &mpd_url()).unwrap();
+    let rep = mpd.representation("v1080").unwrap();
+    let periods = vec![(
+        rep.init_segment().unwrap(),
+        mpd.segments(0, rep, 0.0).unwrap(),
+    )];
+    let playlist = mpd::build_media_playlist(&mpd, &periods, &url_builder()).unwrap();
+
+    assert!(playlist.contains("#EXT-X-TARGETDURATION:4\n"));
+    assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:1\n"));
+    assert!(playlist.contains("#EXT-X-PLAYLIST-TYPE:VOD\n"));
+    assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+
+    let segment = playlist
+        .lines()
+        .find(|line| line.starts_with("http://proxy:8888/proxy/mpd/segment?"))
+        .unwrap();
+    assert_eq!(
+        query_param(segment, "d").as_deref(),
+        Some("https://cdn.example.com/vod/media/v1080/seg-00001.m4s")
+    );
+}
+
+#[test]
+fn test_segment_list_and_base() {
+    let content = r#"<MPD type="static" mediaPresentationDuration="PT10S">
+  <Period>
+    <AdaptationSet mimeType="video/mp4">
+      <Representation id="list" bandwidth="1000">
+        <SegmentList timescale="10" duration="50">
+          <Initialization sourceURL="init.mp4"/>
+          <SegmentURL media="a.m4s"/>
+          <SegmentURL media="b.m4s" mediaRange="100-199"/>
+        </SegmentList>
+      </Representation>
+      <Representation id="base" bandwidth="1000">
+        <BaseURL>file.mp4</BaseURL>
+        <SegmentBase indexRange="800-899"><Initialization range="0-799"/></SegmentBase>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+    let mpd = Mpd::parse(content, &mpd_url()).unwrap();
+
+    let list = mpd.representation("list").unwrap();
+    let segments = mpd.segments(0, list, 0.0).unwrap();
+    assert_eq!(segments.len(), 2);
+    assert_eq!(segments[0].duration, 5.0);
+    assert_eq!(
+        segments[1].byte_range,
+        Some(ByteRange {
+            start: 100,
+            end: 199
+        })
+    );
+
+    let base = mpd.representation("base").unwrap();
+    assert_eq!(
+        base.index_range(),
+        Some(ByteRange {
+            start: 800,
+            end: 899
+        })
+    );
+    let init = base.init_segment().unwrap().unwrap();
+    assert_eq!(init.url, "https://cdn.example.com/vod/file.mp4");
+    assert_eq!(init.byte_range, Some(ByteRange { start: 0, end: 799 }));
+}
+
+#[test]
+fn test_sidx_segments() {
+    // version 0 sidx with two references
+    let mut sidx = Vec::new();
+    sidx.extend_from_slice(&52u32.to_be_bytes());
+    sidx.extend_from_slice(b"sidx");
+    sidx.extend_from_slice(&[0, 0, 0, 0]); // version + flags
+    sidx.extend_from_slice(&1u32.to_be_bytes()); // reference_ID
+    sidx.extend_from_slice(&1000u32.to_be_bytes()); // timescale
+    sidx.extend_from_slice(&0u32.to_be_bytes()); // earliest_presentation_time
+    sidx.extend_from_slice(&0u32.to_be_bytes()); // first_offset
+    sidx.extend_from_slice(&[0, 0, 0, 2]); // reserved + reference_count
+    for (size, duration) in [(1000u32, 2000u32), (500, 1500)] {
+        sidx.extend_from_slice(&size.to_be_bytes());
+        sidx.extend_from_slice(&duration.to_be_bytes());
+        sidx.extend_from_slice(&0x9000_0000u32.to_be_bytes());
+    }
+
+    let index_range = ByteRange {
+        start: 800,
+        end: 851,
+    };
+    let segments = sidx_segments(&sidx, index_range, "https://cdn.example.com/file.mp4").unwrap();
+    assert_eq!(segments.len(), 2);
+    assert_eq!(
+        segments[0].byte_range,
+        Some(ByteRange {
+            start: 852,
+            end: 1851
+        })
+    );
+    assert_eq!(
+        segments[1].byte_range,
+        Some(ByteRange {
+            start: 1852,
+            end: 2351
+        })
+    );
+    assert_eq!(segments[1].duration, 1.5);
+}
+
+#[test]
+fn test_sidx_zero_size_reference() {
+    let mut sidx = Vec::new();
+    sidx.extend_from_slice(&44u32.to_be_bytes());
+    sidx.extend_from_slice(b"sidx");
+    sidx.extend_from_slice(&[0, 0, 0, 0]); // version + flags
+    sidx.extend_from_slice(&1u32.to_be_bytes()); // reference_ID
+    sidx.extend_from_slice(&1000u32.to_be_bytes()); // timescale
+    sidx.extend_from_slice(&0u32.to_be_bytes()); // earliest_presentation_time
+    sidx.extend_from_slice(&0u32.to_be_bytes()); // first_offset
+    sidx.extend_from_slice(&[0, 0, 0, 1]); // reserved + reference_count
+    sidx.extend_from_slice(&0u32.to_be_bytes());
+    sidx.extend_from_slice(&1000u32.to_be_bytes());
+    sidx.extend_from_slice(&0x9000_0000u32.to_be_bytes());
+
+    let index_range = ByteRange { start: 0, end: 43 };
+    // The box itself would start at 0 with a size of 0
+    let mut at_zero = sidx.clone();
+    at_zero[..4].copy_from_slice(&0u32.to_be_bytes());
+    for data in [&sidx, &at_zero] {
+        assert!(sidx_segments(data, index_range, "https://cdn.example.com/file.mp4").is_err());
+    }
+}
+
+#[test]
+fn test_template_segment_count() {
+    let template = |duration: &str| {
+        format!(
+            r#"<MPD type="static" mediaPresentationDuration="PT1H">
+  <Period>
+    <AdaptationSet mimeType="video/mp4">
+      <SegmentTemplate timescale="1000" duration="{}" media="$Number$.m4s"/>
+      <Representation id="v" bandwidth="1000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#,
+            duration
+        )
+    };
+
+    for duration in ["0", "1"] {
+        let mpd = Mpd::parse(&template(duration), &mpd_url()).unwrap();
+        let rep = mpd.representation("v").unwrap();
+        assert!(mpd.segments(0, rep, 0.0).is_err());
+    }
+
+    let mpd = Mpd::parse(&template("2000"), &mpd_url()).unwrap();
+    let rep = mpd.representation("v").unwrap();
+    assert_eq!(mpd.segments(0, rep, 0.0).unwrap().len(), 1800);
+}
+
+#[test]
+fn test_live_number_segments() {
+    let content = r#"<MPD type="dynamic" availabilityStartTime="1970-01-01T00:00:00Z" timeShiftBufferDepth="PT20S">
+  <Period start="PT0S">
+    <AdaptationSet mimeType="video/mp4">
+      <SegmentTemplate timescale="1" duration="2" startNumber="1" media="$Number$.m4s"/>
+      <Representation id="v" bandwidth="1000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+    let mpd = Mpd::parse(content, &mpd_url()).unwrap();
+    assert!(mpd.is_live);
+
+    let rep = mpd.representation("v").unwrap();
+    let segments = mpd.segments(0, rep, 1000.0).unwrap();
+    assert_eq!(segments.len(), 10);
+    assert_eq!(segments.last().unwrap().number, 500);
+}
+
+#[test]
+fn test_passthrough_rewrite() {
+    let content = VOD_MPD.replace(
+        "  <BaseURL>media/</BaseURL>",
+        "  <Location>manifest.mpd?session=1&amp;x=2</Location>\n  <BaseURL>media/</BaseURL>",
+    );
+    let rewritten = mpd::rewrite_manifest(&content, &mpd_url(), &url_builder()).unwrap();
+    let document = roxmltree::Document::parse(&rewritten).unwrap();
+    let element = |name: &str| {
+        document
+            .descendants()
+            .find(|n| n.has_tag_name(name))
+            .unwrap()
+    };
+
+    let location = element("Location").text().unwrap();
+    assert!(location.starts_with("http://proxy:8888/proxy/mpd/manifest.mpd?"));
+    assert_eq!(
+        query_param(location, "d").as_deref(),
+        Some("https://cdn.example.com/vod/manifest.mpd?session=1&x=2")
+    );
+    let base_url = element("BaseURL").text().unwrap();
+    assert!(base_url.starts_with("http://proxy:8888/proxy/stream?"));
+    assert_eq!(
+        query_param(base_url, "d").as_deref(),
+        Some("https://cdn.example.com/vod/media/")
+    );
+
+    // Identifiers stay in the URL for the player to fill in
+    let media = element("SegmentTemplate").attribute("media").unwrap();
+    assert!(media.contains("&tpl0=$RepresentationID$&tpl1=$Number%05d$"));
+    let requested = media
+        .replace("$RepresentationID$", "v720")
+        .replace("$Number%05d$", "00003");
+    assert_eq!(
+        query_param(&requested, "api_password").as_deref(),
+        Some("secret")
+    );
+    let destination = query_param(&requested, "d").unwrap();
+    assert_eq!(
+        destination,
+        "https://cdn.example.com/vod/media/$RepresentationID$/seg-$Number%05d$.m4s"
+    );
+    let values = [
+        query_param(&requested, "tpl0").unwrap(),
+        query_param(&requested, "tpl1").unwrap(),
+    ];
+    assert_eq!(
+        mpd::expand_template(&destination, &[&values[0], &values[1]]).unwrap(),
+        "https://cdn.example.com/vod/media/v720/seg-00003.m4s"
+    );
+
+    // Templates without identifiers are plain proxied URLs
+    let audio_init = document
+        .descendants()
+        .filter(|n| n.has_tag_name("SegmentTemplate"))
+        .nth(1)
+        .unwrap()
+        .attribute("initialization")
+        .unwrap();
+    assert_eq!(
+        query_param(audio_init, "d").as_deref(),
+        Some("https://cdn.example.com/vod/media/audio/init.mp4")
+    );
+    assert_eq!(query_param(audio_init, "tpl0"), None);
+}
+
+#[test]
+fn test_expand_template() {
+    assert_eq!(
+        mpd::expand_template("https://cdn.example.com/$Time$-$$.m4s", &["96000"]).unwrap(),
+        "https://cdn.example.com/96000-$.m4s"
+    );
+    // Values cannot reach outside the authorized URL
+    assert!(mpd::expand_template("https://cdn.example.com/$Number$", &["1/../x"]).is_err());
+    assert!(mpd::expand_template("https://cdn.example.com/$Number$", &["@evil"]).is_err());
+    assert!(mpd::expand_template("https://cdn.example.com/$Number$", &["1", "2"]).is_err());
+}
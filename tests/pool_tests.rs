@@ -0,0 +1,107 @@
+use mediaflow_proxy_light::config::{ProxyConfig, ProxyRouteConfig, ProxyRouter};
+use std::collections::HashMap;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+fn proxy_config(transport_routes: HashMap<String, ProxyRouteConfig>) -> ProxyConfig {
+    ProxyConfig {
+        connect_timeout: 5,
+        buffer_size: 8192,
+        follow_redirects: true,
+        proxy_url: None,
+        all_proxy: false,
+        transport_routes,
+        manifest_cache: true,
+        pool_max_idle_per_host: 4,
+        pool_idle_timeout: 30,
+        head_fallback: true,
+        resume_retries: 3,
+        resume_backoff_ms: 500,
+    }
+}
+
+/// Minimal keep-alive HTTP/1.1 server answering every request with "ok".
+async fn serve() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buffer = vec![0u8; 4096];
+                let mut request = Vec::new();
+                loop {
+                    let n = match socket.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => n,
+                    };
+                    request.extend_from_slice(&buffer[..n]);
+                    while let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        request.drain(..end + 4);
+                        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                        if socket.write_all(response).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    format!("http://{}", address)
+}
+
+#[tokio::test]
+async fn test_connections_are_reused() {
+    let base_url = serve().await;
+    let router = ProxyRouter::from_config(&proxy_config(HashMap::new()));
+
+    for _ in 0..5 {
+        let client = router.get_client(&base_url).unwrap();
+        router.pool_stats().record_request();
+        let body = client
+            .get(&base_url)
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(body, "ok");
+    }
+
+    assert_eq!(router.pool_stats().requests(), 5);
+    assert_eq!(router.pool_stats().connections(), 1);
+    assert!((router.pool_stats().reuse_ratio() - 0.8).abs() < f64::EPSILON);
+}
+
+#[tokio::test]
+async fn test_routes_share_clients_by_settings() {
+    let routes = HashMap::from([
+        (
+            "all://*.example.com".to_string(),
+            ProxyRouteConfig {
+                proxy: false,
+                proxy_url: None,
+                verify_ssl: false,
+                prefetch: 0,
+            },
+        ),
+        (
+            "all://*.example.org".to_string(),
+            ProxyRouteConfig {
+                proxy: true,
+                proxy_url: Some("not a proxy url".to_string()),
+                verify_ssl: true,
+                prefetch: 0,
+            },
+        ),
+    ]);
+    let router = ProxyRouter::from_config(&proxy_config(routes));
+
+    assert!(router.get_client("https://cdn.example.com/a.ts").is_ok());
+    assert!(router.get_client("https://other.host/a.ts").is_ok());
+    // A route whose proxy cannot be configured fails instead of bypassing the proxy
+    assert!(router.get_client("https://cdn.example.org/a.ts").is_err());
+}
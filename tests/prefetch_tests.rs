@@ -0,0 +1,148 @@
+use futures::StreamExt;
+use mediaflow_proxy_light::config::{CacheConfig, ProxyConfig, ProxyRouteConfig};
+use mediaflow_proxy_light::proxy::prefetch::Prefetcher;
+use mediaflow_proxy_light::proxy::stream::StreamManager;
+use reqwest::header::HeaderMap;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+fn segments(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|i| format!("https://cdn.example.com/seg-{}.ts", i))
+        .collect()
+}
+
+#[test]
+fn test_prefetcher_positions() {
+    let prefetcher = Prefetcher::new();
+
+    // VOD players start at the beginning, live players at the newest segments
+    assert_eq!(prefetcher.playlist(segments(5), false, 2), segments(5)[..2]);
+    assert_eq!(prefetcher.playlist(segments(5), true, 2), segments(5)[3..]);
+    assert_eq!(prefetcher.len(), 5);
+
+    assert_eq!(
+        prefetcher.following("https://cdn.example.com/seg-1.ts", 2),
+        segments(5)[2..4]
+    );
+    assert_eq!(
+        prefetcher.following("https://cdn.example.com/seg-4.ts", 2),
+        Vec::<String>::new()
+    );
+    assert!(prefetcher
+        .following("https://cdn.example.com/other.ts", 2)
+        .is_empty());
+}
+
+/// Serve `/seg-N.ts` segments, recording the requested paths.
+async fn serve_segments() -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let recorded = requests.clone();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let recorded = recorded.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = vec![0u8; 4096];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match socket.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request);
+                let path = request.split(' ').nth(1).unwrap_or_default().to_string();
+                recorded.lock().unwrap().push(path.clone());
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: video/mp2t\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    path.len(),
+                    path
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+
+    (format!("http://{}", address), requests)
+}
+
+fn stream_manager(prefetch: usize) -> StreamManager {
+    StreamManager::new(
+        ProxyConfig {
+            connect_timeout: 5,
+            buffer_size: 8192,
+            follow_redirects: true,
+            proxy_url: None,
+            all_proxy: false,
+            transport_routes: HashMap::from([(
+                "all://127.0.0.1:*".to_string(),
+                ProxyRouteConfig {
+                    proxy: false,
+                    proxy_url: None,
+                    verify_ssl: true,
+                    prefetch,
+                },
+            )]),
+            manifest_cache: false,
+            pool_max_idle_per_host: 4,
+            pool_idle_timeout: 30,
+            head_fallback: true,
+            resume_retries: 3,
+            resume_backoff_ms: 500,
+        },
+        CacheConfig::default(),
+    )
+}
+
+async fn wait_for(requests: &Mutex<Vec<String>>, count: usize) {
+    for _ in 0..100 {
+        if requests.lock().unwrap().len() >= count {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_segments_are_prefetched() {
+    let (base, requests) = serve_segments().await;
+    let urls: Vec<String> = (0..5).map(|i| format!("{}/seg-{}.ts", base, i)).collect();
+    let manager = stream_manager(2);
+
+    manager.prefetch_playlist(urls.clone(), false, &HeaderMap::new());
+    wait_for(&requests, 2).await;
+
+    // The player's request for the first segment is served from the cache,
+    // and the segments after it are fetched ahead
+    manager.prefetch_after(&urls[0], &HeaderMap::new());
+    let upstream = manager
+        .create_stream(urls[0].clone(), HeaderMap::new(), false)
+        .await
+        .unwrap();
+    let body = upstream.body.unwrap().next().await.unwrap().unwrap();
+    assert_eq!(body, "/seg-0.ts");
+    wait_for(&requests, 3).await;
+
+    let mut requested = requests.lock().unwrap().clone();
+    requested.sort();
+    assert_eq!(requested, ["/seg-0.ts", "/seg-1.ts", "/seg-2.ts"]);
+}
+
+#[tokio::test]
+async fn test_no_prefetch_without_depth() {
+    let (base, requests) = serve_segments().await;
+    let urls: Vec<String> = (0..3).map(|i| format!("{}/seg-{}.ts", base, i)).collect();
+    let manager = stream_manager(0);
+
+    manager.prefetch_playlist(urls.clone(), false, &HeaderMap::new());
+    manager.prefetch_after(&urls[0], &HeaderMap::new());
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(requests.lock().unwrap().is_empty());
+}
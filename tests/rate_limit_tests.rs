@@ -0,0 +1,219 @@
+use actix_web::{test::TestRequest, web, App, HttpResponse};
+use futures::stream;
+use mediaflow_proxy_light::auth::encryption::{EncryptionHandler, ProxyData};
+use mediaflow_proxy_light::auth::middleware::AuthMiddleware;
+use mediaflow_proxy_light::config::{ApiKeyConfig, AuthConfig, RateLimitConfig, RateLimitKey};
+use mediaflow_proxy_light::proxy::rate_limit::RateLimiter;
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+async fn ok() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+/// A response whose body never ends.
+async fn endless() -> HttpResponse {
+    HttpResponse::Ok().streaming(stream::pending::<Result<web::Bytes, actix_web::Error>>())
+}
+
+/// 15000 bytes in three chunks.
+async fn large() -> HttpResponse {
+    let chunks = (0..3).map(|_| Ok::<_, actix_web::Error>(web::Bytes::from(vec![0u8; 5_000])));
+    HttpResponse::Ok().streaming(stream::iter(chunks))
+}
+
+fn auth_config() -> AuthConfig {
+    let key = |label: &str| ApiKeyConfig {
+        label: label.to_string(),
+        key: format!("{}-secret", label),
+        endpoints: Vec::new(),
+        hosts: Vec::new(),
+        expires_at: None,
+        max_streams: None,
+        max_bandwidth: None,
+    };
+    AuthConfig {
+        api_password: "secret".to_string(),
+        previous_passwords: Vec::new(),
+        legacy_tokens: false,
+        python_tokens: false,
+        keys: vec![key("alice"), key("bob")],
+    }
+}
+
+/// A token signed by `password`, bound to `ip` when given. Each one is
+/// encrypted with a fresh nonce, like the URLs rewritten by the proxy.
+fn token(password: &str, ip: Option<&str>) -> String {
+    EncryptionHandler::new(b"secret")
+        .unwrap()
+        .encrypt(&ProxyData {
+            destination: "https://cdn.example.com/video.mp4".to_string(),
+            query_params: Some(json!({ "api_password": password })),
+            request_headers: None,
+            response_headers: None,
+            exp: None,
+            ip: ip.map(str::to_string),
+            mirrors: Vec::new(),
+        })
+        .unwrap()
+}
+
+fn request(uri: &str, ip: &str) -> TestRequest {
+    TestRequest::get()
+        .uri(uri)
+        .insert_header(("x-forwarded-for", ip))
+}
+
+#[actix_web::test]
+async fn test_request_rate() {
+    let limiter = RateLimiter::new(RateLimitConfig {
+        requests_per_second: Some(1),
+        burst: Some(2),
+        ..Default::default()
+    });
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(limiter)
+            .route("/proxy/stream", web::get().to(ok))
+            .route("/health", web::get().to(ok)),
+    )
+    .await;
+
+    for _ in 0..2 {
+        let res =
+            actix_web::test::call_service(&app, request("/proxy/stream", "10.0.0.1").to_request())
+                .await;
+        assert_eq!(res.status(), 200);
+    }
+    let error =
+        actix_web::test::try_call_service(&app, request("/proxy/stream", "10.0.0.1").to_request())
+            .await
+            .unwrap_err();
+    let res = error.error_response();
+    assert_eq!(res.status(), 429);
+    assert_eq!(res.headers().get("retry-after").unwrap(), "1");
+
+    // Other clients and the health check are not affected
+    let res =
+        actix_web::test::call_service(&app, request("/proxy/stream", "10.0.0.2").to_request())
+            .await;
+    assert_eq!(res.status(), 200);
+    let res =
+        actix_web::test::call_service(&app, request("/health", "10.0.0.1").to_request()).await;
+    assert_eq!(res.status(), 200);
+}
+
+#[actix_web::test]
+async fn test_open_streams() {
+    let limiter = RateLimiter::new(RateLimitConfig {
+        key_by: RateLimitKey::Token,
+        max_streams: Some(1),
+        ..Default::default()
+    });
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(limiter)
+            .wrap(AuthMiddleware::new(&auth_config()))
+            .route("/proxy/stream", web::get().to(endless)),
+    )
+    .await;
+    let uri = |token: String| format!("/proxy/stream?token={}", token);
+
+    let first = actix_web::test::call_service(
+        &app,
+        request(&uri(token("alice-secret", None)), "10.0.0.1").to_request(),
+    )
+    .await;
+    assert_eq!(first.status(), 200);
+
+    // Another token of the same key is the same subject
+    let error = actix_web::test::try_call_service(
+        &app,
+        request(&uri(token("alice-secret", None)), "10.0.0.2").to_request(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(error.error_response().status(), 429);
+
+    // Tokens of other keys, or bound to a client IP, are different subjects
+    let other = actix_web::test::call_service(
+        &app,
+        request(&uri(token("bob-secret", None)), "10.0.0.1").to_request(),
+    )
+    .await;
+    assert_eq!(other.status(), 200);
+    let bound = actix_web::test::call_service(
+        &app,
+        request(&uri(token("alice-secret", Some("10.0.0.3"))), "10.0.0.3").to_request(),
+    )
+    .await;
+    assert_eq!(bound.status(), 200);
+
+    // Dropping the response closes the stream
+    drop(first);
+    let res = actix_web::test::call_service(
+        &app,
+        request(&uri(token("alice-secret", None)), "10.0.0.1").to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+}
+
+#[actix_web::test]
+async fn test_failed_authentication_is_limited() {
+    let limiter = RateLimiter::new(RateLimitConfig {
+        key_by: RateLimitKey::ApiKey,
+        requests_per_second: Some(1),
+        burst: Some(2),
+        ..Default::default()
+    });
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(limiter.clone())
+            .wrap(AuthMiddleware::new(&auth_config()))
+            .wrap(limiter.before_auth())
+            .route("/proxy/stream", web::get().to(ok)),
+    )
+    .await;
+
+    for _ in 0..2 {
+        let error = actix_web::test::try_call_service(
+            &app,
+            request("/proxy/stream?api_password=guess", "10.0.0.1").to_request(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.error_response().status(), 401);
+    }
+    let error = actix_web::test::try_call_service(
+        &app,
+        request("/proxy/stream?api_password=guess", "10.0.0.1").to_request(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(error.error_response().status(), 429);
+}
+
+#[actix_web::test]
+async fn test_client_bandwidth() {
+    let limiter = RateLimiter::new(RateLimitConfig {
+        max_bandwidth: Some(10_000),
+        ..Default::default()
+    });
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(limiter)
+            .route("/proxy/stream", web::get().to(large)),
+    )
+    .await;
+
+    // One second of burst, then 5000 bytes at 10000 bytes/s
+    let started = Instant::now();
+    let body = actix_web::test::call_and_read_body(
+        &app,
+        request("/proxy/stream", "10.0.0.1").to_request(),
+    )
+    .await;
+    assert_eq!(body.len(), 15_000);
+    assert!(started.elapsed() >= Duration::from_millis(450));
+}
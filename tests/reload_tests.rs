@@ -0,0 +1,163 @@
+use actix_web::{web, App, HttpResponse};
+use futures::stream;
+use mediaflow_proxy_light::auth::middleware::AuthMiddleware;
+use mediaflow_proxy_light::config::{Config, RateLimitConfig};
+use mediaflow_proxy_light::proxy::rate_limit::RateLimiter;
+use mediaflow_proxy_light::proxy::stream::StreamManager;
+use mediaflow_proxy_light::reload::ConfigReloader;
+use std::env;
+use std::fs;
+
+async fn ok() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+/// A response whose body never ends.
+async fn endless() -> HttpResponse {
+    HttpResponse::Ok().streaming(stream::pending::<Result<web::Bytes, actix_web::Error>>())
+}
+
+const CONFIG_BEFORE: &str = r#"
+[auth]
+api_password = "first"
+
+[proxy.transport_routes."all://*.example.com"]
+proxy = false
+verify_ssl = true
+"#;
+
+const CONFIG_AFTER: &str = r#"
+[auth]
+api_password = "second"
+
+[proxy.transport_routes."all://*.example.org"]
+proxy = false
+verify_ssl = true
+"#;
+
+#[actix_web::test]
+async fn test_reload_from_config_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(&path, CONFIG_BEFORE).unwrap();
+    env::set_var("CONFIG_PATH", &path);
+
+    let config = Config::from_env().unwrap();
+    let auth = AuthMiddleware::new(&config.auth);
+    let stream_manager = StreamManager::new(config.proxy.clone(), config.cache.clone());
+    let rate_limiter = RateLimiter::new(config.rate_limit.clone());
+    let reloader = ConfigReloader::new(stream_manager.clone(), auth.clone(), rate_limiter);
+    let app =
+        actix_web::test::init_service(App::new().wrap(auth).route("/proxy/ip", web::get().to(ok)))
+            .await;
+
+    let status = |password: &'static str| {
+        let request = actix_web::test::TestRequest::get()
+            .uri(&format!("/proxy/ip?api_password={}", password))
+            .to_request();
+        let app = &app;
+        async move {
+            match actix_web::test::try_call_service(app, request).await {
+                Ok(res) => res.status().as_u16(),
+                Err(e) => e.error_response().status().as_u16(),
+            }
+        }
+    };
+    assert_eq!(status("first").await, 200);
+    let router = stream_manager.proxy_router();
+    assert_eq!(
+        router.route_name("https://cdn.example.com/a.ts"),
+        "all://*.example.com"
+    );
+
+    fs::write(&path, CONFIG_AFTER).unwrap();
+    reloader.reload().unwrap();
+
+    assert_eq!(status("first").await, 401);
+    assert_eq!(status("second").await, 200);
+    assert_eq!(
+        stream_manager
+            .proxy_router()
+            .route_name("https://cdn.example.org/a.ts"),
+        "all://*.example.org"
+    );
+    // The router taken before the reload still works for the streams using it
+    assert!(router.get_client("https://cdn.example.com/a.ts").is_ok());
+
+    // An invalid configuration is not applied
+    fs::write(&path, "[auth\napi_password =").unwrap();
+    assert!(reloader.reload().is_err());
+    assert_eq!(status("second").await, 200);
+    env::remove_var("CONFIG_PATH");
+}
+
+#[actix_web::test]
+async fn test_reload_rate_limits() {
+    let limiter = RateLimiter::new(RateLimitConfig {
+        requests_per_second: Some(1),
+        burst: Some(1),
+        ..Default::default()
+    });
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(limiter.clone())
+            .route("/proxy/stream", web::get().to(ok)),
+    )
+    .await;
+    let request = || {
+        actix_web::test::TestRequest::get()
+            .uri("/proxy/stream")
+            .insert_header(("x-forwarded-for", "10.0.0.1"))
+            .to_request()
+    };
+
+    let res = actix_web::test::call_service(&app, request()).await;
+    assert_eq!(res.status(), 200);
+    assert!(actix_web::test::try_call_service(&app, request())
+        .await
+        .is_err());
+
+    limiter.reload(RateLimitConfig {
+        requests_per_second: Some(100),
+        burst: Some(100),
+        ..Default::default()
+    });
+    for _ in 0..10 {
+        let res = actix_web::test::call_service(&app, request()).await;
+        assert_eq!(res.status(), 200);
+    }
+}
+
+#[actix_web::test]
+async fn test_open_streams_survive_rate_limit_reload() {
+    let limits = || RateLimitConfig {
+        max_streams: Some(1),
+        ..Default::default()
+    };
+    let limiter = RateLimiter::new(limits());
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(limiter.clone())
+            .route("/proxy/stream", web::get().to(endless)),
+    )
+    .await;
+    let request = || {
+        actix_web::test::TestRequest::get()
+            .uri("/proxy/stream")
+            .insert_header(("x-forwarded-for", "10.0.0.1"))
+            .to_request()
+    };
+
+    let open = actix_web::test::call_service(&app, request()).await;
+    assert_eq!(open.status(), 200);
+
+    limiter.reload(limits());
+    let error = actix_web::test::try_call_service(&app, request())
+        .await
+        .unwrap_err();
+    assert_eq!(error.error_response().status(), 429);
+
+    drop(open);
+    let res = actix_web::test::call_service(&app, request()).await;
+    assert_eq!(res.status(), 200);
+}
@@ -0,0 +1,434 @@
+use mediaflow_proxy_light::proxy::mp4::{parse_boxes, Mp4Box};
+use mediaflow_proxy_light::proxy::remux::TsRemuxer;
+use mediaflow_proxy_light::proxy::stream::SegmentProcessor;
+use mediaflow_proxy_light::proxy::ts::{self, STREAM_TYPE_AAC, STREAM_TYPE_H264, STREAM_TYPE_H265};
+
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x100;
+const AUDIO_PID: u16 = 0x101;
+
+/// Writes parameter set fields, Exp-Golomb codes included.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u64, count: usize) -> &mut Self {
+        for i in (0..count).rev() {
+            if self.bits.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if (value >> i) & 1 == 1 {
+                *self.bytes.last_mut().unwrap() |= 0x80 >> (self.bits % 8);
+            }
+            self.bits += 1;
+        }
+        self
+    }
+
+    fn ue(&mut self, value: u64) -> &mut Self {
+        let code = value + 1;
+        let length = 64 - code.leading_zeros() as usize;
+        self.bits(0, length - 1).bits(code, length)
+    }
+
+    /// RBSP stop bit and alignment, then emulation prevention bytes.
+    fn finish(&mut self) -> Vec<u8> {
+        self.bits(1, 1);
+        let mut output = Vec::new();
+        let mut zeros = 0;
+        for &byte in &self.bytes {
+            if zeros >= 2 && byte <= 3 {
+                output.push(3);
+                zeros = 0;
+            }
+            zeros = if byte == 0 { zeros + 1 } else { 0 };
+            output.push(byte);
+        }
+        output
+    }
+}
+
+/// Baseline H.264 SPS for 640x360: 40x23 macroblocks cropped by 8 lines.
+fn h264_sps() -> Vec<u8> {
+    let mut sps = vec![0x67];
+    let mut writer = BitWriter::default();
+    writer.bits(66, 8).bits(0xc0, 8).bits(30, 8);
+    writer.ue(0).ue(0).ue(0).ue(0).ue(1).bits(0, 1);
+    writer.ue(39).ue(22).bits(1, 1).bits(1, 1);
+    writer.bits(1, 1).ue(0).ue(0).ue(0).ue(4).bits(0, 1);
+    sps.extend(writer.finish());
+    sps
+}
+
+/// Main profile H.265 SPS for 1920x1080.
+fn h265_sps() -> Vec<u8> {
+    let mut sps = vec![0x42, 0x01];
+    let mut writer = BitWriter::default();
+    writer.bits(0, 4).bits(0, 3).bits(1, 1);
+    writer
+        .bits(0x01, 8)
+        .bits(0x6000_0000, 32)
+        .bits(0x9000_0000_0000, 48)
+        .bits(93, 8);
+    writer.ue(0).ue(1).ue(1920).ue(1080).bits(0, 1).ue(0).ue(0);
+    sps.extend(writer.finish());
+    sps
+}
+
+fn annex_b(units: &[&[u8]]) -> Vec<u8> {
+    units
+        .iter()
+        .flat_map(|unit| [&[0, 0, 0, 1][..], unit].concat())
+        .collect()
+}
+
+fn timestamp(prefix: u8, ts: u64) -> [u8; 5] {
+    [
+        (prefix << 4) | (((ts >> 29) & 0x0e) as u8) | 1,
+        (ts >> 22) as u8,
+        (((ts >> 14) & 0xfe) as u8) | 1,
+        (ts >> 7) as u8,
+        (((ts << 1) & 0xfe) as u8) | 1,
+    ]
+}
+
+fn pes(stream_id: u8, pts: u64, dts: Option<u64>, payload: &[u8]) -> Vec<u8> {
+    let mut header = vec![0x80];
+    match dts {
+        Some(dts) => {
+            header.extend_from_slice(&[0xc0, 10]);
+            header.extend_from_slice(&timestamp(3, pts));
+            header.extend_from_slice(&timestamp(1, dts));
+        }
+        None => {
+            header.extend_from_slice(&[0x80, 5]);
+            header.extend_from_slice(&timestamp(2, pts));
+        }
+    }
+    // Video packets are unbounded
+    let length = if stream_id == 0xe0 {
+        0
+    } else {
+        header.len() + payload.len()
+    };
+    let mut packet = vec![0, 0, 1, stream_id];
+    packet.extend_from_slice(&(length as u16).to_be_bytes());
+    packet.extend(header);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Split a PES packet or section into transport packets, stuffing the last one.
+fn packets(pid: u16, payload: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    for (index, chunk) in payload.chunks(184).enumerate() {
+        let unit_start = if index == 0 { 0x40 } else { 0 };
+        output.extend_from_slice(&[0x47, unit_start | (pid >> 8) as u8, pid as u8]);
+        if chunk.len() == 184 {
+            output.push(0x10);
+        } else {
+            let stuffing = 183 - chunk.len();
+            output.push(0x30);
+            output.push(stuffing as u8);
+            if stuffing > 0 {
+                output.push(0x00);
+                output.extend(std::iter::repeat_n(0xff, stuffing - 1));
+            }
+        }
+        output.extend_from_slice(chunk);
+    }
+    output
+}
+
+fn section(table_id: u8, body: &[u8]) -> Vec<u8> {
+    let length = body.len() + 4;
+    let mut section = vec![0, table_id, 0xb0 | (length >> 8) as u8, length as u8];
+    section.extend_from_slice(body);
+    section.extend_from_slice(&[0; 4]);
+    section
+}
+
+fn segment(video_type: u8, video: &[(u64, u64, Vec<u8>)], audio: &[(u64, Vec<u8>)]) -> Vec<u8> {
+    let mut data = packets(
+        0,
+        &section(0x00, &[0, 1, 0xc1, 0, 0, 0, 1, 0xf0, PMT_PID as u8]),
+    );
+    data.extend(packets(
+        PMT_PID,
+        &section(
+            0x02,
+            &[
+                0,
+                1,
+                0xc1,
+                0,
+                0,
+                0xe1,
+                0x00,
+                0xf0,
+                0x00,
+                video_type,
+                0xe1,
+                VIDEO_PID as u8,
+                0xf0,
+                0x00,
+                STREAM_TYPE_AAC,
+                0xe1,
+                AUDIO_PID as u8,
+                0xf0,
+                0x00,
+            ],
+        ),
+    ));
+    for (pts, dts, access_unit) in video {
+        data.extend(packets(
+            VIDEO_PID,
+            &pes(0xe0, *pts, Some(*dts), access_unit),
+        ));
+    }
+    for (pts, frames) in audio {
+        data.extend(packets(AUDIO_PID, &pes(0xc0, *pts, None, frames)));
+    }
+    data
+}
+
+/// An AAC-LC 48 kHz stereo ADTS frame.
+fn adts_frame(payload: &[u8]) -> Vec<u8> {
+    let length = payload.len() + 7;
+    let mut frame = vec![
+        0xff,
+        0xf1,
+        (1 << 6) | (3 << 2),
+        (2 << 6) | (length >> 11) as u8,
+        (length >> 3) as u8,
+        ((length & 0x07) << 5) as u8 | 0x1f,
+        0xfc,
+    ];
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn h264_segment() -> Vec<u8> {
+    let sps = h264_sps();
+    let pps = [0x68, 0xce, 0x3c, 0x80];
+    let aud = [0x09, 0xf0];
+    let idr = [0x65, 0x88, 0x84, 0x00, 0x33];
+    let slice = [0x41, 0x9a, 0x02, 0x04];
+    let big_slice: Vec<u8> = [0x41]
+        .into_iter()
+        .chain(std::iter::repeat_n(0x5a, 400))
+        .collect();
+
+    segment(
+        STREAM_TYPE_H264,
+        &[
+            (900_000 + 6000, 900_000, annex_b(&[&aud, &sps, &pps, &idr])),
+            (900_000, 903_000, annex_b(&[&aud, &slice])),
+            (900_000 + 9000, 906_000, annex_b(&[&aud, &big_slice])),
+        ],
+        &[(
+            900_000,
+            [adts_frame(&[0x21; 10]), adts_frame(&[0x22; 12])].concat(),
+        )],
+    )
+}
+
+fn child<'a>(parent: &'a Mp4Box, path: &[&[u8; 4]]) -> &'a Mp4Box {
+    parent
+        .find(path)
+        .unwrap_or_else(|| panic!("missing {:?}", path))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[test]
+fn test_demux() {
+    let streams = ts::demux(&h264_segment()).unwrap();
+    assert_eq!(streams.len(), 2);
+    assert_eq!(streams[0].stream_type, STREAM_TYPE_H264);
+    assert_eq!(streams[0].packets.len(), 3);
+    assert_eq!(streams[0].packets[0].pts, 906_000);
+    assert_eq!(streams[0].packets[0].dts, 900_000);
+    assert_eq!(streams[1].packets[0].pts, 900_000);
+    assert_eq!(streams[1].packets[0].dts, 900_000);
+
+    assert!(ts::demux(b"not a transport stream").is_err());
+}
+
+#[test]
+fn test_h264_init_segment() {
+    let init = TsRemuxer::InitSegment
+        .process(h264_segment().into())
+        .unwrap();
+    let boxes = parse_boxes(&init).unwrap();
+    assert!(boxes[0].is(b"ftyp"));
+    let moov = &boxes[1];
+    assert_eq!(moov.children_of(b"trak").count(), 2);
+    assert_eq!(child(moov, &[b"mvex"]).children_of(b"trex").count(), 2);
+
+    let video = moov.children_of(b"trak").next().unwrap();
+    let tkhd = child(video, &[b"tkhd"]);
+    assert_eq!(u32_at(&tkhd.payload, 12), 1);
+    // Width and height as 16.16 fixed point, from the SPS with its cropping
+    assert_eq!(u32_at(&tkhd.payload, 76) >> 16, 640);
+    assert_eq!(u32_at(&tkhd.payload, 80) >> 16, 360);
+
+    let stsd = child(video, &[b"mdia", b"minf", b"stbl", b"stsd"]);
+    let avc1 = &stsd.children[0];
+    assert!(avc1.is(b"avc1"));
+    let avcc = &parse_boxes(&avc1.payload[78..]).unwrap()[0];
+    assert!(avcc.is(b"avcC"));
+    assert_eq!(&avcc.payload[..5], &[1, 66, 0xc0, 30, 0xff]);
+    assert_eq!(avcc.payload[5], 0xe1);
+
+    let audio = moov.children_of(b"trak").nth(1).unwrap();
+    let mdhd = child(audio, &[b"mdia", b"mdhd"]);
+    assert_eq!(u32_at(&mdhd.payload, 12), 48_000);
+    let stsd = child(audio, &[b"mdia", b"minf", b"stbl", b"stsd"]);
+    let mp4a = &stsd.children[0];
+    assert!(mp4a.is(b"mp4a"));
+    // AAC-LC, 48 kHz, stereo
+    let esds = &parse_boxes(&mp4a.payload[28..]).unwrap()[0];
+    assert!(esds.is(b"esds"));
+    assert!(esds
+        .payload
+        .windows(4)
+        .any(|w| w == [0x05, 0x02, 0x11, 0x90]));
+}
+
+#[test]
+fn test_h264_media_segment() {
+    let fragment = TsRemuxer::MediaSegment { sequence: 7 }
+        .process(h264_segment().into())
+        .unwrap();
+    let boxes = parse_boxes(&fragment).unwrap();
+    let (moof, mdat) = (&boxes[0], &boxes[1]);
+    assert!(mdat.is(b"mdat"));
+    assert_eq!(u32_at(&child(moof, &[b"mfhd"]).payload, 4), 7);
+
+    let trafs: Vec<&Mp4Box> = moof.children_of(b"traf").collect();
+    assert_eq!(trafs.len(), 2);
+
+    let video = trafs[0];
+    assert_eq!(u32_at(&child(video, &[b"tfhd"]).payload, 4), 1);
+    let tfdt = &child(video, &[b"tfdt"]).payload;
+    assert_eq!(u64::from_be_bytes(tfdt[4..12].try_into().unwrap()), 900_000);
+
+    let trun = &child(video, &[b"trun"]).payload;
+    assert_eq!(u32_at(trun, 4), 3);
+    let data_offset = u32_at(trun, 8) as usize;
+    let samples: Vec<[u32; 4]> = (0..3)
+        .map(|i| std::array::from_fn(|field| u32_at(trun, 12 + i * 16 + field * 4)))
+        .collect();
+    // Durations from the decode timestamps, the last one repeating the one before
+    assert_eq!(
+        samples.iter().map(|s| s[0]).collect::<Vec<_>>(),
+        [3000, 3000, 3000]
+    );
+    // Only the IDR picture is a sync sample
+    assert_eq!(samples[0][2], 0x0200_0000);
+    assert_eq!(samples[1][2], 0x0101_0000);
+    // Composition offsets, signed
+    assert_eq!(samples[0][3], 6000);
+    assert_eq!(samples[1][3] as i32, -3000);
+    assert_eq!(samples[2][3], 3000);
+
+    // Length-prefixed slices, without delimiters and parameter sets
+    let first = &fragment[data_offset..data_offset + samples[0][1] as usize];
+    assert_eq!(first, &[0, 0, 0, 5, 0x65, 0x88, 0x84, 0x00, 0x33]);
+    assert_eq!(samples[2][1], 4 + 401);
+
+    let audio = trafs[1];
+    let tfdt = &child(audio, &[b"tfdt"]).payload;
+    // 10 s in the 48 kHz timescale
+    assert_eq!(u64::from_be_bytes(tfdt[4..12].try_into().unwrap()), 480_000);
+    let trun = &child(audio, &[b"trun"]).payload;
+    assert_eq!(u32_at(trun, 4), 2);
+    assert_eq!(u32_at(trun, 12), 1024);
+    assert_eq!(u32_at(trun, 16), 10);
+    let data_offset = u32_at(trun, 8) as usize;
+    assert_eq!(&fragment[data_offset..data_offset + 10], &[0x21; 10]);
+}
+
+#[test]
+fn test_h265_init_segment() {
+    let vps = [0x40, 0x01, 0x0c, 0x01, 0xff, 0xff];
+    let sps = h265_sps();
+    let pps = [0x44, 0x01, 0xc1, 0x72];
+    let idr = [0x26, 0x01, 0xaf, 0x06];
+    let data = segment(
+        STREAM_TYPE_H265,
+        &[(900_000, 900_000, annex_b(&[&vps, &sps, &pps, &idr]))],
+        &[],
+    );
+
+    let init = TsRemuxer::InitSegment.process(data.clone().into());
+    // The program lists an audio stream without any frame
+    assert!(init.is_err());
+
+    let fragment = TsRemuxer::MediaSegment { sequence: 1 }
+        .process(data.into())
+        .unwrap();
+    let boxes = parse_boxes(&fragment).unwrap();
+    let traf = child(&boxes[0], &[b"traf"]);
+    let trun = &child(traf, &[b"trun"]).payload;
+    assert_eq!(u32_at(trun, 4), 1);
+    // IRAP pictures are sync samples
+    assert_eq!(u32_at(trun, 20), 0x0200_0000);
+
+    let mut data = packets(0, &section(0x00, &[0, 1, 0xc1, 0, 0, 0, 1, 0xf0, 0x00]));
+    data.extend(packets(
+        PMT_PID,
+        &section(
+            0x02,
+            &[
+                0,
+                1,
+                0xc1,
+                0,
+                0,
+                0xe1,
+                0x00,
+                0xf0,
+                0x00,
+                STREAM_TYPE_H265,
+                0xe1,
+                VIDEO_PID as u8,
+                0xf0,
+                0x00,
+            ],
+        ),
+    ));
+    data.extend(packets(
+        VIDEO_PID,
+        &pes(
+            0xe0,
+            900_000,
+            Some(900_000),
+            &annex_b(&[&vps, &sps, &pps, &idr]),
+        ),
+    ));
+    let init = TsRemuxer::InitSegment.process(data.into()).unwrap();
+    let moov = &parse_boxes(&init).unwrap()[1];
+    let trak = child(moov, &[b"trak"]);
+    let tkhd = child(trak, &[b"tkhd"]);
+    assert_eq!(u32_at(&tkhd.payload, 76) >> 16, 1920);
+    assert_eq!(u32_at(&tkhd.payload, 80) >> 16, 1080);
+
+    let stsd = child(trak, &[b"mdia", b"minf", b"stbl", b"stsd"]);
+    let entry = &stsd.children[0];
+    assert!(entry.is(b"hvc1"));
+    let hvcc = &parse_boxes(&entry.payload[78..]).unwrap()[0];
+    assert!(hvcc.is(b"hvcC"));
+    // Main profile, level 3.1
+    assert_eq!(hvcc.payload[1], 0x01);
+    assert_eq!(hvcc.payload[12], 93);
+    // VPS, SPS and PPS arrays
+    assert_eq!(hvcc.payload[22], 3);
+    assert_eq!(hvcc.payload[23], 0x80 | 32);
+}
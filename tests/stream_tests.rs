@@ -0,0 +1,661 @@
+use actix_web::{web, App, ResponseError};
+use futures::StreamExt;
+use mediaflow_proxy_light::auth::middleware::AuthMiddleware;
+use mediaflow_proxy_light::config::{
+    AuthConfig, CacheConfig, DiskCacheConfig, EvictionPolicy, ProxyConfig,
+};
+use mediaflow_proxy_light::error::AppError;
+use mediaflow_proxy_light::proxy::handler;
+use mediaflow_proxy_light::proxy::stream::StreamManager;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+fn proxy_config(head_fallback: bool) -> ProxyConfig {
+    ProxyConfig {
+        connect_timeout: 5,
+        buffer_size: 8192,
+        follow_redirects: true,
+        proxy_url: None,
+        all_proxy: false,
+        transport_routes: HashMap::new(),
+        manifest_cache: false,
+        pool_max_idle_per_host: 4,
+        pool_idle_timeout: 30,
+        head_fallback,
+        resume_retries: 3,
+        resume_backoff_ms: 500,
+    }
+}
+
+fn stream_manager(head_fallback: bool) -> StreamManager {
+    StreamManager::new(
+        proxy_config(head_fallback),
+        CacheConfig {
+            enabled: false,
+            ..Default::default()
+        },
+    )
+}
+
+/// Serve each request with `respond(request_head)`, closing the connection afterwards.
+async fn serve<F>(respond: F) -> String
+where
+    F: Fn(&str) -> String + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let respond = Arc::new(respond);
+
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let respond = respond.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = vec![0u8; 4096];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match socket.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                let response = respond(&String::from_utf8_lossy(&request));
+                let _ = socket.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+
+    format!("http://{}/video.mp4", address)
+}
+
+#[tokio::test]
+async fn test_head_is_sent_upstream() {
+    let gets = Arc::new(AtomicUsize::new(0));
+    let counter = gets.clone();
+    let url = serve(move |request| {
+        if request.starts_with("HEAD ") {
+            "HTTP/1.1 200 OK\r\nContent-Length: 1234\r\nContent-Type: video/mp4\r\nConnection: close\r\n\r\n".to_string()
+        } else {
+            counter.fetch_add(1, Ordering::SeqCst);
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        }
+    })
+    .await;
+
+    let response = stream_manager(true)
+        .create_stream(url, HeaderMap::new(), true)
+        .await
+        .unwrap();
+
+    assert!(response.body.is_none());
+    assert_eq!(response.headers.get(CONTENT_LENGTH).unwrap(), "1234");
+    assert_eq!(gets.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn test_rejected_head_falls_back_to_ranged_get() {
+    let url = serve(|request| {
+        if request.starts_with("HEAD ") {
+            "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string()
+        } else if request.to_ascii_lowercase().contains("range: bytes=0-0") {
+            "HTTP/1.1 206 Partial Content\r\nContent-Length: 1\r\nContent-Range: bytes 0-0/1234\r\nContent-Type: video/mp4\r\nConnection: close\r\n\r\nx".to_string()
+        } else {
+            "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string()
+        }
+    })
+    .await;
+
+    let response = stream_manager(true)
+        .create_stream(url.clone(), HeaderMap::new(), true)
+        .await
+        .unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.headers.get(CONTENT_LENGTH).unwrap(), "1234");
+    assert!(response.headers.get(CONTENT_RANGE).is_none());
+    assert_eq!(response.headers.get("content-type").unwrap(), "video/mp4");
+
+    assert!(stream_manager(false)
+        .create_stream(url, HeaderMap::new(), true)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_missing_resource_is_not_retried_as_get() {
+    let gets = Arc::new(AtomicUsize::new(0));
+    let counter = gets.clone();
+    let url = serve(move |request| {
+        if request.starts_with("HEAD ") {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string()
+        } else {
+            counter.fetch_add(1, Ordering::SeqCst);
+            "HTTP/1.1 206 Partial Content\r\nContent-Length: 1\r\nContent-Range: bytes 0-0/1234\r\nConnection: close\r\n\r\nx".to_string()
+        }
+    })
+    .await;
+
+    let error = match stream_manager(true)
+        .create_stream(url, HeaderMap::new(), true)
+        .await
+    {
+        Err(error) => error,
+        Ok(_) => panic!("expected the HEAD status"),
+    };
+    assert!(matches!(
+        error,
+        AppError::UpstreamStatus { status: 404, .. }
+    ));
+    assert_eq!(gets.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn test_partial_content_is_passed_through() {
+    let url = serve(|request| {
+        if request.to_ascii_lowercase().contains("range: bytes=2-4") {
+            "HTTP/1.1 206 Partial Content\r\nContent-Length: 3\r\nContent-Range: bytes 2-4/10\r\nConnection: close\r\n\r\ncde".to_string()
+        } else {
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\nConnection: close\r\n\r\nabcdefghij".to_string()
+        }
+    })
+    .await;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(RANGE, HeaderValue::from_static("bytes=2-4"));
+    let response = stream_manager(true)
+        .create_stream(url, headers, false)
+        .await
+        .unwrap();
+
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers.get(CONTENT_RANGE).unwrap(), "bytes 2-4/10");
+    let mut body = Vec::new();
+    let mut stream = response.body.unwrap();
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(&chunk.unwrap());
+    }
+    assert_eq!(body, b"cde");
+}
+
+#[tokio::test]
+async fn test_bodyless_statuses_are_passed_through() {
+    let url = serve(|request| {
+        if request.to_ascii_lowercase().contains("if-none-match") {
+            "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n".to_string()
+        } else {
+            "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */10\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        }
+    })
+    .await;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(RANGE, HeaderValue::from_static("bytes=20-"));
+    let response = stream_manager(true)
+        .create_stream(url.clone(), headers, false)
+        .await
+        .unwrap();
+    assert_eq!(response.status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(response.headers.get(CONTENT_RANGE).unwrap(), "bytes */10");
+    assert!(response.body.is_none());
+
+    let mut headers = HeaderMap::new();
+    headers.insert("if-none-match", HeaderValue::from_static("\"v1\""));
+    let response = stream_manager(true)
+        .create_stream(url, headers, false)
+        .await
+        .unwrap();
+    assert_eq!(response.status, StatusCode::NOT_MODIFIED);
+    assert!(response.body.is_none());
+}
+
+#[tokio::test]
+async fn test_upstream_errors_keep_their_status() {
+    let url = serve(|_| {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    })
+    .await;
+
+    let error = match stream_manager(true)
+        .create_stream(url, HeaderMap::new(), false)
+        .await
+    {
+        Err(error) => error,
+        Ok(_) => panic!("expected an upstream error"),
+    };
+    assert!(matches!(
+        error,
+        AppError::UpstreamStatus { status: 404, .. }
+    ));
+    assert_eq!(error.error_response().status().as_u16(), 404);
+
+    let error = AppError::UpstreamStatus {
+        status: 401,
+        message: "Unauthorized".to_string(),
+    };
+    assert_eq!(error.error_response().status().as_u16(), 502);
+}
+
+#[tokio::test]
+async fn test_cached_segment_is_revalidated_locally() {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    let url = serve(move |_| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        "HTTP/1.1 200 OK\r\nContent-Type: video/mp2t\r\nContent-Length: 4\r\nETag: \"v1\"\r\nConnection: close\r\n\r\ndata"
+            .to_string()
+    })
+    .await;
+    let stream_manager = StreamManager::new(proxy_config(true), CacheConfig::default());
+
+    let mut body = Vec::new();
+    let mut stream = stream_manager
+        .create_stream(url.clone(), HeaderMap::new(), false)
+        .await
+        .unwrap()
+        .body
+        .unwrap();
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(&chunk.unwrap());
+    }
+    assert_eq!(body, b"data");
+
+    let mut headers = HeaderMap::new();
+    headers.insert("if-none-match", HeaderValue::from_static("\"v1\""));
+    let response = stream_manager
+        .create_stream(url.clone(), headers, false)
+        .await
+        .unwrap();
+    assert_eq!(response.status, StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers.get("etag").unwrap(), "\"v1\"");
+    assert!(response.body.is_none());
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    let mut headers = HeaderMap::new();
+    headers.insert("if-none-match", HeaderValue::from_static("\"v0\""));
+    let response = stream_manager
+        .create_stream(url, headers, false)
+        .await
+        .unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.body.is_some());
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
+
+/// Upstream serving "0123456789" with an ETag, breaking off every body after four bytes.
+fn flaky_upstream(request: &str) -> String {
+    let request = request.to_ascii_lowercase();
+    let body = "0123456789";
+    let range = request
+        .lines()
+        .find_map(|line| line.strip_prefix("range: bytes="))
+        .and_then(|range| range.trim().split_once('-'))
+        .map(|(start, end)| {
+            let start: usize = start.parse().unwrap();
+            let end: usize = end.parse().unwrap_or(body.len() - 1);
+            (start, end)
+        });
+    let same_entity = request
+        .lines()
+        .find_map(|line| line.strip_prefix("if-range: "))
+        .is_none_or(|etag| etag.trim() == "\"v1\"");
+
+    let (status, content_range, content) = match range.filter(|_| same_entity) {
+        Some((start, end)) => (
+            "206 Partial Content",
+            format!("Content-Range: bytes {}-{}/{}\r\n", start, end, body.len()),
+            &body[start..=end],
+        ),
+        None => ("200 OK", String::new(), body),
+    };
+    let sent = &content[..content.len().min(4)];
+    format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\n{}ETag: \"v1\"\r\nConnection: close\r\n\r\n{}",
+        status,
+        content.len(),
+        content_range,
+        sent
+    )
+}
+
+fn resuming_stream_manager() -> StreamManager {
+    StreamManager::new(
+        ProxyConfig {
+            resume_backoff_ms: 10,
+            ..proxy_config(true)
+        },
+        CacheConfig {
+            enabled: false,
+            ..Default::default()
+        },
+    )
+}
+
+async fn read_body(
+    response: mediaflow_proxy_light::proxy::stream::UpstreamResponse,
+) -> (String, Option<AppError>) {
+    let mut body = response.body.unwrap();
+    let mut received = String::new();
+    while let Some(chunk) = body.next().await {
+        match chunk {
+            Ok(chunk) => received.push_str(std::str::from_utf8(&chunk).unwrap()),
+            Err(e) => return (received, Some(e)),
+        }
+    }
+    (received, None)
+}
+
+#[tokio::test]
+async fn test_broken_body_is_resumed() {
+    let url = serve(flaky_upstream).await;
+    let response = resuming_stream_manager()
+        .create_stream(url.clone(), HeaderMap::new(), false)
+        .await
+        .unwrap();
+    let (body, error) = read_body(response).await;
+    assert!(error.is_none());
+    assert_eq!(body, "0123456789");
+
+    // A client range is resumed within its bounds
+    let mut headers = HeaderMap::new();
+    headers.insert(RANGE, HeaderValue::from_static("bytes=1-8"));
+    let response = resuming_stream_manager()
+        .create_stream(url, headers, false)
+        .await
+        .unwrap();
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    let (body, error) = read_body(response).await;
+    assert!(error.is_none());
+    assert_eq!(body, "12345678");
+}
+
+#[tokio::test]
+async fn test_changed_entity_is_not_resumed() {
+    let resumes = Arc::new(AtomicUsize::new(0));
+    let counter = resumes.clone();
+    let url = serve(move |request| {
+        if request.to_ascii_lowercase().contains("if-range") {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }
+        // The ETag changed since the first response
+        flaky_upstream(request).replacen("\"v1\"", "\"v2\"", 1)
+    })
+    .await;
+
+    let response = resuming_stream_manager()
+        .create_stream(url, HeaderMap::new(), false)
+        .await
+        .unwrap();
+    let (body, error) = read_body(response).await;
+    assert_eq!(body, "0123");
+    assert!(matches!(error, Some(AppError::Upstream(_))));
+    assert_eq!(resumes.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_failover_to_mirrors() {
+    let failing = serve(|_| {
+        "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_string()
+    })
+    .await;
+    let missing = serve(|_| {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    })
+    .await;
+    let mirror = serve(|_| {
+        "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok".to_string()
+    })
+    .await;
+    // Nothing listens there once the listener is dropped
+    let unreachable = {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        format!("http://{}/video.mp4", listener.local_addr().unwrap())
+    };
+
+    let manager = stream_manager(true);
+    let (served_by, response) = manager
+        .create_stream_with_mirrors(
+            unreachable,
+            &[failing.clone(), mirror.clone()],
+            HeaderMap::new(),
+            false,
+        )
+        .await
+        .unwrap();
+    assert_eq!(served_by, mirror);
+    let (body, error) = read_body(response).await;
+    assert!(error.is_none());
+    assert_eq!(body, "ok");
+
+    // A resource missing upstream is not looked up on the mirrors
+    let error = manager
+        .create_stream_with_mirrors(missing, &[mirror], HeaderMap::new(), false)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(error.error_response().status(), 404);
+
+    // Without a working destination, the last failure is reported
+    let error = manager
+        .create_stream_with_mirrors(failing, &[], HeaderMap::new(), false)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(error.error_response().status(), 503);
+}
+
+#[tokio::test]
+async fn test_broken_body_is_resumed_from_mirror() {
+    // The origin breaks off and then refuses range requests, the mirror has the same entity
+    let origin = serve(|request| {
+        if request.to_ascii_lowercase().contains("if-range") {
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string()
+        } else {
+            flaky_upstream(request)
+        }
+    })
+    .await;
+    let mirror = serve(flaky_upstream).await;
+
+    let (served_by, response) = resuming_stream_manager()
+        .create_stream_with_mirrors(origin.clone(), &[mirror], HeaderMap::new(), false)
+        .await
+        .unwrap();
+    assert_eq!(served_by, origin);
+    let (body, error) = read_body(response).await;
+    assert!(error.is_none());
+    assert_eq!(body, "0123456789");
+}
+
+#[actix_web::test]
+async fn test_unsatisfiable_range_keeps_content_range() {
+    let url = serve(|_| {
+        "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */10\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    })
+    .await;
+    let auth = AuthConfig {
+        api_password: "secret".to_string(),
+        previous_passwords: Vec::new(),
+        legacy_tokens: true,
+        python_tokens: false,
+        keys: Vec::new(),
+    };
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(AuthMiddleware::new(&auth))
+            .app_data(web::Data::new(stream_manager(true)))
+            .route("/proxy/stream", web::get().to(handler::proxy_stream_get)),
+    )
+    .await;
+
+    let request = actix_web::test::TestRequest::get()
+        .uri(&format!(
+            "/proxy/stream?api_password=secret&d={}",
+            urlencoding::encode(&url)
+        ))
+        .insert_header(("range", "bytes=20-"))
+        .to_request();
+    let response = actix_web::test::call_service(&app, request).await;
+    assert_eq!(response.status(), 416);
+    assert_eq!(
+        response.headers().get("content-range").unwrap(),
+        "bytes */10"
+    );
+}
+
+#[tokio::test]
+async fn test_resources_are_fetched_once() {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    let url = serve(move |_| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        "HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\ninit".to_string()
+    })
+    .await;
+    let stream_manager = StreamManager::new(
+        ProxyConfig {
+            manifest_cache: true,
+            ..proxy_config(true)
+        },
+        CacheConfig {
+            enabled: false,
+            ..Default::default()
+        },
+    );
+
+    for _ in 0..3 {
+        let body = stream_manager
+            .fetch_resource(url.clone(), HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"init");
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    // Init segments addressed by range are told apart
+    let mut headers = HeaderMap::new();
+    headers.insert(RANGE, HeaderValue::from_static("bytes=0-3"));
+    stream_manager.fetch_resource(url, headers).await.unwrap();
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+
+/// A stream manager sharing segments through the in-memory cache.
+fn caching_stream_manager(config: ProxyConfig) -> StreamManager {
+    StreamManager::new(
+        config,
+        CacheConfig {
+            enabled: true,
+            ..Default::default()
+        },
+    )
+}
+
+#[tokio::test]
+async fn test_cached_path_relays_redirects() {
+    let url = serve(|_| {
+        "HTTP/1.1 302 Found\r\nLocation: /elsewhere.ts\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    })
+    .await;
+
+    let response = caching_stream_manager(ProxyConfig {
+        follow_redirects: false,
+        ..proxy_config(true)
+    })
+    .create_stream(url, HeaderMap::new(), false)
+    .await
+    .unwrap();
+
+    assert_eq!(response.status, StatusCode::FOUND);
+    assert_eq!(response.headers.get("location").unwrap(), "/elsewhere.ts");
+    assert!(response.body.is_none());
+}
+
+#[tokio::test]
+async fn test_large_responses_fill_the_disk_cache() {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    let url = serve(move |_| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: video/mp4\r\nContent-Length: 1000\r\nConnection: close\r\n\r\n{}",
+            "x".repeat(1000)
+        )
+    })
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    // Too large for the in-memory cache
+    let stream_manager = StreamManager::new(
+        proxy_config(true),
+        CacheConfig {
+            enabled: true,
+            max_entry_size: 100,
+            disk: Some(DiskCacheConfig {
+                path: dir.path().to_path_buf(),
+                max_size: 1 << 20,
+                eviction: EvictionPolicy::Lru,
+                ttl: 3600,
+            }),
+            ..Default::default()
+        },
+    );
+
+    let response = stream_manager
+        .create_stream(url.clone(), HeaderMap::new(), false)
+        .await
+        .unwrap();
+    assert_eq!(read_body(response).await.0.len(), 1000);
+
+    for _ in 0..200 {
+        let stored = std::fs::read_dir(dir.path())
+            .unwrap()
+            .any(|entry| entry.unwrap().path().extension() == Some("meta".as_ref()));
+        if stored {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+
+    let response = stream_manager
+        .create_stream(url, HeaderMap::new(), false)
+        .await
+        .unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(read_body(response).await.0.len(), 1000);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_cached_path_resumes_broken_bodies() {
+    let url = serve(flaky_upstream).await;
+    let stream_manager = caching_stream_manager(ProxyConfig {
+        resume_backoff_ms: 10,
+        ..proxy_config(true)
+    });
+
+    let response = stream_manager
+        .create_stream(url.clone(), HeaderMap::new(), false)
+        .await
+        .unwrap();
+    let (body, error) = read_body(response).await;
+    assert!(error.is_none());
+    assert_eq!(body, "0123456789");
+
+    let mut headers = HeaderMap::new();
+    headers.insert(RANGE, HeaderValue::from_static("bytes=1-8"));
+    let response = stream_manager
+        .create_stream(url, headers, false)
+        .await
+        .unwrap();
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    let (body, error) = read_body(response).await;
+    assert!(error.is_none());
+    assert_eq!(body, "12345678");
+}
@@ -0,0 +1,95 @@
+use actix_web::web::Bytes;
+use futures::{stream, StreamExt};
+use mediaflow_proxy_light::config::BandwidthConfig;
+use mediaflow_proxy_light::error::AppError;
+use mediaflow_proxy_light::proxy::stream::ByteStream;
+use mediaflow_proxy_light::proxy::throttle::Shaper;
+use std::time::{Duration, Instant};
+
+/// `count` chunks of 5000 bytes.
+fn body(count: usize) -> ByteStream {
+    let chunks = (0..count).map(|_| Ok::<_, AppError>(Bytes::from(vec![0u8; 5_000])));
+    Box::pin(stream::iter(chunks))
+}
+
+async fn drain(mut body: ByteStream) -> usize {
+    let mut total = 0;
+    while let Some(chunk) = body.next().await {
+        total += chunk.unwrap().len();
+    }
+    total
+}
+
+#[tokio::test]
+async fn test_unlimited_passthrough() {
+    let shaper = Shaper::new(&BandwidthConfig::default());
+    let started = Instant::now();
+    assert_eq!(drain(shaper.shape(body(20), Vec::new())).await, 100_000);
+    assert!(started.elapsed() < Duration::from_millis(50));
+    assert_eq!(shaper.active_streams(), 0);
+}
+
+#[tokio::test]
+async fn test_stream_bandwidth() {
+    let shaper = Shaper::new(&BandwidthConfig {
+        max_stream_bandwidth: Some(10_000),
+        burst: Some(5_000),
+        ..Default::default()
+    });
+
+    // The burst covers the first chunk, the other two take half a second each
+    let started = Instant::now();
+    assert_eq!(drain(shaper.shape(body(3), Vec::new())).await, 15_000);
+    assert!(started.elapsed() >= Duration::from_millis(950));
+
+    // Every stream gets its own allowance
+    let started = Instant::now();
+    let (a, b) = futures::join!(
+        drain(shaper.shape(body(2), Vec::new())),
+        drain(shaper.shape(body(2), Vec::new()))
+    );
+    assert_eq!(a + b, 20_000);
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(450) && elapsed < Duration::from_millis(900));
+}
+
+#[tokio::test]
+async fn test_total_bandwidth_shared_fairly() {
+    let shaper = Shaper::new(&BandwidthConfig {
+        max_total_bandwidth: Some(20_000),
+        burst: Some(5_000),
+        ..Default::default()
+    });
+
+    let first = shaper.shape(body(3), Vec::new());
+    let second = shaper.shape(body(3), Vec::new());
+    assert_eq!(shaper.active_streams(), 2);
+
+    // 30000 bytes at 20000 bytes/s overall, less the burst of each stream
+    let started = Instant::now();
+    let (a, b) = futures::join!(drain(first), drain(second));
+    assert_eq!(a + b, 30_000);
+    assert!(started.elapsed() >= Duration::from_millis(1_000));
+    assert_eq!(shaper.active_streams(), 0);
+}
+
+#[tokio::test]
+async fn test_idle_streams_leave_their_share() {
+    let shaper = Shaper::new(&BandwidthConfig {
+        max_total_bandwidth: Some(20_000),
+        burst: Some(5_000),
+        ..Default::default()
+    });
+
+    // Open, but with nothing to send
+    let idle = shaper.shape(Box::pin(stream::pending()), Vec::new());
+    let busy = shaper.shape(body(3), Vec::new());
+    assert_eq!(shaper.active_streams(), 2);
+
+    // 15000 bytes at the whole 20000 bytes/s, less the burst
+    let started = Instant::now();
+    assert_eq!(drain(busy).await, 15_000);
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(450) && elapsed < Duration::from_millis(900));
+    drop(idle);
+}